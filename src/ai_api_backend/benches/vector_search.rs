@@ -0,0 +1,47 @@
+//! Wall-clock comparison of brute-force and LSH vector search at several corpus sizes.
+//! Run with `cargo bench -p ai_api_backend`; the on-canister counterpart is `benchmark_search`.
+
+#[path = "../src/vector_index.rs"]
+mod vector_index;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use vector_index::{brute_force_search, random_vectors, recall, LshIndex, XorShift};
+
+const DIM: usize = 384;
+const K: usize = 5;
+const QUERIES: usize = 50;
+const SIZES: &[usize] = &[1_000, 10_000, 50_000];
+
+fn time_per_query(queries: &[Vec<f32>], mut search: impl FnMut(&[f32]) -> Vec<(usize, f32)>) -> Duration {
+    let start = Instant::now();
+    for query in queries {
+        black_box(search(query));
+    }
+    start.elapsed() / queries.len() as u32
+}
+
+fn main() {
+    let mut rng = XorShift::new(42);
+    println!("{:>8} {:>5} {:>14} {:>14} {:>12} {:>8}", "vectors", "bits", "brute/query", "lsh/query", "lsh build", "recall");
+    
+    for &size in SIZES {
+        let vectors = random_vectors(size, DIM, &mut rng);
+        let queries = random_vectors(QUERIES, DIM, &mut rng);
+        
+        let build_start = Instant::now();
+        let index = LshIndex::build(vectors.clone(), 7);
+        let build = build_start.elapsed();
+        
+        let brute = time_per_query(&queries, |q| brute_force_search(&vectors, q, K));
+        let lsh = time_per_query(&queries, |q| index.search(q, K));
+        
+        let mean_recall = queries
+            .iter()
+            .map(|q| recall(&brute_force_search(&vectors, q, K), &index.search(q, K)))
+            .sum::<f32>() / QUERIES as f32;
+        
+        println!("{:>8} {:>5} {:>14?} {:>14?} {:>12?} {:>8.2}", size, index.signature_bits(), brute, lsh, build, mean_recall);
+    }
+}
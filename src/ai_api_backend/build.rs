@@ -0,0 +1,109 @@
+//! Records build provenance for build_info(). Everything emitted here comes from the source
+//! tree and the build environment, so two builds of the same commit with the same toolchain and
+//! SOURCE_DATE_EPOCH produce the same module.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let manifest_path = Path::new(&manifest_dir).join("Cargo.toml");
+    let lock_path = Path::new(&manifest_dir).join("../../Cargo.lock");
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", lock_path.display());
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+
+    // Reproducible builds pin the timestamp; without it none is recorded rather than a wall clock
+    if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+        println!("cargo:rustc-env=BUILD_TIMESTAMP={}", epoch.trim());
+    }
+
+    let features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|name| name.to_lowercase().replace('_', "-")))
+        .collect();
+    println!("cargo:rustc-env=BUILD_FEATURES={}", sorted(features).join(","));
+
+    let manifest = fs::read_to_string(&manifest_path).unwrap_or_default();
+    let lock = fs::read_to_string(&lock_path).unwrap_or_default();
+    let dependencies: Vec<String> = direct_dependencies(&manifest)
+        .into_iter()
+        .filter_map(|(name, requirement)| {
+            locked_version(&lock, &name, &requirement).map(|version| format!("{}={}", name, version))
+        })
+        .collect();
+    println!("cargo:rustc-env=BUILD_DEPENDENCIES={}", sorted(dependencies).join(","));
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc_version.trim());
+    println!("cargo:rustc-env=BUILD_TARGET={}", env::var("TARGET").unwrap_or_default());
+    println!("cargo:rustc-env=BUILD_PROFILE={}", env::var("PROFILE").unwrap_or_default());
+}
+
+fn sorted(mut items: Vec<String>) -> Vec<String> {
+    items.sort();
+    items
+}
+
+/// (name, version requirement) of each entry under [dependencies] in Cargo.toml
+fn direct_dependencies(manifest: &str) -> Vec<(String, String)> {
+    let mut in_dependencies = false;
+    let mut dependencies = Vec::new();
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_dependencies = line == "[dependencies]";
+            continue;
+        }
+        let Some((name, spec)) = line.split_once('=').filter(|_| in_dependencies) else {
+            continue;
+        };
+        // Either `name = "1.0"` or `name = { version = "1.0", ... }`
+        let spec = spec.split_once("version").map(|(_, rest)| rest).unwrap_or(spec);
+        let requirement = spec.split('"').nth(1).unwrap_or_default();
+        dependencies.push((name.trim().to_string(), requirement.trim_start_matches(['^', '=', '~']).to_string()));
+    }
+    dependencies
+}
+
+/// Version of `name` resolved in Cargo.lock. Several versions of a crate can be locked for
+/// the workspace, so take the one matching this crate's requirement
+fn locked_version(lock: &str, name: &str, requirement: &str) -> Option<String> {
+    let name_line = format!("name = \"{}\"", name);
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line.trim() != name_line {
+            continue;
+        }
+        let version = lines.next()
+            .and_then(|version| version.trim().strip_prefix("version = \""))
+            .map(|version| version.trim_end_matches('"'));
+        if let Some(version) = version.filter(|version| caret_compatible(requirement, version)) {
+            return Some(version.to_string());
+        }
+    }
+    None
+}
+
+/// Whether `version` satisfies `requirement` under Cargo's default (caret) rules: components
+/// up to and including the first non-zero one must match
+fn caret_compatible(requirement: &str, version: &str) -> bool {
+    let mut version_parts = version.split('.');
+    for part in requirement.split('.') {
+        if version_parts.next() != Some(part) {
+            return false;
+        }
+        if part != "0" {
+            return true;
+        }
+    }
+    true
+}
@@ -0,0 +1,19 @@
+use std::cell::RefCell;
+
+// Failure injection for the PocketIC integration suite. Compiled only with the `chaos` feature
+// and never part of a production build. A trap rolls back everything the message changed, so
+// faults are not used up by the calls they fail: they stay in place until turned off.
+
+thread_local! {
+    static LLM_TIMEOUT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+pub fn set_llm_timeout(enabled: bool) {
+    LLM_TIMEOUT.with(|timeout| *timeout.borrow_mut() = enabled);
+}
+
+/// The rejection an LLM call gets instead of reaching the LLM canister, when a timeout is simulated
+pub fn llm_fault() -> Option<String> {
+    LLM_TIMEOUT.with(|timeout| *timeout.borrow())
+        .then(|| "SysTransient chaos: simulated LLM canister timeout".to_string())
+}
@@ -1,10 +1,40 @@
 use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// How long conversation memory formed in a room is kept
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RetentionPolicy {
+    None,
+    SevenDays,
+    ThirtyDays,
+    Forever,
+}
+
+impl RetentionPolicy {
+    /// Maximum age of stored memory in nanoseconds, or None when kept forever
+    pub fn max_age_ns(&self) -> Option<u64> {
+        const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+        match self {
+            RetentionPolicy::None => Some(0),
+            RetentionPolicy::SevenDays => Some(7 * DAY_NS),
+            RetentionPolicy::ThirtyDays => Some(30 * DAY_NS),
+            RetentionPolicy::Forever => None,
+        }
+    }
+}
 
 #[derive(CandidType, Deserialize, Debug)]
 pub struct RoomConfig {
     pub id: String,
     pub name: String,
     pub description: String,
+    pub retention: RetentionPolicy,
+}
+
+thread_local! {
+    // Admin overrides of the built-in per-room retention defaults
+    static ROOM_RETENTION_OVERRIDES: RefCell<HashMap<String, RetentionPolicy>> = RefCell::new(HashMap::new());
 }
 
 const DEFAULT_SYSTEM_PROMPT: &str = r#"You are Lain Iwakura from Serial Experiments Lain.
@@ -55,63 +85,130 @@ Use this context to inform your response while maintaining your character as Lai
     )
 }
 
-/// Get all available room configurations
+/// Get all available room configurations (with retention overrides applied)
 pub fn get_all_room_configs() -> Vec<RoomConfig> {
+    let mut configs = default_room_configs();
+    for config in configs.iter_mut() {
+        if let Some(policy) = ROOM_RETENTION_OVERRIDES.with(|o| o.borrow().get(&config.id).copied()) {
+            config.retention = policy;
+        }
+    }
+    configs
+}
+
+/// Effective retention policy for a room; unknown rooms keep memory forever
+pub fn get_room_retention(room_id: &str) -> RetentionPolicy {
+    if let Some(policy) = ROOM_RETENTION_OVERRIDES.with(|o| o.borrow().get(room_id).copied()) {
+        return policy;
+    }
+    
+    default_room_configs()
+        .into_iter()
+        .find(|config| config.id == room_id)
+        .map(|config| config.retention)
+        .unwrap_or(RetentionPolicy::Forever)
+}
+
+pub fn set_room_retention(room_id: &str, policy: RetentionPolicy) {
+    ROOM_RETENTION_OVERRIDES.with(|o| {
+        o.borrow_mut().insert(room_id.to_string(), policy);
+    });
+}
+
+/// Every room with an effective retention policy, including override-only rooms
+pub fn get_all_room_retentions() -> Vec<(String, RetentionPolicy)> {
+    let mut rooms: Vec<(String, RetentionPolicy)> = get_all_room_configs()
+        .into_iter()
+        .map(|config| (config.id, config.retention))
+        .collect();
+    
+    for (room_id, policy) in get_room_retention_overrides() {
+        if !rooms.iter().any(|(id, _)| *id == room_id) {
+            rooms.push((room_id, policy));
+        }
+    }
+    
+    rooms
+}
+
+// Functions for upgrade persistence
+pub fn get_room_retention_overrides() -> Vec<(String, RetentionPolicy)> {
+    ROOM_RETENTION_OVERRIDES.with(|o| o.borrow().iter().map(|(k, v)| (k.clone(), *v)).collect())
+}
+
+pub fn restore_room_retention_overrides(overrides: Vec<(String, RetentionPolicy)>) {
+    ROOM_RETENTION_OVERRIDES.with(|o| {
+        *o.borrow_mut() = overrides.into_iter().collect();
+    });
+}
+
+fn default_room_configs() -> Vec<RoomConfig> {
     vec![
         RoomConfig {
             id: "#general".to_string(),
             name: "General Chat".to_string(),
             description: "General conversation and discussion".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#tech".to_string(),
             name: "Technology".to_string(),
             description: "Programming, tech news, and innovation".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#gaming".to_string(),
             name: "Gaming".to_string(),
             description: "Video games, gaming culture, and reviews".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#food".to_string(),
             name: "Food & Cooking".to_string(),
             description: "Recipes, cooking tips, and food culture".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#random".to_string(),
             name: "Random".to_string(),
             description: "Random conversations and spontaneous topics".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#art".to_string(),
             name: "Art & Design".to_string(),
             description: "Visual arts, design, and creative techniques".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#music".to_string(),
             name: "Music".to_string(),
             description: "All genres, artists, and music discussion".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#movies".to_string(),
             name: "Movies & TV".to_string(),
             description: "Films, TV shows, and entertainment".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#sports".to_string(),
             name: "Sports".to_string(),
             description: "Sports discussion, teams, and athletics".to_string(),
+            retention: RetentionPolicy::Forever,
         },
         RoomConfig {
             id: "#news".to_string(),
             name: "News & Current Events".to_string(),
             description: "Current events and world news discussion".to_string(),
+            retention: RetentionPolicy::None,
         },
         RoomConfig {
             id: "#memes".to_string(),
             name: "Memes & Internet Culture".to_string(),
             description: "Memes, viral content, and internet culture".to_string(),
+            retention: RetentionPolicy::None,
         },
     ]
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+
+// Oldest outcomes are dropped beyond this many
+const MAX_TRACKED_CONVERSIONS: usize = 10_000;
+
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ConversionStatus {
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// A recommendation a user acted on by sending a friend request
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct RecommendationConversion {
+    pub requester: String,
+    pub recommended: String,
+    pub request_id: String,   // database_backend friend request id
+    pub similarity: f32,      // Compatibility score when the request was sent
+    pub sent_at: u64,
+    pub status: ConversionStatus,
+    pub resolved_at: Option<u64>,
+}
+
+/// How well recommendations turn into friendships
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct RecommendationMetrics {
+    pub requests_sent: u32,
+    pub accepted: u32,
+    pub rejected: u32,
+    pub pending: u32,
+    pub acceptance_rate: f32, // Accepted share of resolved requests
+    pub avg_similarity_accepted: f32,
+    pub avg_similarity_rejected: f32,
+}
+
+thread_local! {
+    static CONVERSIONS: RefCell<Vec<RecommendationConversion>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn record_sent(requester: &str, recommended: &str, request_id: &str, similarity: f32, now: u64) {
+    CONVERSIONS.with(|conversions| {
+        let mut conversions = conversions.borrow_mut();
+        conversions.push(RecommendationConversion {
+            requester: requester.to_string(),
+            recommended: recommended.to_string(),
+            request_id: request_id.to_string(),
+            similarity,
+            sent_at: now,
+            status: ConversionStatus::Pending,
+            resolved_at: None,
+        });
+        if conversions.len() > MAX_TRACKED_CONVERSIONS {
+            let excess = conversions.len() - MAX_TRACKED_CONVERSIONS;
+            conversions.drain(..excess);
+        }
+    });
+}
+
+/// Resolve a pending conversion; false when the request did not come from a recommendation
+pub fn record_outcome(request_id: &str, accepted: bool, now: u64) -> bool {
+    CONVERSIONS.with(|conversions| {
+        let mut conversions = conversions.borrow_mut();
+        let Some(conversion) = conversions
+            .iter_mut()
+            .find(|c| c.request_id == request_id && c.status == ConversionStatus::Pending)
+        else {
+            return false;
+        };
+        conversion.status = if accepted { ConversionStatus::Accepted } else { ConversionStatus::Rejected };
+        conversion.resolved_at = Some(now);
+        true
+    })
+}
+
+pub fn get_metrics() -> RecommendationMetrics {
+    CONVERSIONS.with(|conversions| {
+        let conversions = conversions.borrow();
+        let with_status = |status: ConversionStatus| conversions.iter().filter(move |c| c.status == status);
+        let average = |similarities: Vec<f32>| {
+            if similarities.is_empty() {
+                0.0
+            } else {
+                similarities.iter().sum::<f32>() / similarities.len() as f32
+            }
+        };
+
+        let accepted: Vec<f32> = with_status(ConversionStatus::Accepted).map(|c| c.similarity).collect();
+        let rejected: Vec<f32> = with_status(ConversionStatus::Rejected).map(|c| c.similarity).collect();
+        let pending = with_status(ConversionStatus::Pending).count() as u32;
+        let resolved = accepted.len() + rejected.len();
+
+        RecommendationMetrics {
+            requests_sent: conversions.len() as u32,
+            accepted: accepted.len() as u32,
+            rejected: rejected.len() as u32,
+            pending,
+            acceptance_rate: if resolved == 0 { 0.0 } else { accepted.len() as f32 / resolved as f32 },
+            avg_similarity_accepted: average(accepted),
+            avg_similarity_rejected: average(rejected),
+        }
+    })
+}
+
+pub fn get_all_conversions() -> Vec<RecommendationConversion> {
+    CONVERSIONS.with(|conversions| conversions.borrow().clone())
+}
+
+pub fn restore_conversions(stored: Vec<RecommendationConversion>) {
+    CONVERSIONS.with(|conversions| *conversions.borrow_mut() = stored);
+}
@@ -0,0 +1,208 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+
+// Maximum characters of a retrieved memory shown back to the user
+const TITLE_MAX_CHARS: usize = 60;
+
+// Answers per user whose context is kept so feedback can be applied to them
+const RECENT_ANSWERS_PER_USER: usize = 20;
+
+/// A retrieved text that went into a prompt; kept server-side and never returned verbatim
+#[derive(Debug, Clone)]
+pub struct UsedContext {
+    pub source: String,           // "personality", "wiki", "shared_memory" or "conversation_history"
+    pub category: Option<String>, // Knowledge category when known
+    pub text: String,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ContextItem {
+    pub source: String,
+    pub category: Option<String>,
+    pub title: String,            // Truncated preview, never the full text or vector
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ContextDisclosure {
+    pub answer_id: u64,           // Reference for submit_feedback
+    pub endpoint: String,         // Chat endpoint that used the context
+    pub channel_id: String,
+    pub items: Vec<ContextItem>,
+    pub recorded_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FeedbackRating {
+    Positive,
+    Negative,
+}
+
+/// Full context behind one answer, retained for the feedback loop
+#[derive(Debug, Clone)]
+pub struct AnswerContext {
+    pub answer_id: u64,
+    pub channel_id: String,
+    pub used: Vec<UsedContext>,
+    pub rated: bool,
+}
+
+/// A user's standing rating of one retrieved memory
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct MemoryRating {
+    pub user_id: String,
+    pub channel_id: String,
+    pub text: String,
+    pub rating: FeedbackRating,
+}
+
+thread_local! {
+    // (user, channel, memory text) -> the rating that user's feedback last applied to it
+    static MEMORY_RATINGS: RefCell<HashMap<(String, String, String), FeedbackRating>> = RefCell::new(HashMap::new());
+    
+    // Everything below is transient, so not persisted across upgrades
+    static LAST_CONTEXT: RefCell<HashMap<String, ContextDisclosure>> = RefCell::new(HashMap::new());
+    static RECENT_ANSWERS: RefCell<HashMap<String, VecDeque<AnswerContext>>> = RefCell::new(HashMap::new());
+    static NEXT_ANSWER_ID: RefCell<u64> = const { RefCell::new(1) };
+}
+
+/// Shorten a retrieved text to a preview safe to show the user
+pub fn redact_title(text: &str) -> String {
+    let trimmed = text.trim();
+    if trimmed.chars().count() <= TITLE_MAX_CHARS {
+        return trimmed.to_string();
+    }
+    
+    let preview: String = trimmed.chars().take(TITLE_MAX_CHARS).collect();
+    format!("{}…", preview.trim_end())
+}
+
+/// Wrap a batch of retrieved texts from one source
+pub fn items_from_texts(source: &str, texts: &[String]) -> Vec<UsedContext> {
+    texts
+        .iter()
+        .map(|text| UsedContext {
+            source: source.to_string(),
+            category: None,
+            text: text.clone(),
+        })
+        .collect()
+}
+
+/// Remember which context informed the caller's latest answer; returns the answer id
+pub fn record_context_used(user_id: &str, endpoint: &str, channel_id: &str, used: Vec<UsedContext>) -> u64 {
+    let answer_id = NEXT_ANSWER_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next;
+        *next += 1;
+        id
+    });
+    
+    let disclosure = ContextDisclosure {
+        answer_id,
+        endpoint: endpoint.to_string(),
+        channel_id: channel_id.to_string(),
+        items: used
+            .iter()
+            .map(|item| ContextItem {
+                source: item.source.clone(),
+                category: item.category.clone(),
+                title: redact_title(&item.text),
+            })
+            .collect(),
+        recorded_at: ic_cdk::api::time(),
+    };
+    
+    LAST_CONTEXT.with(|last| {
+        last.borrow_mut().insert(user_id.to_string(), disclosure);
+    });
+    
+    RECENT_ANSWERS.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        let answers = recent.entry(user_id.to_string()).or_default();
+        answers.push_back(AnswerContext {
+            answer_id,
+            channel_id: channel_id.to_string(),
+            used,
+            rated: false,
+        });
+        while answers.len() > RECENT_ANSWERS_PER_USER {
+            answers.pop_front();
+        }
+    });
+    
+    answer_id
+}
+
+pub fn get_last_context_used(user_id: &str) -> Option<ContextDisclosure> {
+    LAST_CONTEXT.with(|last| last.borrow().get(user_id).cloned())
+}
+
+/// Claim one of the user's recent answers for rating; each answer can be rated once
+pub fn take_answer_for_feedback(user_id: &str, answer_id: u64) -> Result<AnswerContext, String> {
+    RECENT_ANSWERS.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        let answer = recent
+            .get_mut(user_id)
+            .and_then(|answers| answers.iter_mut().find(|a| a.answer_id == answer_id))
+            .ok_or_else(|| "Answer not found or too old to rate".to_string())?;
+        
+        if answer.rated {
+            return Err("Answer already rated".to_string());
+        }
+        answer.rated = true;
+        Ok(answer.clone())
+    })
+}
+
+/// Record `rating` as the user's view of each of `texts` and return the importance change
+/// each memory needs, so one user moves a memory by at most one step however many answers
+/// they rate: repeating a rating changes nothing, and reversing one undoes the earlier step
+pub fn apply_memory_ratings(
+    user_id: &str,
+    channel_id: &str,
+    texts: &[String],
+    rating: FeedbackRating,
+    step: impl Fn(FeedbackRating) -> f32,
+) -> Vec<(String, f32)> {
+    MEMORY_RATINGS.with(|ratings| {
+        let mut ratings = ratings.borrow_mut();
+        texts
+            .iter()
+            .filter_map(|text| {
+                let key = (user_id.to_string(), channel_id.to_string(), text.clone());
+                let delta = match ratings.insert(key, rating) {
+                    Some(previous) if previous == rating => return None,
+                    Some(previous) => step(rating) - step(previous),
+                    None => step(rating),
+                };
+                Some((text.clone(), delta))
+            })
+            .collect()
+    })
+}
+
+// Functions for upgrade persistence
+pub fn get_all_memory_ratings() -> Vec<MemoryRating> {
+    MEMORY_RATINGS.with(|ratings| {
+        ratings
+            .borrow()
+            .iter()
+            .map(|((user_id, channel_id, text), rating)| MemoryRating {
+                user_id: user_id.clone(),
+                channel_id: channel_id.clone(),
+                text: text.clone(),
+                rating: *rating,
+            })
+            .collect()
+    })
+}
+
+pub fn restore_memory_ratings(ratings: Vec<MemoryRating>) {
+    MEMORY_RATINGS.with(|stored| {
+        *stored.borrow_mut() = ratings
+            .into_iter()
+            .map(|r| ((r.user_id, r.channel_id, r.text), r.rating))
+            .collect();
+    });
+}
@@ -0,0 +1,91 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use crate::personality;
+
+// Engagement counts half as much after this long without discussing the topic
+const ENGAGEMENT_HALF_LIFE_NS: f64 = 30.0 * 24.0 * 60.0 * 60.0 * 1_000_000_000.0;
+
+// Share of the ranking score taken by expertise; the rest is recent engagement
+const EXPERTISE_WEIGHT: f32 = 0.6;
+
+/// Entry on a topic leaderboard
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct TopicExpert {
+    pub user_id: String,
+    pub topic: String,
+    pub expertise_level: f32,
+    pub engagement_score: f32,
+    pub last_mentioned: u64,
+    pub score: f32,
+}
+
+thread_local! {
+    // Users who agreed to appear on topic leaderboards
+    static EXPERT_OPT_INS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+pub fn set_opt_in(user_id: &str, opted_in: bool) {
+    EXPERT_OPT_INS.with(|opt_ins| {
+        let mut opt_ins = opt_ins.borrow_mut();
+        if opted_in {
+            opt_ins.insert(user_id.to_string());
+        } else {
+            opt_ins.remove(user_id);
+        }
+    });
+}
+
+pub fn is_opted_in(user_id: &str) -> bool {
+    EXPERT_OPT_INS.with(|opt_ins| opt_ins.borrow().contains(user_id))
+}
+
+/// Carry a merged-away id's opt-in over to the primary
+pub fn adopt_opt_in(primary: &str, duplicate: &str) {
+    EXPERT_OPT_INS.with(|opt_ins| {
+        let mut opt_ins = opt_ins.borrow_mut();
+        if opt_ins.remove(duplicate) {
+            opt_ins.insert(primary.to_string());
+        }
+    });
+}
+
+pub fn get_all_opt_ins() -> Vec<String> {
+    EXPERT_OPT_INS.with(|opt_ins| opt_ins.borrow().iter().cloned().collect())
+}
+
+pub fn restore_opt_ins(user_ids: Vec<String>) {
+    EXPERT_OPT_INS.with(|opt_ins| *opt_ins.borrow_mut() = user_ids.into_iter().collect());
+}
+
+/// Opted-in users with profiled interest in `topic`, ranked by expertise and how
+/// recently and actively they have engaged with it
+pub fn get_topic_experts(topic: &str, limit: usize, now: u64) -> Vec<TopicExpert> {
+    let topic = topic.trim().to_lowercase();
+
+    let mut experts: Vec<TopicExpert> = personality::get_all_profiles()
+        .into_iter()
+        .filter(|profile| is_opted_in(&profile.user_id))
+        .filter_map(|profile| {
+            let interest = profile.interests.into_iter().find(|interest| interest.topic == topic)?;
+            let age = now.saturating_sub(interest.last_mentioned) as f64;
+            let recency = 0.5f64.powf(age / ENGAGEMENT_HALF_LIFE_NS) as f32;
+            let score = interest.expertise_level * EXPERTISE_WEIGHT
+                + interest.engagement_score * recency * (1.0 - EXPERTISE_WEIGHT);
+
+            Some(TopicExpert {
+                user_id: profile.user_id,
+                topic: interest.topic,
+                expertise_level: interest.expertise_level,
+                engagement_score: interest.engagement_score,
+                last_mentioned: interest.last_mentioned,
+                score,
+            })
+        })
+        .collect();
+
+    experts.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    experts.truncate(limit);
+    experts
+}
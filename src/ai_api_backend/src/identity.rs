@@ -0,0 +1,61 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Outcome of consolidating duplicate identities under one primary id
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct MergeReport {
+    pub primary: String,
+    pub merged: Vec<String>,
+    pub conversations_moved: u32,
+    pub memories_moved: u32,
+    pub profile_rebuilt: bool,
+}
+
+thread_local! {
+    // duplicate id -> primary id. Lookups through here keep stale clients writing
+    // under the consolidated identity after a merge
+    static IDENTITY_LINKS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// The id data for `user_id` lives under (itself unless it was merged away)
+pub fn resolve_user_id(user_id: &str) -> String {
+    IDENTITY_LINKS.with(|links| links.borrow().get(user_id).cloned().unwrap_or_else(|| user_id.to_string()))
+}
+
+/// Record `duplicate` as an alias of `primary`. Earlier aliases of `duplicate` are
+/// re-pointed so lookups never need more than one hop
+pub fn link_identity(primary: &str, duplicate: &str) {
+    IDENTITY_LINKS.with(|links| {
+        let mut links = links.borrow_mut();
+        for target in links.values_mut() {
+            if target == duplicate {
+                *target = primary.to_string();
+            }
+        }
+        links.insert(duplicate.to_string(), primary.to_string());
+    });
+}
+
+/// Every id that has been merged into `primary`
+pub fn get_linked_identities(primary: &str) -> Vec<String> {
+    IDENTITY_LINKS.with(|links| {
+        let mut aliases: Vec<String> = links.borrow()
+            .iter()
+            .filter(|(_, target)| *target == primary)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        aliases.sort();
+        aliases
+    })
+}
+
+// === UPGRADE PERSISTENCE ===
+
+pub fn get_all_identity_links() -> Vec<(String, String)> {
+    IDENTITY_LINKS.with(|links| links.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+}
+
+pub fn restore_identity_links(links: Vec<(String, String)>) {
+    IDENTITY_LINKS.with(|stored| *stored.borrow_mut() = links.into_iter().collect());
+}
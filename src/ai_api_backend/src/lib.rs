@@ -1,12 +1,38 @@
 use candid::{CandidType, Deserialize};
 use ic_llm::{ChatMessage, Model, ParameterType};
 use ic_cdk::storage::{stable_save, stable_restore};
+use std::time::Duration;
 
+#[cfg(feature = "chaos")]
+mod chaos;
 mod context;
+mod conversions;
+mod disclosure;
+mod experts;
+mod identity;
+mod llm;
+mod moderation;
 mod personality;
+mod presence;
+mod privacy;
+mod resummarize;
+mod sentiment;
+mod shared_memory;
 mod user_profiling;
+mod vector_index;
 
-use context::{RoomConfig, get_system_prompt_for_room, get_all_room_configs, get_enhanced_system_prompt_for_room};
+use disclosure::{ContextDisclosure, FeedbackRating, UsedContext};
+use experts::TopicExpert;
+use identity::MergeReport;
+use llm::GenerationParams;
+use moderation::{Flag, SharedSuspension};
+use privacy::{PendingPurge, PrivateRange};
+use resummarize::{ResummarizeFilter, ResummarizeJob, ResummarizeProgress};
+use sentiment::{MoodPoint, RoomActivityStats};
+use shared_memory::{CurateAction, SharedMemory, SharedMemorySource};
+use user_profiling::SimilarityWeights;
+use conversions::RecommendationMetrics;
+use context::{RoomConfig, RetentionPolicy, get_system_prompt_for_room, get_all_room_configs, get_enhanced_system_prompt_for_room};
 use personality::{
     PersonalityEmbedding,
     UserMemory,
@@ -47,14 +73,127 @@ pub struct HttpResponse {
     pub body: Vec<u8>,
 }
 
+#[derive(CandidType, Deserialize, Debug)]
+pub struct HealthStatus {
+    pub version: String,
+    pub git_commit: String,
+    pub schema_version: u32,
+    pub store_counts: Vec<(String, u64)>,
+    pub self_test_ok: bool,
+    pub checked_at: u64,
+}
+
+/// What was compiled into the running module, recorded by build.rs
+#[derive(CandidType, Deserialize, Debug)]
+pub struct BuildInfo {
+    pub package_version: String,
+    pub git_commit: String,
+    pub rustc_version: String,
+    pub target: String,
+    pub profile: String,
+    pub features: Vec<String>,
+    pub dependencies: Vec<(String, String)>, // (crate, version) as locked in Cargo.lock
+    pub build_timestamp: Option<u64>,        // SOURCE_DATE_EPOCH seconds; None if not set
+    pub wasm_module_hash: Option<String>,    // Hex sha256 of the installed module; None until looked up
+}
+
+thread_local! {
+    // Looked up after each install or upgrade; a module cannot embed its own hash at build time
+    static MODULE_HASH: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Instruction cost of brute-force vs LSH vector search on synthetic data
+#[derive(CandidType, Deserialize)]
+struct SearchBenchmark {
+    n_vectors: u32,
+    dim: u32,
+    k: u32,
+    queries: u32,
+    signature_bits: u32,
+    build_instructions: u64,
+    brute_force_instructions_per_query: u64,
+    ann_instructions_per_query: u64,
+    recall: f32,            // Mean share of the exact top-k the ANN index found
+}
+
 const MODEL: Model = Model::Llama3_1_8B;
 
+// Bump whenever the layout saved in pre_upgrade changes
+const SCHEMA_VERSION: u32 = 2;
+
+// Shared lore items included alongside personality context
+const SHARED_MEMORY_CONTEXT_SIZE: usize = 2;
+
+// Pinned memories allowed per user (across rooms), and their length
+const MAX_PINNED_MEMORIES: usize = 20;
+const MAX_PINNED_MEMORY_CHARS: usize = 500;
+
+// Conversation chunks Lain reads when extracting shared lore
+const LORE_EXTRACTION_CHUNKS: usize = 20;
+
+// Recommendation pool size, as a multiple of the requested limit, re-ranked by room presence
+const PRESENCE_CANDIDATE_FACTOR: u32 = 3;
+
+// How often room retention policies are enforced
+const RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Canister state added after the original four stores. Every field is optional so
+/// snapshots written by older builds still decode.
+#[derive(CandidType, Deserialize, Default)]
+struct ExtendedState {
+    room_retention_overrides: Option<Vec<(String, RetentionPolicy)>>,
+    shared_memories: Option<Vec<SharedMemory>>,
+    room_moderators: Option<Vec<(String, Vec<String>)>>,
+    resummarize_job: Option<ResummarizeJob>,
+    identity_links: Option<Vec<(String, String)>>,
+    database_canister: Option<candid::Principal>,
+    trusted_canisters: Option<Vec<candid::Principal>>,
+    user_timezones: Option<Vec<(String, i32)>>,
+    similarity_weights: Option<SimilarityWeights>,
+    expert_opt_ins: Option<Vec<String>>,
+    recommendation_conversions: Option<Vec<conversions::RecommendationConversion>>,
+    moderation_flags: Option<Vec<Flag>>,
+    suspensions: Option<Vec<(String, SharedSuspension)>>,
+    mood_tracking_opt_ins: Option<Vec<String>>,
+    private_ranges: Option<Vec<PrivateRange>>,
+    memory_ratings: Option<Vec<disclosure::MemoryRating>>,
+}
+
+/// `prompt` followed by the facts the user pinned in the room
+fn with_pinned_memories(prompt: String, pinned_memories: &[String]) -> String {
+    if pinned_memories.is_empty() {
+        return prompt;
+    }
+    format!("{}\n\nThe user asked you to always remember: {}", prompt, pinned_memories.join("; "))
+}
+
+/// Reply for a caller suspended on database_backend, or None if they may chat
+fn suspended_reply(user_id: &str) -> Option<String> {
+    moderation::active_suspension(user_id, ic_cdk::api::time())
+        .map(|suspension| format!("Your account is suspended: {}", suspension.reason))
+}
+
 #[ic_cdk::update]
-async fn chat(messages: Vec<ChatMessage>, room_id: Option<String>) -> String {
+async fn chat(messages: Vec<ChatMessage>, room_id: Option<String>, generation: Option<GenerationParams>) -> String {
     let channel_id = room_id.as_ref().map(|s| s.as_str()).unwrap_or("#general");
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    if let Some(reply) = suspended_reply(&user_id) {
+        return reply;
+    }
+    if let Err(reply) = llm::reject_generation(generation.as_ref()) {
+        return reply;
+    }
     
     // Automatically retrieve personality context for the channel using stored embeddings
-    let personality_context = get_channel_personality_context(channel_id, 3);
+    let mut personality_context = get_channel_personality_context(channel_id, 3);
+    let shared_context = shared_memory::get_shared_memory_context(channel_id, None, SHARED_MEMORY_CONTEXT_SIZE);
+    let pinned_memories = personality::get_pinned_memories(&user_id, channel_id);
+    
+    let mut used_context = disclosure::items_from_texts("personality", &personality_context);
+    used_context.extend(disclosure::items_from_texts("shared_memory", &shared_context));
+    used_context.extend(disclosure::items_from_texts("pinned_memory", &pinned_memories));
+    disclosure::record_context_used(&user_id, "chat", channel_id, used_context);
+    personality_context.extend(shared_context);
     
     // Use enhanced system prompt with personality context if available, otherwise fall back to basic prompt
     let system_prompt = if personality_context.is_empty() {
@@ -62,14 +201,17 @@ async fn chat(messages: Vec<ChatMessage>, room_id: Option<String>) -> String {
     } else {
         get_enhanced_system_prompt_for_room(channel_id, &personality_context)
     };
+    let system_prompt = sentiment::adjust_tone(with_pinned_memories(system_prompt, &pinned_memories), &user_id, ic_cdk::api::time());
     
     let mut all_messages = vec![ChatMessage::System {
         content: system_prompt,
     }];
     all_messages.extend(messages);
 
-    let chat = ic_llm::chat(MODEL).with_messages(all_messages);
-    let response = chat.send().await;
+    let response = match llm::chat(MODEL).with_messages(all_messages).send().await {
+        Ok(response) => response,
+        Err(error) => return error,
+    };
 
     response.message.content.unwrap_or_default()
 }
@@ -78,57 +220,62 @@ async fn chat(messages: Vec<ChatMessage>, room_id: Option<String>) -> String {
 async fn chat_with_rag(
     messages: Vec<ChatMessage>, 
     room_id: Option<String>, 
-    query_embedding: Vec<f32>
+    query_embedding: Vec<f32>,
+    generation: Option<GenerationParams>
 ) -> String {
     let channel_id = room_id.as_ref().map(|s| s.as_str()).unwrap_or("#general");
     
     // Get caller's principal as user ID
     let caller = ic_cdk::caller();
-    let user_id = caller.to_text();
-    
+    let user_id = identity::resolve_user_id(&caller.to_text());
+    if let Some(reply) = suspended_reply(&user_id) {
+        return reply;
+    }
+    if let Err(reply) = llm::reject_generation(generation.as_ref()) {
+        return reply;
+    }
     
     // Retrieve relevant personality context using RAG
-    let personality_context = search_personality_context(channel_id, &query_embedding, 3);
+    let mut personality_context = search_personality_context(channel_id, &query_embedding, 3);
+    let shared_context = shared_memory::get_shared_memory_context(channel_id, Some(&query_embedding), SHARED_MEMORY_CONTEXT_SIZE);
     
     // Get user conversation history
-    let user_conversation_context = search_conversation_history(&user_id, channel_id, &query_embedding, 2);
+    let user_conversation_context = search_conversation_history(&user_id, channel_id, &user_id, &query_embedding, 2);
+    let pinned_memories = personality::get_pinned_memories(&user_id, channel_id);
+    
+    let mut used_context = disclosure::items_from_texts("personality", &personality_context);
+    used_context.extend(disclosure::items_from_texts("shared_memory", &shared_context));
+    used_context.extend(disclosure::items_from_texts("conversation_history", &user_conversation_context));
+    used_context.extend(disclosure::items_from_texts("pinned_memory", &pinned_memories));
+    disclosure::record_context_used(&user_id, "chat_with_rag", channel_id, used_context);
+    personality_context.extend(shared_context);
     
     // Generate enhanced system prompt with retrieved context
     let enhanced_system_prompt = get_enhanced_system_prompt_for_room(channel_id, &personality_context);
+    let enhanced_system_prompt = sentiment::adjust_tone(with_pinned_memories(enhanced_system_prompt, &pinned_memories), &user_id, ic_cdk::api::time());
     
     let mut all_messages = vec![ChatMessage::System {
         content: enhanced_system_prompt,
     }];
     all_messages.extend(messages);
 
-    // Create chat with optional friendship tool for #friends channel only
-    let mut chat = ic_llm::chat(MODEL).with_messages(all_messages);
+    // Create chat with the room's tools (friend recommendations in #friends, topic experts in #tech)
+    let mut chat = llm::chat(MODEL).with_messages(all_messages);
     
-    // Add friendship recommendation tool only in #friends channel
-    if channel_id == "#friends" {
-        chat = chat.with_tools(vec![
-            ic_llm::tool("get_friendship_recommendations")
-                .with_description("Find users with compatible personality traits and interests for friendship recommendations. Use when users ask about meeting people, finding friends, or social connections.")
-                .with_parameter(
-                    ic_llm::parameter("user_id", ParameterType::String)
-                        .with_description("The user ID to find recommendations for")
-                        .is_required()
-                )
-                .with_parameter(
-                    ic_llm::parameter("limit", ParameterType::Number)
-                        .with_description("Maximum number of recommendations to return (default: 5)")
-                )
-                .build()
-        ]);
-    } else {
+    let tools = room_tools(channel_id);
+    if !tools.is_empty() {
+        chat = chat.with_tools(tools);
     }
     
-    let response = chat.send().await;
+    let response = match chat.send().await {
+        Ok(response) => response,
+        Err(error) => return error,
+    };
     
     
     // Handle tool calls if any
     if !response.message.tool_calls.is_empty() {
-        return handle_friendship_tool_calls(response, &user_id, channel_id, &personality_context, &user_conversation_context).await;
+        return handle_tool_calls(response, &user_id, channel_id, &personality_context, &user_conversation_context).await;
     }
 
     response.message.content.unwrap_or_default()
@@ -140,11 +287,18 @@ async fn chat_with_knowledge(
     messages: Vec<ChatMessage>,
     room_id: Option<String>,
     query_embedding: Vec<f32>,
-    knowledge_categories: Option<Vec<String>>
+    knowledge_categories: Option<Vec<String>>,
+    generation: Option<GenerationParams>
 ) -> String {
     let channel_id = room_id.as_ref().map(|s| s.as_str()).unwrap_or("#general");
     let caller = ic_cdk::caller();
-    let user_id = caller.to_text();
+    let user_id = identity::resolve_user_id(&caller.to_text());
+    if let Some(reply) = suspended_reply(&user_id) {
+        return reply;
+    }
+    if let Err(reply) = llm::reject_generation(generation.as_ref()) {
+        return reply;
+    }
     
     // Search unified knowledge base for relevant context
     let knowledge_results = personality::search_unified_knowledge(
@@ -156,8 +310,15 @@ async fn chat_with_knowledge(
     // Separate personality and wiki context
     let mut personality_context = Vec::new();
     let mut wiki_context = Vec::new();
+    let mut used_context = Vec::new();
     
     for result in knowledge_results {
+        used_context.push(UsedContext {
+            source: if result.category.starts_with("wiki_") { "wiki" } else { "personality" }.to_string(),
+            category: Some(result.category.clone()),
+            text: result.text.clone(),
+        });
+        
         if result.category.starts_with("wiki_") {
             wiki_context.push(format!("[{}] {}", result.source_info, result.text));
         } else {
@@ -166,7 +327,17 @@ async fn chat_with_knowledge(
     }
     
     // Get user conversation context
-    let user_conversation_context = search_conversation_history(&user_id, channel_id, &query_embedding, 2);
+    let user_conversation_context = search_conversation_history(&user_id, channel_id, &user_id, &query_embedding, 2);
+    
+    // Get shared lore for the room
+    let shared_context = shared_memory::get_shared_memory_context(channel_id, Some(&query_embedding), SHARED_MEMORY_CONTEXT_SIZE);
+    
+    let pinned_memories = personality::get_pinned_memories(&user_id, channel_id);
+    used_context.extend(disclosure::items_from_texts("shared_memory", &shared_context));
+    used_context.extend(disclosure::items_from_texts("conversation_history", &user_conversation_context));
+    used_context.extend(disclosure::items_from_texts("pinned_memory", &pinned_memories));
+    disclosure::record_context_used(&user_id, "chat_with_knowledge", channel_id, used_context);
+    personality_context.extend(shared_context);
     
     // Build enhanced system prompt with all contexts
     let base_prompt = get_system_prompt_for_room(channel_id);
@@ -185,12 +356,14 @@ async fn chat_with_knowledge(
     }
     
     let mut all_messages = vec![ChatMessage::System {
-        content: enhanced_prompt,
+        content: sentiment::adjust_tone(with_pinned_memories(enhanced_prompt, &pinned_memories), &user_id, ic_cdk::api::time()),
     }];
     all_messages.extend(messages);
     
-    let chat = ic_llm::chat(MODEL).with_messages(all_messages);
-    let response = chat.send().await;
+    let response = match llm::chat(MODEL).with_messages(all_messages).send().await {
+        Ok(response) => response,
+        Err(error) => return error,
+    };
     
     response.message.content.unwrap_or_default()
 }
@@ -200,10 +373,59 @@ fn get_available_rooms() -> Vec<RoomConfig> {
     get_all_room_configs()
 }
 
+/// Chunks, active users and mood of a room over the last week
+#[ic_cdk::query]
+fn get_room_activity_stats(room_id: String) -> RoomActivityStats {
+    sentiment::room_activity_stats(&room_id, ic_cdk::api::time())
+}
+
+/// Let the caller see (or stop keeping visible) their own daily mood trend
+#[ic_cdk::update]
+fn set_mood_tracking(enabled: bool) {
+    sentiment::set_opt_in(&identity::resolve_user_id(&ic_cdk::caller().to_text()), enabled);
+}
+
+#[ic_cdk::query]
+fn get_mood_tracking() -> bool {
+    sentiment::is_opted_in(&identity::resolve_user_id(&ic_cdk::caller().to_text()))
+}
+
+/// The caller's mood per day over the last `days` days (default 30). Only available to users
+/// who turned mood tracking on, and only for themselves
+#[ic_cdk::query]
+fn get_my_mood_trend(days: Option<u32>) -> Result<Vec<MoodPoint>, String> {
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    if !sentiment::is_opted_in(&user_id) {
+        return Err("Mood tracking is off; enable it with set_mood_tracking".to_string());
+    }
+    Ok(sentiment::user_mood_trend(&user_id, days.unwrap_or(30), ic_cdk::api::time()))
+}
+
+/// Override how long conversation memory is kept for a room (controllers only)
+#[ic_cdk::update]
+fn set_room_retention(room_id: String, policy: RetentionPolicy) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    
+    context::set_room_retention(&room_id, policy);
+    Ok(())
+}
+
+/// Prune conversation chunks and de-weight personality embeddings past each room's retention window
+fn enforce_retention_policies() {
+    let now = ic_cdk::api::time();
+    for (room_id, policy) in context::get_all_room_retentions() {
+        if let Some(max_age_ns) = policy.max_age_ns() {
+            personality::enforce_room_retention(&room_id, max_age_ns, now);
+        }
+    }
+}
+
 // Backward compatibility function (without room_id parameter)
 #[ic_cdk::update]
 async fn chat_default(messages: Vec<ChatMessage>) -> String {
-    chat(messages, None).await
+    chat(messages, None, None).await
 }
 
 // Personality management endpoints
@@ -223,11 +445,63 @@ fn store_personality_batch(embeddings: Vec<PersonalityEmbedding>) -> String {
 }
 
 #[ic_cdk::update]
-fn store_user_memory_endpoint(memory: UserMemory) -> String {
+fn store_user_memory_endpoint(mut memory: UserMemory) -> String {
+    memory.user_id = identity::resolve_user_id(&memory.user_id);
+    // Pins only come from the user through pin_memory
+    memory.pinned = None;
     store_user_memory(memory);
     "User memory stored successfully".to_string()
 }
 
+/// Pin a fact Lain should always remember about the caller in a room
+#[ic_cdk::update]
+fn pin_memory(channel_id: String, text: String) -> Result<(), String> {
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        return Err("Pinned memory cannot be empty".to_string());
+    }
+    if text.chars().count() > MAX_PINNED_MEMORY_CHARS {
+        return Err(format!("Pinned memory can be at most {} characters", MAX_PINNED_MEMORY_CHARS));
+    }
+    if personality::get_pinned_memories(&user_id, &channel_id).contains(&text) {
+        return Err("Memory is already pinned".to_string());
+    }
+    if personality::count_pinned_memories(&user_id) >= MAX_PINNED_MEMORIES {
+        return Err(format!("You can pin at most {} memories; unpin one first", MAX_PINNED_MEMORIES));
+    }
+
+    store_user_memory(UserMemory {
+        user_id,
+        text,
+        embedding: Vec::new(),
+        channel_id,
+        memory_type: "pinned".to_string(),
+        created_at: ic_cdk::api::time(),
+        visibility: Some(personality::Visibility::Private),
+        importance: Some(1.0),
+        pinned: Some(true),
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unpin_memory(channel_id: String, text: String) -> Result<(), String> {
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    if personality::unpin_memory(&user_id, &channel_id, text.trim()) {
+        Ok(())
+    } else {
+        Err("No such pinned memory".to_string())
+    }
+}
+
+/// The caller's pinned memories, in one room or all of them
+#[ic_cdk::query]
+fn get_pinned_memories(channel_id: Option<String>) -> Vec<UserMemory> {
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    personality::list_pinned_memories(&user_id, channel_id.as_deref())
+}
+
 #[ic_cdk::query]
 fn get_personality_embeddings() -> Vec<PersonalityEmbedding> {
     get_all_personality_embeddings()
@@ -282,18 +556,67 @@ fn search_knowledge_by_text(
 // === CONVERSATION EMBEDDING ENDPOINTS ===
 
 #[ic_cdk::update]
-fn store_conversation_chunk(conversation: ConversationEmbedding) -> String {
+fn store_conversation_chunk(mut conversation: ConversationEmbedding) -> String {
+    conversation.user_id = identity::resolve_user_id(&conversation.user_id);
+    if context::get_room_retention(&conversation.channel_id) == RetentionPolicy::None {
+        return "Conversation chunk not stored: room does not retain memory".to_string();
+    }
+    if privacy::is_private(&conversation) {
+        return "Conversation chunk not stored: conversation marked private".to_string();
+    }
+    conversation.sentiment = Some(sentiment::score(&conversation.conversation_text));
     store_conversation_embedding(conversation);
     "Conversation chunk stored successfully".to_string()
 }
 
+/// Keep Lain from learning from the caller's conversation in `channel_id` between `from_ts`
+/// and `to_ts` (inclusive). Chunks in the range are no longer stored; ones stored earlier
+/// are removed once a controller has reviewed the request and purged them
+#[ic_cdk::update]
+fn mark_conversation_private(channel_id: String, from_ts: u64, to_ts: u64) -> Result<PrivateRange, String> {
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    privacy::mark(&user_id, channel_id.trim(), from_ts, to_ts, ic_cdk::api::time())
+}
+
+#[ic_cdk::query]
+fn get_my_private_ranges() -> Vec<PrivateRange> {
+    privacy::ranges_of(&identity::resolve_user_id(&ic_cdk::caller().to_text()))
+}
+
+/// Private ranges still waiting for a purge, with how many stored chunks each would remove
+#[ic_cdk::query]
+fn get_pending_private_purges() -> Result<Vec<PendingPurge>, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    Ok(privacy::pending_purges())
+}
+
+/// Remove the stored chunks overlapping the reviewed ranges `range_ids`; returns the number
+/// of chunks removed
+#[ic_cdk::update]
+fn purge_private_conversations(range_ids: Vec<u64>) -> Result<u32, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    privacy::purge(&range_ids, ic_cdk::api::time())
+}
+
 #[ic_cdk::query]
 fn get_user_conversations(user_id: String, channel_id: String) -> Vec<ConversationEmbedding> {
+    let user_id = identity::resolve_user_id(&user_id);
+    let viewer_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
     get_user_conversation_history(&user_id, &channel_id)
+        .into_iter()
+        .filter(|conv| {
+            personality::is_visible(conv.resolved_visibility(), Some(&conv.user_id), &conv.channel_id, Some(&viewer_id), Some(&channel_id))
+        })
+        .collect()
 }
 
 #[ic_cdk::query]
 fn get_next_conversation_chunk_index(user_id: String, channel_id: String) -> u32 {
+    let user_id = identity::resolve_user_id(&user_id);
     get_next_chunk_index(&user_id, &channel_id)
 }
 
@@ -304,8 +627,10 @@ fn search_user_conversation_history(
     query_embedding: Vec<f32>,
     limit: Option<u32>
 ) -> Vec<String> {
+    let user_id = identity::resolve_user_id(&user_id);
+    let viewer_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
     let top_k = limit.unwrap_or(3) as usize;
-    search_conversation_history(&user_id, &channel_id, &query_embedding, top_k)
+    search_conversation_history(&user_id, &channel_id, &viewer_id, &query_embedding, top_k)
 }
 
 #[ic_cdk::query]
@@ -314,12 +639,15 @@ fn get_recent_user_conversations(
     channel_id: String,
     chunk_count: Option<u32>
 ) -> Vec<String> {
+    let user_id = identity::resolve_user_id(&user_id);
+    let viewer_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
     let count = chunk_count.unwrap_or(3) as usize;
-    get_recent_conversation_context(&user_id, &channel_id, count)
+    get_recent_conversation_context(&user_id, &channel_id, &viewer_id, count)
 }
 
 #[ic_cdk::query]
 fn get_user_conversation_stats(user_id: String, channel_id: String) -> (u32, u32) {
+    let user_id = identity::resolve_user_id(&user_id);
     get_conversation_stats(&user_id, &channel_id)
 }
 
@@ -329,15 +657,38 @@ async fn chat_with_user_context(
     messages: Vec<ChatMessage>,
     user_id: String,
     room_id: Option<String>,
-    query_embedding: Vec<f32>
+    query_embedding: Vec<f32>,
+    generation: Option<GenerationParams>
 ) -> String {
     let channel_id = room_id.as_ref().map(|s| s.as_str()).unwrap_or("#general");
+    let user_id = identity::resolve_user_id(&user_id);
+    let viewer_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    if let Some(reply) = suspended_reply(&viewer_id) {
+        return reply;
+    }
+    if let Err(reply) = llm::reject_generation(generation.as_ref()) {
+        return reply;
+    }
     
     // Get personality context
-    let personality_context = search_personality_context(channel_id, &query_embedding, 2);
+    let mut personality_context = search_personality_context(channel_id, &query_embedding, 2);
+    let shared_context = shared_memory::get_shared_memory_context(channel_id, Some(&query_embedding), SHARED_MEMORY_CONTEXT_SIZE);
     
     // Get user conversation history
-    let user_conversation_context = search_conversation_history(&user_id, channel_id, &query_embedding, 2);
+    let user_conversation_context = search_conversation_history(&user_id, channel_id, &viewer_id, &query_embedding, 2);
+    // Pins are private to the user who made them
+    let pinned_memories = if viewer_id == user_id {
+        personality::get_pinned_memories(&user_id, channel_id)
+    } else {
+        Vec::new()
+    };
+    
+    let mut used_context = disclosure::items_from_texts("personality", &personality_context);
+    used_context.extend(disclosure::items_from_texts("shared_memory", &shared_context));
+    used_context.extend(disclosure::items_from_texts("conversation_history", &user_conversation_context));
+    used_context.extend(disclosure::items_from_texts("pinned_memory", &pinned_memories));
+    disclosure::record_context_used(&viewer_id, "chat_with_user_context", channel_id, used_context);
+    personality_context.extend(shared_context);
     
     // Combine contexts
     let mut context_parts = Vec::new();
@@ -363,18 +714,39 @@ async fn chat_with_user_context(
     } else {
         format!("{}{}", base_prompt, enhanced_context)
     };
+    let system_prompt = sentiment::adjust_tone(with_pinned_memories(system_prompt, &pinned_memories), &viewer_id, ic_cdk::api::time());
     
     let mut all_messages = vec![ChatMessage::System {
         content: system_prompt,
     }];
     all_messages.extend(messages);
 
-    // Create chat with optional friendship tool for #friends channel only
-    let mut chat = ic_llm::chat(MODEL).with_messages(all_messages);
+    // Create chat with the room's tools (friend recommendations in #friends, topic experts in #tech)
+    let mut chat = llm::chat(MODEL).with_messages(all_messages);
+    
+    let tools = room_tools(channel_id);
+    if !tools.is_empty() {
+        chat = chat.with_tools(tools);
+    }
+    
+    let response = match chat.send().await {
+        Ok(response) => response,
+        Err(error) => return error,
+    };
+    
+    
+    // Handle tool calls if any
+    if !response.message.tool_calls.is_empty() {
+        return handle_tool_calls(response, &user_id, channel_id, &personality_context, &user_conversation_context).await;
+    }
     
-    // Add friendship recommendation tool only in #friends channel
-    if channel_id == "#friends" {
-        chat = chat.with_tools(vec![
+    response.message.content.unwrap_or_default()
+}
+
+/// Tools Lain may call in a room
+fn room_tools(channel_id: &str) -> Vec<ic_llm::Tool> {
+    match channel_id {
+        "#friends" => vec![
             ic_llm::tool("get_friendship_recommendations")
                 .with_description("Find users with compatible personality traits and interests for friendship recommendations. Use when users ask about meeting people, finding friends, or social connections.")
                 .with_parameter(
@@ -387,23 +759,27 @@ async fn chat_with_user_context(
                         .with_description("Maximum number of recommendations to return (default: 5)")
                 )
                 .build()
-        ]);
-    } else {
-    }
-    
-    let response = chat.send().await;
-    
-    
-    // Handle tool calls if any
-    if !response.message.tool_calls.is_empty() {
-        return handle_friendship_tool_calls(response, &user_id, channel_id, &personality_context, &user_conversation_context).await;
+        ],
+        "#tech" => vec![
+            ic_llm::tool("get_topic_experts")
+                .with_description("Find people who know a topic well and have agreed to be asked about it. Use when someone needs help and another user could answer, then suggest them by @-mentioning.")
+                .with_parameter(
+                    ic_llm::parameter("topic", ParameterType::String)
+                        .with_description("One of: technology, art, music, philosophy, science, relationships, gaming, books, movies, food")
+                        .is_required()
+                )
+                .with_parameter(
+                    ic_llm::parameter("limit", ParameterType::Number)
+                        .with_description("Maximum number of people to return (default: 3)")
+                )
+                .build()
+        ],
+        _ => Vec::new(),
     }
-    
-    response.message.content.unwrap_or_default()
 }
 
-/// Handle friendship tool calls and generate follow-up response
-async fn handle_friendship_tool_calls(
+/// Handle tool calls and generate follow-up response
+async fn handle_tool_calls(
     response: ic_llm::Response,
     user_id: &str,
     channel_id: &str,
@@ -425,23 +801,49 @@ async fn handle_friendship_tool_calls(
                     .unwrap_or(5);
                 
                 
-                // Get recommendations
-                let recommendations = get_friendship_recommendations(target_user_id, Some(limit));
+                // Get recommendations from a wider pool so people in the room right now can be preferred
+                let candidates = get_friendship_recommendations(target_user_id, Some(limit.saturating_mul(PRESENCE_CANDIDATE_FACTOR)));
+                let recommendations = presence::prefer_active(channel_id, candidates, limit as usize, ic_cdk::api::time());
                 
                 
                 let result = if recommendations.is_empty() {
                     "No friendship recommendations found. You might want to have more conversations first to build your profile!".to_string()
                 } else {
                     let mut formatted = "Here are your friendship recommendations based on personality and interest compatibility:\n\n".to_string();
-                    for (i, (recommended_user_id, similarity)) in recommendations.iter().enumerate() {
-                        formatted.push_str(&format!("{}. **{}** - {}% compatibility\n", 
-                            i + 1, recommended_user_id, (similarity * 100.0) as u32));
+                    for (i, (recommended_user_id, similarity, active)) in recommendations.iter().enumerate() {
+                        formatted.push_str(&format!("{}. **{}** - {}% compatibility{}\n", 
+                            i + 1, recommended_user_id, (similarity * 100.0) as u32,
+                            if *active { " (in this room now)" } else { "" }));
                     }
+                    formatted.push_str(&format!("\nCompatibility weighs {}.", user_profiling::get_similarity_weights().describe()));
                     formatted.push_str("\nWould you like to know more about what makes you compatible with any of these users?");
                     formatted
                 };
                 
                 
+                tool_results.push(ChatMessage::Tool {
+                    content: result,
+                    tool_call_id: tool_call.id.clone(),
+                });
+            }
+            "get_topic_experts" => {
+                let topic = tool_call.function.get("topic").unwrap_or_default();
+                let limit = tool_call.function.get("limit")
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .unwrap_or(3);
+                
+                let experts = experts::get_topic_experts(&topic, limit, ic_cdk::api::time());
+                let result = if experts.is_empty() {
+                    format!("Nobody has opted in as someone to ask about {} yet.", topic)
+                } else {
+                    let mut formatted = format!("People worth asking about {}:\n\n", topic);
+                    for expert in &experts {
+                        formatted.push_str(&format!("- @{} (expertise {}%)\n",
+                            expert.user_id, (expert.expertise_level * 100.0) as u32));
+                    }
+                    formatted
+                };
+                
                 tool_results.push(ChatMessage::Tool {
                     content: result,
                     tool_call_id: tool_call.id.clone(),
@@ -466,24 +868,343 @@ async fn handle_friendship_tool_calls(
     follow_up_messages.extend(tool_results);
 
     
-    let follow_up_response = ic_llm::chat(MODEL)
-        .with_messages(follow_up_messages)
+    match llm::chat(MODEL).with_messages(follow_up_messages).send().await {
+        Ok(follow_up_response) => follow_up_response.message.content.unwrap_or_default(),
+        Err(error) => error,
+    }
+}
+
+// === SHARED MEMORY (GROUP LORE) ENDPOINTS ===
+
+#[ic_cdk::update]
+fn add_room_moderator(room_id: String, moderator: candid::Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    
+    shared_memory::add_room_moderator(&room_id, &moderator.to_text());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_room_moderator(room_id: String, moderator: candid::Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    
+    shared_memory::remove_room_moderator(&room_id, &moderator.to_text());
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_room_moderators(room_id: String) -> Vec<String> {
+    shared_memory::get_room_moderators(&room_id)
+}
+
+#[ic_cdk::update]
+fn add_shared_memory(
+    channel_id: String,
+    text: String,
+    embedding: Option<Vec<f32>>,
+    importance: Option<f32>
+) -> Result<SharedMemory, String> {
+    let caller = ic_cdk::caller();
+    if !shared_memory::is_room_moderator(&channel_id, &caller) {
+        return Err("Unauthorized: caller is not a moderator of this room".to_string());
+    }
+    if text.trim().is_empty() {
+        return Err("Shared memory text cannot be empty".to_string());
+    }
+    
+    Ok(shared_memory::add_shared_memory(
+        &channel_id,
+        text,
+        embedding.unwrap_or_default(),
+        SharedMemorySource::Moderator,
+        caller.to_text(),
+        importance.unwrap_or(0.8),
+    ))
+}
+
+/// List a room's shared memories; moderators also see extracted lore awaiting approval
+#[ic_cdk::query]
+fn list_shared_memories(channel_id: String, include_pending: bool) -> Result<Vec<SharedMemory>, String> {
+    if !shared_memory::is_room_moderator(&channel_id, &ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a moderator of this room".to_string());
+    }
+    
+    Ok(shared_memory::list_shared_memories(&channel_id, include_pending))
+}
+
+#[ic_cdk::update]
+fn curate_shared_memory(id: u64, action: CurateAction) -> Result<Option<SharedMemory>, String> {
+    let memory = shared_memory::get_shared_memory(id)
+        .ok_or_else(|| "Shared memory not found".to_string())?;
+    if !shared_memory::is_room_moderator(&memory.channel_id, &ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a moderator of this room".to_string());
+    }
+    
+    Ok(shared_memory::curate_shared_memory(id, action))
+}
+
+/// Ask Lain to propose group lore from recent room conversations; results await moderator approval
+#[ic_cdk::update]
+async fn extract_shared_memories(channel_id: String) -> Result<Vec<SharedMemory>, String> {
+    if !shared_memory::is_room_moderator(&channel_id, &ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a moderator of this room".to_string());
+    }
+    
+    let recent = personality::get_recent_channel_conversations(&channel_id, LORE_EXTRACTION_CHUNKS);
+    if recent.is_empty() {
+        return Ok(Vec::new());
+    }
+    
+    let instructions = format!(
+        "You maintain the shared lore of the {} room. From the conversations below, list up to {} running jokes, \
+        shared facts or community traditions that the whole room would recognize. One item per line, no numbering. \
+        Skip anything personal about a single user. Reply with NONE if there is nothing worth keeping.",
+        channel_id,
+        shared_memory::MAX_EXTRACTED_PER_PASS
+    );
+    
+    let response = llm::chat(MODEL)
+        .with_messages(vec![
+            ChatMessage::System { content: instructions },
+            ChatMessage::User { content: recent.join("\n---\n") },
+        ])
         .send()
-        .await;
+        .await?;
+    
+    let extracted = shared_memory::parse_extracted_lore(&response.message.content.unwrap_or_default())
+        .into_iter()
+        .map(|text| shared_memory::add_shared_memory(
+            &channel_id,
+            text,
+            Vec::new(),
+            SharedMemorySource::Extracted,
+            "lain".to_string(),
+            0.6,
+        ))
+        .collect();
+    
+    Ok(extracted)
+}
+
+/// What informed the caller's most recent chat answer (previews only, no vectors)
+#[ic_cdk::query]
+fn get_last_context_used() -> Option<ContextDisclosure> {
+    disclosure::get_last_context_used(&identity::resolve_user_id(&ic_cdk::caller().to_text()))
+}
+
+// Importance shift applied to the context behind a rated answer
+const FEEDBACK_BOOST: f32 = 0.05;
+const FEEDBACK_DECAY: f32 = 0.1;
+// Rated memories never drop out entirely or exceed full importance
+const FEEDBACK_MIN_IMPORTANCE: f32 = 0.05;
+const FEEDBACK_MAX_IMPORTANCE: f32 = 1.0;
 
+fn feedback_step(rating: FeedbackRating) -> f32 {
+    match rating {
+        FeedbackRating::Positive => FEEDBACK_BOOST,
+        FeedbackRating::Negative => -FEEDBACK_DECAY,
+    }
+}
+
+/// Rate one of the caller's recent answers (see `answer_id` in get_last_context_used).
+/// Boosts the importance of the memories behind a positive answer and decays those
+/// behind a negative one. Each user moves a given memory by one step at most; memories
+/// they already rated the same way are left alone. Returns how many memories were adjusted
+#[ic_cdk::update]
+fn submit_feedback(answer_id: u64, rating: FeedbackRating) -> Result<u32, String> {
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    let answer = disclosure::take_answer_for_feedback(&user_id, answer_id)?;
+    
+    // Conversation history is the user's own transcript, not ranked memory, so it is left alone
+    let texts_from = |sources: &[&str]| -> Vec<String> {
+        answer
+            .used
+            .iter()
+            .filter(|item| sources.contains(&item.source.as_str()))
+            .map(|item| item.text.clone())
+            .collect()
+    };
+    
+    let personality_changes = disclosure::apply_memory_ratings(
+        &user_id,
+        &answer.channel_id,
+        &texts_from(&["personality", "wiki"]),
+        rating,
+        feedback_step,
+    );
+    let shared_changes = disclosure::apply_memory_ratings(
+        &user_id,
+        &answer.channel_id,
+        &texts_from(&["shared_memory"]),
+        rating,
+        feedback_step,
+    );
+    
+    let mut adjusted = 0;
+    for (text, delta) in personality_changes {
+        adjusted += personality::adjust_personality_importance(
+            &answer.channel_id,
+            &[text],
+            delta,
+            FEEDBACK_MIN_IMPORTANCE,
+            FEEDBACK_MAX_IMPORTANCE,
+        );
+    }
+    for (text, delta) in shared_changes {
+        adjusted += shared_memory::adjust_shared_memory_importance(
+            &answer.channel_id,
+            &[text],
+            delta,
+            FEEDBACK_MIN_IMPORTANCE,
+            FEEDBACK_MAX_IMPORTANCE,
+        );
+    }
     
-    follow_up_response.message.content.unwrap_or_default()
+    Ok(adjusted)
+}
+
+/// Regenerate the summaries of every conversation chunk matching `filter` with the
+/// current summarization prompt. Runs in batches of `batch_size` across timer ticks
+#[ic_cdk::update]
+fn resummarize(filter: ResummarizeFilter, batch_size: Option<u32>) -> Result<ResummarizeProgress, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    resummarize::start(filter, batch_size)
+}
+
+#[ic_cdk::update]
+fn cancel_resummarize() -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    if !resummarize::cancel() {
+        return Err("No resummarize job is running".to_string());
+    }
+    Ok(())
+}
+
+/// Progress of the running or most recent resummarize job
+#[ic_cdk::query]
+fn get_resummarize_progress() -> Option<ResummarizeProgress> {
+    resummarize::get_progress()
+}
+
+// === IDENTITY LINKING ===
+
+/// Consolidate duplicate identities (e.g. a text id and a principal, or a pre-migration
+/// principal) under `primary`. Conversations, memories and profiles move to the primary,
+/// and later reads and writes for a duplicate id are redirected to it
+#[ic_cdk::update]
+fn merge_identities(primary: String, duplicates: Vec<String>) -> Result<MergeReport, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    
+    let primary = identity::resolve_user_id(primary.trim());
+    if primary.is_empty() {
+        return Err("Primary id cannot be empty".to_string());
+    }
+    
+    let mut report = MergeReport {
+        primary: primary.clone(),
+        merged: Vec::new(),
+        conversations_moved: 0,
+        memories_moved: 0,
+        profile_rebuilt: false,
+    };
+    
+    for duplicate in duplicates {
+        let duplicate = duplicate.trim().to_string();
+        if duplicate.is_empty() || duplicate == primary || report.merged.contains(&duplicate) {
+            continue;
+        }
+        
+        let (chunks, memories) = personality::reassign_user_data(&primary, &duplicate);
+        identity::link_identity(&primary, &duplicate);
+        user_profiling::adopt_timezone(&primary, &duplicate);
+        experts::adopt_opt_in(&primary, &duplicate);
+        sentiment::adopt_opt_in(&primary, &duplicate);
+        privacy::adopt_ranges(&primary, &duplicate);
+        report.conversations_moved += chunks;
+        report.memories_moved += memories;
+        report.merged.push(duplicate);
+    }
+    
+    if report.conversations_moved > 0 {
+        report.profile_rebuilt = generate_user_profile(&primary).is_some();
+    }
+    
+    Ok(report)
+}
+
+/// Ids that have been merged into `primary`
+#[ic_cdk::query]
+fn get_linked_identities(primary: String) -> Vec<String> {
+    identity::get_linked_identities(&identity::resolve_user_id(&primary))
+}
+
+// === ROOM PRESENCE ===
+
+/// The database_backend canister this canister calls. Calls into this canister are
+/// authorized separately by the trusted canister set
+#[ic_cdk::update]
+fn set_database_canister(canister: Option<candid::Principal>) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    presence::set_database_canister(canister);
+    Ok(())
+}
+
+/// Replace the set of canisters allowed to call integration endpoints
+#[ic_cdk::update]
+fn set_trusted_canisters(canisters: Vec<candid::Principal>) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    presence::set_trusted_canisters(canisters);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_trusted_canisters() -> Result<Vec<candid::Principal>, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    Ok(presence::get_trusted_canisters())
+}
+
+/// Presence snapshot pushed by database_backend: active principals per room. Large
+/// snapshots arrive in several calls; `continuation` marks every call after the first
+#[ic_cdk::update]
+fn update_room_presence(rooms: Vec<(String, Vec<candid::Principal>)>, continuation: Option<bool>) -> Result<(), String> {
+    if !presence::is_trusted_canister(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a trusted canister".to_string());
+    }
+    presence::update_room_presence(rooms, continuation.unwrap_or(false), ic_cdk::api::time())
+}
+
+#[ic_cdk::query]
+fn get_room_presence(room_id: String) -> Vec<String> {
+    presence::get_active_users(&room_id, ic_cdk::api::time())
 }
 
 // === USER PROFILING API ENDPOINTS ===
 
 #[ic_cdk::query]
 pub fn get_user_profile_by_id(user_id: String) -> Option<UserProfile> {
+    let user_id = identity::resolve_user_id(&user_id);
     get_user_profile(&user_id)
 }
 
 #[ic_cdk::update]
 pub fn create_user_profile(user_id: String) -> Option<UserProfile> {
+    let user_id = identity::resolve_user_id(&user_id);
     generate_user_profile(&user_id)
 }
 
@@ -494,6 +1215,7 @@ pub fn get_all_user_profiles() -> Vec<UserProfile> {
 
 #[ic_cdk::query]
 pub fn analyze_user_personality(user_id: String) -> Option<BigFiveTraits> {
+    let user_id = identity::resolve_user_id(&user_id);
     let conversations = get_user_conversation_history(&user_id, "");
     if conversations.is_empty() {
         return None;
@@ -509,24 +1231,365 @@ pub fn analyze_user_personality(user_id: String) -> Option<BigFiveTraits> {
 
 #[ic_cdk::query]
 pub fn analyze_user_interests(user_id: String) -> Vec<TopicInterest> {
+    let user_id = identity::resolve_user_id(&user_id);
     let conversations = get_user_conversation_history(&user_id, "");
     analyze_topic_interests(&conversations)
 }
 
 #[ic_cdk::query]
 pub fn calculate_user_similarity(user1_id: String, user2_id: String) -> Option<f32> {
-    let profile1 = get_user_profile(&user1_id)?;
-    let profile2 = get_user_profile(&user2_id)?;
+    let profile1 = get_user_profile(&identity::resolve_user_id(&user1_id))?;
+    let profile2 = get_user_profile(&identity::resolve_user_id(&user2_id))?;
     
     Some(user_profiling::calculate_user_similarity(&profile1, &profile2))
 }
 
 #[ic_cdk::query]
 pub fn get_friendship_recommendations(user_id: String, limit: Option<u32>) -> Vec<(String, f32)> {
+    let user_id = identity::resolve_user_id(&user_id);
     let limit = limit.unwrap_or(10);
     user_profiling::get_friendship_recommendations(&user_id, limit)
 }
 
+// Only users in the caller's top recommendations can be sent a request through the AI
+const ACTIONABLE_RECOMMENDATIONS: u32 = 20;
+
+/// The parts of database_backend's ApiResponse<FriendRequest> this canister reads
+#[derive(CandidType, Deserialize)]
+struct DbFriendRequestResponse {
+    success: bool,
+    data: Option<DbFriendRequest>,
+    error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DbFriendRequest {
+    id: String,
+}
+
+/// Send a friend request to one of the caller's recommendations. The caller must have
+/// granted this canister the friend-request-send scope on database_backend; returns the
+/// new request id
+#[ic_cdk::update]
+async fn act_on_recommendation(recommended_user: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let user_id = identity::resolve_user_id(&caller.to_text());
+    let recommended_user = identity::resolve_user_id(&recommended_user);
+    if let Some(reply) = suspended_reply(&user_id) {
+        return Err(reply);
+    }
+
+    let Some(database_canister) = presence::get_database_canister() else {
+        return Err("Database canister is not configured".to_string());
+    };
+    if !presence::is_trusted_canister(&database_canister) {
+        return Err("Database canister is not in the trusted canister set".to_string());
+    }
+    let Some(similarity) = user_profiling::get_friendship_recommendations(&user_id, ACTIONABLE_RECOMMENDATIONS)
+        .into_iter()
+        .find(|(candidate, _)| *candidate == recommended_user)
+        .map(|(_, similarity)| similarity)
+    else {
+        return Err("User is not among your friendship recommendations".to_string());
+    };
+    let target = candid::Principal::from_text(&recommended_user)
+        .map_err(|_| "Recommended user is not a principal".to_string())?;
+
+    let (response,): (DbFriendRequestResponse,) =
+        ic_cdk::call(database_canister, "app_send_friend_request", (caller, target))
+            .await
+            .map_err(|(code, message)| format!("Database canister call failed: {:?} {}", code, message))?;
+    let request = match response.data {
+        Some(request) if response.success => request,
+        _ => return Err(response.error.unwrap_or_else(|| "Friend request was not sent".to_string())),
+    };
+
+    conversions::record_sent(&user_id, &recommended_user, &request.id, similarity, ic_cdk::api::time());
+    Ok(request.id)
+}
+
+/// Accept/reject outcome pushed by database_backend for a friend request
+#[ic_cdk::update]
+fn record_friend_request_outcome(request_id: String, accepted: bool) {
+    if !presence::is_trusted_canister(&ic_cdk::caller()) {
+        ic_cdk::trap("Unauthorized: caller is not a trusted canister");
+    }
+    conversions::record_outcome(&request_id, accepted, ic_cdk::api::time());
+}
+
+/// Flag a user for abuse in a room. Once enough different moderators have flagged the user
+/// recently, database_backend is told so it can lower their trust tier; returns how many
+/// different moderators did
+#[ic_cdk::update]
+fn flag_user(user_id: String, room_id: String, reason: String) -> Result<u32, String> {
+    let caller = ic_cdk::caller();
+    if !shared_memory::is_room_moderator(&room_id, &caller) {
+        return Err("Unauthorized: caller is not a moderator of this room".to_string());
+    }
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return Err("A reason is required to flag a user".to_string());
+    }
+
+    let user_id = identity::resolve_user_id(&user_id);
+    let now = ic_cdk::api::time();
+    moderation::add_flag(Flag {
+        user_id: user_id.clone(),
+        room_id,
+        reason,
+        flagged_by: caller.to_text(),
+        flagged_at: now,
+    });
+
+    let recent = moderation::recent_flags(&user_id, now);
+    let count = moderation::distinct_flaggers(&recent);
+    if count >= moderation::REPORT_THRESHOLD {
+        report_abuse_signal(&user_id, count, recent.into_iter().map(|flag| format!("{}: {}", flag.room_id, flag.reason)).collect());
+    }
+    Ok(count)
+}
+
+/// Fire-and-forget report of repeated flags to database_backend. Users whose id is not a
+/// principal have no account there and are skipped
+fn report_abuse_signal(user_id: &str, flag_count: u32, reasons: Vec<String>) {
+    let Some(database_canister) = presence::get_database_canister() else {
+        return;
+    };
+    if !presence::is_trusted_canister(&database_canister) {
+        return;
+    }
+    let Ok(user) = candid::Principal::from_text(user_id) else {
+        return;
+    };
+
+    if let Err(code) = ic_cdk::notify(database_canister, "report_abuse_signal", (user, flag_count, reasons)) {
+        ic_cdk::println!("abuse signal for {} not sent: {:?}", user_id, code);
+    }
+}
+
+/// Every flag raised against a user, oldest first
+#[ic_cdk::query]
+fn get_user_flags(user_id: String) -> Result<Vec<Flag>, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    Ok(moderation::get_user_flags(&identity::resolve_user_id(&user_id)))
+}
+
+/// Suspension pushed by database_backend when an account is suspended (Some) or the
+/// suspension is lifted (None)
+#[ic_cdk::update]
+fn apply_suspension(user: candid::Principal, suspension: Option<SharedSuspension>) {
+    if !presence::is_trusted_canister(&ic_cdk::caller()) {
+        ic_cdk::trap("Unauthorized: caller is not a trusted canister");
+    }
+    moderation::set_suspension(&identity::resolve_user_id(&user.to_text()), suspension);
+}
+
+/// How often acted-on recommendations became friendships
+#[ic_cdk::query]
+fn get_recommendation_metrics() -> RecommendationMetrics {
+    conversions::get_metrics()
+}
+
+/// Opt the caller in to (or out of) topic leaderboards
+#[ic_cdk::update]
+fn set_topic_expert_opt_in(opted_in: bool) {
+    experts::set_opt_in(&identity::resolve_user_id(&ic_cdk::caller().to_text()), opted_in);
+}
+
+#[ic_cdk::query]
+fn get_topic_expert_opt_in() -> bool {
+    experts::is_opted_in(&identity::resolve_user_id(&ic_cdk::caller().to_text()))
+}
+
+/// Opted-in users ranked by expertise in `topic` and recent engagement with it
+#[ic_cdk::query]
+fn get_topic_experts(topic: String, limit: Option<u32>) -> Vec<TopicExpert> {
+    experts::get_topic_experts(&topic, limit.unwrap_or(10) as usize, ic_cdk::api::time())
+}
+
+/// Replace the weights calculate_similarity combines its components with. They must be
+/// non-negative and sum to 1.0
+#[ic_cdk::update]
+fn set_similarity_weights(weights: SimilarityWeights) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    user_profiling::set_similarity_weights(weights)
+}
+
+#[ic_cdk::query]
+fn get_similarity_weights() -> SimilarityWeights {
+    user_profiling::get_similarity_weights()
+}
+
+/// Set or clear the caller's UTC offset (minutes). Recommendations use it to estimate
+/// when the caller is awake until enough chat history exists to observe it
+#[ic_cdk::update]
+fn set_timezone_offset(offset_minutes: Option<i32>) -> Result<(), String> {
+    let user_id = identity::resolve_user_id(&ic_cdk::caller().to_text());
+    user_profiling::set_timezone_offset(&user_id, offset_minutes)
+}
+
+#[ic_cdk::query]
+fn get_timezone_offset() -> Option<i32> {
+    user_profiling::get_timezone_offset(&identity::resolve_user_id(&ic_cdk::caller().to_text()))
+}
+
+// === HEALTH ===
+
+/// Round-trip a probe record through the same Candid encoding used by pre_upgrade
+fn serialization_self_test(now: u64) -> bool {
+    let probe = PersonalityEmbedding {
+        text: "health-probe".to_string(),
+        embedding: vec![0.5, -0.5],
+        channel_id: "#health".to_string(),
+        category: "probe".to_string(),
+        importance: 1.0,
+        created_at: now,
+        visibility: None,
+    };
+    
+    candid::encode_one(&probe)
+        .ok()
+        .and_then(|bytes| candid::decode_one::<PersonalityEmbedding>(&bytes).ok())
+        .map(|decoded| decoded.created_at == now && decoded.embedding == probe.embedding)
+        .unwrap_or(false)
+}
+
+// === BENCHMARKS ===
+
+// Largest synthetic corpus (n_vectors * dim floats) a benchmark may allocate
+const MAX_BENCHMARK_FLOATS: u64 = 4_000_000;
+
+// Queries averaged per benchmark run
+const BENCHMARK_QUERIES: u32 = 10;
+
+/// Measure instructions for brute-force vs ANN search over `n_vectors` random vectors of `dim`
+/// dimensions. Call with increasing sizes to chart how each approach scales
+#[ic_cdk::update]
+fn benchmark_search(n_vectors: u32, dim: u32, k: u32) -> Result<SearchBenchmark, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    if n_vectors == 0 || dim == 0 || k == 0 {
+        return Err("n_vectors, dim and k must be positive".to_string());
+    }
+    if n_vectors as u64 * dim as u64 > MAX_BENCHMARK_FLOATS {
+        return Err(format!("n_vectors * dim must not exceed {}", MAX_BENCHMARK_FLOATS));
+    }
+    
+    let mut rng = vector_index::XorShift::new(ic_cdk::api::time());
+    let vectors = vector_index::random_vectors(n_vectors as usize, dim as usize, &mut rng);
+    let queries = vector_index::random_vectors(BENCHMARK_QUERIES as usize, dim as usize, &mut rng);
+    
+    let start = ic_cdk::api::instruction_counter();
+    let index = vector_index::LshIndex::build(vectors.clone(), rng.next_u64());
+    let build_instructions = ic_cdk::api::instruction_counter() - start;
+    
+    let mut brute_force_instructions = 0;
+    let mut ann_instructions = 0;
+    let mut total_recall = 0.0;
+    for query in &queries {
+        let start = ic_cdk::api::instruction_counter();
+        let exact = vector_index::brute_force_search(&vectors, query, k as usize);
+        let mid = ic_cdk::api::instruction_counter();
+        let approximate = index.search(query, k as usize);
+        let end = ic_cdk::api::instruction_counter();
+        
+        brute_force_instructions += mid - start;
+        ann_instructions += end - mid;
+        total_recall += vector_index::recall(&exact, &approximate);
+    }
+    
+    Ok(SearchBenchmark {
+        n_vectors,
+        dim,
+        k,
+        queries: BENCHMARK_QUERIES,
+        signature_bits: index.signature_bits(),
+        build_instructions,
+        brute_force_instructions_per_query: brute_force_instructions / BENCHMARK_QUERIES as u64,
+        ann_instructions_per_query: ann_instructions / BENCHMARK_QUERIES as u64,
+        recall: total_recall / BENCHMARK_QUERIES as f32,
+    })
+}
+
+/// Make every LLM call fail as if it timed out, until turned off. Only built with the `chaos`
+/// feature, for integration-test deployments
+#[cfg(feature = "chaos")]
+#[ic_cdk::update]
+fn simulate_llm_timeout(enabled: bool) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Unauthorized: caller is not a controller".to_string());
+    }
+    chaos::set_llm_timeout(enabled);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn health() -> HealthStatus {
+    let now = ic_cdk::api::time();
+    
+    HealthStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("GIT_COMMIT").unwrap_or("unknown").to_string(),
+        schema_version: SCHEMA_VERSION,
+        store_counts: personality::get_store_counts(),
+        self_test_ok: serialization_self_test(now),
+        checked_at: now,
+    }
+}
+
+/// Comma-separated list recorded by build.rs, empty entries dropped
+fn build_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').filter(|item| !item.is_empty())
+}
+
+/// Provenance of the deployed module, for checking it against a rebuild of the audited source
+#[ic_cdk::query]
+fn build_info() -> BuildInfo {
+    BuildInfo {
+        package_version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("GIT_COMMIT").unwrap_or("unknown").to_string(),
+        rustc_version: env!("BUILD_RUSTC_VERSION").to_string(),
+        target: env!("BUILD_TARGET").to_string(),
+        profile: env!("BUILD_PROFILE").to_string(),
+        features: build_list(env!("BUILD_FEATURES")).map(str::to_string).collect(),
+        dependencies: build_list(env!("BUILD_DEPENDENCIES"))
+            .filter_map(|dependency| dependency.split_once('='))
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect(),
+        build_timestamp: option_env!("BUILD_TIMESTAMP").and_then(|epoch| epoch.parse().ok()),
+        wasm_module_hash: MODULE_HASH.with(|hash| hash.borrow().clone()),
+    }
+}
+
+/// Look up the installed module's hash through the management canister and cache it
+async fn fetch_module_hash() {
+    let request = ic_cdk::api::management_canister::main::CanisterInfoRequest {
+        canister_id: ic_cdk::id(),
+        num_requested_changes: None,
+    };
+    match ic_cdk::api::management_canister::main::canister_info(request).await {
+        Ok((info,)) => {
+            let hash = info.module_hash.map(|hash| hash.iter().map(|byte| format!("{:02x}", byte)).collect());
+            MODULE_HASH.with(|cached| *cached.borrow_mut() = hash);
+        }
+        Err((code, message)) => ic_cdk::println!("module hash lookup failed: {:?} {}", code, message),
+    }
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(RETENTION_SWEEP_INTERVAL, enforce_retention_policies);
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(fetch_module_hash()));
+}
+
+#[ic_cdk::init]
+fn init() {
+    start_timers();
+}
 
 #[ic_cdk::pre_upgrade]
 fn pre_upgrade() {
@@ -534,23 +1597,73 @@ fn pre_upgrade() {
     let user_memories = personality::get_all_user_memories();
     let conversation_embeddings = personality::get_all_conversation_embeddings();
     let user_profiles = personality::get_all_user_profiles();
+    let extended = ExtendedState {
+        room_retention_overrides: Some(context::get_room_retention_overrides()),
+        shared_memories: Some(shared_memory::get_all_shared_memories()),
+        room_moderators: Some(shared_memory::get_all_room_moderators()),
+        resummarize_job: resummarize::get_job(),
+        identity_links: Some(identity::get_all_identity_links()),
+        database_canister: presence::get_database_canister(),
+        trusted_canisters: Some(presence::get_trusted_canisters()),
+        user_timezones: Some(user_profiling::get_all_timezones()),
+        similarity_weights: Some(user_profiling::get_similarity_weights()),
+        expert_opt_ins: Some(experts::get_all_opt_ins()),
+        recommendation_conversions: Some(conversions::get_all_conversions()),
+        moderation_flags: Some(moderation::get_all_flags()),
+        suspensions: Some(moderation::get_all_suspensions()),
+        mood_tracking_opt_ins: Some(sentiment::get_all_opt_ins()),
+        private_ranges: Some(privacy::get_all_ranges()),
+        memory_ratings: Some(disclosure::get_all_memory_ratings()),
+    };
     
-    stable_save((personality_data, user_memories, conversation_embeddings, user_profiles))
+    stable_save((personality_data, user_memories, conversation_embeddings, user_profiles, Some(extended)))
         .expect("Failed to save data before upgrade");
 }
 
 #[ic_cdk::post_upgrade]
 fn post_upgrade() {
-    if let Ok((personality_data, user_memories, conversation_embeddings, user_profiles)) = stable_restore::<(
+    if let Ok((personality_data, user_memories, conversation_embeddings, user_profiles, extended)) = stable_restore::<(
         Vec<personality::PersonalityEmbedding>,
         Vec<personality::UserMemory>,
         Vec<personality::ConversationEmbedding>,
-        Vec<personality::UserProfile>
+        Vec<personality::UserProfile>,
+        Option<ExtendedState>
     )>() {
         personality::restore_all_data(personality_data, user_memories, conversation_embeddings);
         // Restore user profiles
         personality::USER_PROFILES.with(|profiles| {
             *profiles.borrow_mut() = user_profiles;
         });
+        
+        let extended = extended.unwrap_or_default();
+        if let Some(overrides) = extended.room_retention_overrides {
+            context::restore_room_retention_overrides(overrides);
+        }
+        shared_memory::restore_shared_memories(
+            extended.shared_memories.unwrap_or_default(),
+            extended.room_moderators.unwrap_or_default(),
+        );
+        identity::restore_identity_links(extended.identity_links.unwrap_or_default());
+        user_profiling::restore_timezones(extended.user_timezones.unwrap_or_default());
+        experts::restore_opt_ins(extended.expert_opt_ins.unwrap_or_default());
+        sentiment::restore_opt_ins(extended.mood_tracking_opt_ins.unwrap_or_default());
+        privacy::restore_ranges(extended.private_ranges.unwrap_or_default());
+        disclosure::restore_memory_ratings(extended.memory_ratings.unwrap_or_default());
+        conversions::restore_conversions(extended.recommendation_conversions.unwrap_or_default());
+        moderation::restore(
+            extended.moderation_flags.unwrap_or_default(),
+            extended.suspensions.unwrap_or_default(),
+        );
+        if let Some(weights) = extended.similarity_weights {
+            let _ = user_profiling::set_similarity_weights(weights);
+        }
+        presence::set_database_canister(extended.database_canister);
+        // Before the trusted set existed the database canister was implicitly trusted
+        let trusted_canisters = extended.trusted_canisters
+            .unwrap_or_else(|| extended.database_canister.into_iter().collect());
+        presence::set_trusted_canisters(trusted_canisters);
+        resummarize::restore_job(extended.resummarize_job);
     }
+    
+    start_timers();
 }
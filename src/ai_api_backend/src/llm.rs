@@ -0,0 +1,88 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_llm::{ChatMessage, Model, Response, Tool};
+
+// Same canister ic_llm talks to; we call it directly so failures come back as errors
+const LLM_CANISTER: &str = "w36hm-eqaaa-aaaal-qr76a-cai";
+
+/// Sampling parameters clients may send with a chat request. The LLM canister's v1_chat
+/// takes only the model, messages and tools, so there is nowhere to send these yet; requests
+/// that set any field are rejected (see `reject_generation`) instead of silently ignored
+#[derive(CandidType, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GenerationParams {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub top_p: Option<f32>,
+}
+
+impl GenerationParams {
+    pub fn is_unset(&self) -> bool {
+        *self == GenerationParams::default()
+    }
+}
+
+/// Error for a request that sets generation parameters, which the LLM canister cannot apply
+pub fn reject_generation(generation: Option<&GenerationParams>) -> Result<(), String> {
+    match generation {
+        Some(generation) if !generation.is_unset() => Err(
+            "Generation parameters are not supported: the LLM canister only accepts a model, messages and tools".to_string()
+        ),
+        _ => Ok(()),
+    }
+}
+
+// Candid shape of a v1_chat request
+#[derive(CandidType, Debug)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    tools: Option<Vec<Tool>>,
+}
+
+/// Drop-in replacement for `ic_llm::ChatBuilder` whose `send` reports a failed call instead
+/// of trapping
+pub struct ChatBuilder {
+    model: String,
+    messages: Vec<ChatMessage>,
+    tools: Vec<Tool>,
+}
+
+pub fn chat(model: Model) -> ChatBuilder {
+    ChatBuilder {
+        model: model.to_string(),
+        messages: Vec::new(),
+        tools: Vec::new(),
+    }
+}
+
+impl ChatBuilder {
+    pub fn with_messages(mut self, messages: Vec<ChatMessage>) -> Self {
+        self.messages = messages;
+        self
+    }
+    
+    pub fn with_tools(mut self, tools: Vec<Tool>) -> Self {
+        self.tools = tools;
+        self
+    }
+    
+    pub async fn send(self) -> Result<Response, String> {
+        let llm_canister = Principal::from_text(LLM_CANISTER).expect("invalid canister id");
+        
+        let request = ChatRequest {
+            model: self.model,
+            messages: self.messages,
+            tools: if self.tools.is_empty() { None } else { Some(self.tools) },
+        };
+        
+        #[cfg(feature = "chaos")]
+        if let Some(fault) = crate::chaos::llm_fault() {
+            return Err(format!("LLM canister call failed: {}", fault));
+        }
+        
+        let res: (Response,) = ic_cdk::call(llm_canister, "v1_chat", (request,))
+            .await
+            .map_err(|(code, msg)| format!("LLM canister call failed: {:?} {}", code, msg))?;
+        
+        Ok(res.0)
+    }
+}
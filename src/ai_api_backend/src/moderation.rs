@@ -0,0 +1,109 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+// Flags raised by room moderators, and suspensions shared by database_backend. Users flagged
+// by several moderators are reported to database_backend, which lowers the user's trust tier.
+// Only distinct flaggers count, so one moderator flagging a user again and again is not enough.
+
+// Only flags this recent count towards a report
+const FLAG_WINDOW_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Distinct recent flaggers at which a user is reported to database_backend
+pub const REPORT_THRESHOLD: u32 = 3;
+
+// Oldest flags are dropped beyond this many per user
+const MAX_FLAGS_PER_USER: usize = 100;
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct Flag {
+    pub user_id: String,
+    pub room_id: String,
+    pub reason: String,
+    pub flagged_by: String,
+    pub flagged_at: u64,
+}
+
+/// The parts of database_backend's Suspension record this canister keeps
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SharedSuspension {
+    pub reason: String,
+    pub until: Option<u64>, // None = until lifted
+}
+
+thread_local! {
+    // user_id -> flags, oldest first
+    static FLAGS: RefCell<HashMap<String, Vec<Flag>>> = RefCell::new(HashMap::new());
+    // user_id -> suspension pushed by database_backend
+    static SUSPENSIONS: RefCell<HashMap<String, SharedSuspension>> = RefCell::new(HashMap::new());
+}
+
+pub fn add_flag(flag: Flag) {
+    FLAGS.with(|flags| {
+        let mut flags = flags.borrow_mut();
+        let user_flags = flags.entry(flag.user_id.clone()).or_default();
+        user_flags.push(flag);
+        if user_flags.len() > MAX_FLAGS_PER_USER {
+            let excess = user_flags.len() - MAX_FLAGS_PER_USER;
+            user_flags.drain(..excess);
+        }
+    });
+}
+
+/// Flags of a user raised within the window, oldest first
+pub fn recent_flags(user_id: &str, now: u64) -> Vec<Flag> {
+    let window_start = now.saturating_sub(FLAG_WINDOW_NS);
+    FLAGS.with(|flags| {
+        flags.borrow()
+            .get(user_id)
+            .map(|user_flags| user_flags.iter().filter(|flag| flag.flagged_at >= window_start).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// How many different moderators raised `flags`
+pub fn distinct_flaggers(flags: &[Flag]) -> u32 {
+    flags.iter().map(|flag| flag.flagged_by.as_str()).collect::<HashSet<_>>().len() as u32
+}
+
+pub fn get_user_flags(user_id: &str) -> Vec<Flag> {
+    FLAGS.with(|flags| flags.borrow().get(user_id).cloned().unwrap_or_default())
+}
+
+pub fn set_suspension(user_id: &str, suspension: Option<SharedSuspension>) {
+    SUSPENSIONS.with(|suspensions| {
+        let mut suspensions = suspensions.borrow_mut();
+        match suspension {
+            Some(suspension) => suspensions.insert(user_id.to_string(), suspension),
+            None => suspensions.remove(user_id),
+        };
+    });
+}
+
+/// The user's suspension, if still in force
+pub fn active_suspension(user_id: &str, now: u64) -> Option<SharedSuspension> {
+    SUSPENSIONS.with(|suspensions| suspensions.borrow().get(user_id).cloned())
+        .filter(|suspension| suspension.until.is_none_or(|until| until > now))
+}
+
+pub fn get_all_flags() -> Vec<Flag> {
+    FLAGS.with(|flags| flags.borrow().values().flatten().cloned().collect())
+}
+
+pub fn get_all_suspensions() -> Vec<(String, SharedSuspension)> {
+    SUSPENSIONS.with(|suspensions| suspensions.borrow().iter().map(|(user, s)| (user.clone(), s.clone())).collect())
+}
+
+pub fn restore(flags: Vec<Flag>, suspensions: Vec<(String, SharedSuspension)>) {
+    FLAGS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        stored.clear();
+        for flag in flags {
+            stored.entry(flag.user_id.clone()).or_default().push(flag);
+        }
+        for user_flags in stored.values_mut() {
+            user_flags.sort_by_key(|flag| flag.flagged_at);
+        }
+    });
+    SUSPENSIONS.with(|stored| *stored.borrow_mut() = suspensions.into_iter().collect());
+}
@@ -1,6 +1,31 @@
 use candid::{CandidType, Deserialize};
 use std::collections::HashMap;
 
+/// Who retrieval may surface a stored item to
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Private,    // Only the user it was derived from
+    Room,       // Anyone in the channel it was formed in
+    Global,     // Any channel and any user
+}
+
+/// Whether an item owned by `owner` (None for authored content) and formed in `channel_id`
+/// may be shown to `viewer_id` in `viewer_channel`. Private items without an owner are
+/// never surfaced
+pub fn is_visible(
+    visibility: Visibility,
+    owner: Option<&str>,
+    channel_id: &str,
+    viewer_id: Option<&str>,
+    viewer_channel: Option<&str>,
+) -> bool {
+    match visibility {
+        Visibility::Private => owner.is_some() && owner == viewer_id,
+        Visibility::Room => viewer_channel == Some(channel_id),
+        Visibility::Global => true,
+    }
+}
+
 #[derive(CandidType, Deserialize, Debug, Clone)]
 pub struct PersonalityEmbedding {
     pub text: String,           // The original personality text/memory
@@ -9,6 +34,13 @@ pub struct PersonalityEmbedding {
     pub category: String,       // "experience", "preference", "opinion", etc.
     pub importance: f32,        // How important this memory is (0.0-1.0)
     pub created_at: u64,        // Timestamp
+    pub visibility: Option<Visibility>, // None: Global (authored persona and wiki content)
+}
+
+impl PersonalityEmbedding {
+    pub fn resolved_visibility(&self) -> Visibility {
+        self.visibility.unwrap_or(Visibility::Global)
+    }
 }
 
 #[derive(CandidType, Deserialize, Debug, Clone)]
@@ -19,6 +51,15 @@ pub struct UserMemory {
     pub channel_id: String,     // Where this memory was formed
     pub memory_type: String,    // "preference", "skill", "interaction", etc.
     pub created_at: u64,        // When this was learned
+    pub visibility: Option<Visibility>, // None: Private (derived from the user)
+    pub importance: Option<f32>, // 0.0-1.0; None: unrated
+    pub pinned: Option<bool>,   // Pinned by the user: always in their context in the channel, never decays
+}
+
+impl UserMemory {
+    pub fn is_pinned(&self) -> bool {
+        self.pinned.unwrap_or(false)
+    }
 }
 
 #[derive(CandidType, Deserialize, Debug, Clone)]
@@ -31,6 +72,18 @@ pub struct ConversationEmbedding {
     pub chunk_index: u32,       // Sequential chunk number (0, 1, 2, ...)
     pub created_at: u64,        // When this chunk was stored
     pub summary: String,        // Brief summary of the conversation chunk
+    pub visibility: Option<Visibility>, // None: Private (derived from the user)
+    pub sentiment: Option<f32>, // Lexicon score in [-1, 1]; None for chunks stored before scoring
+}
+
+impl ConversationEmbedding {
+    pub fn resolved_visibility(&self) -> Visibility {
+        self.visibility.unwrap_or(Visibility::Private)
+    }
+
+    fn is_visible_to(&self, viewer_id: Option<&str>, viewer_channel: Option<&str>) -> bool {
+        is_visible(self.resolved_visibility(), Some(&self.user_id), &self.channel_id, viewer_id, viewer_channel)
+    }
 }
 
 #[derive(CandidType, Deserialize, Debug, Clone)]
@@ -123,6 +176,14 @@ pub fn get_personality_embeddings(channel_id: &str) -> Vec<PersonalityEmbedding>
     })
 }
 
+/// Personality embeddings of a channel that may be used as context inside it
+fn visible_personality_embeddings(channel_id: &str) -> Vec<PersonalityEmbedding> {
+    get_personality_embeddings(channel_id)
+        .into_iter()
+        .filter(|e| is_visible(e.resolved_visibility(), None, &e.channel_id, None, Some(channel_id)))
+        .collect()
+}
+
 /// Get all personality embeddings (for debugging/inspection)
 pub fn get_all_personality_embeddings() -> Vec<PersonalityEmbedding> {
     PERSONALITY_EMBEDDINGS.with(|embeddings| {
@@ -149,7 +210,7 @@ pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
 
 /// Search for relevant personality context based on query embedding
 pub fn search_personality_context(channel_id: &str, query_embedding: &[f32], top_k: usize) -> Vec<String> {
-    let embeddings = get_personality_embeddings(channel_id);
+    let embeddings = visible_personality_embeddings(channel_id);
     
     let mut scored_embeddings: Vec<(f32, &PersonalityEmbedding)> = embeddings
         .iter()
@@ -170,7 +231,7 @@ pub fn search_personality_context(channel_id: &str, query_embedding: &[f32], top
 /// Get channel-specific personality context without needing query embeddings
 /// Returns the most important personality traits for a given channel
 pub fn get_channel_personality_context(channel_id: &str, top_k: usize) -> Vec<String> {
-    let embeddings = get_personality_embeddings(channel_id);
+    let embeddings = visible_personality_embeddings(channel_id);
     
     // Sort by importance score (descending) and return top traits
     let mut sorted_embeddings: Vec<&PersonalityEmbedding> = embeddings.iter().collect();
@@ -183,13 +244,14 @@ pub fn get_channel_personality_context(channel_id: &str, top_k: usize) -> Vec<St
         .collect()
 }
 
-/// Search for relevant user memories
-pub fn search_user_memories(user_id: &str, query_embedding: &[f32], top_k: usize) -> Vec<String> {
+/// Search for relevant user memories, as seen by that user in `channel_id`
+pub fn search_user_memories(user_id: &str, channel_id: &str, query_embedding: &[f32], top_k: usize) -> Vec<String> {
     USER_MEMORIES.with(|memories| {
         let borrowed_memories = memories.borrow();
         let user_memories: Vec<_> = borrowed_memories
             .iter()
             .filter(|m| m.user_id == user_id)
+            .filter(|m| is_visible(m.visibility.unwrap_or(Visibility::Private), Some(&m.user_id), &m.channel_id, Some(user_id), Some(channel_id)))
             .collect();
 
         let mut scored_memories: Vec<(f32, &UserMemory)> = user_memories
@@ -209,6 +271,43 @@ pub fn search_user_memories(user_id: &str, query_embedding: &[f32], top_k: usize
     })
 }
 
+/// Texts of the memories `user_id` pinned in `channel_id`, oldest first
+pub fn get_pinned_memories(user_id: &str, channel_id: &str) -> Vec<String> {
+    USER_MEMORIES.with(|memories| {
+        memories.borrow()
+            .iter()
+            .filter(|m| m.is_pinned() && m.user_id == user_id && m.channel_id == channel_id)
+            .map(|m| m.text.clone())
+            .collect()
+    })
+}
+
+/// Pinned memories of a user, in one channel or all of them
+pub fn list_pinned_memories(user_id: &str, channel_id: Option<&str>) -> Vec<UserMemory> {
+    USER_MEMORIES.with(|memories| {
+        memories.borrow()
+            .iter()
+            .filter(|m| m.is_pinned() && m.user_id == user_id)
+            .filter(|m| channel_id.is_none_or(|channel_id| m.channel_id == channel_id))
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn count_pinned_memories(user_id: &str) -> usize {
+    USER_MEMORIES.with(|memories| memories.borrow().iter().filter(|m| m.is_pinned() && m.user_id == user_id).count())
+}
+
+/// Remove a pinned memory by its text; returns false if there was none
+pub fn unpin_memory(user_id: &str, channel_id: &str, text: &str) -> bool {
+    USER_MEMORIES.with(|memories| {
+        let mut memories = memories.borrow_mut();
+        let position = memories.iter()
+            .position(|m| m.is_pinned() && m.user_id == user_id && m.channel_id == channel_id && m.text == text);
+        position.map(|position| memories.remove(position)).is_some()
+    })
+}
+
 /// Enhanced context retrieval that combines personality and user memories
 pub fn get_enhanced_context(
     channel_id: &str, 
@@ -220,7 +319,7 @@ pub fn get_enhanced_context(
     
     // Get user-specific context if user_id is provided
     let user_context = if let Some(uid) = user_id {
-        search_user_memories(uid, query_embedding, 2)
+        search_user_memories(uid, channel_id, query_embedding, 2)
     } else {
         Vec::new()
     };
@@ -260,10 +359,12 @@ pub fn get_next_chunk_index(user_id: &str, channel_id: &str) -> u32 {
     })
 }
 
-/// Search conversation history using semantic similarity
+/// Search `user_id`'s conversation history in a channel using semantic similarity,
+/// keeping only chunks `viewer_id` may see
 pub fn search_conversation_history(
     user_id: &str,
     channel_id: &str,
+    viewer_id: &str,
     query_embedding: &[f32],
     top_k: usize
 ) -> Vec<String> {
@@ -271,6 +372,7 @@ pub fn search_conversation_history(
         let mut scored_conversations: Vec<(f32, ConversationEmbedding)> = conversations.borrow()
             .iter()
             .filter(|conv| conv.user_id == user_id && conv.channel_id == channel_id)
+            .filter(|conv| conv.is_visible_to(Some(viewer_id), Some(channel_id)))
             .map(|conv| {
                 let similarity = cosine_similarity(query_embedding, &conv.embedding);
                 (similarity, conv.clone())
@@ -293,16 +395,18 @@ pub fn search_conversation_history(
     })
 }
 
-/// Get recent conversation context for a user (last N chunks)
+/// Get recent conversation context for a user (last N chunks) that `viewer_id` may see
 pub fn get_recent_conversation_context(
     user_id: &str,
     channel_id: &str,
+    viewer_id: &str,
     chunk_count: usize
 ) -> Vec<String> {
     CONVERSATION_EMBEDDINGS.with(|conversations| {
         let mut user_conversations: Vec<ConversationEmbedding> = conversations.borrow()
             .iter()
             .filter(|conv| conv.user_id == user_id && conv.channel_id == channel_id)
+            .filter(|conv| conv.is_visible_to(Some(viewer_id), Some(channel_id)))
             .cloned()
             .collect();
 
@@ -322,6 +426,31 @@ pub fn get_recent_conversation_context(
     })
 }
 
+/// Get the most recent conversation chunks in a channel across all users. Private chunks
+/// are left out since no single user is viewing them
+pub fn get_recent_channel_conversations(channel_id: &str, chunk_count: usize) -> Vec<String> {
+    CONVERSATION_EMBEDDINGS.with(|conversations| {
+        let mut channel_conversations: Vec<ConversationEmbedding> = conversations.borrow()
+            .iter()
+            .filter(|conv| conv.channel_id == channel_id && conv.is_visible_to(None, Some(channel_id)))
+            .cloned()
+            .collect();
+
+        // Sort by creation time (most recent first)
+        channel_conversations.sort_by_key(|conv| std::cmp::Reverse(conv.created_at));
+
+        channel_conversations
+            .into_iter()
+            .take(chunk_count)
+            .map(|conv| if conv.summary.is_empty() {
+                conv.conversation_text
+            } else {
+                conv.summary
+            })
+            .collect()
+    })
+}
+
 /// Get conversation statistics for a user
 pub fn get_conversation_stats(user_id: &str, channel_id: &str) -> (u32, u32) {
     CONVERSATION_EMBEDDINGS.with(|conversations| {
@@ -340,6 +469,163 @@ pub fn get_conversation_stats(user_id: &str, channel_id: &str) -> (u32, u32) {
         (chunk_count, total_messages)
     })
 }
+/// (user, created_at, sentiment) of every chunk accepted by `filter`
+pub fn conversation_sentiments<F>(filter: F) -> Vec<(String, u64, f32)>
+where
+    F: Fn(&ConversationEmbedding) -> bool,
+{
+    CONVERSATION_EMBEDDINGS.with(|conversations| {
+        conversations.borrow()
+            .iter()
+            .filter(|conv| filter(conv))
+            .map(|conv| (conv.user_id.clone(), conv.created_at, crate::sentiment::chunk_sentiment(conv)))
+            .collect()
+    })
+}
+
+/// Conversation chunk text by its (user, channel, chunk index) key
+pub fn get_conversation_chunk_text(user_id: &str, channel_id: &str, chunk_index: u32) -> Option<String> {
+    CONVERSATION_EMBEDDINGS.with(|conversations| {
+        conversations.borrow()
+            .iter()
+            .find(|conv| conv.user_id == user_id && conv.channel_id == channel_id && conv.chunk_index == chunk_index)
+            .map(|conv| conv.conversation_text.clone())
+    })
+}
+
+/// Keys (user, channel, chunk index) of every chunk accepted by `filter`
+pub fn find_conversation_chunks<F>(filter: F) -> Vec<(String, String, u32)>
+where
+    F: Fn(&ConversationEmbedding) -> bool,
+{
+    CONVERSATION_EMBEDDINGS.with(|conversations| {
+        conversations.borrow()
+            .iter()
+            .filter(|conv| filter(conv))
+            .map(|conv| (conv.user_id.clone(), conv.channel_id.clone(), conv.chunk_index))
+            .collect()
+    })
+}
+
+/// Replace the summary of one conversation chunk; returns false if it no longer exists
+pub fn update_conversation_summary(user_id: &str, channel_id: &str, chunk_index: u32, summary: String) -> bool {
+    CONVERSATION_EMBEDDINGS.with(|conversations| {
+        match conversations.borrow_mut()
+            .iter_mut()
+            .find(|conv| conv.user_id == user_id && conv.channel_id == channel_id && conv.chunk_index == chunk_index)
+        {
+            Some(conv) => {
+                conv.summary = summary;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Remove every conversation chunk accepted by `filter`; returns how many were removed
+pub fn remove_conversation_chunks<F>(filter: F) -> u32
+where
+    F: Fn(&ConversationEmbedding) -> bool,
+{
+    CONVERSATION_EMBEDDINGS.with(|conversations| {
+        let mut conversations = conversations.borrow_mut();
+        let before = conversations.len();
+        conversations.retain(|conv| !filter(conv));
+        (before - conversations.len()) as u32
+    })
+}
+
+/// Move a duplicate user's conversations and memories under `primary_id`. Chunks are
+/// renumbered after the primary's existing chunks in each channel, oldest first, and the
+/// duplicate's profile is dropped. Returns (chunks moved, memories moved)
+pub fn reassign_user_data(primary_id: &str, duplicate_id: &str) -> (u32, u32) {
+    let chunks_moved = CONVERSATION_EMBEDDINGS.with(|conversations| {
+        let mut conversations = conversations.borrow_mut();
+        
+        let mut next_index: HashMap<String, u32> = HashMap::new();
+        for conv in conversations.iter().filter(|conv| conv.user_id == primary_id) {
+            let next = next_index.entry(conv.channel_id.clone()).or_insert(0);
+            *next = (*next).max(conv.chunk_index + 1);
+        }
+        
+        let mut moved: Vec<&mut ConversationEmbedding> = conversations
+            .iter_mut()
+            .filter(|conv| conv.user_id == duplicate_id)
+            .collect();
+        moved.sort_by_key(|conv| (conv.created_at, conv.chunk_index));
+        
+        for conv in moved.iter_mut() {
+            let next = next_index.entry(conv.channel_id.clone()).or_insert(0);
+            conv.user_id = primary_id.to_string();
+            conv.chunk_index = *next;
+            *next += 1;
+        }
+        moved.len() as u32
+    });
+    
+    let memories_moved = USER_MEMORIES.with(|memories| {
+        let mut count = 0;
+        for memory in memories.borrow_mut().iter_mut().filter(|m| m.user_id == duplicate_id) {
+            memory.user_id = primary_id.to_string();
+            count += 1;
+        }
+        count
+    });
+    
+    USER_PROFILES.with(|profiles| profiles.borrow_mut().retain(|p| p.user_id != duplicate_id));
+    
+    (chunks_moved, memories_moved)
+}
+
+// Importance ceiling for personality embeddings older than their room's retention window
+const EXPIRED_IMPORTANCE_CAP: f32 = 0.1;
+
+/// Apply a room's retention window: drop conversation chunks older than `max_age_ns`
+/// and cap the importance of stale personality embeddings so they rarely surface.
+/// Returns (chunks pruned, embeddings de-weighted)
+pub fn enforce_room_retention(channel_id: &str, max_age_ns: u64, now: u64) -> (u32, u32) {
+    let cutoff = now.saturating_sub(max_age_ns);
+    
+    let pruned = CONVERSATION_EMBEDDINGS.with(|conversations| {
+        let mut conversations = conversations.borrow_mut();
+        let before = conversations.len();
+        conversations.retain(|conv| conv.channel_id != channel_id || conv.created_at >= cutoff);
+        (before - conversations.len()) as u32
+    });
+    
+    let deweighted = PERSONALITY_EMBEDDINGS.with(|embeddings| {
+        let mut count = 0;
+        for embedding in embeddings.borrow_mut().iter_mut() {
+            if embedding.channel_id == channel_id
+                && embedding.created_at < cutoff
+                && embedding.importance > EXPIRED_IMPORTANCE_CAP
+            {
+                embedding.importance = EXPIRED_IMPORTANCE_CAP;
+                count += 1;
+            }
+        }
+        count
+    });
+    
+    (pruned, deweighted)
+}
+
+/// Shift the importance of personality embeddings in a channel whose text matches
+/// one of `texts`, keeping it within [min, max]. Returns how many were adjusted
+pub fn adjust_personality_importance(channel_id: &str, texts: &[String], delta: f32, min: f32, max: f32) -> u32 {
+    PERSONALITY_EMBEDDINGS.with(|embeddings| {
+        let mut count = 0;
+        for embedding in embeddings.borrow_mut().iter_mut() {
+            if embedding.channel_id == channel_id && texts.contains(&embedding.text) {
+                embedding.importance = (embedding.importance + delta).clamp(min, max);
+                count += 1;
+            }
+        }
+        count
+    })
+}
+
 // Functions for upgrade persistence
 pub fn get_all_user_memories() -> Vec<UserMemory> {
     USER_MEMORIES.with(|memories| memories.borrow().clone())
@@ -353,6 +639,16 @@ pub fn get_all_user_profiles() -> Vec<UserProfile> {
     USER_PROFILES.with(|profiles| profiles.borrow().clone())
 }
 
+/// Number of records in each in-memory store (for health reporting)
+pub fn get_store_counts() -> Vec<(String, u64)> {
+    vec![
+        ("personality_embeddings".to_string(), PERSONALITY_EMBEDDINGS.with(|e| e.borrow().len() as u64)),
+        ("user_memories".to_string(), USER_MEMORIES.with(|m| m.borrow().len() as u64)),
+        ("conversation_embeddings".to_string(), CONVERSATION_EMBEDDINGS.with(|c| c.borrow().len() as u64)),
+        ("user_profiles".to_string(), USER_PROFILES.with(|p| p.borrow().len() as u64)),
+    ]
+}
+
 pub fn restore_all_data(
     personality_data: Vec<PersonalityEmbedding>,
     user_memories: Vec<UserMemory>,
@@ -614,6 +910,11 @@ pub fn search_unified_knowledge(
         let borrowed_embeddings = embeddings.borrow();
         
         for embedding in borrowed_embeddings.iter() {
+            // Searches across channels only see globally visible content
+            if embedding.resolved_visibility() != Visibility::Global {
+                continue;
+            }
+            
             // Filter by categories if specified
             if let Some(ref cats) = categories {
                 if !cats.contains(&embedding.category) && !cats.iter().any(|cat| embedding.category.starts_with(cat)) {
@@ -780,6 +1081,10 @@ pub fn search_knowledge_by_text(
         let borrowed_embeddings = embeddings.borrow();
         
         for embedding in borrowed_embeddings.iter() {
+            if embedding.resolved_visibility() != Visibility::Global {
+                continue;
+            }
+            
             // Filter by categories if specified
             if let Some(ref cats) = categories {
                 if !cats.iter().any(|cat| embedding.category.starts_with(cat) || embedding.category == *cat) {
@@ -0,0 +1,93 @@
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::identity;
+
+// Snapshots older than this are ignored (database_backend pushes every minute)
+const PRESENCE_STALE_AFTER_NS: u64 = 5 * 60 * 1_000_000_000;
+
+// Same bound database_backend puts on room ids
+const MAX_ROOM_ID_CHARS: usize = 64;
+
+thread_local! {
+    // database_backend this canister calls; set by a controller
+    static DATABASE_CANISTER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    // Canisters allowed to call the integration endpoints (presence pushes, outcome reports)
+    static TRUSTED_CANISTERS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    // room_id -> active user ids from the latest push. Transient, so not persisted
+    static ROOM_PRESENCE: RefCell<HashMap<String, Vec<String>>> = RefCell::new(HashMap::new());
+    static PRESENCE_UPDATED_AT: RefCell<u64> = const { RefCell::new(0) };
+}
+
+pub fn set_database_canister(canister: Option<Principal>) {
+    DATABASE_CANISTER.with(|db| *db.borrow_mut() = canister);
+}
+
+pub fn get_database_canister() -> Option<Principal> {
+    DATABASE_CANISTER.with(|db| *db.borrow())
+}
+
+pub fn is_trusted_canister(principal: &Principal) -> bool {
+    TRUSTED_CANISTERS.with(|trusted| trusted.borrow().contains(principal))
+}
+
+pub fn set_trusted_canisters(canisters: Vec<Principal>) {
+    TRUSTED_CANISTERS.with(|trusted| *trusted.borrow_mut() = canisters.into_iter().collect());
+}
+
+pub fn get_trusted_canisters() -> Vec<Principal> {
+    TRUSTED_CANISTERS.with(|trusted| trusted.borrow().iter().copied().collect())
+}
+
+/// Replace the presence snapshot, or extend it with the next part of one (`continuation`).
+/// Principals are stored under their consolidated user ids. Nothing is stored if any room id
+/// is invalid
+pub fn update_room_presence(rooms: Vec<(String, Vec<Principal>)>, continuation: bool, now: u64) -> Result<(), String> {
+    if let Some((room_id, _)) = rooms.iter().find(|(room_id, _)| room_id.is_empty() || room_id.chars().count() > MAX_ROOM_ID_CHARS) {
+        return Err(format!("Invalid room id '{}'", room_id.chars().take(MAX_ROOM_ID_CHARS).collect::<String>()));
+    }
+    
+    ROOM_PRESENCE.with(|presence| {
+        let mut presence = presence.borrow_mut();
+        if !continuation {
+            presence.clear();
+        }
+        for (room_id, members) in rooms {
+            presence
+                .entry(room_id)
+                .or_default()
+                .extend(members.iter().map(|p| identity::resolve_user_id(&p.to_text())));
+        }
+    });
+    PRESENCE_UPDATED_AT.with(|updated| *updated.borrow_mut() = now);
+    Ok(())
+}
+
+/// Users currently active in a room; empty when no fresh snapshot exists
+pub fn get_active_users(room_id: &str, now: u64) -> Vec<String> {
+    let updated_at = PRESENCE_UPDATED_AT.with(|updated| *updated.borrow());
+    if now.saturating_sub(updated_at) > PRESENCE_STALE_AFTER_NS {
+        return Vec::new();
+    }
+    
+    ROOM_PRESENCE.with(|presence| presence.borrow().get(room_id).cloned().unwrap_or_default())
+}
+
+/// Move recommendations for users active in `room_id` ahead of the rest (keeping
+/// compatibility order within each group), then keep the first `limit`.
+/// Each entry is (user_id, similarity, active)
+pub fn prefer_active(room_id: &str, recommendations: Vec<(String, f32)>, limit: usize, now: u64) -> Vec<(String, f32, bool)> {
+    let active = get_active_users(room_id, now);
+    
+    let mut ranked: Vec<(String, f32, bool)> = recommendations
+        .into_iter()
+        .map(|(user_id, similarity)| {
+            let is_active = active.contains(&user_id);
+            (user_id, similarity, is_active)
+        })
+        .collect();
+    ranked.sort_by_key(|(_, _, is_active)| !*is_active);
+    ranked.truncate(limit);
+    ranked
+}
@@ -0,0 +1,130 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+
+use crate::personality::{self, ConversationEmbedding};
+
+// Conversation ranges users asked Lain not to learn from. New chunks inside a range are not
+// stored; chunks stored before it was marked stay until a controller reviews the range and
+// purges them.
+
+// Ranges one user may keep
+const MAX_RANGES_PER_USER: usize = 100;
+
+/// Time range of one of a user's conversations excluded from learning
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PrivateRange {
+    pub id: u64,
+    pub user_id: String,
+    pub channel_id: String,
+    pub from_ts: u64,
+    pub to_ts: u64,               // Inclusive
+    pub marked_at: u64,
+    pub purged_at: Option<u64>,   // None while stored chunks may still overlap it
+}
+
+impl PrivateRange {
+    pub fn covers(&self, conv: &ConversationEmbedding) -> bool {
+        conv.user_id == self.user_id
+            && conv.channel_id == self.channel_id
+            && (self.from_ts..=self.to_ts).contains(&conv.created_at)
+    }
+}
+
+/// A range waiting for review, with the chunks a purge would remove
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PendingPurge {
+    pub range: PrivateRange,
+    pub overlapping_chunks: u32,
+}
+
+thread_local! {
+    static PRIVATE_RANGES: RefCell<Vec<PrivateRange>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn mark(user_id: &str, channel_id: &str, from_ts: u64, to_ts: u64, now: u64) -> Result<PrivateRange, String> {
+    if channel_id.trim().is_empty() {
+        return Err("Channel id cannot be empty".to_string());
+    }
+    if from_ts > to_ts {
+        return Err("from_ts must not be after to_ts".to_string());
+    }
+
+    PRIVATE_RANGES.with(|ranges| {
+        let mut ranges = ranges.borrow_mut();
+        if ranges.iter().filter(|range| range.user_id == user_id).count() >= MAX_RANGES_PER_USER {
+            return Err(format!("At most {} private ranges per user", MAX_RANGES_PER_USER));
+        }
+
+        let range = PrivateRange {
+            id: ranges.iter().map(|range| range.id + 1).max().unwrap_or(0),
+            user_id: user_id.to_string(),
+            channel_id: channel_id.to_string(),
+            from_ts,
+            to_ts,
+            marked_at: now,
+            purged_at: None,
+        };
+        ranges.push(range.clone());
+        Ok(range)
+    })
+}
+
+/// Whether `conv` falls in a range its user marked private
+pub fn is_private(conv: &ConversationEmbedding) -> bool {
+    PRIVATE_RANGES.with(|ranges| ranges.borrow().iter().any(|range| range.covers(conv)))
+}
+
+pub fn ranges_of(user_id: &str) -> Vec<PrivateRange> {
+    PRIVATE_RANGES.with(|ranges| {
+        ranges.borrow().iter().filter(|range| range.user_id == user_id).cloned().collect()
+    })
+}
+
+/// Ranges not purged yet, oldest first
+pub fn pending_purges() -> Vec<PendingPurge> {
+    PRIVATE_RANGES.with(|ranges| {
+        ranges.borrow()
+            .iter()
+            .filter(|range| range.purged_at.is_none())
+            .map(|range| PendingPurge {
+                overlapping_chunks: personality::find_conversation_chunks(|conv| range.covers(conv)).len() as u32,
+                range: range.clone(),
+            })
+            .collect()
+    })
+}
+
+/// Remove the stored chunks overlapping each of `range_ids` and mark those ranges purged.
+/// Returns the number of chunks removed
+pub fn purge(range_ids: &[u64], now: u64) -> Result<u32, String> {
+    PRIVATE_RANGES.with(|ranges| {
+        let mut ranges = ranges.borrow_mut();
+        if let Some(missing) = range_ids.iter().find(|id| !ranges.iter().any(|range| range.id == **id)) {
+            return Err(format!("No private range with id {}", missing));
+        }
+
+        let mut removed = 0;
+        for range in ranges.iter_mut().filter(|range| range_ids.contains(&range.id)) {
+            removed += personality::remove_conversation_chunks(|conv| range.covers(conv));
+            range.purged_at = Some(now);
+        }
+        Ok(removed)
+    })
+}
+
+/// Carry a merged-away id's ranges over to the primary
+pub fn adopt_ranges(primary: &str, duplicate: &str) {
+    PRIVATE_RANGES.with(|ranges| {
+        for range in ranges.borrow_mut().iter_mut().filter(|range| range.user_id == duplicate) {
+            range.user_id = primary.to_string();
+        }
+    });
+}
+
+pub fn get_all_ranges() -> Vec<PrivateRange> {
+    PRIVATE_RANGES.with(|ranges| ranges.borrow().clone())
+}
+
+pub fn restore_ranges(restored: Vec<PrivateRange>) {
+    PRIVATE_RANGES.with(|ranges| *ranges.borrow_mut() = restored);
+}
@@ -0,0 +1,235 @@
+use candid::{CandidType, Deserialize};
+use ic_llm::ChatMessage;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+use crate::llm;
+use crate::personality::{self, ConversationEmbedding};
+
+// Upper bound on chunks summarized per timer tick, keeping each round well inside instruction limits
+pub const MAX_BATCH_SIZE: u32 = 10;
+const DEFAULT_BATCH_SIZE: u32 = 5;
+
+// Pause between batches so chat traffic is not starved of LLM capacity
+const BATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+// Current summarization prompt; bump the wording here and run `resummarize` to refresh old chunks
+const SUMMARY_INSTRUCTIONS: &str = "Summarize the following conversation between a user and Lain in two or three \
+    sentences. Keep names, topics, preferences and any commitments made. Write in the third person and do not \
+    add anything that was not said.";
+
+/// Which conversation chunks a re-summarization job covers; unset fields match everything
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct ResummarizeFilter {
+    pub channel_id: Option<String>,
+    pub user_id: Option<String>,
+    pub created_before: Option<u64>,
+}
+
+impl ResummarizeFilter {
+    fn matches(&self, conv: &ConversationEmbedding) -> bool {
+        self.channel_id.as_ref().is_none_or(|c| *c == conv.channel_id)
+            && self.user_id.as_ref().is_none_or(|u| *u == conv.user_id)
+            && self.created_before.is_none_or(|t| conv.created_at < t)
+    }
+}
+
+/// Job state, persisted across upgrades so a long run resumes where it stopped
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ResummarizeJob {
+    filter: ResummarizeFilter,
+    batch_size: u32,
+    pending: Vec<(String, String, u32)>, // (user_id, channel_id, chunk_index) still to do
+    total: u32,
+    updated: u32,
+    failed: u32,
+    started_at: u64,
+    finished_at: Option<u64>,
+    cancelled: bool,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ResummarizeProgress {
+    pub filter: ResummarizeFilter,
+    pub batch_size: u32,
+    pub total: u32,
+    pub updated: u32,
+    pub failed: u32,
+    pub remaining: u32,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub cancelled: bool,
+}
+
+impl ResummarizeJob {
+    fn progress(&self) -> ResummarizeProgress {
+        ResummarizeProgress {
+            filter: self.filter.clone(),
+            batch_size: self.batch_size,
+            total: self.total,
+            updated: self.updated,
+            failed: self.failed,
+            remaining: self.pending.len() as u32,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            cancelled: self.cancelled,
+        }
+    }
+    
+    fn is_running(&self) -> bool {
+        self.finished_at.is_none()
+    }
+}
+
+thread_local! {
+    // Most recent job (running or finished)
+    static JOB: RefCell<Option<ResummarizeJob>> = const { RefCell::new(None) };
+    // Bumped whenever a job starts or resumes. A batch started for an earlier generation
+    // (a job since cancelled and replaced) stops instead of touching the new job
+    static GENERATION: Cell<u64> = const { Cell::new(0) };
+}
+
+fn next_generation() -> u64 {
+    GENERATION.with(|generation| {
+        generation.set(generation.get() + 1);
+        generation.get()
+    })
+}
+
+fn is_current(generation: u64) -> bool {
+    GENERATION.with(Cell::get) == generation
+}
+
+/// Start a job over every chunk matching `filter`; only one job runs at a time
+pub fn start(filter: ResummarizeFilter, batch_size: Option<u32>) -> Result<ResummarizeProgress, String> {
+    if JOB.with(|job| job.borrow().as_ref().is_some_and(|j| j.is_running())) {
+        return Err("A resummarize job is already running".to_string());
+    }
+    
+    let pending = personality::find_conversation_chunks(|conv| filter.matches(conv));
+    let now = ic_cdk::api::time();
+    let job = ResummarizeJob {
+        filter,
+        batch_size: batch_size.unwrap_or(DEFAULT_BATCH_SIZE).clamp(1, MAX_BATCH_SIZE),
+        total: pending.len() as u32,
+        finished_at: if pending.is_empty() { Some(now) } else { None },
+        pending,
+        updated: 0,
+        failed: 0,
+        started_at: now,
+        cancelled: false,
+    };
+    let progress = job.progress();
+    let running = job.is_running();
+    
+    JOB.with(|slot| *slot.borrow_mut() = Some(job));
+    if running {
+        schedule_batch(Duration::ZERO, next_generation());
+    }
+    Ok(progress)
+}
+
+/// Stop the running job after its current batch; returns false if nothing was running
+pub fn cancel() -> bool {
+    JOB.with(|job| match job.borrow_mut().as_mut() {
+        Some(job) if job.is_running() => {
+            job.cancelled = true;
+            job.finished_at = Some(ic_cdk::api::time());
+            job.pending.clear();
+            true
+        }
+        _ => false,
+    })
+}
+
+pub fn get_progress() -> Option<ResummarizeProgress> {
+    JOB.with(|job| job.borrow().as_ref().map(|j| j.progress()))
+}
+
+fn schedule_batch(delay: Duration, generation: u64) {
+    ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(run_batch(generation)));
+}
+
+async fn run_batch(generation: u64) {
+    let batch: Vec<(String, String, u32)> = JOB.with(|job| {
+        job.borrow()
+            .as_ref()
+            .filter(|j| j.is_running() && is_current(generation))
+            .map(|j| j.pending.iter().take(j.batch_size as usize).cloned().collect())
+            .unwrap_or_default()
+    });
+    
+    for (user_id, channel_id, chunk_index) in batch {
+        let succeeded = match personality::get_conversation_chunk_text(&user_id, &channel_id, chunk_index) {
+            Some(text) => match summarize(text).await {
+                Ok(summary) => personality::update_conversation_summary(&user_id, &channel_id, chunk_index, summary),
+                Err(e) => {
+                    ic_cdk::println!("resummarize {}/{}#{} failed: {}", user_id, channel_id, chunk_index, e);
+                    false
+                }
+            },
+            // Pruned since the job started
+            None => false,
+        };
+        
+        let key = (user_id, channel_id, chunk_index);
+        let still_running = JOB.with(|job| match job.borrow_mut().as_mut() {
+            Some(job) if job.is_running() && is_current(generation) => {
+                job.pending.retain(|k| *k != key);
+                if succeeded {
+                    job.updated += 1;
+                } else {
+                    job.failed += 1;
+                }
+                true
+            }
+            _ => false,
+        });
+        if !still_running {
+            return;
+        }
+    }
+    
+    let done = JOB.with(|job| match job.borrow_mut().as_mut() {
+        Some(job) if job.is_running() && is_current(generation) => {
+            if job.pending.is_empty() {
+                job.finished_at = Some(ic_cdk::api::time());
+            }
+            job.pending.is_empty()
+        }
+        _ => true,
+    });
+    if !done {
+        schedule_batch(BATCH_INTERVAL, generation);
+    }
+}
+
+async fn summarize(conversation_text: String) -> Result<String, String> {
+    let response = llm::chat(crate::MODEL)
+        .with_messages(vec![
+            ChatMessage::System { content: SUMMARY_INSTRUCTIONS.to_string() },
+            ChatMessage::User { content: conversation_text },
+        ])
+        .send()
+        .await?;
+    
+    response.message.content
+        .map(|summary| summary.trim().to_string())
+        .filter(|summary| !summary.is_empty())
+        .ok_or_else(|| "LLM returned an empty summary".to_string())
+}
+
+// === UPGRADE PERSISTENCE ===
+
+pub fn get_job() -> Option<ResummarizeJob> {
+    JOB.with(|job| job.borrow().clone())
+}
+
+/// Restore a saved job and pick an unfinished one back up
+pub fn restore_job(job: Option<ResummarizeJob>) {
+    let running = job.as_ref().is_some_and(|j| j.is_running());
+    JOB.with(|slot| *slot.borrow_mut() = job);
+    if running {
+        schedule_batch(BATCH_INTERVAL, next_generation());
+    }
+}
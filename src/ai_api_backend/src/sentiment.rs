@@ -0,0 +1,207 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::personality::{self, ConversationEmbedding};
+
+// Lexicon-based sentiment of conversation chunks, scored when a chunk is stored. Rooms get an
+// aggregate mood; per-user trends are only kept visible to users who opt in, and only to them.
+
+const POSITIVE_WORDS: &[&str] = &[
+    "good", "great", "love", "happy", "glad", "awesome", "amazing", "nice", "fun", "thanks",
+    "thank", "excited", "cool", "beautiful", "wonderful", "enjoy", "enjoyed", "lol", "haha",
+    "best", "calm", "hopeful", "proud", "grateful", "yay",
+];
+const NEGATIVE_WORDS: &[&str] = &[
+    "bad", "sad", "hate", "angry", "tired", "lonely", "alone", "depressed", "anxious", "awful",
+    "terrible", "worst", "upset", "stressed", "cry", "crying", "hurt", "miss", "afraid", "scared",
+    "worried", "exhausted", "hopeless", "sick", "ugh",
+];
+// Flip the polarity of the word that follows
+const NEGATORS: &[&str] = &["not", "no", "never", "dont", "don't", "isnt", "isn't", "wasnt", "wasn't", "cant", "can't"];
+
+// Scores beyond these are labelled positive / negative
+const MOOD_LABEL_THRESHOLD: f32 = 0.25;
+
+// Room moods cover chunks stored this recently
+const ROOM_MOOD_WINDOW_NS: u64 = 7 * DAY_NS;
+
+// A user seems down when their latest chunks from this recently average below the threshold
+const RECENT_MOOD_WINDOW_NS: u64 = 2 * DAY_NS;
+const RECENT_MOOD_CHUNKS: usize = 3;
+const DOWN_THRESHOLD: f32 = -0.3;
+
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_TREND_DAYS: u32 = 90;
+
+pub const GENTLE_TONE_GUIDANCE: &str = "The user seems to be having a hard time lately. Be warm and gentle, \
+    keep replies calm and unhurried, avoid sarcasm and teasing, and do not point out their mood unless they bring it up.";
+
+/// Recent activity and mood of a room
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct RoomActivityStats {
+    pub room_id: String,
+    pub chunk_count: u32,         // Chunks stored in the window
+    pub active_users: u32,
+    pub last_activity_at: Option<u64>,
+    pub mood: f32,                // Mean chunk sentiment in [-1, 1]; 0 without chunks
+    pub mood_label: String,       // "positive", "neutral" or "negative"
+    pub window_days: u32,
+}
+
+/// A user's mean sentiment over one day
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct MoodPoint {
+    pub day_start: u64,
+    pub mood: f32,
+    pub chunk_count: u32,
+}
+
+thread_local! {
+    // Users who asked to see their own mood trend
+    static MOOD_TRACKING_OPT_INS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Sentiment of `text` in [-1, 1]: the balance of positive and negative lexicon words.
+/// 0 when it contains neither
+pub fn score(text: &str) -> f32 {
+    let mut positive = 0u32;
+    let mut negative = 0u32;
+    let mut negated = false;
+
+    for word in text.split(|c: char| !c.is_alphanumeric() && c != '\'') {
+        if word.is_empty() {
+            continue;
+        }
+        let word = word.to_lowercase();
+        let polarity = if POSITIVE_WORDS.contains(&word.as_str()) {
+            1
+        } else if NEGATIVE_WORDS.contains(&word.as_str()) {
+            -1
+        } else {
+            0
+        };
+        match (polarity, negated) {
+            (1, false) | (-1, true) => positive += 1,
+            (-1, false) | (1, true) => negative += 1,
+            _ => {}
+        }
+        negated = NEGATORS.contains(&word.as_str());
+    }
+
+    let total = positive + negative;
+    if total == 0 {
+        return 0.0;
+    }
+    (positive as f32 - negative as f32) / total as f32
+}
+
+/// Stored score of a chunk, scoring chunks from before sentiment existed on the fly
+pub fn chunk_sentiment(conv: &ConversationEmbedding) -> f32 {
+    conv.sentiment.unwrap_or_else(|| score(&conv.conversation_text))
+}
+
+fn mood_label(mood: f32) -> &'static str {
+    if mood > MOOD_LABEL_THRESHOLD {
+        "positive"
+    } else if mood < -MOOD_LABEL_THRESHOLD {
+        "negative"
+    } else {
+        "neutral"
+    }
+}
+
+fn mean(scores: impl Iterator<Item = f32>) -> f32 {
+    let (sum, count) = scores.fold((0.0, 0u32), |(sum, count), score| (sum + score, count + 1));
+    if count == 0 { 0.0 } else { sum / count as f32 }
+}
+
+pub fn room_activity_stats(room_id: &str, now: u64) -> RoomActivityStats {
+    let window_start = now.saturating_sub(ROOM_MOOD_WINDOW_NS);
+    let chunks = personality::conversation_sentiments(|conv| conv.channel_id == room_id && conv.created_at >= window_start);
+
+    let active_users: HashSet<&str> = chunks.iter().map(|(user_id, _, _)| user_id.as_str()).collect();
+    let mood = mean(chunks.iter().map(|(_, _, score)| *score));
+
+    RoomActivityStats {
+        room_id: room_id.to_string(),
+        chunk_count: chunks.len() as u32,
+        active_users: active_users.len() as u32,
+        last_activity_at: chunks.iter().map(|(_, created_at, _)| *created_at).max(),
+        mood,
+        mood_label: mood_label(mood).to_string(),
+        window_days: (ROOM_MOOD_WINDOW_NS / DAY_NS) as u32,
+    }
+}
+
+/// Daily mood of a user over the last `days` days, oldest first; days without chunks are left out
+pub fn user_mood_trend(user_id: &str, days: u32, now: u64) -> Vec<MoodPoint> {
+    let days = days.clamp(1, MAX_TREND_DAYS) as u64;
+    let window_start = (now / DAY_NS).saturating_sub(days - 1) * DAY_NS;
+    let chunks = personality::conversation_sentiments(|conv| conv.user_id == user_id && conv.created_at >= window_start);
+
+    let mut by_day: BTreeMap<u64, Vec<f32>> = BTreeMap::new();
+    for (_, created_at, score) in chunks {
+        by_day.entry(created_at / DAY_NS * DAY_NS).or_default().push(score);
+    }
+    by_day.into_iter()
+        .map(|(day_start, scores)| MoodPoint {
+            day_start,
+            mood: mean(scores.iter().copied()),
+            chunk_count: scores.len() as u32,
+        })
+        .collect()
+}
+
+/// Whether the user's latest conversations read as low, so replies should be gentler
+pub fn seems_down(user_id: &str, now: u64) -> bool {
+    let window_start = now.saturating_sub(RECENT_MOOD_WINDOW_NS);
+    let mut chunks = personality::conversation_sentiments(|conv| conv.user_id == user_id && conv.created_at >= window_start);
+    if chunks.is_empty() {
+        return false;
+    }
+    chunks.sort_by_key(|(_, created_at, _)| std::cmp::Reverse(*created_at));
+    mean(chunks.iter().take(RECENT_MOOD_CHUNKS).map(|(_, _, score)| *score)) < DOWN_THRESHOLD
+}
+
+/// `prompt` with gentle-tone guidance appended when the user seems down
+pub fn adjust_tone(prompt: String, user_id: &str, now: u64) -> String {
+    if seems_down(user_id, now) {
+        format!("{}\n\n{}", prompt, GENTLE_TONE_GUIDANCE)
+    } else {
+        prompt
+    }
+}
+
+pub fn set_opt_in(user_id: &str, opted_in: bool) {
+    MOOD_TRACKING_OPT_INS.with(|opt_ins| {
+        let mut opt_ins = opt_ins.borrow_mut();
+        if opted_in {
+            opt_ins.insert(user_id.to_string());
+        } else {
+            opt_ins.remove(user_id);
+        }
+    });
+}
+
+pub fn is_opted_in(user_id: &str) -> bool {
+    MOOD_TRACKING_OPT_INS.with(|opt_ins| opt_ins.borrow().contains(user_id))
+}
+
+/// Carry a merged-away id's opt-in over to the primary
+pub fn adopt_opt_in(primary: &str, duplicate: &str) {
+    MOOD_TRACKING_OPT_INS.with(|opt_ins| {
+        let mut opt_ins = opt_ins.borrow_mut();
+        if opt_ins.remove(duplicate) {
+            opt_ins.insert(primary.to_string());
+        }
+    });
+}
+
+pub fn get_all_opt_ins() -> Vec<String> {
+    MOOD_TRACKING_OPT_INS.with(|opt_ins| opt_ins.borrow().iter().cloned().collect())
+}
+
+pub fn restore_opt_ins(opt_ins: Vec<String>) {
+    MOOD_TRACKING_OPT_INS.with(|stored| *stored.borrow_mut() = opt_ins.into_iter().collect());
+}
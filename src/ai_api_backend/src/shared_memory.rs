@@ -0,0 +1,227 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::personality::{cosine_similarity, is_visible, Visibility};
+
+// Upper bound on lore items Lain may propose from a single extraction pass
+pub const MAX_EXTRACTED_PER_PASS: usize = 5;
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub enum SharedMemorySource {
+    Moderator,   // Written directly by a room moderator
+    Extracted,   // Proposed by Lain from room conversations
+}
+
+/// Channel-scoped memory shared by everyone in a room (running jokes, group facts)
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SharedMemory {
+    pub id: u64,
+    pub channel_id: String,
+    pub text: String,
+    pub embedding: Vec<f32>,       // Optional vector; empty when written without one
+    pub source: SharedMemorySource,
+    pub author: String,            // Moderator principal, or "lain" for extracted lore
+    pub approved: bool,            // Extracted lore waits for a moderator before use
+    pub importance: f32,           // 0.0-1.0
+    pub created_at: u64,
+    pub visibility: Option<Visibility>, // None: Room
+}
+
+impl SharedMemory {
+    pub fn resolved_visibility(&self) -> Visibility {
+        self.visibility.unwrap_or(Visibility::Room)
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum CurateAction {
+    Approve,
+    Remove,
+    EditText(String),
+    SetImportance(f32),
+}
+
+thread_local! {
+    static SHARED_MEMORIES: RefCell<Vec<SharedMemory>> = const { RefCell::new(Vec::new()) };
+    static NEXT_SHARED_MEMORY_ID: RefCell<u64> = const { RefCell::new(1) };
+    // room_id -> moderator principals (text)
+    static ROOM_MODERATORS: RefCell<HashMap<String, Vec<String>>> = RefCell::new(HashMap::new());
+}
+
+// === MODERATORS ===
+
+/// Controllers moderate every room; others need an explicit grant
+pub fn is_room_moderator(room_id: &str, principal: &candid::Principal) -> bool {
+    if ic_cdk::api::is_controller(principal) {
+        return true;
+    }
+    
+    let principal_text = principal.to_text();
+    ROOM_MODERATORS.with(|mods| {
+        mods.borrow()
+            .get(room_id)
+            .map(|list| list.contains(&principal_text))
+            .unwrap_or(false)
+    })
+}
+
+pub fn add_room_moderator(room_id: &str, principal: &str) {
+    ROOM_MODERATORS.with(|mods| {
+        let mut mods = mods.borrow_mut();
+        let list = mods.entry(room_id.to_string()).or_default();
+        if !list.iter().any(|p| p == principal) {
+            list.push(principal.to_string());
+        }
+    });
+}
+
+pub fn remove_room_moderator(room_id: &str, principal: &str) {
+    ROOM_MODERATORS.with(|mods| {
+        if let Some(list) = mods.borrow_mut().get_mut(room_id) {
+            list.retain(|p| p != principal);
+        }
+    });
+}
+
+pub fn get_room_moderators(room_id: &str) -> Vec<String> {
+    ROOM_MODERATORS.with(|mods| mods.borrow().get(room_id).cloned().unwrap_or_default())
+}
+
+// === SHARED MEMORIES ===
+
+pub fn add_shared_memory(
+    channel_id: &str,
+    text: String,
+    embedding: Vec<f32>,
+    source: SharedMemorySource,
+    author: String,
+    importance: f32,
+) -> SharedMemory {
+    let id = NEXT_SHARED_MEMORY_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next;
+        *next += 1;
+        id
+    });
+    
+    let memory = SharedMemory {
+        id,
+        channel_id: channel_id.to_string(),
+        text,
+        embedding,
+        approved: source == SharedMemorySource::Moderator,
+        source,
+        author,
+        importance: importance.clamp(0.0, 1.0),
+        created_at: ic_cdk::api::time(),
+        visibility: Some(Visibility::Room),
+    };
+    
+    SHARED_MEMORIES.with(|memories| memories.borrow_mut().push(memory.clone()));
+    memory
+}
+
+pub fn list_shared_memories(channel_id: &str, include_pending: bool) -> Vec<SharedMemory> {
+    SHARED_MEMORIES.with(|memories| {
+        memories.borrow()
+            .iter()
+            .filter(|m| m.channel_id == channel_id && (include_pending || m.approved))
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn get_shared_memory(id: u64) -> Option<SharedMemory> {
+    SHARED_MEMORIES.with(|memories| memories.borrow().iter().find(|m| m.id == id).cloned())
+}
+
+/// Apply a moderator decision; returns the updated memory, or None if it was removed
+pub fn curate_shared_memory(id: u64, action: CurateAction) -> Option<SharedMemory> {
+    SHARED_MEMORIES.with(|memories| {
+        let mut memories = memories.borrow_mut();
+        
+        if let CurateAction::Remove = action {
+            memories.retain(|m| m.id != id);
+            return None;
+        }
+        
+        let memory = memories.iter_mut().find(|m| m.id == id)?;
+        match action {
+            CurateAction::Approve => memory.approved = true,
+            CurateAction::EditText(text) => {
+                memory.text = text;
+                // The old vector no longer describes the text
+                memory.embedding.clear();
+            }
+            CurateAction::SetImportance(importance) => memory.importance = importance.clamp(0.0, 1.0),
+            CurateAction::Remove => {}
+        }
+        Some(memory.clone())
+    })
+}
+
+/// Shift the importance of a room's lore whose text matches one of `texts`,
+/// keeping it within [min, max]. Returns how many were adjusted
+pub fn adjust_shared_memory_importance(channel_id: &str, texts: &[String], delta: f32, min: f32, max: f32) -> u32 {
+    SHARED_MEMORIES.with(|memories| {
+        let mut count = 0;
+        for memory in memories.borrow_mut().iter_mut() {
+            if memory.channel_id == channel_id && texts.contains(&memory.text) {
+                memory.importance = (memory.importance + delta).clamp(min, max);
+                count += 1;
+            }
+        }
+        count
+    })
+}
+
+/// Approved lore for a room, ranked by similarity to the query when vectors are available,
+/// otherwise by importance
+pub fn get_shared_memory_context(channel_id: &str, query_embedding: Option<&[f32]>, top_k: usize) -> Vec<String> {
+    let mut scored: Vec<(f32, String)> = list_shared_memories(channel_id, false)
+        .into_iter()
+        .filter(|memory| {
+            is_visible(memory.resolved_visibility(), Some(&memory.author), &memory.channel_id, None, Some(channel_id))
+        })
+        .map(|memory| {
+            let score = match query_embedding {
+                Some(query) if !memory.embedding.is_empty() => {
+                    cosine_similarity(query, &memory.embedding) * memory.importance
+                }
+                _ => memory.importance,
+            };
+            (score, memory.text)
+        })
+        .collect();
+    
+    scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    scored.into_iter().take(top_k).map(|(_, text)| text).collect()
+}
+
+/// Parse the LLM extraction output into individual lore lines
+pub fn parse_extracted_lore(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(|line| line.trim().trim_start_matches(['-', '*', '•']).trim())
+        .filter(|line| !line.is_empty() && !line.eq_ignore_ascii_case("none"))
+        .take(MAX_EXTRACTED_PER_PASS)
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// Functions for upgrade persistence
+pub fn get_all_shared_memories() -> Vec<SharedMemory> {
+    SHARED_MEMORIES.with(|memories| memories.borrow().clone())
+}
+
+pub fn get_all_room_moderators() -> Vec<(String, Vec<String>)> {
+    ROOM_MODERATORS.with(|mods| mods.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+}
+
+pub fn restore_shared_memories(memories: Vec<SharedMemory>, moderators: Vec<(String, Vec<String>)>) {
+    let next_id = memories.iter().map(|m| m.id).max().unwrap_or(0) + 1;
+    SHARED_MEMORIES.with(|m| *m.borrow_mut() = memories);
+    NEXT_SHARED_MEMORY_ID.with(|next| *next.borrow_mut() = next_id);
+    ROOM_MODERATORS.with(|mods| *mods.borrow_mut() = moderators.into_iter().collect());
+}
@@ -1,6 +1,82 @@
 use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use crate::personality::{UserProfile, ConversationEmbedding, BigFiveTraits, TopicInterest};
 
+// Accepted UTC offsets, in minutes (UTC-12:00 to UTC+14:00)
+const MIN_TIMEZONE_OFFSET: i32 = -12 * 60;
+const MAX_TIMEZONE_OFFSET: i32 = 14 * 60;
+
+// Chunks needed before observed activity is trusted over the timezone-based guess
+const MIN_ACTIVITY_SAMPLES: usize = 5;
+
+// Local hours assumed awake when a user has a timezone but little chat history
+const ASSUMED_WAKING_HOURS: std::ops::Range<i32> = 8..23;
+
+// How far the weights may drift from summing to exactly 1.0
+const WEIGHT_SUM_TOLERANCE: f32 = 0.001;
+
+/// Share each component contributes to calculate_similarity. Components must be
+/// non-negative and sum to 1.0
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SimilarityWeights {
+    pub semantic: f32,
+    pub personality: f32,
+    pub interests: f32,
+    pub style: f32,
+    pub interaction: f32,
+    pub active_hours: f32,
+}
+
+impl SimilarityWeights {
+    pub const DEFAULT: SimilarityWeights = SimilarityWeights {
+        semantic: 0.30,
+        personality: 0.25,
+        interests: 0.20,
+        style: 0.15,
+        interaction: 0.05,
+        active_hours: 0.05,
+    };
+    
+    fn components(&self) -> [(&'static str, f32); 6] {
+        [
+            ("semantic", self.semantic),
+            ("personality", self.personality),
+            ("interests", self.interests),
+            ("style", self.style),
+            ("interaction", self.interaction),
+            ("active hours", self.active_hours),
+        ]
+    }
+    
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some((name, _)) = self.components().iter().find(|(_, weight)| !weight.is_finite() || *weight < 0.0) {
+            return Err(format!("Weight for {} must be a non-negative number", name));
+        }
+        let sum: f32 = self.components().iter().map(|(_, weight)| weight).sum();
+        if (sum - 1.0).abs() > WEIGHT_SUM_TOLERANCE {
+            return Err(format!("Weights must sum to 1.0 (got {:.3})", sum));
+        }
+        Ok(())
+    }
+    
+    /// e.g. "semantic 30%, personality 25%, ..."
+    pub fn describe(&self) -> String {
+        self.components()
+            .iter()
+            .map(|(name, weight)| format!("{} {}%", name, (weight * 100.0).round() as u32))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+thread_local! {
+    // user_id -> UTC offset in minutes, set by the user
+    static USER_TIMEZONES: RefCell<HashMap<String, i32>> = RefCell::new(HashMap::new());
+    
+    static SIMILARITY_WEIGHTS: RefCell<SimilarityWeights> = const { RefCell::new(SimilarityWeights::DEFAULT) };
+}
+
 #[derive(CandidType, Deserialize, Debug, Clone)]
 pub struct ConversationStyle {
     pub formality: f32,        // 0.0 = very casual, 1.0 = very formal
@@ -29,29 +105,37 @@ pub struct InteractionPatterns {
 impl UserProfile {
     /// Calculate multi-dimensional similarity between two user profiles
     pub fn calculate_similarity(&self, other: &UserProfile) -> f32 {
-        // 1. Semantic similarity using aggregated embeddings (35% weight)
+        // 1. Semantic similarity using aggregated embeddings
         let semantic_similarity = calculate_cosine_similarity(&self.aggregated_embedding, &other.aggregated_embedding);
         
-        // 2. Personality trait similarity (25% weight)  
+        // 2. Personality trait similarity  
         let personality_similarity = calculate_personality_similarity(&self.personality_traits, &other.personality_traits);
         
-        // 3. Interest overlap (20% weight)
+        // 3. Interest overlap
         let interest_similarity = calculate_interest_overlap(&self.interests, &other.interests);
         
-        // 4. Conversation style similarity (15% weight)
+        // 4. Conversation style similarity
         let conversations_self = crate::personality::get_user_conversation_history(&self.user_id, "");
         let conversations_other = crate::personality::get_user_conversation_history(&other.user_id, ""); 
         let style_similarity = calculate_style_similarity(&conversations_self, &conversations_other);
         
-        // 5. Interaction patterns (5% weight)
+        // 5. Interaction patterns
         let interaction_similarity = calculate_interaction_similarity(&conversations_self, &conversations_other);
         
+        // 6. Overlap of active hours
+        let active_hours_similarity = calculate_active_hours_overlap(
+            &active_hours(&conversations_self, get_timezone_offset(&self.user_id)),
+            &active_hours(&conversations_other, get_timezone_offset(&other.user_id)),
+        );
+        
         // Weighted combination
-        let overall_similarity = semantic_similarity * 0.35 +
-            personality_similarity * 0.25 +
-            interest_similarity * 0.20 +
-            style_similarity * 0.15 +
-            interaction_similarity * 0.05;
+        let weights = get_similarity_weights();
+        let overall_similarity = semantic_similarity * weights.semantic +
+            personality_similarity * weights.personality +
+            interest_similarity * weights.interests +
+            style_similarity * weights.style +
+            interaction_similarity * weights.interaction +
+            active_hours_similarity * weights.active_hours;
             
         overall_similarity.min(1.0).max(0.0)
     }
@@ -195,6 +279,41 @@ fn analyze_interaction_patterns(conversations: &[ConversationEmbedding]) -> Inte
     }
 }
 
+/// Share of a user's activity in each UTC hour. Observed from chunk timestamps once there are
+/// enough of them, otherwise guessed from their timezone; None when there is nothing to go on
+fn active_hours(conversations: &[ConversationEmbedding], timezone_offset: Option<i32>) -> Option<[f32; 24]> {
+    let mut hours = [0.0f32; 24];
+    
+    if conversations.len() >= MIN_ACTIVITY_SAMPLES {
+        for conversation in conversations {
+            let hour = (conversation.created_at / 1_000_000_000 / 3600) % 24;
+            hours[hour as usize] += 1.0;
+        }
+    } else if let Some(offset) = timezone_offset {
+        for local_hour in ASSUMED_WAKING_HOURS {
+            let utc_hour = (local_hour * 60 - offset).div_euclid(60).rem_euclid(24);
+            hours[utc_hour as usize] += 1.0;
+        }
+    } else {
+        return None;
+    }
+    
+    let total: f32 = hours.iter().sum();
+    for share in hours.iter_mut() {
+        *share /= total;
+    }
+    Some(hours)
+}
+
+/// How much of two activity distributions coincide (1.0 = awake at exactly the same times).
+/// Neutral when either user's hours are unknown
+fn calculate_active_hours_overlap(hours1: &Option<[f32; 24]>, hours2: &Option<[f32; 24]>) -> f32 {
+    match (hours1, hours2) {
+        (Some(hours1), Some(hours2)) => hours1.iter().zip(hours2.iter()).map(|(a, b)| a.min(*b)).sum(),
+        _ => 0.5,
+    }
+}
+
 /// Simple heuristics for analyzing conversation characteristics
 fn analyze_formality(conversations: &[ConversationEmbedding]) -> f32 {
     // Count formal vs informal words
@@ -273,6 +392,55 @@ fn analyze_politeness(conversations: &[ConversationEmbedding]) -> f32 {
     polite_count as f32 / total as f32
 }
 
+/// Store (or clear) the user's UTC offset in minutes
+pub fn set_timezone_offset(user_id: &str, offset_minutes: Option<i32>) -> Result<(), String> {
+    match offset_minutes {
+        Some(offset) if !(MIN_TIMEZONE_OFFSET..=MAX_TIMEZONE_OFFSET).contains(&offset) => {
+            Err(format!("Timezone offset must be between {} and {} minutes", MIN_TIMEZONE_OFFSET, MAX_TIMEZONE_OFFSET))
+        }
+        Some(offset) => {
+            USER_TIMEZONES.with(|timezones| timezones.borrow_mut().insert(user_id.to_string(), offset));
+            Ok(())
+        }
+        None => {
+            USER_TIMEZONES.with(|timezones| timezones.borrow_mut().remove(user_id));
+            Ok(())
+        }
+    }
+}
+
+pub fn get_timezone_offset(user_id: &str) -> Option<i32> {
+    USER_TIMEZONES.with(|timezones| timezones.borrow().get(user_id).copied())
+}
+
+/// Keep a merged-away id's timezone unless the primary has its own
+pub fn adopt_timezone(primary: &str, duplicate: &str) {
+    USER_TIMEZONES.with(|timezones| {
+        let mut timezones = timezones.borrow_mut();
+        if let Some(offset) = timezones.remove(duplicate) {
+            timezones.entry(primary.to_string()).or_insert(offset);
+        }
+    });
+}
+
+pub fn get_all_timezones() -> Vec<(String, i32)> {
+    USER_TIMEZONES.with(|timezones| timezones.borrow().iter().map(|(id, offset)| (id.clone(), *offset)).collect())
+}
+
+pub fn restore_timezones(timezones: Vec<(String, i32)>) {
+    USER_TIMEZONES.with(|stored| *stored.borrow_mut() = timezones.into_iter().collect());
+}
+
+pub fn get_similarity_weights() -> SimilarityWeights {
+    SIMILARITY_WEIGHTS.with(|weights| *weights.borrow())
+}
+
+pub fn set_similarity_weights(weights: SimilarityWeights) -> Result<(), String> {
+    weights.validate()?;
+    SIMILARITY_WEIGHTS.with(|stored| *stored.borrow_mut() = weights);
+    Ok(())
+}
+
 /// Public API function for calculating similarity between users
 pub fn calculate_user_similarity(profile1: &UserProfile, profile2: &UserProfile) -> f32 {
     profile1.calculate_similarity(profile2)
@@ -0,0 +1,127 @@
+//! Approximate nearest-neighbour index (random-hyperplane LSH) and the brute-force baseline it
+//! is measured against. Pure Rust with no canister APIs, so the bench target can include it.
+
+use std::collections::HashMap;
+
+// Signature width bounds; more bits means smaller buckets but more misses
+const MIN_SIGNATURE_BITS: u32 = 4;
+const MAX_SIGNATURE_BITS: u32 = 16;
+
+// Target average bucket size when choosing the signature width
+const TARGET_BUCKET_SIZE: usize = 16;
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Exact top-k by cosine similarity: (vector index, similarity), best first
+pub fn brute_force_search(vectors: &[Vec<f32>], query: &[f32], k: usize) -> Vec<(usize, f32)> {
+    top_k(vectors.iter().enumerate().map(|(i, v)| (i, cosine(query, v))), k)
+}
+
+fn top_k(scored: impl Iterator<Item = (usize, f32)>, k: usize) -> Vec<(usize, f32)> {
+    let mut scored: Vec<(usize, f32)> = scored.collect();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    scored.truncate(k);
+    scored
+}
+
+/// Deterministic xorshift generator so benchmarks are reproducible without a rand dependency
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        XorShift(seed.max(1))
+    }
+    
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    
+    /// Uniform in [-1, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+pub fn random_vectors(count: usize, dim: usize, rng: &mut XorShift) -> Vec<Vec<f32>> {
+    (0..count).map(|_| (0..dim).map(|_| rng.next_f32()).collect()).collect()
+}
+
+/// Buckets vectors by which side of `bits` random hyperplanes they fall on. A query probes its
+/// own bucket and every bucket one bit away, then re-ranks the candidates exactly
+pub struct LshIndex {
+    planes: Vec<Vec<f32>>,
+    buckets: HashMap<u32, Vec<usize>>,
+    vectors: Vec<Vec<f32>>,
+}
+
+impl LshIndex {
+    pub fn build(vectors: Vec<Vec<f32>>, seed: u64) -> Self {
+        let dim = vectors.first().map(|v| v.len()).unwrap_or(0);
+        let bits = ((vectors.len() / TARGET_BUCKET_SIZE).max(1).ilog2()).clamp(MIN_SIGNATURE_BITS, MAX_SIGNATURE_BITS);
+        
+        let mut rng = XorShift::new(seed);
+        let planes = random_vectors(bits as usize, dim, &mut rng);
+        
+        let mut index = LshIndex { planes, buckets: HashMap::new(), vectors: Vec::new() };
+        for (i, vector) in vectors.iter().enumerate() {
+            let signature = index.signature(vector);
+            index.buckets.entry(signature).or_default().push(i);
+        }
+        index.vectors = vectors;
+        index
+    }
+    
+    fn signature(&self, vector: &[f32]) -> u32 {
+        self.planes.iter().enumerate().fold(0, |signature, (bit, plane)| {
+            let dot: f32 = plane.iter().zip(vector).map(|(p, v)| p * v).sum();
+            if dot >= 0.0 { signature | (1 << bit) } else { signature }
+        })
+    }
+    
+    pub fn signature_bits(&self) -> u32 {
+        self.planes.len() as u32
+    }
+    
+    /// Approximate top-k: (vector index, similarity), best first. Falls back to a full scan
+    /// when the probed buckets hold fewer than k vectors
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        let signature = self.signature(query);
+        
+        let mut candidates: Vec<usize> = Vec::new();
+        let probes = std::iter::once(signature).chain((0..self.planes.len()).map(|bit| signature ^ (1 << bit)));
+        for probe in probes {
+            if let Some(bucket) = self.buckets.get(&probe) {
+                candidates.extend(bucket);
+            }
+        }
+        
+        if candidates.len() < k {
+            return brute_force_search(&self.vectors, query, k);
+        }
+        top_k(candidates.into_iter().map(|i| (i, cosine(query, &self.vectors[i]))), k)
+    }
+}
+
+/// Share of the exact top-k that the approximate result found
+pub fn recall(exact: &[(usize, f32)], approximate: &[(usize, f32)]) -> f32 {
+    if exact.is_empty() {
+        return 1.0;
+    }
+    let hits = exact.iter().filter(|(i, _)| approximate.iter().any(|(j, _)| i == j)).count();
+    hits as f32 / exact.len() as f32
+}
@@ -0,0 +1,88 @@
+//! Failure injection for the PocketIC integration suite. Compiled only with the `chaos` feature
+//! and never part of a production build.
+//!
+//! A trap rolls back everything the message changed, including any bookkeeping done here, so
+//! an armed trap keeps firing until it is cleared. Tests arm it, make the call that should
+//! fail, check what survived, then clear it.
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::Memory;
+use std::cell::RefCell;
+
+use crate::storage;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ChaosState {
+    // Trap at the next write point; None = not armed, Some(None) = any point
+    pub trap_at: Option<Option<String>>,
+    // Outbox deliveries still to fail before calls go through again
+    pub failing_outbox_calls: u32,
+    // WASM pages grown into the ballast region by fill_stable_memory
+    pub ballast_pages: u64,
+}
+
+thread_local! {
+    static STATE: RefCell<ChaosState> = RefCell::new(ChaosState::default());
+}
+
+pub fn arm_trap(point: Option<String>) {
+    STATE.with(|state| state.borrow_mut().trap_at = Some(point));
+}
+
+/// Trap if a trap is armed for `point`. Called between the steps of multi-map writes
+pub fn write_point(point: &str) {
+    let armed = STATE.with(|state| match &state.borrow().trap_at {
+        Some(None) => true,
+        Some(Some(target)) => target == point,
+        None => false,
+    });
+    if armed {
+        ic_cdk::trap(&format!("chaos: trap at write point {}", point));
+    }
+}
+
+pub fn fail_outbox_calls(count: u32) {
+    STATE.with(|state| state.borrow_mut().failing_outbox_calls = count);
+}
+
+/// Whether this outbox delivery should fail, using up one of the injected failures
+pub fn take_outbox_failure() -> bool {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.failing_outbox_calls == 0 {
+            return false;
+        }
+        state.failing_outbox_calls -= 1;
+        true
+    })
+}
+
+/// Grow the ballast region by up to `max_pages` (until growth fails when None), returning the
+/// pages added. Stable memory never shrinks, so only a reinstall gives the space back
+pub fn fill_stable_memory(max_pages: Option<u64>) -> u64 {
+    const STEP_PAGES: u64 = 16;
+    let memory = storage::chaos_ballast_memory();
+    let mut grown = 0;
+    while max_pages.is_none_or(|max| grown < max) {
+        let step = max_pages.map_or(STEP_PAGES, |max| (max - grown).min(STEP_PAGES));
+        if memory.grow(step) < 0 {
+            break;
+        }
+        grown += step;
+    }
+    STATE.with(|state| state.borrow_mut().ballast_pages += grown);
+    grown
+}
+
+/// Disarm every fault. Ballast already grown stays allocated
+pub fn reset() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.trap_at = None;
+        state.failing_outbox_calls = 0;
+    });
+}
+
+pub fn get_state() -> ChaosState {
+    STATE.with(|state| state.borrow().clone())
+}
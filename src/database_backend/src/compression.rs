@@ -0,0 +1,150 @@
+use candid::{CandidType, Deserialize, Encode};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+// Payloads smaller than this are sent as-is; gzip overhead outweighs the saving
+pub const COMPRESSION_MIN_BYTES: usize = 16 * 1024;
+
+// Largest payload we return, leaving headroom under the replicated response limit
+pub const MAX_PAYLOAD_BYTES: usize = 3_100_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PayloadEncoding {
+    Identity,
+    Gzip,
+}
+
+/// Candid-encoded response data, gzipped when the client accepts it and it is large enough.
+/// Clients decompress (if needed) and decode `data` as the plain endpoint's data type
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EncodedPayload {
+    pub encoding: PayloadEncoding,
+    pub data: Vec<u8>,
+    pub uncompressed_size: u64,
+    pub chunk: Option<PayloadChunk>, // Set when the payload is too large for one response
+}
+
+/// Position of `data` within a payload split across responses. Clients fetch chunks 0..count
+/// by index and concatenate them before decompressing; if `total_size` changes between
+/// chunks the data changed underneath them and they start over
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PayloadChunk {
+    pub index: u32,
+    pub count: u32,
+    pub total_size: u64,
+}
+
+/// Why a payload could not be produced
+pub enum EncodeError {
+    Candid,
+    ChunkOutOfRange(u32), // Number of chunks the payload has
+}
+
+/// Encode `value`, splitting it into chunks when it would not fit in one response. `chunk`
+/// picks the chunk to return (the first when None)
+pub fn encode<T: CandidType>(value: &T, accepts_compression: bool, chunk: Option<u32>) -> Result<EncodedPayload, EncodeError> {
+    let raw = Encode!(value).map_err(|_| EncodeError::Candid)?;
+    let uncompressed_size = raw.len() as u64;
+
+    let (encoding, data) = if accepts_compression && raw.len() >= COMPRESSION_MIN_BYTES {
+        match gzip(&raw) {
+            Some(compressed) if compressed.len() < raw.len() => (PayloadEncoding::Gzip, compressed),
+            _ => (PayloadEncoding::Identity, raw),
+        }
+    } else {
+        (PayloadEncoding::Identity, raw)
+    };
+
+    if data.len() <= MAX_PAYLOAD_BYTES && chunk.unwrap_or(0) == 0 {
+        return Ok(EncodedPayload { encoding, data, uncompressed_size, chunk: None });
+    }
+
+    let count = data.len().div_ceil(MAX_PAYLOAD_BYTES) as u32;
+    let index = chunk.unwrap_or(0);
+    if index >= count {
+        return Err(EncodeError::ChunkOutOfRange(count));
+    }
+    let start = index as usize * MAX_PAYLOAD_BYTES;
+    let end = (start + MAX_PAYLOAD_BYTES).min(data.len());
+    Ok(EncodedPayload {
+        encoding,
+        data: data[start..end].to_vec(),
+        uncompressed_size,
+        chunk: Some(PayloadChunk { index, count, total_size: data.len() as u64 }),
+    })
+}
+
+pub fn gzip(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(bytes.len() / 2), Compression::default());
+    encoder.write_all(bytes).ok()?;
+    encoder.finish().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    // A blob whose Candid encoding is exactly `size` bytes
+    fn blob_encoding_to(size: usize) -> Vec<u8> {
+        let overhead = Encode!(&vec![0u8; size]).unwrap().len() - size;
+        let blob = vec![7u8; size - overhead];
+        assert_eq!(Encode!(&blob).unwrap().len(), size);
+        blob
+    }
+
+    #[test]
+    fn payload_at_the_limit_fits_in_one_response() {
+        let payload = encode(&blob_encoding_to(MAX_PAYLOAD_BYTES), false, None).ok().unwrap();
+        assert_eq!(payload.encoding, PayloadEncoding::Identity);
+        assert_eq!(payload.data.len(), MAX_PAYLOAD_BYTES);
+        assert!(payload.chunk.is_none());
+    }
+
+    #[test]
+    fn payload_just_under_the_limit_fits_in_one_response() {
+        let payload = encode(&blob_encoding_to(MAX_PAYLOAD_BYTES - 1), false, None).ok().unwrap();
+        assert_eq!(payload.data.len(), MAX_PAYLOAD_BYTES - 1);
+        assert!(payload.chunk.is_none());
+    }
+
+    #[test]
+    fn payload_just_over_the_limit_is_chunked() {
+        let value = blob_encoding_to(MAX_PAYLOAD_BYTES + 1);
+
+        let first = encode(&value, false, None).ok().unwrap();
+        let chunk = first.chunk.unwrap();
+        assert_eq!((chunk.index, chunk.count, chunk.total_size), (0, 2, MAX_PAYLOAD_BYTES as u64 + 1));
+        assert_eq!(first.data.len(), MAX_PAYLOAD_BYTES);
+
+        let last = encode(&value, false, Some(1)).ok().unwrap();
+        assert_eq!(last.data.len(), 1);
+        assert!(matches!(encode(&value, false, Some(2)), Err(EncodeError::ChunkOutOfRange(2))));
+
+        let mut joined = first.data;
+        joined.extend(last.data);
+        assert_eq!(joined, Encode!(&value).unwrap());
+    }
+
+    #[test]
+    fn compressed_payload_over_the_limit_fits_in_one_response() {
+        let value = blob_encoding_to(MAX_PAYLOAD_BYTES * 2);
+        let payload = encode(&value, true, None).ok().unwrap();
+        assert_eq!(payload.encoding, PayloadEncoding::Gzip);
+        assert!(payload.chunk.is_none());
+        assert!(payload.data.len() <= MAX_PAYLOAD_BYTES);
+        assert_eq!(payload.uncompressed_size, MAX_PAYLOAD_BYTES as u64 * 2);
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(payload.data.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, Encode!(&value).unwrap());
+    }
+
+    #[test]
+    fn small_payloads_are_not_compressed() {
+        let payload = encode(&blob_encoding_to(COMPRESSION_MIN_BYTES - 1), true, None).ok().unwrap();
+        assert_eq!(payload.encoding, PayloadEncoding::Identity);
+    }
+}
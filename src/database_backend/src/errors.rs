@@ -0,0 +1,248 @@
+use candid::Principal;
+use ic_cdk::caller;
+
+use crate::storage;
+use crate::types::ApiResponse;
+
+/// Every error code with its English text, served when no catalog has the code. Templates name their parameters as {param}
+pub const BUILTIN_MESSAGES: &[(&str, &str)] = &[
+    ("unauthorized_controller", "Unauthorized: caller is not a controller"),
+    ("unauthorized_moderator", "Unauthorized: caller is not a moderator"),
+    ("unauthorized_admin", "Unauthorized: caller is not an admin"),
+    ("untrusted_canister", "Unauthorized: caller is not a trusted canister"),
+    ("not_authenticated", "Not authenticated: sign in to do this"),
+    ("outbox_entry_not_found", "Outbox entry not found"),
+    ("payload_encoding_failed", "Response could not be encoded"),
+    ("payload_too_large", "Response of {size} bytes exceeds the {max} byte limit; use a paginated method"),
+    ("response_too_large", "Response of {size} bytes exceeds the {max} byte limit; use {alternative} instead"),
+    ("payload_chunk_out_of_range", "This payload has {count} chunks"),
+    ("account_suspended", "Account suspended: {reason}"),
+    ("account_banned", "Account banned: {reason}"),
+    ("account_not_banned", "Account is not banned"),
+    ("ban_reason_required", "A ban reason is required"),
+    ("cannot_ban_staff", "Admins and moderators cannot be banned; revoke their role first"),
+    ("role_not_held", "This principal does not hold that role"),
+    ("user_not_registered", "User not registered"),
+    ("user_already_registered", "User already registered"),
+    ("user_not_found", "User not found"),
+    ("display_name_taken", "Display name '{name}' is already taken"),
+    ("handle_invalid", "Handles must be {min} to {max} characters of letters, digits and underscores"),
+    ("handle_taken", "That handle is already taken"),
+    ("handle_change_too_soon", "Your handle can be changed again in {days} days"),
+    ("handle_not_found", "No user has that handle"),
+    ("display_name_reserved", "Display name '{name}' is reserved"),
+    ("handle_reserved", "That handle is reserved"),
+    ("reserved_name_empty", "A reserved name needs at least one letter or digit"),
+    ("reserved_name_not_found", "That name is not reserved"),
+    ("invite_required", "Registration is by invitation only; enter an invite code"),
+    ("invite_invalid", "That invite code is not valid"),
+    ("invite_used", "That invite code has already been used"),
+    ("invite_not_found", "Invite code not found"),
+    ("invite_count_invalid", "Mint between 1 and {max} invite codes at a time"),
+    ("friend_not_found", "Friend user not found"),
+    ("already_friends", "Already friends"),
+    ("not_friends", "This user is not in your friends list"),
+    ("cannot_follow_self", "You cannot follow yourself"),
+    ("already_following", "Already following this user"),
+    ("not_following", "You are not following this user"),
+    ("follow_blocked", "Cannot follow: user is blocked"),
+    ("follow_request_not_found", "Follow request not found"),
+    ("too_many_friendship_checks", "At most {max} principals can be checked at once"),
+    ("add_friend_blocked", "Cannot add friend: user is blocked"),
+    ("friend_request_not_found", "Friend request not found"),
+    ("friend_request_already_sent", "Friend request already sent"),
+    ("friend_request_incoming_exists", "This user has already sent you a friend request. Check your pending requests."),
+    ("friend_request_blocked", "Cannot send friend request: you are blocked"),
+    ("friend_request_note_too_long", "Friend request notes can be at most {max} characters"),
+    ("friend_requests_closed", "This user is not accepting friend requests"),
+    ("friend_requests_friends_of_friends_only", "This user only accepts friend requests from friends of friends"),
+    ("friend_request_limit_reached", "You can have at most {max} friend requests waiting for an answer"),
+    ("friend_limit_reached", "You have reached the limit of {max} friends"),
+    ("recipient_friend_limit_reached", "This user has reached the limit of {max} friends"),
+    ("friend_quota_zero", "Friend quotas must be at least 1"),
+    ("request_not_pending", "Request is not pending"),
+    ("request_accept_not_authorized", "Not authorized to accept this request"),
+    ("request_reject_not_authorized", "Not authorized to reject this request"),
+    ("sync_data_not_found", "No sync data found for user"),
+    ("sender_not_registered", "Sender not registered"),
+    ("recipient_not_found", "Recipient not found"),
+    ("invalid_friend_principal", "Invalid friend principal"),
+    ("dm_to_self", "Cannot send DM to yourself"),
+    ("message_too_long", "Messages can be at most {max} characters"),
+    ("dm_send_not_friends", "Cannot send DM: not friends"),
+    ("dm_read_not_friends", "Cannot read DMs: not friends"),
+    ("dm_blocked", "Cannot send DM: user is blocked"),
+    ("dm_read_blocked", "Cannot read DMs: user is blocked"),
+    ("dm_message_not_found", "Message not found"),
+    ("message_not_starred", "Message is not starred"),
+    ("too_many_starred_messages", "You can star at most {max} messages"),
+    ("dm_delete_not_sender", "Only the sender can delete a message"),
+    ("dm_already_deleted", "Message has already been deleted"),
+    ("encrypted_dm_size", "Encrypted messages must be between 1 and {max} bytes"),
+    ("message_not_forwardable", "Only text messages can be forwarded"),
+    ("forward_target_not_member", "You can only forward to a conversation you are in"),
+    ("reaction_invalid_emoji", "Reactions must be a single emoji of at most {max_bytes} bytes"),
+    ("reaction_limit_reached", "You can add at most {max} reactions to a message"),
+    ("reaction_not_found", "You have not reacted with that emoji"),
+    ("thread_parent_deleted", "Cannot reply to a deleted message"),
+    ("thread_parent_is_reply", "Replies cannot start threads of their own"),
+    ("search_query_empty", "Search query cannot be empty"),
+    ("invalid_cursor", "Invalid cursor"),
+    ("cursor_scope_mismatch", "Cursor belongs to a different listing"),
+    ("journal_entry_empty", "Journal entry cannot be empty"),
+    ("journal_entry_too_large", "Journal entry exceeds {max_bytes} bytes"),
+    ("journal_entry_not_found", "Journal entry not found"),
+    ("device_name_invalid", "Device name must be 1-{max} characters"),
+    ("device_limit_reached", "You can link at most {max} devices; revoke one first"),
+    ("device_not_found", "Device not found"),
+    ("device_revoked", "This device has been revoked"),
+    ("device_required", "Name the device this sync comes from; register it first if it is new"),
+    ("device_key_invalid", "A device key must be its own principal, not the account's or anonymous"),
+    ("device_key_taken", "This key already belongs to an account or another device"),
+    ("device_key_required", "This device syncs with its own key; sign the sync with it"),
+    ("key_service_failed", "Key service call failed: {detail}"),
+    ("key_rate_limited", "Too many key requests: wait {retry_after_seconds}s and reuse the keys you already have"),
+    ("watch_term_empty", "Watch term cannot be empty"),
+    ("watch_term_not_found", "Watch term not found"),
+    ("notification_not_found", "Notification not found"),
+    ("report_not_found", "Report not found"),
+    ("report_details_too_long", "Report details can be at most {max} characters"),
+    ("mute_end_in_past", "Mute end must be in the future"),
+    ("conversation_not_found", "Conversation not found"),
+    ("conversation_not_muted", "Conversation is not muted"),
+    ("conversation_not_archived", "Conversation is not archived"),
+    ("draft_too_long", "Drafts can be at most {max} bytes"),
+    ("auto_accept_too_many_rules", "An auto-accept policy can have at most {max} rules"),
+    ("auto_accept_mutuals_out_of_range", "Mutual friend rules need between 1 and {max} mutual friends"),
+    ("message_lifetime_out_of_range", "Disappearing messages must last between {min} and {max} seconds"),
+    ("suspension_reason_required", "A suspension reason is required"),
+    ("suspension_end_in_past", "Suspension end must be in the future"),
+    ("account_not_suspended", "Account is not suspended"),
+    ("appeal_message_empty", "Appeal message cannot be empty"),
+    ("appeal_already_pending", "An appeal is already pending review"),
+    ("appeal_already_decided", "Your appeal against this suspension has already been decided"),
+    ("appeal_message_too_long", "Appeal message must be at most {max} characters"),
+    ("appeal_not_found", "Appeal not found"),
+    ("appeal_already_reviewed", "Appeal has already been reviewed"),
+    ("trust_reason_required", "A reason is required to change a trust tier"),
+    ("trust_restricted", "Your account is restricted and cannot send friend requests"),
+    ("slow_mode_active", "Slow mode is on in {channel}: wait {seconds_remaining}s before posting again"),
+    ("send_rate_limited", "You are sending messages too quickly: wait {retry_after_seconds}s before sending again"),
+    ("slow_mode_too_long", "Slow mode can be at most {max_seconds} seconds"),
+    ("retention_too_short", "Retention must be at least one day"),
+    ("room_id_empty", "Room id cannot be empty"),
+    ("room_id_invalid", "Room ids are at most {max} characters with no spaces or control characters"),
+    ("invalid_locale", "Invalid locale '{locale}'"),
+    ("unknown_error_code", "Unknown error code '{code}'"),
+    ("app_name_required", "An app name is required"),
+    ("app_scopes_required", "At least one scope must be granted"),
+    ("app_grant_to_self", "Cannot grant access to yourself"),
+    ("app_grant_expiry_in_past", "Grant expiry must be in the future"),
+    ("app_grant_not_found", "No access grant for this app"),
+    ("app_scope_not_granted", "App has not been granted '{scope}'"),
+    ("poll_question_empty", "Poll question cannot be empty"),
+    ("poll_option_count", "A poll needs between {min} and {max} distinct options"),
+    ("poll_close_in_past", "Poll closing time must be in the future"),
+    ("poll_channel_not_member", "You are not a participant in this channel"),
+    ("poll_not_found", "Poll not found"),
+    ("poll_closed", "Poll is closed"),
+    ("poll_option_invalid", "Poll option {option} does not exist"),
+    ("poll_already_voted", "You have already voted in this poll"),
+    ("event_title_empty", "Event title cannot be empty"),
+    ("event_start_in_past", "Event start must be in the future"),
+    ("event_invitee_count", "An event needs between 1 and {max} invitees"),
+    ("event_invitee_not_friend", "{principal} is not in your friends list"),
+    ("event_not_found", "Event not found"),
+    ("event_not_invited", "You are not invited to this event"),
+    ("event_already_started", "Event has already started"),
+    ("group_name_empty", "Group name cannot be empty"),
+    ("group_not_found", "Group not found"),
+    ("group_message_empty", "Group messages cannot be empty"),
+    ("group_not_member", "You are not a member of this group"),
+    ("group_already_member", "You are already a member of this group"),
+    ("group_owner_cannot_leave", "The group owner cannot leave the group"),
+    ("group_invite_needs_limit", "An invite must expire or be single-use"),
+    ("group_invite_invalid", "Invite is invalid, expired or already used"),
+    ("group_invite_not_found", "Invite not found"),
+    ("group_join_blocked", "You cannot join this group"),
+    ("attachment_chunk_empty", "Chunk cannot be empty"),
+    ("attachment_chunk_too_large", "Chunks can be at most {max_bytes} bytes"),
+    ("attachment_too_large", "Attachments can be at most {max_bytes} bytes"),
+    ("attachment_name_empty", "A file name is required"),
+    ("attachment_not_found", "Attachment not found"),
+    ("attachment_chunk_out_of_range", "Attachment has {chunk_count} chunks"),
+    ("avatar_chunk_empty", "Avatar chunk cannot be empty"),
+    ("avatar_chunk_too_large", "Avatar chunks can be at most {max_bytes} bytes"),
+    ("avatar_chunk_out_of_order", "Avatar chunks must be uploaded in order; expected chunk {expected}"),
+    ("avatar_too_large", "Avatars can be at most {max_bytes} bytes"),
+    ("avatar_upload_empty", "No avatar image was uploaded"),
+    ("avatar_invalid_type", "Avatars must be PNG, JPEG, GIF or WebP images"),
+    ("banner_invalid_type", "Banners must be PNG, JPEG, GIF or WebP images"),
+    ("banner_too_large", "Banners can be at most {max_bytes} bytes"),
+    ("banner_not_found", "This user has no banner"),
+    ("theme_invalid_color", "'{color}' is not a color; use #rrggbb"),
+    ("avatar_invalid_encoding", "Avatar is not valid base64"),
+    ("avatar_not_found", "Avatar not found"),
+    ("emoji_invalid_shortcode", "Shortcodes are {min} to {max} lowercase letters, digits or underscores"),
+    ("emoji_shortcode_taken", "The pack already has an emoji called :{shortcode}:"),
+    ("emoji_too_large", "This image can be at most {max_bytes} bytes"),
+    ("emoji_upload_empty", "No emoji image was uploaded"),
+    ("emoji_invalid_type", "Emoji and stickers must be images"),
+    ("emoji_pack_full", "Packs can hold at most {max} emoji and stickers"),
+    ("emoji_not_found", "Emoji not found"),
+    ("upload_not_found", "Upload not found"),
+    ("upload_chunk_out_of_order", "Chunks must be uploaded in order; expected chunk {expected}"),
+    ("random_unavailable", "Could not generate a secure token: {detail}"),
+    ("fixture_job_running", "A test data job is already running"),
+    ("channel_import_running", "A channel history import is already running"),
+    ("fixture_limits_exceeded", "Test data is limited to 1-{max_users} users, {max_friends} friends and {max_messages} messages per user"),
+];
+
+fn builtin_message(code: &str) -> Option<&'static str> {
+    BUILTIN_MESSAGES.iter().find(|(c, _)| *c == code).map(|(_, message)| *message)
+}
+
+/// Normalize a locale tag ("pt_BR", "PT-br" -> "pt-br")
+pub fn normalize_locale(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_lowercase()
+}
+
+/// Template for `code` in `locale`: exact locale, then its language ("pt-br" -> "pt"), then English
+fn template_for(code: &str, locale: Option<&str>) -> String {
+    if let Some(locale) = locale {
+        let language = locale.split('-').next().unwrap_or(locale);
+        for candidate in [locale, language] {
+            let found = storage::MESSAGE_CATALOGS.with(|catalogs| {
+                catalogs.borrow().get(&(candidate.to_string(), code.to_string()))
+            });
+            if let Some(template) = found {
+                return template;
+            }
+        }
+    }
+    
+    builtin_message(code).unwrap_or(code).to_string()
+}
+
+fn render(template: &str, params: &[(String, String)]) -> String {
+    params.iter().fold(template.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+pub fn preferred_locale(principal: &Principal) -> Option<String> {
+    storage::PREFERRED_LOCALES.with(|locales| locales.borrow().get(principal))
+}
+
+/// Error response carrying a stable code and its parameters, with the human text
+/// rendered in the caller's preferred locale
+pub fn coded<T>(code: &str, params: &[(&str, String)]) -> ApiResponse<T> {
+    let params: Vec<(String, String)> = params
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect();
+    let locale = preferred_locale(&caller());
+    let message = render(&template_for(code, locale.as_deref()), &params);
+    
+    ApiResponse::coded_error(code.to_string(), params, message)
+}
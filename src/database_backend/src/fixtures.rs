@@ -0,0 +1,230 @@
+//! Synthetic data for load testing. Compiled only with the `test-fixtures` feature and never
+//! part of a production build.
+
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::message_index::{self, MessageRef};
+use crate::{http, storage};
+use crate::types::{ChatMessage, Friend, UserDataSync, UserProfile};
+
+// Work done per timer tick, sized to stay well inside the per-message instruction limit
+const USERS_PER_TICK: u32 = 1_000;
+const FRIEND_USERS_PER_TICK: u32 = 250;
+const MESSAGE_USERS_PER_TICK: u32 = 200;
+
+// Fixture principals are this prefix followed by the user index, so they never collide with real ones
+const FIXTURE_PRINCIPAL_PREFIX: &[u8] = b"fixture";
+
+const ADJECTIVES: &[&str] = &["quiet", "neon", "lost", "wired", "static", "hollow", "bright", "distant", "silver", "restless"];
+const NOUNS: &[&str] = &["signal", "protocol", "navi", "echo", "packet", "ghost", "layer", "cipher", "relay", "node"];
+const BIOS: &[&str] = &[
+    "Present day, present time.",
+    "Collecting old hardware and older memories.",
+    "Night owl. Mostly lurking in #general.",
+    "Here for the memes and the philosophy.",
+];
+const MESSAGES: &[&str] = &[
+    "hey lain, what do you think about the wired today?",
+    "does anyone remember the first protocol?",
+    "I keep hearing static when I close my eyes",
+    "recommend me something to read tonight",
+    "who else is up this late",
+    "the network feels different lately",
+    "can you summarize what we talked about yesterday?",
+    "lol that meme again",
+];
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum FixturePhase {
+    Users,
+    Friends,
+    Messages,
+    Done,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FixtureProgress {
+    pub users: u32,
+    pub friends_per_user: u32,
+    pub messages_per_user: u32,
+    pub phase: FixturePhase,
+    pub next_index: u32,      // Next user index to process in the current phase
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+thread_local! {
+    static JOB: RefCell<Option<FixtureProgress>> = const { RefCell::new(None) };
+}
+
+pub fn fixture_principal(index: u32) -> Principal {
+    let mut bytes = FIXTURE_PRINCIPAL_PREFIX.to_vec();
+    bytes.extend_from_slice(&index.to_be_bytes());
+    Principal::from_slice(&bytes)
+}
+
+fn fixture_name(index: u32) -> String {
+    let i = index as usize;
+    format!("{}_{}_{}", ADJECTIVES[i % ADJECTIVES.len()], NOUNS[(i / ADJECTIVES.len()) % NOUNS.len()], index)
+}
+
+pub fn is_running() -> bool {
+    JOB.with(|job| job.borrow().as_ref().is_some_and(|j| j.phase != FixturePhase::Done))
+}
+
+pub fn start(users: u32, friends_per_user: u32, messages_per_user: u32) -> FixtureProgress {
+    let progress = FixtureProgress {
+        users,
+        friends_per_user: friends_per_user.min(users.saturating_sub(1)),
+        messages_per_user,
+        phase: FixturePhase::Users,
+        next_index: 0,
+        started_at: ic_cdk::api::time(),
+        finished_at: None,
+    };
+    
+    JOB.with(|job| *job.borrow_mut() = Some(progress.clone()));
+    schedule_tick();
+    progress
+}
+
+pub fn get_progress() -> Option<FixtureProgress> {
+    JOB.with(|job| job.borrow().clone())
+}
+
+fn schedule_tick() {
+    ic_cdk_timers::set_timer(Duration::ZERO, run_tick);
+}
+
+fn run_tick() {
+    let Some(mut job) = get_progress() else {
+        return;
+    };
+    let now = ic_cdk::api::time();
+    
+    let per_tick = match job.phase {
+        FixturePhase::Users => USERS_PER_TICK,
+        FixturePhase::Friends => FRIEND_USERS_PER_TICK,
+        FixturePhase::Messages => MESSAGE_USERS_PER_TICK,
+        FixturePhase::Done => return,
+    };
+    let end = job.next_index.saturating_add(per_tick).min(job.users);
+    
+    for index in job.next_index..end {
+        match job.phase {
+            FixturePhase::Users => insert_user(index, now),
+            FixturePhase::Friends => insert_friends(index, &job, now),
+            FixturePhase::Messages => insert_messages(index, &job, now),
+            FixturePhase::Done => {}
+        }
+    }
+    
+    job.next_index = end;
+    if job.next_index >= job.users {
+        job.next_index = 0;
+        job.phase = match job.phase {
+            FixturePhase::Users if job.friends_per_user > 0 => FixturePhase::Friends,
+            FixturePhase::Users | FixturePhase::Friends if job.messages_per_user > 0 => FixturePhase::Messages,
+            _ => FixturePhase::Done,
+        };
+    }
+    if job.phase == FixturePhase::Done {
+        job.finished_at = Some(now);
+    }
+    
+    let done = job.phase == FixturePhase::Done;
+    JOB.with(|slot| *slot.borrow_mut() = Some(job));
+    if !done {
+        schedule_tick();
+    }
+}
+
+fn insert_user(index: u32, now: u64) {
+    let principal = fixture_principal(index);
+    let profile = UserProfile {
+        principal,
+        display_name: fixture_name(index),
+        avatar_base64: None,
+        bio: Some(BIOS[index as usize % BIOS.len()].to_string()),
+        created_at: now,
+        avatar: None,
+        handle: None,
+        banner: None,
+        theme: None,
+    };
+    
+    http::certify_profile(&profile);
+    crate::claim_display_name(principal, &profile.display_name, None);
+    storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow_mut().insert(principal, profile);
+    });
+}
+
+/// Connect each user with the next `friends_per_user` users (wrapping), in both directions
+fn insert_friends(index: u32, job: &FixtureProgress, now: u64) {
+    let principal = fixture_principal(index);
+    
+    storage::FRIENDS.with(|friends| {
+        let mut friends = friends.borrow_mut();
+        for offset in 1..=job.friends_per_user {
+            let other_index = (index + offset) % job.users;
+            let other = fixture_principal(other_index);
+            
+            friends.insert((principal, other), Friend {
+                principal: other,
+                display_name: fixture_name(other_index),
+                avatar_base64: None,
+                added_at: now,
+                favorited_at: None,
+            });
+            friends.insert((other, principal), Friend {
+                principal,
+                display_name: fixture_name(index),
+                avatar_base64: None,
+                added_at: now,
+                favorited_at: None,
+            });
+        }
+    });
+}
+
+/// Alternating user/bot chat history spread over the rooms
+fn insert_messages(index: u32, job: &FixtureProgress, now: u64) {
+    let principal = fixture_principal(index);
+    let rooms = ["#general", "#tech", "#memes", "#news"];
+    
+    let chat_messages: Vec<ChatMessage> = (0..job.messages_per_user)
+        .map(|n| {
+            let from_user = n % 2 == 0;
+            ChatMessage {
+                id: format!("fixture_{}_{}", index, n),
+                text: if from_user {
+                    MESSAGES[(index + n) as usize % MESSAGES.len()].to_string()
+                } else {
+                    "That's an interesting thought. Tell me more.".to_string()
+                },
+                sender: if from_user { "me" } else { "bot" }.to_string(),
+                timestamp: now / 1_000_000 + n as u64,
+                channel: Some(rooms[(index as usize + (n / 2) as usize) % rooms.len()].to_string()),
+                thread_parent_id: None,
+                mentions: None,
+                device_id: None,
+            }
+        })
+        .collect();
+    
+    for message in &chat_messages {
+        message_index::index_message(principal, MessageRef::Chat { message_id: message.id.clone() }, &message.text);
+    }
+    
+    let profile = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal));
+    storage::USER_DATA_SYNC.with(|sync_data| {
+        sync_data.borrow_mut().insert(principal, UserDataSync {
+            chat_messages,
+            profile,
+            last_sync: now,
+        });
+    });
+}
@@ -0,0 +1,27 @@
+use std::collections::HashSet;
+
+// Trigram similarity for fuzzy user search. Text is padded so that short words and word starts
+// still produce trigrams: "lain" gives "  l", " la", "lai", "ain" and "in ".
+
+fn trigrams(text: &str) -> HashSet<[char; 3]> {
+    let chars: Vec<char> = "  ".chars().chain(text.chars()).chain(" ".chars()).collect();
+    chars.windows(3).map(|window| [window[0], window[1], window[2]]).collect()
+}
+
+/// Share of trigrams `a` and `b` have in common, as a percentage. Pass both lowercased
+pub fn similarity(a: &str, b: &str) -> u32 {
+    let (a, b) = (trigrams(a), trigrams(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 0;
+    }
+    (a.intersection(&b).count() * 100 / union) as u32
+}
+
+/// Best similarity between `query` and the whole of `text` or any single word of it
+pub fn best_similarity(query: &str, text: &str) -> u32 {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| similarity(query, word))
+        .fold(similarity(query, text), u32::max)
+}
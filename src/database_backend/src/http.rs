@@ -0,0 +1,349 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_certification::{label, labeled_hash, AsHashTree, Hash, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::ops::Bound;
+use std::time::Duration;
+
+use crate::base64;
+use crate::compression;
+use crate::schema;
+use crate::storage;
+use crate::types::UserProfile;
+
+// Profile pages are certified (served from queries); everything else is answered by
+// upgrading to an update call, whose response goes through consensus instead.
+
+const PROFILE_PATH_PREFIX: &str = "/api/profiles/";
+pub const SCHEMA_PATH: &str = "/api/schema";
+pub const DIRECTORY_PATH: &str = "/profiles/index";
+
+// Directory pages change with opt-ins and renames, so let caches hold them only briefly
+const DIRECTORY_CACHE_CONTROL: &str = "public, max-age=300";
+
+// Profiles hashed per backfill pass for users registered before certification existed
+const BACKFILL_BATCH: usize = 100;
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub certificate_version: Option<u16>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,
+}
+
+pub enum Route {
+    Profile(Principal),
+    ProfileFriends(Principal),
+    Search,
+    Schema,
+    Directory(u32), // 1-based page
+    NotFound,
+}
+
+thread_local! {
+    // Certified path -> sha256 of its response body. Heap only, rebuilt from PROFILE_BODY_HASHES
+    static CERTIFIED_BODIES: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // Number of directory pages currently in CERTIFIED_BODIES
+    static CERTIFIED_DIRECTORY_PAGES: Cell<u32> = const { Cell::new(0) };
+
+    // Last profile the hash backfill looked at; the next pass resumes after it
+    static BACKFILL_CURSOR: Cell<Option<Principal>> = const { Cell::new(None) };
+
+    // The OpenAPI document only changes with the code, so it is rendered once per install
+    static SCHEMA_BODY: Vec<u8> = serde_json::to_vec(&schema::document()).unwrap_or_default();
+}
+
+/// Split a request url into its path and (still encoded) query string
+pub fn split_url(url: &str) -> (&str, &str) {
+    url.split_once('?').unwrap_or((url, ""))
+}
+
+pub fn route(path: &str) -> Route {
+    match path {
+        "/api/search" => return Route::Search,
+        SCHEMA_PATH => return Route::Schema,
+        DIRECTORY_PATH => return Route::Directory(1),
+        _ => {}
+    }
+    if let Some(page) = path.strip_prefix(DIRECTORY_PATH).and_then(|rest| rest.strip_prefix('/')) {
+        return match page.parse::<u32>() {
+            Ok(page) if page >= 1 => Route::Directory(page),
+            _ => Route::NotFound,
+        };
+    }
+
+    let Some(rest) = path.strip_prefix(PROFILE_PATH_PREFIX) else {
+        return Route::NotFound;
+    };
+    let (principal, friends) = match rest.strip_suffix("/friends") {
+        Some(principal) => (principal, true),
+        None => (rest, false),
+    };
+    match Principal::from_text(principal) {
+        Ok(principal) if friends => Route::ProfileFriends(principal),
+        Ok(principal) => Route::Profile(principal),
+        Err(_) => Route::NotFound,
+    }
+}
+
+/// Decoded value of `name` in a query string
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+pub fn json_response<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    json_body_response(status_code, serde_json::to_vec(value).unwrap_or_default())
+}
+
+pub fn json_body_response(status_code: u16, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+/// Whether the request's Accept-Encoding lists gzip (and does not refuse it with q=0)
+fn accepts_gzip(request: &HttpRequest) -> bool {
+    request.headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("accept-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            parts.next().is_some_and(|name| name.eq_ignore_ascii_case("gzip"))
+                && parts.all(|param| param.replace(' ', "") != "q=0")
+        })
+}
+
+/// Gzip the body of `response` when it is large enough to be worth it and the client accepts
+/// gzip. Certified bodies stay verifiable since the gateway hashes the decoded body
+pub fn compress_response(request: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+    if response.upgrade == Some(true) || response.body.len() < compression::COMPRESSION_MIN_BYTES {
+        return response;
+    }
+    response.headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    if !accepts_gzip(request) {
+        return response;
+    }
+    if let Some(compressed) = compression::gzip(&response.body) {
+        if compressed.len() < response.body.len() {
+            response.body = compressed;
+            response.headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+        }
+    }
+    response
+}
+
+/// The OpenAPI document, certified when answered from a query
+pub fn schema_response() -> HttpResponse {
+    let body = SCHEMA_BODY.with(|body| body.clone());
+    let certificate = certificate_header(SCHEMA_PATH, &body);
+
+    let mut response = json_body_response(200, body);
+    response.headers.push(("X-Schema-Version".to_string(), schema::SCHEMA_VERSION.to_string()));
+    response.headers.extend(certificate);
+    response
+}
+
+pub fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    json_response(status_code, &serde_json::json!({ "error": message }))
+}
+
+/// Ask the gateway to repeat the request as an update call
+pub fn upgrade_response() -> HttpResponse {
+    HttpResponse { status_code: 200, headers: Vec::new(), body: Vec::new(), upgrade: Some(true) }
+}
+
+pub fn directory_response(body: Vec<u8>) -> HttpResponse {
+    let mut response = json_body_response(200, body);
+    response.headers.push(("Cache-Control".to_string(), DIRECTORY_CACHE_CONTROL.to_string()));
+    response
+}
+
+// ============ CERTIFICATION ============
+
+pub fn profile_path(principal: &Principal) -> String {
+    format!("{}{}", PROFILE_PATH_PREFIX, principal.to_text())
+}
+
+/// Profile pages are public, so they carry only the fields anyone may see
+pub fn profile_body(profile: &UserProfile) -> Vec<u8> {
+    serde_json::to_vec(&crate::redact_profile(profile.clone(), &Principal::anonymous())).unwrap_or_default()
+}
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+fn publish_root_hash() {
+    let root = CERTIFIED_BODIES.with(|bodies| bodies.borrow().root_hash());
+    ic_cdk::api::set_certified_data(&labeled_hash(b"http_assets", &root));
+}
+
+/// Certify the current profile page of `profile`; call after every profile write
+pub fn certify_profile(profile: &UserProfile) {
+    let hash = sha256(&profile_body(profile));
+    storage::PROFILE_BODY_HASHES.with(|hashes| {
+        hashes.borrow_mut().insert(profile.principal, hash);
+    });
+    CERTIFIED_BODIES.with(|bodies| bodies.borrow_mut().insert(profile_path(&profile.principal), hash));
+    publish_root_hash();
+}
+
+pub fn uncertify_all_profiles() {
+    let principals: Vec<Principal> = storage::PROFILE_BODY_HASHES.with(|hashes| {
+        hashes.borrow().iter().map(|(principal, _)| principal).collect()
+    });
+    CERTIFIED_BODIES.with(|bodies| {
+        let mut bodies = bodies.borrow_mut();
+        for principal in &principals {
+            bodies.delete(profile_path(principal).as_bytes());
+        }
+    });
+    storage::PROFILE_BODY_HASHES.with(|hashes| {
+        hashes.borrow_mut().clear_new();
+    });
+    publish_root_hash();
+}
+
+pub fn directory_path(page: u32) -> String {
+    format!("{}/{}", DIRECTORY_PATH, page)
+}
+
+/// Certify the directory as `pages` (bodies of pages 1..), replacing the previous set
+pub fn certify_directory(pages: &[Vec<u8>]) {
+    CERTIFIED_BODIES.with(|bodies| {
+        let mut bodies = bodies.borrow_mut();
+        bodies.delete(DIRECTORY_PATH.as_bytes());
+        for page in 1..=CERTIFIED_DIRECTORY_PAGES.with(Cell::get) {
+            bodies.delete(directory_path(page).as_bytes());
+        }
+
+        for (index, body) in pages.iter().enumerate() {
+            let hash = sha256(body);
+            if index == 0 {
+                bodies.insert(DIRECTORY_PATH.to_string(), hash);
+            }
+            bodies.insert(directory_path(index as u32 + 1), hash);
+        }
+    });
+    CERTIFIED_DIRECTORY_PAGES.with(|count| count.set(pages.len() as u32));
+    publish_root_hash();
+}
+
+fn certify_schema() {
+    let hash = SCHEMA_BODY.with(|body| sha256(body));
+    CERTIFIED_BODIES.with(|bodies| bodies.borrow_mut().insert(SCHEMA_PATH.to_string(), hash));
+}
+
+/// Certify the static documents of a fresh install
+pub fn init_certification() {
+    certify_schema();
+    publish_root_hash();
+}
+
+/// Rebuild the certified tree after an upgrade and hash any profiles that have none yet
+pub fn restore_certification() {
+    certify_schema();
+    CERTIFIED_BODIES.with(|bodies| {
+        let mut bodies = bodies.borrow_mut();
+        storage::PROFILE_BODY_HASHES.with(|hashes| {
+            for (principal, hash) in hashes.borrow().iter() {
+                bodies.insert(profile_path(&principal), hash);
+            }
+        });
+    });
+    publish_root_hash();
+
+    BACKFILL_CURSOR.with(|cursor| cursor.set(None));
+    ic_cdk_timers::set_timer(Duration::ZERO, backfill_profile_hashes);
+}
+
+fn backfill_profile_hashes() {
+    let start = match BACKFILL_CURSOR.with(Cell::get) {
+        Some(last) => Bound::Excluded(last),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<UserProfile> = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow()
+            .range((start, Bound::Unbounded))
+            .take(BACKFILL_BATCH)
+            .map(|(_, profile)| profile)
+            .collect()
+    });
+
+    for profile in &batch {
+        let hashed = storage::PROFILE_BODY_HASHES.with(|hashes| hashes.borrow().contains_key(&profile.principal));
+        if !hashed {
+            certify_profile(profile);
+        }
+    }
+    BACKFILL_CURSOR.with(|cursor| cursor.set(batch.last().map(|profile| profile.principal)));
+    if batch.len() == BACKFILL_BATCH {
+        ic_cdk_timers::set_timer(Duration::ZERO, backfill_profile_hashes);
+    }
+}
+
+/// IC-Certificate header proving `body` is what this canister certified for `path`.
+/// None when called outside a query or when the body is not (or no longer) certified
+pub fn certificate_header(path: &str, body: &[u8]) -> Option<(String, String)> {
+    let certified = CERTIFIED_BODIES.with(|bodies| bodies.borrow().get(path.as_bytes()).copied())?;
+    if certified != sha256(body) {
+        return None;
+    }
+    let certificate = ic_cdk::api::data_certificate()?;
+
+    let witness = CERTIFIED_BODIES.with(|bodies| bodies.borrow().witness(path.as_bytes()));
+    let tree = label(b"http_assets".to_vec(), witness);
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    serializer.self_describe().ok()?;
+    tree.serialize(&mut serializer).ok()?;
+
+    Some((
+        "IC-Certificate".to_string(),
+        format!("certificate=:{}:, tree=:{}:", base64::encode(&certificate), base64::encode(&serializer.into_inner())),
+    ))
+}
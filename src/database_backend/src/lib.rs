@@ -1,15 +1,48 @@
+mod base64;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod compression;
+mod errors;
+#[cfg(feature = "test-fixtures")]
+mod fixtures;
+mod fuzzy;
+mod http;
+mod message_index;
+mod pagination;
+mod pair_map;
+mod payload_size;
+mod rate_limit;
+mod schema;
 mod storage;
 mod types;
 
-use candid::Principal;
-use ic_cdk::{caller, query, update};
-use types::{ApiResponse, Friend, FriendRequest, FriendRequestStatus, UserProfile, UserSearchResult, BlockedUser, ChatMessage, UserDataSync, SyncResponse, DirectMessage, DmMessages, DmMessagesResponse};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{caller, init, inspect_message, post_upgrade, query, update};
+use sha2::Digest;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::time::Duration;
+use compression::EncodedPayload;
+use message_index::MessageRef;
+use types::{ApiResponse, ChannelMessage, ChannelImportProgress, Friend, FriendRequest, FriendRequestStatus, AutoAcceptRule, AutoAcceptPolicy, UserProfile, HandleClaim, ReservedName, NameMatch, Device, InviteCode, BannerRef, ProfileTheme, UserSearchResult, SearchField, FriendSuggestion, FriendQuotas, Relationship, BlockedUser, BlockReason, UserReport, ChatMessage, UserDataSync, SyncResponse, DataExport, DirectMessage, ForwardedFrom, DeliveryState, DmMessages, DmMessagesResponse, JournalEntry, JournalEntriesResponse, ActivityKind, ActivityEvent, PresenceStatus, FriendPresence, WatchTerm, AlertSource, ModeratorNotification, Page, Suspension, Appeal, AppealStatus, Role, RoleAssignment, Ban, TrustTier, TrustSource, TrustChange, TrustRecord, OnboardingState, AuditEntry, AuditKind, AppScope, AppGrant, MessageKind, Poll, PollResults, ThreadView, UnreadThread, ReadMarker, ReadState, ReactionCount, MessageReactions, UnreadCount, UnreadSummary, ConversationMute, ArchivedChannel, Draft, MessageStar, StarredMessage, DisappearingChannel, Event, RsvpStatus, NotificationKind, UserNotification, Mention, MentionSetting, NotificationPreferences, FriendRequestSetting, ProfileVisibility, PrivacySettings, FollowStatus, FollowEntry, OutboxEntry, OutboxStatus, Group, GroupInvite, PendingUpload, Attachment, AvatarRef, CustomEmoji, EmojiKind, BuildInfo, DirectoryEntry, DirectoryPage, MessageSearchSource, MessageSnippet, MessageSearchHit, HealthStatus, ApiVersionInfo, DeprecatedMethod, DeprecationNotice, QueryDiagnostics};
 
 // ============ USER REGISTRY METHODS ============
 
+/// Register the caller, optionally claiming a handle in the same call. `invite_code` is
+/// required while invite mode is on; a code given when it is off is still used up, so the
+/// invite is recorded
 #[update]
-fn register_user(display_name: String, avatar_base64: Option<String>, bio: Option<String>) -> ApiResponse<UserProfile> {
+fn register_user(
+    display_name: String,
+    avatar_base64: Option<String>,
+    bio: Option<String>,
+    handle: Option<String>,
+    invite_code: Option<String>,
+) -> ApiResponse<UserProfile> {
     let principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&principal) {
+        return rejection;
+    }
     
     // Check if user already registered
     let existing = storage::USER_PROFILES.with(|profiles| {
@@ -17,75 +50,378 @@ fn register_user(display_name: String, avatar_base64: Option<String>, bio: Optio
     });
     
     if existing.is_some() {
-        return ApiResponse::error("User already registered".to_string());
+        return errors::coded("user_already_registered", &[]);
     }
     
+    if is_reserved_name(&display_name) {
+        return errors::coded("display_name_reserved", &[("name", display_name)]);
+    }
     // Check if display name is already taken by another user
-    let display_name_lower = display_name.to_lowercase();
-    let is_taken = storage::USER_PROFILES.with(|profiles| {
-        profiles.borrow()
-            .iter()
-            .any(|(_, profile)| {
-                profile.display_name.to_lowercase() == display_name_lower
-            })
-    });
+    if !display_name_holders(&display_name).is_empty() {
+        return errors::coded("display_name_taken", &[("name", display_name)]);
+    }
+    
+    let handle = match handle.map(|handle| available_handle(principal, &handle)).transpose() {
+        Ok(handle) => handle,
+        Err(code) => return handle_error(code),
+    };
     
-    if is_taken {
-        return ApiResponse::error(format!("Display name '{}' is already taken", display_name));
+    let invite = match invite_code.map(|code| usable_invite(&code)).transpose() {
+        Ok(invite) => invite,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    if invite.is_none() && invite_only() {
+        return errors::coded("invite_required", &[]);
     }
     
-    let profile = UserProfile {
+    let now = ic_cdk::api::time();
+    let mut profile = UserProfile {
         principal,
         display_name,
-        avatar_base64,
+        avatar_base64: None,
         bio,
-        created_at: ic_cdk::api::time(),
+        created_at: now,
+        avatar: None,
+        handle: handle.clone(),
+        banner: None,
+        theme: None,
     };
+    if let Some(legacy) = avatar_base64 {
+        if let Err(code) = set_legacy_avatar(&mut profile, &legacy) {
+            return avatar_error(code);
+        }
+    }
     
+    if let Some(handle) = handle {
+        storage::HANDLES.with(|handles| {
+            handles.borrow_mut().insert(handle, HandleClaim { principal, claimed_at: now });
+        });
+    }
+    if let Some(mut invite) = invite {
+        invite.used_by = Some(principal);
+        invite.used_at = Some(now);
+        storage::INVITE_CODES.with(|codes| codes.borrow_mut().insert(invite.code.clone(), invite));
+    }
+    claim_display_name(principal, &profile.display_name, None);
     storage::USER_PROFILES.with(|profiles| {
         profiles.borrow_mut().insert(principal, profile.clone());
     });
+    http::certify_profile(&profile);
     
     ApiResponse::success(profile)
 }
 
-#[query]
-fn search_users(query: String) -> ApiResponse<Vec<UserSearchResult>> {
+// Limit to 50 results to avoid exceeding ICP's 3.1MB response limit
+const SEARCH_RESULT_LIMIT: usize = 50;
+
+// Most prefix matches taken from each index per search, in name order; offsets past the
+// ranked candidates return nothing
+const MAX_SEARCH_CANDIDATES: usize = 1_000;
+
+// Search ranking weights. Match quality dominates; social signals order results within a
+// match tier, and penalties can push an account below the tier beneath it
+const EXACT_MATCH_SCORE: i64 = 300;
+const PREFIX_MATCH_SCORE: i64 = 200;
+const SUBSTRING_MATCH_SCORE: i64 = 100;
+const FRIEND_BOOST: i64 = 40;
+const MUTUAL_FRIEND_BOOST: i64 = 5;
+const MAX_MUTUAL_BOOST: i64 = 50;
+const SUSPENDED_PENALTY: i64 = 150;
+const FLAGGED_PENALTY: i64 = 60;
+const LIMITED_TRUST_PENALTY: i64 = 40;
+const RESTRICTED_TRUST_PENALTY: i64 = 100;
+
+// Fuzzy matches score their trigram similarity (a percentage), so they rank below substring
+// matches. Less similar text does not match at all
+const FUZZY_MIN_SIMILARITY: u32 = 30;
+// Bios are long and loosely related to who someone is, so their matches count for less
+const BIO_MATCH_DIVISOR: i64 = 2;
+
+// A profile found by a search: its text relevance and the field that matched best
+struct SearchMatch {
+    relevance: i64,
+    field: SearchField,
+    profile: UserProfile,
+}
+
+/// How well `text` matches `query_lower`: exact > prefix > substring > fuzzy. 0 for no match
+fn text_relevance(query_lower: &str, text: &str) -> i64 {
+    let text = text.to_lowercase();
+    if text == query_lower {
+        EXACT_MATCH_SCORE
+    } else if text.starts_with(query_lower) {
+        PREFIX_MATCH_SCORE
+    } else if text.contains(query_lower) {
+        SUBSTRING_MATCH_SCORE
+    } else {
+        let similarity = fuzzy::best_similarity(query_lower, &text);
+        if similarity >= FUZZY_MIN_SIMILARITY { similarity as i64 } else { 0 }
+    }
+}
+
+/// The best-matching field of `profile`, if any matches. Pass the profile as the searcher may
+/// see it, so hidden bios are not searched
+fn profile_match(query_lower: &str, profile: UserProfile, include_bio: bool) -> Option<SearchMatch> {
+    let handle_query = query_lower.strip_prefix('@').unwrap_or(query_lower);
+    let mut fields = vec![(text_relevance(query_lower, &profile.display_name), SearchField::DisplayName)];
+    if let Some(handle) = &profile.handle {
+        fields.push((text_relevance(handle_query, handle), SearchField::Handle));
+    }
+    if let Some(bio) = profile.bio.as_ref().filter(|_| include_bio) {
+        fields.push((text_relevance(query_lower, bio) / BIO_MATCH_DIVISOR, SearchField::Bio));
+    }
+    
+    // The first field wins a tie, so a name beats a handle and a handle beats a bio
+    let (relevance, field) = fields.into_iter().rev().max_by_key(|(relevance, _)| *relevance)?;
+    (relevance > 0).then_some(SearchMatch { relevance, field, profile })
+}
+
+/// `matches` best first: by relevance, with friends and mutuals of `searcher` boosted and
+/// suspended, flagged or reduced-trust accounts penalized
+fn rank_matches(matches: Vec<SearchMatch>, searcher: Principal, offset: usize, limit: usize) -> Vec<SearchMatch> {
+    if matches.is_empty() {
+        return Vec::new();
+    }
+    
+    let friends_of = |principal: Principal| -> HashSet<Principal> {
+        storage::FRIENDS.with(|friends| {
+            friends.borrow()
+                .range((principal, Principal::from_slice(&[]))..)
+                .take_while(|((owner, _), _)| *owner == principal)
+                .map(|((_, friend), _)| friend)
+                .collect()
+        })
+    };
+    let searcher_friends = friends_of(searcher);
+    
+    // Accounts with unreviewed watch-term alerts
+    let flagged: HashSet<Principal> = storage::MODERATOR_NOTIFICATIONS.with(|notifications| {
+        notifications.borrow()
+            .iter()
+            .filter(|(_, notification)| !notification.acknowledged)
+            .map(|(_, notification)| notification.author)
+            .collect()
+    });
+    
+    let mut ranked: Vec<(i64, SearchMatch)> = matches
+        .into_iter()
+        .map(|found| {
+            let principal = found.profile.principal;
+            let mut score = found.relevance;
+            if searcher_friends.contains(&principal) {
+                score += FRIEND_BOOST;
+            }
+            if !searcher_friends.is_empty() {
+                let mutuals = friends_of(principal).intersection(&searcher_friends).count() as i64;
+                score += (mutuals * MUTUAL_FRIEND_BOOST).min(MAX_MUTUAL_BOOST);
+            }
+            if active_suspension(&principal).is_some() {
+                score -= SUSPENDED_PENALTY;
+            }
+            if flagged.contains(&principal) {
+                score -= FLAGGED_PENALTY;
+            }
+            score -= match trust_tier(&principal) {
+                TrustTier::Standard => 0,
+                TrustTier::Limited => LIMITED_TRUST_PENALTY,
+                TrustTier::Restricted => RESTRICTED_TRUST_PENALTY,
+            };
+            (score, found)
+        })
+        .collect();
+    
+    // Ties go to the shorter (closer) name, then alphabetical for a stable order
+    ranked.sort_by(|(score_a, a), (score_b, b)| {
+        let (a, b) = (&a.profile.display_name, &b.profile.display_name);
+        score_b.cmp(score_a)
+            .then(a.len().cmp(&b.len()))
+            .then_with(|| a.to_lowercase().cmp(&b.to_lowercase()))
+    });
+    
+    ranked.into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(_, found)| found)
+        .collect()
+}
+
+// Which indexed names a search takes as candidates
+#[derive(Clone, Copy, PartialEq)]
+enum SearchScope {
+    Prefix,    // Names starting with the query, found by range scans of the indexes
+    Substring, // Names containing it anywhere: what search_users_v1 and /api/search always matched
+}
+
+/// Profiles whose display name or handle matches the query within `scope`, ranked by
+/// rank_matches. Candidates come from the name and handle indexes
+fn ranked_user_search(query: &str, scope: SearchScope, searcher: Principal, offset: usize, limit: usize) -> Vec<SearchMatch> {
     let query_lower = query.to_lowercase();
+    let handle_query = query_lower.strip_prefix('@').unwrap_or(&query_lower).to_string();
+    
+    let mut candidates: HashSet<Principal> = storage::DISPLAY_NAMES.with(|names| {
+        names.borrow()
+            .range(query_lower.clone()..)
+            .take_while(|(name, _)| name.starts_with(&query_lower))
+            .take(MAX_SEARCH_CANDIDATES)
+            .flat_map(|(_, holders)| holders.principals)
+            .collect()
+    });
+    storage::HANDLES.with(|handles| {
+        candidates.extend(handles.borrow()
+            .range(handle_query.clone()..)
+            .take_while(|(handle, _)| handle.starts_with(&handle_query))
+            .take(MAX_SEARCH_CANDIDATES)
+            .map(|(_, claim)| claim.principal));
+    });
+    
+    // Substring matches rank below every prefix match, so they only fill the remaining room
+    if scope == SearchScope::Substring {
+        let room = MAX_SEARCH_CANDIDATES.saturating_sub(candidates.len());
+        let mut contained: Vec<Principal> = storage::DISPLAY_NAMES.with(|names| {
+            names.borrow()
+                .iter()
+                .filter(|(name, _)| name.contains(&query_lower))
+                .flat_map(|(_, holders)| holders.principals)
+                .filter(|principal| !candidates.contains(principal))
+                .take(room)
+                .collect()
+        });
+        storage::HANDLES.with(|handles| {
+            contained.extend(handles.borrow()
+                .iter()
+                .filter(|(handle, claim)| handle.contains(&handle_query) && !candidates.contains(&claim.principal))
+                .map(|(_, claim)| claim.principal)
+                .take(room));
+        });
+        candidates.extend(contained.into_iter().take(room));
+    }
+    
+    let matches = candidates.into_iter()
+        .filter(|principal| !is_banned(principal))
+        .filter_map(|principal| storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal)))
+        .filter_map(|profile| profile_match(&query_lower, profile, false))
+        .collect();
+    
+    rank_matches(matches, searcher, offset, limit)
+        .into_iter()
+        .map(|found| SearchMatch { profile: redact_profile(found.profile, &searcher), ..found })
+        .collect()
+}
+
+fn search_result(found: SearchMatch) -> UserSearchResult {
+    UserSearchResult {
+        principal: found.profile.principal,
+        display_name: found.profile.display_name,
+        created_at: found.profile.created_at,
+        handle: found.profile.handle,
+        matched_field: found.field,
+        score: found.relevance.max(0) as u32,
+    }
+}
+
+/// Users whose display name or handle starts with `query`, best first. Page with `offset`;
+/// `limit` defaults to and is capped at 50. search_users_fuzzy also finds near misses and
+/// searches bios
+#[query]
+fn search_users(query: String, offset: Option<u32>, limit: Option<u32>) -> ApiResponse<Vec<UserSearchResult>> {
+    let limit = limit.map_or(SEARCH_RESULT_LIMIT, |limit| (limit as usize).min(SEARCH_RESULT_LIMIT));
+    let results = ranked_user_search(&query, SearchScope::Prefix, caller(), offset.unwrap_or(0) as usize, limit)
+        .into_iter()
+        .map(search_result)
+        .collect();
+    
+    diagnosed("search_users", ApiResponse::success(results))
+}
+
+/// Users whose display name, handle or bio matches `query` exactly, as a prefix, as a
+/// substring or with typos, best first. Reads every profile, so call it when a search is
+/// submitted rather than on each keystroke
+#[query]
+fn search_users_fuzzy(query: String, offset: Option<u32>, limit: Option<u32>) -> ApiResponse<Vec<UserSearchResult>> {
+    let query_lower = query.trim().to_lowercase();
+    if query_lower.is_empty() {
+        return errors::coded("search_query_empty", &[]);
+    }
+    let searcher = caller();
     
-    let results = storage::USER_PROFILES.with(|profiles| {
+    let matches = storage::USER_PROFILES.with(|profiles| {
         profiles.borrow()
             .iter()
-            .filter(|(_, profile)| {
-                profile.display_name.to_lowercase().contains(&query_lower)
-            })
-            .take(50) // Limit to 50 results to avoid exceeding ICP's 3.1MB response limit
-            .map(|(_, profile)| UserSearchResult {
-                principal: profile.principal,
-                display_name: profile.display_name.clone(),
-                created_at: profile.created_at,
-            })
-            .collect::<Vec<_>>()
+            .filter(|(principal, _)| !is_banned(principal))
+            .filter_map(|(_, profile)| profile_match(&query_lower, redact_profile(profile, &searcher), true))
+            .collect()
     });
+    let limit = limit.map_or(SEARCH_RESULT_LIMIT, |limit| (limit as usize).min(SEARCH_RESULT_LIMIT));
+    let results = rank_matches(matches, searcher, offset.unwrap_or(0) as usize, limit)
+        .into_iter()
+        .map(search_result)
+        .collect();
     
-    ApiResponse::success(results)
+    diagnosed("search_users_fuzzy", ApiResponse::success(results))
+}
+
+/// Pre-v2 search that returned full profiles (including avatars); kept for old clients
+#[query]
+fn search_users_v1(query: String) -> ApiResponse<Vec<UserProfile>> {
+    let results = ranked_user_search(&query, SearchScope::Substring, caller(), 0, SEARCH_RESULT_LIMIT)
+        .into_iter()
+        .map(|found| found.profile)
+        .collect();
+    
+    deprecated("search_users_v1", ApiResponse::success(results))
 }
 
 #[query]
 fn get_user_by_principal(principal: Principal) -> ApiResponse<UserProfile> {
     match storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal)) {
-        Some(profile) => ApiResponse::success(profile),
-        None => ApiResponse::error("User not found".to_string()),
+        Some(profile) => ApiResponse::success(redact_profile(profile, &caller())),
+        None => errors::coded("user_not_found", &[]),
     }
 }
 
+fn all_user_profiles() -> Vec<UserProfile> {
+    let viewer = caller();
+    storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow().iter().map(|(_, profile)| redact_profile(profile, &viewer)).collect()
+    })
+}
+
+/// Every profile in one response; deprecated in favor of list_users
 #[query]
 fn get_all_users() -> ApiResponse<Vec<UserProfile>> {
-    let users = storage::USER_PROFILES.with(|profiles| {
-        profiles.borrow().iter().map(|(_, profile)| profile).collect()
-    });
-    
-    ApiResponse::success(users)
+    deprecated("get_all_users", diagnosed("get_all_users", size_checked(all_user_profiles(), "list_users")))
+}
+
+/// get_all_users as an encoded payload, gzipped when `accepts_compression` is set and split
+/// into chunks when too large for one response. Deprecated in favor of list_users
+#[query]
+fn get_all_users_encoded(accepts_compression: Option<bool>, chunk: Option<u32>) -> ApiResponse<EncodedPayload> {
+    deprecated("get_all_users_encoded", encoded_response(&all_user_profiles(), accepts_compression, chunk))
+}
+
+/// Wrap `data` for the *_encoded variants of large reads
+fn encoded_response<T: CandidType>(data: &T, accepts_compression: Option<bool>, chunk: Option<u32>) -> ApiResponse<EncodedPayload> {
+    match compression::encode(data, accepts_compression.unwrap_or(false), chunk) {
+        Ok(payload) => ApiResponse::success(payload),
+        Err(compression::EncodeError::Candid) => errors::coded("payload_encoding_failed", &[]),
+        Err(compression::EncodeError::ChunkOutOfRange(count)) => errors::coded("payload_chunk_out_of_range", &[
+            ("count", count.to_string()),
+        ]),
+    }
+}
+
+/// `data` as a plain response, or response_too_large pointing at `alternative` when it would
+/// not fit in one reply (which would otherwise trap)
+fn size_checked<T: CandidType>(data: T, alternative: &str) -> ApiResponse<T> {
+    let size = payload_size::estimate(&data);
+    if size > compression::MAX_PAYLOAD_BYTES {
+        return errors::coded("response_too_large", &[
+            ("size", size.to_string()),
+            ("max", compression::MAX_PAYLOAD_BYTES.to_string()),
+            ("alternative", alternative.to_string()),
+        ]);
+    }
+    ApiResponse::success(data)
 }
 
 #[update]
@@ -94,190 +430,786 @@ fn update_profile(
     avatar_base64: Option<String>,
     bio: Option<String>,
 ) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
     let caller_principal = caller();
     
     // Load existing user profile
     let mut user = match storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) {
         Some(user) => user,
-        None => return ApiResponse::error("User not registered".to_string()),
+        None => return errors::coded("user_not_registered", &[]),
     };
     
+    let previous_name = user.display_name.clone();
+    
     // Update fields if provided
     if let Some(name) = display_name {
-        // Check if the new display name is already taken by another user
-        let display_name_lower = name.to_lowercase();
-        let is_taken = storage::USER_PROFILES.with(|profiles| {
-            profiles.borrow()
-                .iter()
-                .any(|(principal, profile)| {
-                    // Allow the current user to keep their own display name
-                    if principal == caller_principal {
-                        return false;
-                    }
-                    // Check if any other user has this display name (case-insensitive)
-                    profile.display_name.to_lowercase() == display_name_lower
-                })
-        });
-        
-        if is_taken {
-            return ApiResponse::error(format!("Display name '{}' is already taken", name));
+        // A change of case only keeps the name the user already has
+        let renamed = name.to_lowercase() != user.display_name.to_lowercase();
+        if renamed && is_reserved_name(&name) {
+            return errors::coded("display_name_reserved", &[("name", name)]);
+        }
+        if renamed && !display_name_holders(&name).is_empty() {
+            return errors::coded("display_name_taken", &[("name", name)]);
         }
         
+        if name != user.display_name {
+            record_audit(caller_principal, AuditKind::ProfileEdit, "display_name", Some(user.display_name.clone()));
+            if is_listed(&caller_principal) {
+                schedule_directory_refresh();
+            }
+        }
         user.display_name = name;
     }
     if let Some(avatar) = avatar_base64 {
-        user.avatar_base64 = Some(avatar);
+        let previous = user.avatar.clone();
+        if let Err(code) = set_legacy_avatar(&mut user, &avatar) {
+            return avatar_error(code);
+        }
+        if user.avatar != previous {
+            record_avatar_change(caller_principal, previous.as_ref());
+        }
     }
     if let Some(bio_text) = bio {
+        if user.bio.as_ref() != Some(&bio_text) {
+            record_audit(caller_principal, AuditKind::ProfileEdit, "bio", user.bio.clone());
+        }
         user.bio = Some(bio_text);
     }
     
     // Save updated profile
+    if user.display_name.to_lowercase() != previous_name.to_lowercase() {
+        claim_display_name(caller_principal, &user.display_name, Some(&previous_name));
+    }
+    http::certify_profile(&user);
     storage::USER_PROFILES.with(|profiles| {
         profiles.borrow_mut().insert(caller_principal, user);
     });
+    record_activity(caller_principal, ActivityKind::ProfileUpdated);
     
     ApiResponse::success(())
 }
 
 #[query]
 fn is_display_name_taken(display_name: String) -> ApiResponse<bool> {
-    let display_name_lower = display_name.to_lowercase();
+    // Allow the current user to keep their own display name
     let caller_principal = caller();
+    let is_taken = display_name_holders(&display_name).iter().any(|holder| *holder != caller_principal);
     
-    let is_taken = storage::USER_PROFILES.with(|profiles| {
+    ApiResponse::success(is_taken)
+}
+
+/// Everyone holding `display_name`, compared case-insensitively
+fn display_name_holders(display_name: &str) -> Vec<Principal> {
+    storage::DISPLAY_NAMES.with(|names| names.borrow().get(&display_name.to_lowercase()))
+        .map(|holders| holders.principals)
+        .unwrap_or_default()
+}
+
+/// Record `name` as `principal`'s and release `previous`, their old name. Call in the same
+/// message as the availability check, so no one can take the name in between. A released
+/// name stays taken while other accounts still hold it
+fn claim_display_name(principal: Principal, name: &str, previous: Option<&str>) {
+    storage::DISPLAY_NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+        if let Some(previous) = previous.map(str::to_lowercase) {
+            if let Some(mut holders) = names.get(&previous) {
+                holders.principals.retain(|holder| *holder != principal);
+                if holders.principals.is_empty() {
+                    names.remove(&previous);
+                } else {
+                    names.insert(previous, holders);
+                }
+            }
+        }
+        
+        let key = name.to_lowercase();
+        let mut holders = names.get(&key).unwrap_or_default();
+        if !holders.principals.contains(&principal) {
+            holders.principals.push(principal);
+            names.insert(key, holders);
+        }
+    });
+}
+
+/// Fill the name index from existing profiles after the upgrade that introduced it. Accounts
+/// that already share a name are all recorded as its holders, so each stays searchable
+fn index_display_names() {
+    if !storage::DISPLAY_NAMES.with(|names| names.borrow().is_empty()) {
+        return;
+    }
+    let profiles: Vec<(Principal, String)> = storage::USER_PROFILES.with(|profiles| {
         profiles.borrow()
             .iter()
-            .any(|(principal, profile)| {
-                // Allow the current user to keep their own display name
-                if principal == caller_principal {
-                    return false;
-                }
-                // Check if any other user has this display name (case-insensitive)
-                profile.display_name.to_lowercase() == display_name_lower
-            })
+            .map(|(principal, profile)| (principal, profile.display_name))
+            .collect()
     });
-    
-    ApiResponse::success(is_taken)
+    for (principal, name) in profiles {
+        claim_display_name(principal, &name, None);
+    }
 }
 
-// ============ FRIENDS MANAGEMENT METHODS ============
+// ============ HANDLE METHODS ============
+
+const MIN_HANDLE_CHARS: usize = 3;
+const MAX_HANDLE_CHARS: usize = 20;
+// Handles are meant to be stable, so a claimed one is kept at least this long
+const HANDLE_CHANGE_COOLDOWN_DAYS: u64 = 30;
+
+/// `handle` as stored: lowercase without a leading '@'. None unless it is 3-20 letters,
+/// digits or underscores
+fn normalize_handle(handle: &str) -> Option<String> {
+    let handle = handle.trim();
+    let handle = handle.strip_prefix('@').unwrap_or(handle).to_lowercase();
+    let valid = (MIN_HANDLE_CHARS..=MAX_HANDLE_CHARS).contains(&handle.len())
+        && handle.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    valid.then_some(handle)
+}
+
+/// `handle` normalized, or the error code when it is malformed or held by someone other than
+/// `principal`. Claim it in the same call, before any await, so no one can take it in between
+fn available_handle(principal: Principal, handle: &str) -> Result<String, &'static str> {
+    let handle = normalize_handle(handle).ok_or("handle_invalid")?;
+    if is_reserved_name(&handle) {
+        return Err("handle_reserved");
+    }
+    let holder = storage::HANDLES.with(|handles| handles.borrow().get(&handle)).map(|claim| claim.principal);
+    if holder.is_some_and(|holder| holder != principal) {
+        return Err("handle_taken");
+    }
+    Ok(handle)
+}
+
+fn handle_error<T>(code: &str) -> ApiResponse<T> {
+    match code {
+        "handle_invalid" => errors::coded(code, &[
+            ("min", MIN_HANDLE_CHARS.to_string()),
+            ("max", MAX_HANDLE_CHARS.to_string()),
+        ]),
+        _ => errors::coded(code, &[]),
+    }
+}
 
+/// Claim a handle for the caller, releasing their previous one. A handle can be changed once
+/// every 30 days
 #[update]
-fn add_friend(friend_principal: Principal) -> ApiResponse<()> {
+fn set_handle(handle: String) -> ApiResponse<UserProfile> {
     let caller_principal = caller();
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
     
-    // Validate friend exists
-    let friend_profile = storage::USER_PROFILES.with(|profiles| {
-        profiles.borrow().get(&friend_principal)
+    let mut profile = match storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) {
+        Some(profile) => profile,
+        None => return errors::coded("user_not_registered", &[]),
+    };
+    let handle = match available_handle(caller_principal, &handle) {
+        Ok(handle) => handle,
+        Err(code) => return handle_error(code),
+    };
+    if profile.handle.as_ref() == Some(&handle) {
+        return ApiResponse::success(profile);
+    }
+    
+    let now = ic_cdk::api::time();
+    if let Some(previous) = &profile.handle {
+        let claimed_at = storage::HANDLES.with(|handles| handles.borrow().get(previous)).map_or(0, |claim| claim.claimed_at);
+        let allowed_at = claimed_at + HANDLE_CHANGE_COOLDOWN_DAYS * NS_PER_DAY;
+        if now < allowed_at {
+            return errors::coded("handle_change_too_soon", &[
+                ("days", (allowed_at - now).div_ceil(NS_PER_DAY).to_string()),
+            ]);
+        }
+        storage::HANDLES.with(|handles| handles.borrow_mut().remove(previous));
+    }
+    
+    storage::HANDLES.with(|handles| {
+        handles.borrow_mut().insert(handle.clone(), HandleClaim { principal: caller_principal, claimed_at: now });
+    });
+    record_audit(caller_principal, AuditKind::ProfileEdit, "handle", profile.handle.clone());
+    profile.handle = Some(handle);
+    http::certify_profile(&profile);
+    storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow_mut().insert(caller_principal, profile.clone());
     });
     
-    let friend_profile = match friend_profile {
-        Some(p) => p,
-        None => return ApiResponse::error("Friend user not found".to_string()),
+    ApiResponse::success(profile)
+}
+
+/// Look up a user by handle, with or without the leading '@'
+#[query]
+fn get_user_by_handle(handle: String) -> ApiResponse<UserProfile> {
+    let claim = normalize_handle(&handle)
+        .and_then(|handle| storage::HANDLES.with(|handles| handles.borrow().get(&handle)));
+    let Some(claim) = claim else {
+        return errors::coded("handle_not_found", &[]);
     };
     
-    // Check if blocked
-    let is_blocked = storage::BLOCKED_USERS.with(|blocked| {
-        blocked.borrow().contains_key(&(caller_principal, friend_principal)) ||
-        blocked.borrow().contains_key(&(friend_principal, caller_principal))
-    });
+    match storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&claim.principal)) {
+        Some(profile) => ApiResponse::success(redact_profile(profile, &caller())),
+        None => errors::coded("user_not_found", &[]),
+    }
+}
+
+// ============ RESERVED NAME METHODS ============
+
+// Admins keep a list of names no one may register or rename to, such as "admin" or slurs.
+// Entries apply to display names and handles alike, and only to new claims: an existing
+// account keeps its name.
+
+/// `name` reduced to lowercase letters and digits, so "Ad_Min" and "@admin" compare equal
+fn reserved_key(name: &str) -> String {
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    let key = reserved_key(name);
+    storage::RESERVED_NAMES.with(|reserved| {
+        reserved.borrow().iter().any(|(_, entry)| match entry.matching {
+            NameMatch::Exact => key == entry.name,
+            NameMatch::Contains => key.contains(&entry.name),
+        })
+    })
+}
+
+#[update]
+fn add_reserved_name(name: String, matching: NameMatch) -> ApiResponse<ReservedName> {
+    let caller_principal = caller();
+    if !is_admin(&caller_principal) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
     
-    if is_blocked {
-        return ApiResponse::error("Cannot add friend: user is blocked".to_string());
+    let key = reserved_key(&name);
+    if key.is_empty() {
+        return errors::coded("reserved_name_empty", &[]);
     }
     
-    // Create Friend entry
-    let friend = Friend {
-        principal: friend_profile.principal,
-        display_name: friend_profile.display_name.clone(),
-        avatar_base64: friend_profile.avatar_base64.clone(),
-        added_at: ic_cdk::api::time(),
+    let entry = ReservedName {
+        name: key.clone(),
+        matching,
+        added_by: caller_principal,
+        created_at: ic_cdk::api::time(),
     };
-    
-    // Add bidirectional friendship
-    storage::FRIENDS.with(|friends| {
-        let mut friends = friends.borrow_mut();
-        friends.insert((caller_principal, friend_principal), friend.clone());
-        
-        // Reverse direction
-        let caller_profile = storage::USER_PROFILES.with(|profiles| {
-            profiles.borrow().get(&caller_principal).unwrap()
-        });
-        
-        let reverse_friend = Friend {
-            principal: caller_profile.principal,
-            display_name: caller_profile.display_name,
-            avatar_base64: caller_profile.avatar_base64,
-            added_at: ic_cdk::api::time(),
-        };
-        
-        friends.insert((friend_principal, caller_principal), reverse_friend);
+    storage::RESERVED_NAMES.with(|reserved| {
+        reserved.borrow_mut().insert(key, entry.clone());
     });
     
-    ApiResponse::success(())
+    ApiResponse::success(entry)
 }
 
 #[update]
-fn remove_friend(friend_principal: Principal) -> ApiResponse<()> {
-    let caller_principal = caller();
+fn remove_reserved_name(name: String) -> ApiResponse<()> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
     
-    storage::FRIENDS.with(|friends| {
-        let mut friends = friends.borrow_mut();
-        friends.remove(&(caller_principal, friend_principal));
-        friends.remove(&(friend_principal, caller_principal));
-    });
+    let removed = storage::RESERVED_NAMES.with(|reserved| reserved.borrow_mut().remove(&reserved_key(&name)));
+    if removed.is_none() {
+        return errors::coded("reserved_name_not_found", &[]);
+    }
     
     ApiResponse::success(())
 }
 
 #[query]
-fn get_friends() -> ApiResponse<Vec<Friend>> {
-    let caller_principal = caller();
+fn get_reserved_names() -> ApiResponse<Vec<ReservedName>> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
     
-    let friends = storage::FRIENDS.with(|friends| {
-        friends.borrow()
-            .iter()
-            .filter(|((user_principal, _), _)| *user_principal == caller_principal)
-            .map(|(_, friend)| friend)
-            .collect()
+    let entries = storage::RESERVED_NAMES.with(|reserved| {
+        reserved.borrow().iter().map(|(_, entry)| entry).collect()
     });
     
-    ApiResponse::success(friends)
+    ApiResponse::success(entries)
 }
 
-#[query]
-fn is_friend(principal: Principal) -> ApiResponse<bool> {
-    let caller_principal = caller();
+// ============ INVITE CODE METHODS ============
+
+// Invite codes are typed in by hand, so they use letters and digits that cannot be confused
+// (no I, O, 0 or 1). 32 symbols, so a byte maps onto one without bias
+const INVITE_CODE_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const INVITE_CODE_LENGTH: usize = 10;
+
+// Most codes minted by one call
+const MAX_INVITE_BATCH: u32 = 100;
+
+fn invite_only() -> bool {
+    storage::NUMERIC_SETTINGS.with(|settings| settings.borrow().get(&storage::INVITE_ONLY_SETTING)) == Some(1)
+}
+
+/// `code` as stored: uppercase, without the spaces and dashes people add when sharing it
+fn normalize_invite_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_uppercase).collect()
+}
+
+/// The invite behind `code`, if it exists and has not been used yet
+fn usable_invite(code: &str) -> Result<InviteCode, &'static str> {
+    match storage::INVITE_CODES.with(|codes| codes.borrow().get(&normalize_invite_code(code))) {
+        Some(invite) if invite.used_by.is_some() => Err("invite_used"),
+        Some(invite) => Ok(invite),
+        None => Err("invite_invalid"),
+    }
+}
+
+/// Require an invite code to register. Accounts that already exist are not affected
+#[update]
+fn set_invite_only(enabled: bool) -> ApiResponse<()> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
     
-    let is_friend = storage::FRIENDS.with(|friends| {
-        friends.borrow().contains_key(&(caller_principal, principal))
+    storage::NUMERIC_SETTINGS.with(|settings| {
+        settings.borrow_mut().insert(storage::INVITE_ONLY_SETTING, enabled as u64);
     });
     
-    ApiResponse::success(is_friend)
+    ApiResponse::success(())
 }
 
-// ============ FRIEND REQUESTS METHODS ============
+/// Whether registering needs an invite code, so clients know to ask for one
+#[query]
+fn get_invite_only() -> ApiResponse<bool> {
+    ApiResponse::success(invite_only())
+}
 
+/// Mint `count` (default 1) single-use invite codes. Pass `inviter` to mint them for a member
+/// to hand out, so the accounts they bring in are credited to them
 #[update]
-fn send_friend_request(to_principal: Principal) -> ApiResponse<FriendRequest> {
-    let from_principal = caller();
+async fn mint_invite_codes(count: Option<u32>, inviter: Option<Principal>) -> ApiResponse<Vec<InviteCode>> {
+    let caller_principal = caller();
+    if !is_admin(&caller_principal) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
     
-    // Validate users exist
-    let from_profile = storage::USER_PROFILES.with(|profiles| {
-        profiles.borrow().get(&from_principal)
-    });
-    let to_profile = storage::USER_PROFILES.with(|profiles| {
-        profiles.borrow().get(&to_principal)
-    });
+    let count = count.unwrap_or(1);
+    if count == 0 || count > MAX_INVITE_BATCH {
+        return errors::coded("invite_count_invalid", &[("max", MAX_INVITE_BATCH.to_string())]);
+    }
+    if let Some(inviter) = inviter {
+        if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&inviter)) {
+            return errors::coded("user_not_found", &[]);
+        }
+    }
+    
+    // Codes are bearer credentials, so they are derived from the management canister's
+    // randomness: one seed per batch, hashed with each code's index
+    let seed = match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((bytes,)) => bytes,
+        Err((code, msg)) => return errors::coded("random_unavailable", &[("detail", format!("{:?} {}", code, msg))]),
+    };
+    
+    let now = ic_cdk::api::time();
+    let mut minted = Vec::with_capacity(count as usize);
+    storage::INVITE_CODES.with(|codes| {
+        let mut codes = codes.borrow_mut();
+        let mut index: u64 = 0;
+        while minted.len() < count as usize {
+            let digest = sha2::Sha256::new().chain_update(&seed).chain_update(index.to_le_bytes()).finalize();
+            index += 1;
+            let code: String = digest.iter()
+                .take(INVITE_CODE_LENGTH)
+                .map(|byte| INVITE_CODE_ALPHABET[(byte % 32) as usize] as char)
+                .collect();
+            if codes.contains_key(&code) {
+                continue;
+            }
+            
+            let invite = InviteCode {
+                code: code.clone(),
+                inviter: inviter.unwrap_or(caller_principal),
+                created_by: caller_principal,
+                created_at: now,
+                used_by: None,
+                used_at: None,
+            };
+            codes.insert(code, invite.clone());
+            minted.push(invite);
+        }
+    });
+    
+    ApiResponse::success(minted)
+}
+
+/// Withdraw an unused invite code. Used codes cannot be revoked; they record who invited whom
+#[update]
+fn revoke_invite_code(code: String) -> ApiResponse<()> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
+    
+    let code = normalize_invite_code(&code);
+    storage::INVITE_CODES.with(|codes| {
+        let mut codes = codes.borrow_mut();
+        match codes.get(&code) {
+            Some(invite) if invite.used_by.is_some() => errors::coded("invite_used", &[]),
+            Some(_) => {
+                codes.remove(&code);
+                ApiResponse::success(())
+            }
+            None => errors::coded("invite_not_found", &[]),
+        }
+    })
+}
+
+/// Every invite code in code order, used and unused, for growth analytics. Filter on `used_by`
+/// for the accounts each inviter brought in
+#[query]
+fn get_invite_codes(limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<InviteCode>> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
+    
+    let scope = "invite_codes";
+    let cursor = match pagination::resume(cursor, scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let lower = match &cursor.last_key {
+        Some(key) => Bound::Excluded(String::from_utf8_lossy(key).into_owned()),
+        None => Bound::Unbounded,
+    };
+    
+    let page = storage::INVITE_CODES.with(|codes| {
+        let codes = codes.borrow();
+        let items = codes
+            .range((lower, Bound::Unbounded))
+            .filter(|(_, invite)| invite.created_at <= cursor.snapshot_at)
+            .map(|(code, invite)| (code.into_bytes(), invite));
+        pagination::collect_page(items, pagination::page_size(limit), scope, cursor.snapshot_at)
+    });
+    
+    ApiResponse::success(page)
+}
+
+// ============ FRIENDS MANAGEMENT METHODS ============
+
+#[update]
+fn add_friend(friend_principal: Principal) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    create_friendship(caller(), friend_principal)
+}
+
+/// Friend `caller_principal` and `friend_principal` in both directions
+fn create_friendship(caller_principal: Principal, friend_principal: Principal) -> ApiResponse<()> {
+    // Validate friend exists
+    let friend_profile = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow().get(&friend_principal)
+    });
+    
+    let friend_profile = match friend_profile {
+        Some(p) => p,
+        None => return errors::coded("friend_not_found", &[]),
+    };
+    
+    // Check if blocked
+    let is_blocked = storage::BLOCKED_USERS.with(|blocked| {
+        blocked.borrow().contains_key(&(caller_principal, friend_principal)) ||
+        blocked.borrow().contains_key(&(friend_principal, caller_principal))
+    });
+    
+    if is_blocked {
+        return errors::coded("add_friend_blocked", &[]);
+    }
+    
+    let max_friends = friend_quotas().max_friends;
+    if friend_set(caller_principal).len() as u64 >= max_friends {
+        return errors::coded("friend_limit_reached", &[("max", max_friends.to_string())]);
+    }
+    if friend_set(friend_principal).len() as u64 >= max_friends {
+        return errors::coded("recipient_friend_limit_reached", &[("max", max_friends.to_string())]);
+    }
+    
+    // Create Friend entry
+    let friend = Friend {
+        principal: friend_profile.principal,
+        display_name: friend_profile.display_name.clone(),
+        avatar_base64: None,
+        added_at: ic_cdk::api::time(),
+        favorited_at: None,
+    };
+    
+    // Add bidirectional friendship
+    storage::FRIENDS.with(|friends| {
+        let mut friends = friends.borrow_mut();
+        friends.insert((caller_principal, friend_principal), friend.clone());
+        
+        // Reverse direction
+        let caller_profile = storage::USER_PROFILES.with(|profiles| {
+            profiles.borrow().get(&caller_principal).unwrap()
+        });
+        
+        let reverse_friend = Friend {
+            principal: caller_profile.principal,
+            display_name: caller_profile.display_name,
+            avatar_base64: None,
+            added_at: ic_cdk::api::time(),
+            favorited_at: None,
+        };
+        
+        friends.insert((friend_principal, caller_principal), reverse_friend);
+    });
+    
+    for principal in [caller_principal, friend_principal] {
+        update_onboarding(principal, |state| state.first_friend_added = true);
+    }
+    record_activity(caller_principal, ActivityKind::BecameFriends { friend: friend_principal });
+    record_activity(friend_principal, ActivityKind::BecameFriends { friend: caller_principal });
+    
+    ApiResponse::success(())
+}
+
+#[update]
+fn remove_friend(friend_principal: Principal) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    storage::FRIENDS.with(|friends| {
+        let mut friends = friends.borrow_mut();
+        friends.remove(&(caller_principal, friend_principal));
+        friends.remove(&(friend_principal, caller_principal));
+    });
+    
+    ApiResponse::success(())
+}
+
+/// `friend` with the display name from their current profile. Friend records copy the name
+/// when the friendship starts, so listings read it fresh instead of trusting the copy
+fn with_current_profile(mut friend: Friend) -> Friend {
+    if let Some(profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&friend.principal)) {
+        friend.display_name = profile.display_name;
+    }
+    friend
+}
+
+fn friends_of(principal: Principal) -> Vec<Friend> {
+    storage::FRIENDS.with(|friends| {
+        friends.borrow()
+            .range((principal, Principal::from_slice(&[]))..)
+            .take_while(|((user_principal, _), _)| *user_principal == principal)
+            .map(|(_, friend)| with_current_profile(friend))
+            .collect()
+    })
+}
+
+#[query]
+fn get_friends() -> ApiResponse<Vec<Friend>> {
+    ApiResponse::success(friends_of(caller()))
+}
+
+#[query]
+fn is_friend(principal: Principal) -> ApiResponse<bool> {
+    let caller_principal = caller();
+    
+    let is_friend = storage::FRIENDS.with(|friends| {
+        friends.borrow().contains_key(&(caller_principal, principal))
+    });
+    
+    ApiResponse::success(is_friend)
+}
+
+/// Everything a profile page needs to know about the caller's relationship with `principal`.
+/// Blocks win over friendship, and friendship over pending requests
+#[query]
+fn get_relationship(principal: Principal) -> ApiResponse<Relationship> {
+    let caller_principal = caller();
+    
+    let (blocked_by_me, blocked_me) = storage::BLOCKED_USERS.with(|blocked| {
+        let blocked = blocked.borrow();
+        (blocked.contains_key(&(caller_principal, principal)), blocked.contains_key(&(principal, caller_principal)))
+    });
+    if blocked_by_me {
+        return ApiResponse::success(Relationship::BlockedByMe);
+    }
+    if blocked_me {
+        return ApiResponse::success(Relationship::BlockedMe);
+    }
+    if storage::FRIENDS.with(|friends| friends.borrow().contains_key(&(caller_principal, principal))) {
+        return ApiResponse::success(Relationship::Friends);
+    }
+    
+    let pending = storage::FRIEND_REQUESTS.with(|requests| {
+        requests.borrow()
+            .iter()
+            .filter(|(_, req)| req.status == FriendRequestStatus::Pending)
+            .find_map(|(_, req)| {
+                if req.from_principal == caller_principal && req.to_principal == principal {
+                    Some(Relationship::PendingOutgoing)
+                } else if req.from_principal == principal && req.to_principal == caller_principal {
+                    Some(Relationship::PendingIncoming)
+                } else {
+                    None
+                }
+            })
+    });
+    
+    ApiResponse::success(pending.unwrap_or(Relationship::None))
+}
+
+const MAX_FRIENDSHIP_CHECKS: usize = 500;
+
+/// is_friend for many principals at once, in the order given
+#[query]
+fn are_friends(principals: Vec<Principal>) -> ApiResponse<Vec<(Principal, bool)>> {
+    if principals.len() > MAX_FRIENDSHIP_CHECKS {
+        return errors::coded("too_many_friendship_checks", &[("max", MAX_FRIENDSHIP_CHECKS.to_string())]);
+    }
+    let caller_principal = caller();
+    
+    let results = storage::FRIENDS.with(|friends| {
+        let friends = friends.borrow();
+        principals.into_iter()
+            .map(|principal| (principal, friends.contains_key(&(caller_principal, principal))))
+            .collect()
+    });
+    
+    ApiResponse::success(results)
+}
+
+/// Pin or unpin a friend in the caller's favorites. Only the caller's side of the friendship changes
+#[update]
+fn set_favorite(friend_principal: Principal, favorite: bool) -> ApiResponse<Friend> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    storage::FRIENDS.with(|friends| {
+        let mut friends = friends.borrow_mut();
+        let Some(mut friend) = friends.get(&(caller_principal, friend_principal)) else {
+            return errors::coded("not_friends", &[]);
+        };
+        
+        // Re-pinning keeps the original position
+        friend.favorited_at = match (favorite, friend.favorited_at) {
+            (true, Some(at)) => Some(at),
+            (true, None) => Some(ic_cdk::api::time()),
+            (false, _) => None,
+        };
+        friends.insert((caller_principal, friend_principal), friend.clone());
+        ApiResponse::success(with_current_profile(friend))
+    })
+}
+
+/// The caller's favorite friends, in the order they were pinned
+#[query]
+fn get_favorites() -> ApiResponse<Vec<Friend>> {
+    let mut favorites: Vec<Friend> = friends_of(caller())
+        .into_iter()
+        .filter(|friend| friend.favorited_at.is_some())
+        .collect();
+    favorites.sort_by_key(|friend| friend.favorited_at);
+    
+    ApiResponse::success(favorites)
+}
+
+const DEFAULT_FRIEND_SUGGESTIONS: u32 = 20;
+const MAX_FRIEND_SUGGESTIONS: u32 = 50;
+
+/// Friends of the caller's friends, most mutual friends first. Users either side has blocked
+/// and users with a pending request in either direction are left out
+#[query]
+fn get_friend_suggestions(limit: Option<u32>) -> ApiResponse<Vec<FriendSuggestion>> {
+    let caller_principal = caller();
+    let limit = limit.unwrap_or(DEFAULT_FRIEND_SUGGESTIONS).min(MAX_FRIEND_SUGGESTIONS) as usize;
+    
+    let friends = friend_set(caller_principal);
+    let pending: HashSet<Principal> = storage::FRIEND_REQUESTS.with(|requests| {
+        requests.borrow()
+            .iter()
+            .filter(|(_, req)| req.status == FriendRequestStatus::Pending)
+            .filter_map(|(_, req)| {
+                if req.from_principal == caller_principal {
+                    Some(req.to_principal)
+                } else if req.to_principal == caller_principal {
+                    Some(req.from_principal)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    });
+    
+    let mut mutual_counts: HashMap<Principal, u32> = HashMap::new();
+    for friend in &friends {
+        for candidate in friend_set(*friend) {
+            if candidate != caller_principal && !friends.contains(&candidate) && !pending.contains(&candidate) && !is_banned(&candidate) {
+                *mutual_counts.entry(candidate).or_default() += 1;
+            }
+        }
+    }
+    
+    let mut suggestions: Vec<FriendSuggestion> = storage::BLOCKED_USERS.with(|blocked| {
+        let blocked = blocked.borrow();
+        mutual_counts.into_iter()
+            .filter(|(candidate, _)| {
+                !blocked.contains_key(&(caller_principal, *candidate))
+                    && !blocked.contains_key(&(*candidate, caller_principal))
+            })
+            .filter_map(|(candidate, mutual_friends)| {
+                let profile = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&candidate))?;
+                Some(FriendSuggestion { principal: candidate, display_name: profile.display_name, mutual_friends })
+            })
+            .collect()
+    });
+    
+    // Ties go alphabetical for a stable order
+    suggestions.sort_by(|a, b| {
+        b.mutual_friends.cmp(&a.mutual_friends)
+            .then_with(|| a.display_name.to_lowercase().cmp(&b.display_name.to_lowercase()))
+    });
+    suggestions.truncate(limit);
+    
+    diagnosed("get_friend_suggestions", ApiResponse::success(suggestions))
+}
+
+// ============ FRIEND REQUESTS METHODS ============
+
+const MAX_FRIEND_REQUEST_NOTE_CHARS: usize = 200;
+
+/// Send a friend request, optionally with a short note the recipient sees before accepting
+#[update]
+fn send_friend_request(to_principal: Principal, note: Option<String>) -> ApiResponse<FriendRequest> {
+    create_friend_request(caller(), to_principal, note)
+}
+
+/// Shared by send_friend_request and app_send_friend_request
+fn create_friend_request(from_principal: Principal, to_principal: Principal, note: Option<String>) -> ApiResponse<FriendRequest> {
+    if let Some(rejection) = reject_if_suspended(&from_principal) {
+        return rejection;
+    }
+    if trust_tier(&from_principal) == TrustTier::Restricted {
+        return errors::coded("trust_restricted", &[]);
+    }
+    
+    // A blank note is the same as none
+    let note = note.map(|note| note.trim().to_string()).filter(|note| !note.is_empty());
+    if note.as_ref().is_some_and(|note| note.chars().count() > MAX_FRIEND_REQUEST_NOTE_CHARS) {
+        return errors::coded("friend_request_note_too_long", &[("max", MAX_FRIEND_REQUEST_NOTE_CHARS.to_string())]);
+    }
+    
+    // Validate users exist
+    let from_profile = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow().get(&from_principal)
+    });
+    let to_profile = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow().get(&to_principal)
+    });
     
     let from_profile = match from_profile {
         Some(p) => p,
-        None => return ApiResponse::error("Sender not registered".to_string()),
+        None => return errors::coded("sender_not_registered", &[]),
     };
     let to_profile = match to_profile {
         Some(p) => p,
-        None => return ApiResponse::error("Recipient not found".to_string()),
+        None => return errors::coded("recipient_not_found", &[]),
     };
     
     // Check if already friends
@@ -286,7 +1218,7 @@ fn send_friend_request(to_principal: Principal) -> ApiResponse<FriendRequest> {
     });
     
     if already_friends {
-        return ApiResponse::error("Already friends".to_string());
+        return errors::coded("already_friends", &[]);
     }
     
     // Check if blocked
@@ -295,7 +1227,17 @@ fn send_friend_request(to_principal: Principal) -> ApiResponse<FriendRequest> {
     });
     
     if is_blocked {
-        return ApiResponse::error("Cannot send friend request: you are blocked".to_string());
+        return errors::coded("friend_request_blocked", &[]);
+    }
+    
+    match privacy_settings(&to_principal).friend_requests {
+        FriendRequestSetting::Everyone => {}
+        FriendRequestSetting::FriendsOfFriends => {
+            if friend_set(to_principal).is_disjoint(&friend_set(from_principal)) {
+                return errors::coded("friend_requests_friends_of_friends_only", &[]);
+            }
+        }
+        FriendRequestSetting::Nobody => return errors::coded("friend_requests_closed", &[]),
     }
     
     // Check for existing pending request in both directions
@@ -317,11 +1259,25 @@ fn send_friend_request(to_principal: Principal) -> ApiResponse<FriendRequest> {
     });
     
     if existing_request.is_some() {
-        return ApiResponse::error("Friend request already sent".to_string());
+        return errors::coded("friend_request_already_sent", &[]);
     }
     
     if reverse_request.is_some() {
-        return ApiResponse::error("This user has already sent you a friend request. Check your pending requests.".to_string());
+        return errors::coded("friend_request_incoming_exists", &[]);
+    }
+    
+    let quotas = friend_quotas();
+    if friend_set(from_principal).len() as u64 >= quotas.max_friends {
+        return errors::coded("friend_limit_reached", &[("max", quotas.max_friends.to_string())]);
+    }
+    let outstanding = storage::PENDING_REQUESTS_FROM.with(|index| {
+        index.borrow()
+            .range((from_principal, String::new())..)
+            .take_while(|((sender, _), _)| *sender == from_principal)
+            .count() as u64
+    });
+    if outstanding >= quotas.max_outstanding_requests {
+        return errors::coded("friend_request_limit_reached", &[("max", quotas.max_outstanding_requests.to_string())]);
     }
     
     // Create request
@@ -334,17 +1290,29 @@ fn send_friend_request(to_principal: Principal) -> ApiResponse<FriendRequest> {
         to_display_name: to_profile.display_name,
         status: FriendRequestStatus::Pending,
         created_at: ic_cdk::api::time(),
+        note,
     };
     
-    storage::FRIEND_REQUESTS.with(|requests| {
-        requests.borrow_mut().insert(request_id, request.clone());
-    });
+    store_friend_request(request.clone());
+    
+    if auto_accepts(to_principal, from_principal) {
+        return auto_accept_friend_request(request);
+    }
+    push_notification(
+        to_principal,
+        NotificationKind::FriendRequestReceived { request_id: request.id.clone(), from: from_principal },
+        format!("{} sent you a friend request", request.from_display_name),
+    );
     
     ApiResponse::success(request)
 }
 
 #[update]
 fn accept_friend_request(request_id: String) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
     let caller_principal = caller();
     
     // Get and validate request
@@ -354,15 +1322,15 @@ fn accept_friend_request(request_id: String) -> ApiResponse<()> {
     
     let mut request = match request {
         Some(r) => r,
-        None => return ApiResponse::error("Friend request not found".to_string()),
+        None => return errors::coded("friend_request_not_found", &[]),
     };
     
     if request.to_principal != caller_principal {
-        return ApiResponse::error("Not authorized to accept this request".to_string());
+        return errors::coded("request_accept_not_authorized", &[]);
     }
     
     if request.status != FriendRequestStatus::Pending {
-        return ApiResponse::error("Request is not pending".to_string());
+        return errors::coded("request_not_pending", &[]);
     }
     
     // Create bidirectional friendship
@@ -370,12 +1338,17 @@ fn accept_friend_request(request_id: String) -> ApiResponse<()> {
     if !result.success {
         return result;
     }
+    chaos_write_point("accept_friend_request");
     
     // Update request status
     request.status = FriendRequestStatus::Accepted;
-    storage::FRIEND_REQUESTS.with(|requests| {
-        requests.borrow_mut().insert(request_id, request);
-    });
+    store_friend_request(request.clone());
+    report_friend_request_outcome(&request_id, true);
+    push_notification(
+        request.from_principal,
+        NotificationKind::FriendRequestAccepted { request_id, by: caller_principal },
+        format!("{} accepted your friend request", request.to_display_name),
+    );
     
     ApiResponse::success(())
 }
@@ -383,6 +1356,9 @@ fn accept_friend_request(request_id: String) -> ApiResponse<()> {
 #[update]
 fn reject_friend_request(request_id: String) -> ApiResponse<()> {
     let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
     
     let request = storage::FRIEND_REQUESTS.with(|requests| {
         requests.borrow().get(&request_id)
@@ -390,66 +1366,528 @@ fn reject_friend_request(request_id: String) -> ApiResponse<()> {
     
     let mut request = match request {
         Some(r) => r,
-        None => return ApiResponse::error("Friend request not found".to_string()),
+        None => return errors::coded("friend_request_not_found", &[]),
     };
     
     if request.to_principal != caller_principal {
-        return ApiResponse::error("Not authorized to reject this request".to_string());
+        return errors::coded("request_reject_not_authorized", &[]);
     }
     
     if request.status != FriendRequestStatus::Pending {
-        return ApiResponse::error("Request is not pending".to_string());
+        return errors::coded("request_not_pending", &[]);
     }
     
     request.status = FriendRequestStatus::Rejected;
-    storage::FRIEND_REQUESTS.with(|requests| {
-        requests.borrow_mut().insert(request_id, request);
-    });
+    store_friend_request(request);
+    report_friend_request_outcome(&request_id, false);
     
     ApiResponse::success(())
 }
 
-#[query]
-fn get_friend_requests() -> ApiResponse<Vec<FriendRequest>> {
-    let caller_principal = caller();
+/// Tell the AI canister how a request ended so it can score recommendations that led to it.
+/// Fire-and-forget: the AI canister ignores requests it did not originate
+fn report_friend_request_outcome(request_id: &str, accepted: bool) {
+    let Some(ai_canister) = storage::SETTINGS.with(|settings| settings.borrow().get(&storage::AI_CANISTER_SETTING)) else {
+        return;
+    };
     
-    let requests = storage::FRIEND_REQUESTS.with(|requests| {
+    match candid::encode_args((request_id.to_string(), accepted)) {
+        Ok(args) => queue_notification(ai_canister, "record_friend_request_outcome", args),
+        Err(err) => ic_cdk::println!("friend request outcome for {} not encoded: {}", request_id, err),
+    }
+}
+
+// Pending requests expire after 30 days
+const FRIEND_REQUEST_TTL_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// Requests are dropped 90 days after they were sent. Pending ones expire long before, so by
+// then every request has been answered or expired for at least 60 days
+const FRIEND_REQUEST_RETENTION_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+
+const FRIEND_REQUEST_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Requests examined per timer tick
+const FRIEND_REQUEST_SWEEP_BATCH: usize = 500;
+
+fn sweep_friend_requests() {
+    sweep_friend_requests_after(None);
+}
+
+/// Save `request`, keeping it in the per-recipient and per-sender indexes only while pending
+fn store_friend_request(request: FriendRequest) {
+    let to_key = (request.to_principal, request.id.clone());
+    let from_key = (request.from_principal, request.id.clone());
+    if request.status == FriendRequestStatus::Pending {
+        storage::PENDING_REQUESTS_TO.with(|index| index.borrow_mut().insert(to_key, request.created_at));
+        storage::PENDING_REQUESTS_FROM.with(|index| index.borrow_mut().insert(from_key, request.created_at));
+    } else {
+        storage::PENDING_REQUESTS_TO.with(|index| index.borrow_mut().remove(&to_key));
+        storage::PENDING_REQUESTS_FROM.with(|index| index.borrow_mut().remove(&from_key));
+    }
+    storage::FRIEND_REQUESTS.with(|requests| requests.borrow_mut().insert(request.id.clone(), request));
+}
+
+fn remove_friend_request(request: &FriendRequest) {
+    storage::PENDING_REQUESTS_TO.with(|index| index.borrow_mut().remove(&(request.to_principal, request.id.clone())));
+    storage::PENDING_REQUESTS_FROM.with(|index| index.borrow_mut().remove(&(request.from_principal, request.id.clone())));
+    storage::FRIEND_REQUESTS.with(|requests| requests.borrow_mut().remove(&request.id));
+}
+
+/// Fill the pending request indexes from the stored requests when upgrading from a version
+/// that did not keep them
+fn index_pending_friend_requests() {
+    let indexed = storage::PENDING_REQUESTS_TO.with(|index| !index.borrow().is_empty())
+        || storage::PENDING_REQUESTS_FROM.with(|index| !index.borrow().is_empty());
+    if indexed {
+        return;
+    }
+    let pending: Vec<FriendRequest> = storage::FRIEND_REQUESTS.with(|requests| {
         requests.borrow()
             .iter()
-            .filter(|(_, req)| {
-                req.to_principal == caller_principal && 
-                req.status == FriendRequestStatus::Pending
-            })
+            .filter(|(_, req)| req.status == FriendRequestStatus::Pending)
             .map(|(_, req)| req)
             .collect()
     });
-    
-    ApiResponse::success(requests)
+    for request in pending {
+        store_friend_request(request);
+    }
 }
 
-#[query]
-fn get_sent_requests() -> ApiResponse<Vec<FriendRequest>> {
-    let caller_principal = caller();
-    
-    let requests = storage::FRIEND_REQUESTS.with(|requests| {
-        requests.borrow()
-            .iter()
-            .filter(|(_, req)| {
-                req.from_principal == caller_principal && 
-                req.status == FriendRequestStatus::Pending
-            })
-            .map(|(_, req)| req)
+/// Pending requests `principal` has received (or sent), in request id order
+fn pending_friend_requests(principal: Principal, received: bool) -> Vec<FriendRequest> {
+    let index = if received { &storage::PENDING_REQUESTS_TO } else { &storage::PENDING_REQUESTS_FROM };
+    index.with(|index| {
+        storage::FRIEND_REQUESTS.with(|requests| {
+            let requests = requests.borrow();
+            index.borrow()
+                .range((principal, String::new())..)
+                .take_while(|((owner, _), _)| *owner == principal)
+                .filter_map(|((_, id), _)| requests.get(&id))
+                .collect()
+        })
+    })
+}
+
+/// Expire stale pending requests and drop old ones among those after `after`, continuing on
+/// another tick until every request has been looked at
+fn sweep_friend_requests_after(after: Option<String>) {
+    let now = ic_cdk::api::time();
+    let lower = match after {
+        Some(id) => Bound::Excluded(id),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<(String, FriendRequest)> = storage::FRIEND_REQUESTS.with(|requests| {
+        requests.borrow()
+            .range((lower, Bound::Unbounded))
+            .take(FRIEND_REQUEST_SWEEP_BATCH)
+            .collect()
+    });
+    
+    for (id, mut request) in batch.iter().cloned() {
+        let age = now.saturating_sub(request.created_at);
+        if age >= FRIEND_REQUEST_RETENTION_NANOS {
+            remove_friend_request(&request);
+        } else if age >= FRIEND_REQUEST_TTL_NANOS && request.status == FriendRequestStatus::Pending {
+            request.status = FriendRequestStatus::Expired;
+            store_friend_request(request);
+            report_friend_request_outcome(&id, false);
+        }
+    }
+    
+    if batch.len() == FRIEND_REQUEST_SWEEP_BATCH {
+        let last = batch.last().map(|(id, _)| id.clone());
+        ic_cdk_timers::set_timer(Duration::ZERO, move || sweep_friend_requests_after(last));
+    }
+}
+
+// Limits on auto-accept policies
+const MAX_AUTO_ACCEPT_RULES: usize = 5;
+const MAX_AUTO_ACCEPT_MUTUALS: u32 = 100;
+
+fn friend_set(principal: Principal) -> HashSet<Principal> {
+    storage::FRIENDS.with(|friends| {
+        friends.borrow()
+            .range((principal, Principal::from_slice(&[]))..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|((_, friend), _)| friend)
             .collect()
+    })
+}
+
+/// Whether `recipient`'s auto-accept policy lets a request from `sender` through. Senders on
+/// reduced trust always wait for an answer
+fn auto_accepts(recipient: Principal, sender: Principal) -> bool {
+    let Some(policy) = storage::AUTO_ACCEPT_POLICIES.with(|policies| policies.borrow().get(&recipient)) else {
+        return false;
+    };
+    if trust_tier(&sender) != TrustTier::Standard {
+        return false;
+    }
+    
+    policy.rules.iter().any(|rule| match rule {
+        AutoAcceptRule::MutualFriends { min } => {
+            friend_set(recipient).intersection(&friend_set(sender)).count() >= *min as usize
+        }
+        AutoAcceptRule::SharedGroup => storage::GROUPS.with(|groups| {
+            groups.borrow()
+                .iter()
+                .any(|(id, _)| is_group_member(id, recipient) && is_group_member(id, sender))
+        }),
+    })
+}
+
+/// Accept a request just created by create_friend_request on the recipient's behalf and let
+/// both of them know
+fn auto_accept_friend_request(mut request: FriendRequest) -> ApiResponse<FriendRequest> {
+    let result = create_friendship(request.to_principal, request.from_principal);
+    if !result.success {
+        // Leave the request pending for the recipient to answer
+        return ApiResponse::success(request);
+    }
+    
+    request.status = FriendRequestStatus::Accepted;
+    store_friend_request(request.clone());
+    report_friend_request_outcome(&request.id, true);
+    
+    push_notification(
+        request.to_principal,
+        NotificationKind::FriendRequestAutoAccepted { request_id: request.id.clone(), friend: request.from_principal },
+        format!("You and {} are now friends (accepted by your auto-accept rules)", request.from_display_name),
+    );
+    push_notification(
+        request.from_principal,
+        NotificationKind::FriendRequestAutoAccepted { request_id: request.id.clone(), friend: request.to_principal },
+        format!("{} accepted your friend request", request.to_display_name),
+    );
+    
+    ApiResponse::success(request)
+}
+
+/// Accept incoming friend requests matching any of `policy`'s rules without asking, or ask
+/// about every request again with None. Only applies to requests sent from now on
+#[update]
+fn set_auto_accept_policy(policy: Option<AutoAcceptPolicy>) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    match policy {
+        Some(policy) => {
+            if policy.rules.len() > MAX_AUTO_ACCEPT_RULES {
+                return errors::coded("auto_accept_too_many_rules", &[("max", MAX_AUTO_ACCEPT_RULES.to_string())]);
+            }
+            let invalid_mutuals = policy.rules.iter().any(|rule| {
+                matches!(rule, AutoAcceptRule::MutualFriends { min } if !(1..=MAX_AUTO_ACCEPT_MUTUALS).contains(min))
+            });
+            if invalid_mutuals {
+                return errors::coded("auto_accept_mutuals_out_of_range", &[("max", MAX_AUTO_ACCEPT_MUTUALS.to_string())]);
+            }
+            storage::AUTO_ACCEPT_POLICIES.with(|policies| policies.borrow_mut().insert(caller_principal, policy));
+        }
+        None => {
+            storage::AUTO_ACCEPT_POLICIES.with(|policies| policies.borrow_mut().remove(&caller_principal));
+        }
+    }
+    
+    ApiResponse::success(())
+}
+
+#[query]
+fn get_auto_accept_policy() -> ApiResponse<Option<AutoAcceptPolicy>> {
+    ApiResponse::success(storage::AUTO_ACCEPT_POLICIES.with(|policies| policies.borrow().get(&caller())))
+}
+
+#[query]
+fn get_friend_requests() -> ApiResponse<Vec<FriendRequest>> {
+    ApiResponse::success(pending_friend_requests(caller(), true))
+}
+
+#[query]
+fn get_sent_requests() -> ApiResponse<Vec<FriendRequest>> {
+    ApiResponse::success(pending_friend_requests(caller(), false))
+}
+
+// ============ FRIEND QUOTA METHODS ============
+
+const DEFAULT_MAX_FRIENDS: u64 = 1_000;
+const DEFAULT_MAX_OUTSTANDING_REQUESTS: u64 = 100;
+
+fn friend_quotas() -> FriendQuotas {
+    storage::NUMERIC_SETTINGS.with(|settings| {
+        let settings = settings.borrow();
+        FriendQuotas {
+            max_friends: settings.get(&storage::MAX_FRIENDS_SETTING).unwrap_or(DEFAULT_MAX_FRIENDS),
+            max_outstanding_requests: settings.get(&storage::MAX_OUTSTANDING_REQUESTS_SETTING)
+                .unwrap_or(DEFAULT_MAX_OUTSTANDING_REQUESTS),
+        }
+    })
+}
+
+/// Set the per-user caps. Users already over a lowered cap keep what they have but cannot add more
+#[update]
+fn set_friend_quotas(quotas: FriendQuotas) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    if quotas.max_friends == 0 || quotas.max_outstanding_requests == 0 {
+        return errors::coded("friend_quota_zero", &[]);
+    }
+    
+    storage::NUMERIC_SETTINGS.with(|settings| {
+        let mut settings = settings.borrow_mut();
+        settings.insert(storage::MAX_FRIENDS_SETTING, quotas.max_friends);
+        settings.insert(storage::MAX_OUTSTANDING_REQUESTS_SETTING, quotas.max_outstanding_requests);
+    });
+    
+    ApiResponse::success(())
+}
+
+#[query]
+fn get_friend_quotas() -> ApiResponse<FriendQuotas> {
+    ApiResponse::success(friend_quotas())
+}
+
+// ============ PRIVACY METHODS ============
+
+fn privacy_settings(principal: &Principal) -> PrivacySettings {
+    storage::PRIVACY_SETTINGS.with(|settings| settings.borrow().get(principal)).unwrap_or_default()
+}
+
+#[query]
+fn get_privacy_settings() -> ApiResponse<PrivacySettings> {
+    ApiResponse::success(privacy_settings(&caller()))
+}
+
+/// Whether `viewer` may see a field of `owner`'s profile set to `visibility`
+fn profile_field_visible(visibility: Option<ProfileVisibility>, owner: &Principal, viewer: &Principal) -> bool {
+    match visibility.unwrap_or(ProfileVisibility::Public) {
+        ProfileVisibility::Public => true,
+        ProfileVisibility::FriendsOnly => {
+            owner == viewer || storage::FRIENDS.with(|friends| friends.borrow().contains_key(&(*owner, *viewer)))
+        }
+        ProfileVisibility::Private => owner == viewer,
+    }
+}
+
+/// `profile` as `viewer` may see it: hidden bio and avatar become None (the banner goes with
+/// the avatar), a hidden created_at 0.
+/// Profile pages served over HTTP use the anonymous principal's view
+fn redact_profile(mut profile: UserProfile, viewer: &Principal) -> UserProfile {
+    if profile.principal == *viewer {
+        return profile;
+    }
+    let settings = privacy_settings(&profile.principal);
+    
+    if !profile_field_visible(settings.bio, &profile.principal, viewer) {
+        profile.bio = None;
+    }
+    if !profile_field_visible(settings.avatar, &profile.principal, viewer) {
+        profile.avatar = None;
+        profile.banner = None;
+    }
+    if !profile_field_visible(settings.created_at, &profile.principal, viewer) {
+        profile.created_at = 0;
+    }
+    profile
+}
+
+/// Replace the caller's privacy settings. Requests already received are not affected
+#[update]
+fn update_privacy_settings(settings: PrivacySettings) -> ApiResponse<PrivacySettings> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    storage::PRIVACY_SETTINGS.with(|stored| {
+        stored.borrow_mut().insert(caller_principal, settings.clone());
     });
+    // The public profile page shows only public fields
+    if let Some(profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) {
+        http::certify_profile(&profile);
+    }
+    
+    ApiResponse::success(settings)
+}
+
+// ============ FOLLOW METHODS ============
+
+// Following is one-way and separate from friendship: it needs no answer unless the followed
+// user turned on approve_followers in their privacy settings.
+
+/// Follow `principal`, or ask to when they approve their followers
+#[update]
+fn follow(principal: Principal) -> ApiResponse<FollowStatus> {
+    let follower = caller();
+    if let Some(rejection) = reject_if_suspended(&follower) {
+        return rejection;
+    }
+    if follower == principal {
+        return errors::coded("cannot_follow_self", &[]);
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&follower)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&principal)) {
+        return errors::coded("user_not_found", &[]);
+    }
+    if is_blocked_either_way(follower, principal) {
+        return errors::coded("follow_blocked", &[]);
+    }
+    if storage::FOLLOWING.with(|following| following.borrow().contains_key(&(follower, principal))) {
+        return errors::coded("already_following", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    if privacy_settings(&principal).approve_followers == Some(true) {
+        let new_request = storage::FOLLOW_REQUESTS.with(|requests| {
+            let mut requests = requests.borrow_mut();
+            if requests.contains_key(&(principal, follower)) {
+                return false;
+            }
+            requests.insert((principal, follower), now);
+            true
+        });
+        if new_request {
+            push_notification(
+                principal,
+                NotificationKind::FollowRequested { follower },
+                format!("{} asked to follow you", display_name_of(&follower)),
+            );
+        }
+        return ApiResponse::success(FollowStatus::Requested);
+    }
+    
+    start_follow(follower, principal, now);
+    push_notification(
+        principal,
+        NotificationKind::NewFollower { follower },
+        format!("{} started following you", display_name_of(&follower)),
+    );
+    ApiResponse::success(FollowStatus::Following)
+}
+
+fn start_follow(follower: Principal, followed: Principal, now: u64) {
+    storage::FOLLOWING.with(|following| following.borrow_mut().insert((follower, followed), now));
+    storage::FOLLOWERS.with(|followers| followers.borrow_mut().insert((followed, follower), now));
+}
+
+/// Drop `follower`'s follow of `followed`, or their pending request. Returns whether there was one
+fn end_follows(follower: Principal, followed: Principal) -> bool {
+    let followed_before = storage::FOLLOWING.with(|following| following.borrow_mut().remove(&(follower, followed)));
+    storage::FOLLOWERS.with(|followers| followers.borrow_mut().remove(&(followed, follower)));
+    let requested = storage::FOLLOW_REQUESTS.with(|requests| requests.borrow_mut().remove(&(followed, follower)));
+    followed_before.is_some() || requested.is_some()
+}
+
+/// Stop following `principal`, or withdraw a request to
+#[update]
+fn unfollow(principal: Principal) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_anonymous(&caller()) {
+        return rejection;
+    }
+    if !end_follows(caller(), principal) {
+        return errors::coded("not_following", &[]);
+    }
+    ApiResponse::success(())
+}
+
+/// Approve (or turn down) a request to follow the caller
+#[update]
+fn answer_follow_request(follower: Principal, approve: bool) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
     
-    ApiResponse::success(requests)
+    let requested = storage::FOLLOW_REQUESTS.with(|requests| requests.borrow_mut().remove(&(caller_principal, follower)));
+    if requested.is_none() {
+        return errors::coded("follow_request_not_found", &[]);
+    }
+    if approve {
+        start_follow(follower, caller_principal, ic_cdk::api::time());
+        push_notification(
+            follower,
+            NotificationKind::FollowApproved { by: caller_principal },
+            format!("{} approved your follow request", display_name_of(&caller_principal)),
+        );
+    }
+    
+    ApiResponse::success(())
+}
+
+/// `owner`'s side of a follow map: the other principal of each pair and since when
+fn follow_pairs<M: ic_stable_structures::Memory>(
+    map: &ic_stable_structures::StableBTreeMap<(Principal, Principal), u64, M>,
+    owner: Principal,
+) -> Vec<(Principal, u64)> {
+    map.range((owner, Principal::from_slice(&[]))..)
+        .take_while(|((key_owner, _), _)| *key_owner == owner)
+        .map(|((_, other), since)| (other, since))
+        .collect()
+}
+
+fn display_name_of(principal: &Principal) -> String {
+    storage::USER_PROFILES.with(|profiles| profiles.borrow().get(principal))
+        .map(|profile| profile.display_name)
+        .unwrap_or_default()
+}
+
+fn follow_entries(pairs: Vec<(Principal, u64)>) -> Vec<FollowEntry> {
+    storage::USER_PROFILES.with(|profiles| {
+        let profiles = profiles.borrow();
+        pairs.into_iter()
+            .map(|(principal, since)| FollowEntry {
+                principal,
+                display_name: profiles.get(&principal).map(|profile| profile.display_name).unwrap_or_default(),
+                since,
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn get_followers() -> ApiResponse<Vec<FollowEntry>> {
+    let pairs = storage::FOLLOWERS.with(|followers| follow_pairs(&followers.borrow(), caller()));
+    ApiResponse::success(follow_entries(pairs))
+}
+
+#[query]
+fn get_following() -> ApiResponse<Vec<FollowEntry>> {
+    let pairs = storage::FOLLOWING.with(|following| follow_pairs(&following.borrow(), caller()));
+    ApiResponse::success(follow_entries(pairs))
+}
+
+/// Requests to follow the caller waiting for an answer
+#[query]
+fn get_follow_requests() -> ApiResponse<Vec<FollowEntry>> {
+    let pairs = storage::FOLLOW_REQUESTS.with(|requests| follow_pairs(&requests.borrow(), caller()));
+    ApiResponse::success(follow_entries(pairs))
 }
 
 // ============ BLOCKING METHODS ============
 
+const MAX_REPORT_DETAILS_CHARS: usize = 1_000;
+
+// User reports per moderator page
+const DEFAULT_REPORT_PAGE: u32 = 50;
+const MAX_REPORT_PAGE: u32 = 200;
+
+/// Block a user. With a `reason`, a report is also filed for moderators
 #[update]
-fn block_user(blocked_principal: Principal) -> ApiResponse<()> {
+fn block_user(blocked_principal: Principal, reason: Option<BlockReason>) -> ApiResponse<()> {
     let blocker_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&blocker_principal) {
+        return rejection;
+    }
+    
+    if reason.as_ref()
+        .and_then(|reason| reason.details.as_ref())
+        .is_some_and(|details| details.chars().count() > MAX_REPORT_DETAILS_CHARS)
+    {
+        return errors::coded("report_details_too_long", &[("max", MAX_REPORT_DETAILS_CHARS.to_string())]);
+    }
     
     // Validate blocked user exists
     let blocked_profile = storage::USER_PROFILES.with(|profiles| {
@@ -458,7 +1896,7 @@ fn block_user(blocked_principal: Principal) -> ApiResponse<()> {
     
     let blocked_profile = match blocked_profile {
         Some(p) => p,
-        None => return ApiResponse::error("User not found".to_string()),
+        None => return errors::coded("user_not_found", &[]),
     };
     
     // Remove from friends if exists
@@ -478,6 +1916,12 @@ fn block_user(blocked_principal: Principal) -> ApiResponse<()> {
     storage::BLOCKED_USERS.with(|blocked| {
         blocked.borrow_mut().insert((blocker_principal, blocked_principal), blocked_user);
     });
+    end_follows(blocker_principal, blocked_principal);
+    end_follows(blocked_principal, blocker_principal);
+    
+    if let Some(reason) = reason {
+        file_user_report(blocker_principal, blocked_principal, reason);
+    }
     
     ApiResponse::success(())
 }
@@ -485,6 +1929,9 @@ fn block_user(blocked_principal: Principal) -> ApiResponse<()> {
 #[update]
 fn unblock_user(blocked_principal: Principal) -> ApiResponse<()> {
     let blocker_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&blocker_principal) {
+        return rejection;
+    }
     
     storage::BLOCKED_USERS.with(|blocked| {
         blocked.borrow_mut().remove(&(blocker_principal, blocked_principal));
@@ -497,10 +1944,11 @@ fn unblock_user(blocked_principal: Principal) -> ApiResponse<()> {
 fn get_blocked_users() -> ApiResponse<Vec<BlockedUser>> {
     let caller_principal = caller();
     
+    // Keys start with the blocker, so one range covers the caller's blocks
     let blocked = storage::BLOCKED_USERS.with(|blocked| {
         blocked.borrow()
-            .iter()
-            .filter(|((blocker, _), _)| *blocker == caller_principal)
+            .range((caller_principal, Principal::from_slice(&[]))..)
+            .take_while(|((blocker, _), _)| *blocker == caller_principal)
             .map(|(_, user)| user)
             .collect()
     });
@@ -512,26 +1960,268 @@ fn get_blocked_users() -> ApiResponse<Vec<BlockedUser>> {
 fn is_blocked(principal: Principal) -> ApiResponse<bool> {
     let caller_principal = caller();
     
-    let is_blocked = storage::BLOCKED_USERS.with(|blocked| {
-        blocked.borrow().contains_key(&(caller_principal, principal)) ||
-        blocked.borrow().contains_key(&(principal, caller_principal))
-    });
+    let is_blocked = is_blocked_either_way(caller_principal, principal);
     
     ApiResponse::success(is_blocked)
 }
 
-// ============ DATA SYNC METHODS ============
+// ============ DEVICE METHODS ============
+
+// Devices label where synced data came from, and each signs its syncs with a key of its own: a
+// session principal separate from the account's. The account registers the key and the device
+// confirms it by calling confirm_device with that key; syncs signed with it then act for the
+// account. Revoking a device refuses its key from then on, and a key is never linked twice.
+// Devices linked before keys existed sync as the account and name their id instead, which
+// revocation retires but cannot sign out. Once an account has a device, every sync comes from one.
+
+// Longest device name, in characters
+const MAX_DEVICE_NAME_CHARS: usize = 64;
+
+// Devices one user may have linked at a time; revoked ones do not count
+const MAX_DEVICES: usize = 20;
+
+fn devices_of(principal: Principal) -> Vec<Device> {
+    storage::DEVICES.with(|devices| {
+        devices.borrow()
+            .range((principal, 0)..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|(_, device)| device)
+            .collect()
+    })
+}
+
+/// The caller's device `device_id`, as long as it has not been revoked
+fn active_device(principal: Principal, device_id: u64) -> Result<Device, &'static str> {
+    match storage::DEVICES.with(|devices| devices.borrow().get(&(principal, device_id))) {
+        Some(device) if device.revoked_at.is_some() => Err("device_revoked"),
+        Some(device) => Ok(device),
+        None => Err("device_not_found"),
+    }
+}
+
+/// The account a sync call acts for, and the device making it when signed with a confirmed key
+fn sync_caller(principal: Principal) -> Result<(Principal, Option<Device>), &'static str> {
+    match storage::DEVICE_KEYS.with(|keys| keys.borrow().get(&principal)) {
+        Some((owner, device_id)) => active_device(owner, device_id).map(|device| (owner, Some(device))),
+        None => Ok((principal, None)),
+    }
+}
+
+/// Whether `key` is in use as an account or as a confirmed device key
+fn device_key_taken(key: &Principal) -> bool {
+    storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(key))
+        || storage::DEVICE_KEYS.with(|keys| keys.borrow().contains_key(key))
+}
+
+/// Link a device or session to the caller's account. The device then calls confirm_device
+/// signed with `key`, after which its syncs are accepted and attributed to it
+#[update]
+fn register_device(name: String, key: Principal) -> ApiResponse<Device> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_DEVICE_NAME_CHARS {
+        return errors::coded("device_name_invalid", &[("max", MAX_DEVICE_NAME_CHARS.to_string())]);
+    }
+    if key == Principal::anonymous() || key == caller_principal {
+        return errors::coded("device_key_invalid", &[]);
+    }
+    if device_key_taken(&key) {
+        return errors::coded("device_key_taken", &[]);
+    }
+    
+    let devices = devices_of(caller_principal);
+    if devices.iter().filter(|device| device.revoked_at.is_none()).count() >= MAX_DEVICES {
+        return errors::coded("device_limit_reached", &[("max", MAX_DEVICES.to_string())]);
+    }
+    
+    let device = Device {
+        id: devices.last().map_or(1, |device| device.id + 1),
+        name,
+        registered_at: ic_cdk::api::time(),
+        last_seen: None,
+        revoked_at: None,
+        key: Some(key),
+        confirmed_at: None,
+    };
+    storage::DEVICES.with(|stored| stored.borrow_mut().insert((caller_principal, device.id), device.clone()));
+    
+    ApiResponse::success(device)
+}
+
+/// Called by a device, signed with its own key, to confirm the key `account` registered for it
+#[update]
+fn confirm_device(account: Principal, device_id: u64) -> ApiResponse<Device> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let mut device = match active_device(account, device_id) {
+        Ok(device) if device.key == Some(caller_principal) => device,
+        Ok(_) => return errors::coded("device_not_found", &[]),
+        Err(code) => return errors::coded(code, &[]),
+    };
+    if device.confirmed_at.is_some() {
+        return ApiResponse::success(device);
+    }
+    if device_key_taken(&caller_principal) {
+        return errors::coded("device_key_taken", &[]);
+    }
+    
+    device.confirmed_at = Some(ic_cdk::api::time());
+    storage::DEVICE_KEYS.with(|keys| keys.borrow_mut().insert(caller_principal, (account, device_id)));
+    storage::DEVICES.with(|devices| devices.borrow_mut().insert((account, device_id), device.clone()));
+    
+    ApiResponse::success(device)
+}
+
+/// The caller's devices, revoked ones included, oldest first
+#[query]
+fn list_devices() -> ApiResponse<Vec<Device>> {
+    ApiResponse::success(devices_of(caller()))
+}
 
+/// Retire one of the caller's devices. Syncs signed with its key or naming it are refused;
+/// data it already synced stays
 #[update]
-fn sync_user_data(chat_messages: Vec<ChatMessage>) -> ApiResponse<SyncResponse> {
+fn revoke_device(device_id: u64) -> ApiResponse<()> {
     let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    storage::DEVICES.with(|devices| {
+        let mut devices = devices.borrow_mut();
+        let Some(mut device) = devices.get(&(caller_principal, device_id)) else {
+            return errors::coded("device_not_found", &[]);
+        };
+        if device.revoked_at.is_none() {
+            device.revoked_at = Some(ic_cdk::api::time());
+            devices.insert((caller_principal, device_id), device);
+        }
+        ApiResponse::success(())
+    })
+}
+
+// ============ DATA SYNC METHODS ============
+
+/// Replace the account's synced chat history; messages new in this sync are stamped with the
+/// device sending it. Devices with a key sign the call with it. Calls signed by the account
+/// itself name a keyless device in `device_id`, which is required once the account has an
+/// active device
+#[update]
+fn sync_user_data(mut chat_messages: Vec<ChatMessage>, device_id: Option<u64>) -> ApiResponse<SyncResponse> {
+    let (caller_principal, signed_by) = match sync_caller(caller()) {
+        Ok(sync_caller) => sync_caller,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    
     let now = ic_cdk::api::time();
     
+    let device = match signed_by {
+        Some(device) if device_id.is_some_and(|device_id| device_id != device.id) => {
+            return errors::coded("device_not_found", &[]);
+        }
+        Some(device) => Some(device),
+        None => {
+            let device = match device_id.map(|device_id| active_device(caller_principal, device_id)).transpose() {
+                Ok(device) => device,
+                Err(code) => return errors::coded(code, &[]),
+            };
+            if device.as_ref().is_some_and(|device| device.key.is_some()) {
+                return errors::coded("device_key_required", &[]);
+            }
+            if device.is_none() && devices_of(caller_principal).iter().any(|device| device.revoked_at.is_none()) {
+                return errors::coded("device_required", &[]);
+            }
+            device
+        }
+    };
+    let device_id = device.as_ref().map(|device| device.id);
+    
     // Debug: Log incoming messages (commented out for now)
     // for (i, msg) in chat_messages.iter().enumerate() {
     //     ic_cdk::println!("{}: {} {} {} {} {:?}", i, msg.id, msg.text, msg.sender, msg.timestamp, msg.channel);
     // }
     
+    // Only messages new since the last sync are scanned for watch terms
+    let previous_messages: Vec<ChatMessage> = storage::USER_DATA_SYNC.with(|sync_data| {
+        sync_data.borrow()
+            .get(&caller_principal)
+            .map(|data| data.chat_messages)
+            .unwrap_or_default()
+    });
+    let previous_ids: HashSet<String> = previous_messages.iter().map(|msg| msg.id.clone()).collect();
+    
+    // Slow-mode channels accept one new message per sync, and only once the cooldown has passed
+    let mut new_per_channel: HashMap<String, u32> = HashMap::new();
+    for msg in chat_messages.iter().filter(|msg| msg.sender == "me" && !previous_ids.contains(&msg.id)) {
+        *new_per_channel.entry(msg.channel.clone().unwrap_or_else(|| "default".to_string())).or_default() += 1;
+    }
+    for (channel, count) in &new_per_channel {
+        if let Some(seconds_remaining) = slow_mode_wait(caller_principal, channel, now) {
+            return slow_mode_rejection(channel, seconds_remaining);
+        }
+        if *count > 1 && !is_moderator(&caller_principal) {
+            if let Some(seconds) = slow_mode_seconds(channel) {
+                return slow_mode_rejection(channel, seconds);
+            }
+        }
+    }
+    let new_messages: u32 = new_per_channel.values().sum();
+    if new_messages > 0 {
+        if let Some(rejection) = enforce_send_rate(caller_principal, new_messages, now) {
+            return rejection;
+        }
+    }
+    for channel in new_per_channel.keys() {
+        record_slow_mode_post(caller_principal, channel, now);
+    }
+    
+    // Messages present in the last sync but not this one were deleted by the user
+    let current_ids: HashSet<&String> = chat_messages.iter().map(|msg| &msg.id).collect();
+    let mut deleted: HashMap<String, Vec<String>> = HashMap::new();
+    for msg in previous_messages.iter().filter(|msg| !current_ids.contains(&msg.id)) {
+        let channel = msg.channel.clone().unwrap_or_else(|| "default".to_string());
+        deleted.entry(channel).or_default().push(msg.id.clone());
+    }
+    for (channel, message_ids) in deleted {
+        record_audit(caller_principal, AuditKind::MessageDelete, &channel, Some(message_ids.join(",")));
+    }
+    // Messages kept from the last sync keep what was resolved for them then
+    let previous_by_id: HashMap<&String, &ChatMessage> = previous_messages.iter().map(|msg| (&msg.id, msg)).collect();
+    let mut mention_lookup = MentionLookup::default();
+    for msg in chat_messages.iter_mut() {
+        match previous_by_id.get(&msg.id) {
+            Some(previous) => {
+                msg.mentions = previous.mentions.clone();
+                msg.device_id = previous.device_id;
+            }
+            None => {
+                msg.mentions = if msg.sender == "me" { Some(mention_lookup.resolve(&msg.text)) } else { None };
+                msg.device_id = device_id;
+            }
+        }
+    }
+    for msg in chat_messages.iter().filter(|msg| !previous_ids.contains(&msg.id)) {
+        message_index::index_message(caller_principal, MessageRef::Chat { message_id: msg.id.clone() }, &msg.text);
+    }
+    for msg in chat_messages.iter().filter(|msg| msg.sender == "me" && !previous_ids.contains(&msg.id)) {
+        let source = AlertSource::ChannelMessage { channel: msg.channel.clone() };
+        notify_mentions(caller_principal, msg.mentions.as_deref().unwrap_or_default(), &source, &msg.id, &msg.text);
+        raise_watch_term_alerts(&msg.text, source, caller_principal, &msg.id);
+    }
+    
     // Create or update user data sync
     let user_data = UserDataSync {
         chat_messages: chat_messages.clone(),
@@ -547,6 +2237,10 @@ fn sync_user_data(chat_messages: Vec<ChatMessage>) -> ApiResponse<SyncResponse>
     storage::USER_DATA_SYNC.with(|sync_data| {
         sync_data.borrow_mut().insert(caller_principal, user_data);
     });
+    if let Some(mut device) = device {
+        device.last_seen = Some(now);
+        storage::DEVICES.with(|devices| devices.borrow_mut().insert((caller_principal, device.id), device));
+    }
     
     // Debug: Verify storage (commented out for now)
     // let stored_data = storage::USER_DATA_SYNC.with(|sync_data| {
@@ -569,21 +2263,42 @@ fn sync_user_data(chat_messages: Vec<ChatMessage>) -> ApiResponse<SyncResponse>
 
 #[query]
 fn get_user_data_sync() -> ApiResponse<UserDataSync> {
-    let caller_principal = caller();
+    let caller_principal = match sync_caller(caller()) {
+        Ok((account, _)) => account,
+        Err(code) => return errors::coded(code, &[]),
+    };
     
     match storage::USER_DATA_SYNC.with(|sync_data| {
         sync_data.borrow().get(&caller_principal)
     }) {
-        Some(data) => ApiResponse::success(data),
-        None => ApiResponse::error("No sync data found for user".to_string()),
+        Some(data) => size_checked(data, "get_user_data_sync_encoded"),
+        None => errors::coded("sync_data_not_found", &[]),
     }
 }
 
+/// get_user_data_sync as an encoded payload, gzipped when `accepts_compression` is set and
+/// split into chunks when too large for one response
 #[query]
-fn get_user_chat_messages(channel: Option<String>) -> ApiResponse<Vec<ChatMessage>> {
-    let caller_principal = caller();
+fn get_user_data_sync_encoded(accepts_compression: Option<bool>, chunk: Option<u32>) -> ApiResponse<EncodedPayload> {
+    let caller_principal = match sync_caller(caller()) {
+        Ok((account, _)) => account,
+        Err(code) => return errors::coded(code, &[]),
+    };
     
-    match storage::USER_DATA_SYNC.with(|sync_data| {
+    match storage::USER_DATA_SYNC.with(|sync_data| sync_data.borrow().get(&caller_principal)) {
+        Some(data) => encoded_response(&data, accepts_compression, chunk),
+        None => errors::coded("sync_data_not_found", &[]),
+    }
+}
+
+#[query]
+fn get_user_chat_messages(channel: Option<String>) -> ApiResponse<Vec<ChatMessage>> {
+    let caller_principal = match sync_caller(caller()) {
+        Ok((account, _)) => account,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    match storage::USER_DATA_SYNC.with(|sync_data| {
         sync_data.borrow().get(&caller_principal)
     }) {
         Some(data) => {
@@ -594,7 +2309,7 @@ fn get_user_chat_messages(channel: Option<String>) -> ApiResponse<Vec<ChatMessag
             } else {
                 data.chat_messages
             };
-            ApiResponse::success(filtered_messages)
+            size_checked(filtered_messages, "get_user_data_sync_encoded")
         },
         None => ApiResponse::success(vec![]),
     }
@@ -625,7 +2340,7 @@ fn debug_get_user_chat_messages(user_principal: Principal, channel: Option<Strin
             //     ic_cdk::println!("{}: {} {} {} {:?}", i, msg.id, msg.text.chars().take(50).collect::<String>(), msg.sender, msg.channel);
             // }
             
-            ApiResponse::success(filtered_messages)
+            size_checked(filtered_messages, "debug_get_user_chat_messages with a channel")
         },
         None => {
             ApiResponse::success(vec![])
@@ -633,6 +2348,302 @@ fn debug_get_user_chat_messages(user_principal: Principal, channel: Option<Strin
     }
 }
 
+// ============ CHANNEL HISTORY METHODS ============
+
+// Shared rooms keep one message log. Rooms launched with history imported from the synced
+// messages of users who agreed to share them. Groups post to their own log, `group:<id>`.
+
+// Sync blobs scanned per import tick; blobs can hold thousands of messages
+const CHANNEL_IMPORT_USERS_PER_TICK: usize = 100;
+
+// Room log entries looked at per tick when removing a user's imported history
+const IMPORTED_HISTORY_PURGE_BATCH: usize = 500;
+
+fn channel_message_key(timestamp: u64, id: &str) -> String {
+    format!("{:020}_{}", timestamp, id)
+}
+
+/// Agree (or stop agreeing) to the caller's synced room messages being imported into the
+/// shared room log. Withdrawing consent also removes the messages already imported
+#[update]
+fn set_history_sharing_consent(consent: bool) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    storage::HISTORY_SHARING_CONSENT.with(|consents| {
+        let mut consents = consents.borrow_mut();
+        if consent {
+            consents.insert(caller_principal, ic_cdk::api::time());
+        } else {
+            consents.remove(&caller_principal);
+        }
+    });
+    if !consent {
+        purge_imported_history(caller_principal, None);
+    }
+    ApiResponse::success(())
+}
+
+/// Remove the messages imported from `user`'s synced history from the room logs, looking at
+/// the entries after `after` and continuing on another tick until the logs have been covered
+fn purge_imported_history(user: Principal, after: Option<(String, String)>) {
+    let lower = match after {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<((String, String), bool)> = storage::CHANNEL_MESSAGES.with(|messages| {
+        messages.borrow()
+            .range((lower, Bound::Unbounded))
+            .take(IMPORTED_HISTORY_PURGE_BATCH)
+            .map(|(key, message)| (key, message.imported_from == Some(user)))
+            .collect()
+    });
+    
+    storage::CHANNEL_MESSAGES.with(|messages| {
+        let mut messages = messages.borrow_mut();
+        for (key, _) in batch.iter().filter(|(_, imported)| *imported) {
+            messages.remove(key);
+        }
+    });
+    
+    if batch.len() == IMPORTED_HISTORY_PURGE_BATCH {
+        let last = batch.last().map(|(key, _)| key.clone());
+        ic_cdk_timers::set_timer(Duration::ZERO, move || purge_imported_history(user, last));
+    }
+}
+
+#[query]
+fn get_history_sharing_consent() -> ApiResponse<bool> {
+    ApiResponse::success(storage::HISTORY_SHARING_CONSENT.with(|consents| consents.borrow().contains_key(&caller())))
+}
+
+/// A room's or group's log, newest first. Group logs are for members only
+#[query]
+fn get_channel_messages_page(channel: String, limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<ChannelMessage>> {
+    let registered = storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller()));
+    if !registered {
+        return errors::coded("user_not_registered", &[]);
+    }
+    let group_id = channel.strip_prefix(GROUP_CHANNEL_PREFIX).map(|id| id.parse::<u64>().unwrap_or(0));
+    if group_id.is_some_and(|group_id| !is_group_member(group_id, caller())) {
+        return errors::coded("group_not_member", &[]);
+    }
+    
+    let scope = format!("channel:{}", channel);
+    let cursor = match pagination::resume(cursor, &scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let upper = match &cursor.last_key {
+        Some(key) => Bound::Excluded((channel.clone(), String::from_utf8_lossy(key).into_owned())),
+        None => Bound::Included((channel.clone(), channel_message_key(cursor.snapshot_at, "\u{10FFFF}"))),
+    };
+    
+    let page = storage::CHANNEL_MESSAGES.with(|messages| {
+        let messages = messages.borrow();
+        let items = messages
+            .range((Bound::Included((channel.clone(), String::new())), upper))
+            .rev()
+            .map(|((_, key), message)| (key.into_bytes(), message));
+        pagination::collect_page(items, pagination::page_size(limit), &scope, cursor.snapshot_at)
+    });
+    
+    diagnosed("get_channel_messages_page", ApiResponse::success(page))
+}
+
+/// Merge consenting users' synced messages from `channels` (every room when None) into the
+/// shared room log, across timer ticks. Messages already in the log, by timestamp and id, are
+/// skipped, so the import can simply be started again after an upgrade interrupts it
+#[update]
+fn start_channel_history_import(channels: Option<Vec<String>>) -> ApiResponse<ChannelImportProgress> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    let running = storage::CHANNEL_IMPORT.with(|job| job.borrow().as_ref().is_some_and(|job| job.finished_at.is_none()));
+    if running {
+        return errors::coded("channel_import_running", &[]);
+    }
+    
+    let progress = ChannelImportProgress {
+        channels,
+        next_user: None,
+        users_scanned: 0,
+        users_consenting: 0,
+        messages_imported: 0,
+        duplicates_skipped: 0,
+        started_at: ic_cdk::api::time(),
+        finished_at: None,
+    };
+    storage::CHANNEL_IMPORT.with(|job| *job.borrow_mut() = Some(progress.clone()));
+    ic_cdk_timers::set_timer(Duration::ZERO, run_channel_import);
+    
+    ApiResponse::success(progress)
+}
+
+#[query]
+fn get_channel_history_import_progress() -> ApiResponse<Option<ChannelImportProgress>> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    ApiResponse::success(storage::CHANNEL_IMPORT.with(|job| job.borrow().clone()))
+}
+
+fn run_channel_import() {
+    let Some(mut job) = storage::CHANNEL_IMPORT.with(|job| job.borrow().clone()) else {
+        return;
+    };
+    
+    let lower = match job.next_user {
+        Some(principal) => Bound::Excluded(principal),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<(Principal, UserDataSync)> = storage::USER_DATA_SYNC.with(|sync_data| {
+        sync_data.borrow()
+            .range((lower, Bound::Unbounded))
+            .take(CHANNEL_IMPORT_USERS_PER_TICK)
+            .collect()
+    });
+    
+    for (user, data) in &batch {
+        job.users_scanned += 1;
+        let consented = storage::HISTORY_SHARING_CONSENT.with(|consents| consents.borrow().contains_key(user));
+        if !consented {
+            continue;
+        }
+        job.users_consenting += 1;
+        
+        for message in &data.chat_messages {
+            let Some(channel) = message.channel.as_ref().filter(|channel| channel.starts_with('#')) else {
+                continue;
+            };
+            if job.channels.as_ref().is_some_and(|channels| !channels.contains(channel)) {
+                continue;
+            }
+            let author = match message.sender.as_str() {
+                "me" => Some(*user),
+                "bot" => None,
+                _ => continue,
+            };
+            
+            let key = (channel.clone(), channel_message_key(message.timestamp, &message.id));
+            storage::CHANNEL_MESSAGES.with(|messages| {
+                let mut messages = messages.borrow_mut();
+                if messages.get(&key).is_some() {
+                    job.duplicates_skipped += 1;
+                    return;
+                }
+                messages.insert(key, ChannelMessage {
+                    id: message.id.clone(),
+                    channel: channel.clone(),
+                    author,
+                    text: message.text.clone(),
+                    timestamp: message.timestamp,
+                    imported_from: Some(*user),
+                    mentions: None,
+                });
+                job.messages_imported += 1;
+            });
+        }
+    }
+    
+    job.next_user = batch.last().map(|(user, _)| *user).or(job.next_user);
+    let done = batch.len() < CHANNEL_IMPORT_USERS_PER_TICK;
+    if done {
+        job.finished_at = Some(ic_cdk::api::time());
+    }
+    storage::CHANNEL_IMPORT.with(|slot| *slot.borrow_mut() = Some(job));
+    if !done {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_channel_import);
+    }
+}
+
+// ============ DATA EXPORT METHODS ============
+
+/// Every DM channel holding messages of `principal`: the ones with current friends and any
+/// other channel they have sent a message to
+fn dm_history_channels(principal: Principal) -> Vec<String> {
+    let mut channels: Vec<String> = dm_channels_of(principal).into_iter().map(|(channel, _)| channel).collect();
+    let text = principal.to_text();
+    let prefix = &text[..8.min(text.len())];
+    
+    storage::DM_MESSAGES.with(|dm_messages| {
+        for (channel, messages) in dm_messages.borrow().iter() {
+            let is_candidate = channel.strip_prefix("dm_")
+                .is_some_and(|ids| ids.split('_').any(|id| id == prefix));
+            if is_candidate
+                && !channels.contains(&channel)
+                && messages.messages.iter().any(|message| message.sender_principal == principal)
+            {
+                channels.push(channel);
+            }
+        }
+    });
+    channels
+}
+
+fn collect_data_export(principal: Principal) -> DataExport {
+    let friends = storage::FRIENDS.with(|friends| {
+        friends.borrow()
+            .range((principal, Principal::from_slice(&[]))..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|(_, friend)| friend)
+            .collect()
+    });
+    let friend_requests = storage::FRIEND_REQUESTS.with(|requests| {
+        requests.borrow()
+            .iter()
+            .filter(|(_, request)| request.from_principal == principal || request.to_principal == principal)
+            .map(|(_, request)| request)
+            .collect()
+    });
+    let blocked_users = storage::BLOCKED_USERS.with(|blocked| {
+        blocked.borrow()
+            .range((principal, Principal::from_slice(&[]))..)
+            .take_while(|((blocker, _), _)| *blocker == principal)
+            .map(|(_, user)| user)
+            .collect()
+    });
+    let direct_messages = storage::DM_MESSAGES.with(|dm_messages| {
+        let dm_messages = dm_messages.borrow();
+        dm_history_channels(principal)
+            .into_iter()
+            .filter_map(|channel| dm_messages.get(&channel))
+            .flat_map(|channel| channel.messages)
+            .collect()
+    });
+    let journal_entries = storage::JOURNAL_ENTRIES.with(|journal| {
+        journal.borrow()
+            .range((principal, 0)..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    
+    DataExport {
+        profile: storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal)),
+        privacy_settings: privacy_settings(&principal),
+        friends,
+        friend_requests,
+        blocked_users,
+        following: follow_entries(storage::FOLLOWING.with(|following| follow_pairs(&following.borrow(), principal))),
+        followers: follow_entries(storage::FOLLOWERS.with(|followers| follow_pairs(&followers.borrow(), principal))),
+        direct_messages,
+        sync_data: storage::USER_DATA_SYNC.with(|sync_data| sync_data.borrow().get(&principal)),
+        journal_entries,
+        devices: devices_of(principal),
+    }
+}
+
+/// Everything stored for the caller, for data portability requests. Comes as an encoded
+/// payload: gzipped when `accepts_compression` is set and split into chunks when too large for
+/// one response. Fetch all chunks one after another; a write in between changes the payload
+#[query]
+fn export_my_data(accepts_compression: Option<bool>, chunk: Option<u32>) -> ApiResponse<EncodedPayload> {
+    encoded_response(&collect_data_export(caller()), accepts_compression, chunk)
+}
+
 // ============ ADMIN METHODS ============
 
 #[query]
@@ -645,18 +2656,24 @@ fn debug_get_all_friend_requests() -> ApiResponse<Vec<FriendRequest>> {
             .collect()
     });
     
-    ApiResponse::success(all_requests)
+    size_checked(all_requests, "get_friend_requests_page")
 }
 
 #[update]
 fn clear_all_friend_requests() -> ApiResponse<()> {
     if !ic_cdk::api::is_controller(&caller()) {
-        return ApiResponse::error("Unauthorized: caller is not a controller".to_string());
+        return errors::coded("unauthorized_controller", &[]);
     }
 
     storage::FRIEND_REQUESTS.with(|requests| {
         requests.borrow_mut().clear_new();
     });
+    storage::PENDING_REQUESTS_TO.with(|index| {
+        index.borrow_mut().clear_new();
+    });
+    storage::PENDING_REQUESTS_FROM.with(|index| {
+        index.borrow_mut().clear_new();
+    });
     
     ApiResponse::success(())
 }
@@ -664,13 +2681,24 @@ fn clear_all_friend_requests() -> ApiResponse<()> {
 #[update]
 fn admin_clear_database() -> ApiResponse<()> {
     if !ic_cdk::api::is_controller(&caller()) {
-        return ApiResponse::error("Unauthorized: caller is not a controller".to_string());
+        return errors::coded("unauthorized_controller", &[]);
     }
 
     // Clear all user profiles
     storage::USER_PROFILES.with(|profiles| {
         profiles.borrow_mut().clear_new();
     });
+    storage::HANDLES.with(|handles| {
+        handles.borrow_mut().clear_new();
+    });
+    storage::DISPLAY_NAMES.with(|names| {
+        names.borrow_mut().clear_new();
+    });
+    storage::INVITE_CODES.with(|codes| {
+        codes.borrow_mut().clear_new();
+    });
+    http::uncertify_all_profiles();
+    schedule_directory_refresh();
     
     // Clear all friends
     storage::FRIENDS.with(|friends| {
@@ -681,6 +2709,12 @@ fn admin_clear_database() -> ApiResponse<()> {
     storage::FRIEND_REQUESTS.with(|requests| {
         requests.borrow_mut().clear_new();
     });
+    storage::PENDING_REQUESTS_TO.with(|index| {
+        index.borrow_mut().clear_new();
+    });
+    storage::PENDING_REQUESTS_FROM.with(|index| {
+        index.borrow_mut().clear_new();
+    });
     
     // Clear all blocked users
     storage::BLOCKED_USERS.with(|blocked| {
@@ -691,6 +2725,13 @@ fn admin_clear_database() -> ApiResponse<()> {
     storage::USER_DATA_SYNC.with(|sync_data| {
         sync_data.borrow_mut().clear_new();
     });
+    storage::DEVICES.with(|devices| {
+        devices.borrow_mut().clear_new();
+    });
+    storage::DEVICE_KEYS.with(|keys| {
+        keys.borrow_mut().clear_new();
+    });
+    message_index::clear();
     
     ApiResponse::success(())
 }
@@ -704,7 +2745,7 @@ fn debug_get_all_sync_data() -> ApiResponse<Vec<(String, UserDataSync)>> {
             .collect()
     });
     
-    ApiResponse::success(all_sync_data)
+    size_checked(all_sync_data, "debug_get_user_chat_messages")
 }
 
 // ============ DIRECT MESSAGE METHODS ============
@@ -720,13 +2761,47 @@ fn generate_dm_channel_id(principal1: &Principal, principal2: &Principal) -> Str
     }
 }
 
+/// Whether either user has blocked the other
+fn is_blocked_either_way(a: Principal, b: Principal) -> bool {
+    storage::BLOCKED_USERS.with(|blocked| {
+        let blocked = blocked.borrow();
+        blocked.contains_key(&(a, b)) || blocked.contains_key(&(b, a))
+    })
+}
+
+// Longest DM, thread reply or group post, in characters
+const MAX_MESSAGE_CHARS: usize = 4_000;
+
+#[update]
+fn send_dm(to_principal: Principal, text: String, attachment_id: Option<u64>) -> ApiResponse<DirectMessage> {
+    post_dm(to_principal, text, MessageKind::Text, attachment_id)
+}
+
+/// Send a DM encrypted on the client with the channel key from get_encrypted_dm_key. The
+/// canister stores only the ciphertext, so the message cannot be searched or mention anyone
 #[update]
-fn send_dm(to_principal: Principal, text: String) -> ApiResponse<DirectMessage> {
+fn send_encrypted_dm(to_principal: Principal, ciphertext: Vec<u8>) -> ApiResponse<DirectMessage> {
+    if ciphertext.is_empty() || ciphertext.len() > MAX_ENCRYPTED_DM_BYTES {
+        return errors::coded("encrypted_dm_size", &[("max", MAX_ENCRYPTED_DM_BYTES.to_string())]);
+    }
+    post_dm(to_principal, String::new(), MessageKind::Encrypted { ciphertext }, None)
+}
+
+/// Shared by send_dm and send_encrypted_dm
+fn post_dm(to_principal: Principal, text: String, kind: MessageKind, attachment_id: Option<u64>) -> ApiResponse<DirectMessage> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
     let caller_principal = caller();
     
+    if text.chars().count() > MAX_MESSAGE_CHARS {
+        return errors::coded("message_too_long", &[("max", MAX_MESSAGE_CHARS.to_string())]);
+    }
+    
     // Cannot send DM to yourself
     if caller_principal == to_principal {
-        return ApiResponse::error("Cannot send DM to yourself".to_string());
+        return errors::coded("dm_to_self", &[]);
     }
     
     // Validate both users exist
@@ -734,14 +2809,14 @@ fn send_dm(to_principal: Principal, text: String) -> ApiResponse<DirectMessage>
         profiles.borrow().contains_key(&caller_principal)
     });
     if !caller_exists {
-        return ApiResponse::error("Sender not registered".to_string());
+        return errors::coded("sender_not_registered", &[]);
     }
     
     let recipient_exists = storage::USER_PROFILES.with(|profiles| {
         profiles.borrow().contains_key(&to_principal)
     });
     if !recipient_exists {
-        return ApiResponse::error("Recipient not found".to_string());
+        return errors::coded("recipient_not_found", &[]);
     }
     
     // Validate friendship (must be friends to DM)
@@ -749,29 +2824,50 @@ fn send_dm(to_principal: Principal, text: String) -> ApiResponse<DirectMessage>
         friends.borrow().contains_key(&(caller_principal, to_principal))
     });
     if !are_friends {
-        return ApiResponse::error("Cannot send DM: not friends".to_string());
+        return errors::coded("dm_send_not_friends", &[]);
     }
     
     // Check if blocked
-    let is_blocked = storage::BLOCKED_USERS.with(|blocked| {
-        blocked.borrow().contains_key(&(caller_principal, to_principal)) ||
-        blocked.borrow().contains_key(&(to_principal, caller_principal))
-    });
-    if is_blocked {
-        return ApiResponse::error("Cannot send DM: user is blocked".to_string());
+    if is_blocked_either_way(caller_principal, to_principal) {
+        return errors::coded("dm_blocked", &[]);
+    }
+    
+    // Attachments can be the sender's own uploads or ones forwarded to them
+    if let Some(attachment_id) = attachment_id {
+        let readable = storage::ATTACHMENTS.with(|attachments| attachments.borrow().get(&attachment_id))
+            .is_some_and(|attachment| attachment.can_read(&caller_principal));
+        if !readable {
+            return errors::coded("attachment_not_found", &[]);
+        }
     }
     
     // Generate channel ID and message
     let dm_channel_id = generate_dm_channel_id(&caller_principal, &to_principal);
     let now = ic_cdk::api::time();
+    if let Some(rejection) = enforce_post_limits(caller_principal, &dm_channel_id, now) {
+        return rejection;
+    }
     let message_id = format!("{}_{}", now, caller_principal.to_text());
     
+    // Only the other participant can read a DM, so only they can be mentioned in it
+    let mentions: Vec<Mention> = resolve_mentions(&text)
+        .into_iter()
+        .filter(|mention| mention.principal == to_principal)
+        .collect();
+    
     let message = DirectMessage {
         id: message_id,
         text,
         sender_principal: caller_principal,
         timestamp: now,
         dm_channel_id: dm_channel_id.clone(),
+        kind: Some(kind),
+        thread_parent_id: None,
+        mentions: Some(mentions),
+        delivery: Some(DeliveryState::Sent),
+        attachment_id,
+        forwarded_from: None,
+        expires_at: message_expiry(&dm_channel_id, now),
     };
     
     // Store the message
@@ -779,19 +2875,163 @@ fn send_dm(to_principal: Principal, text: String) -> ApiResponse<DirectMessage>
         let mut dm_messages = dm_messages.borrow_mut();
         let mut channel_messages = dm_messages.get(&dm_channel_id).unwrap_or_default();
         channel_messages.messages.push(message.clone());
-        dm_messages.insert(dm_channel_id, channel_messages);
+        dm_messages.insert(dm_channel_id.clone(), channel_messages);
+    });
+    chaos_write_point("send_dm");
+    index_dm(&message, [caller_principal, to_principal]);
+    bump_unread(to_principal, &dm_channel_id);
+    if let Some(attachment_id) = attachment_id {
+        share_attachment(attachment_id, to_principal);
+    }
+    storage::DRAFTS.with(|drafts| drafts.borrow_mut().remove(&(caller_principal, dm_channel_id.clone())));
+    
+    let source = AlertSource::DirectMessage { dm_channel_id };
+    notify_mentions(caller_principal, message.mentions.as_deref().unwrap_or_default(), &source, &message.id, &message.text);
+    raise_watch_term_alerts(&message.text, source, caller_principal, &message.id);
+    
+    ApiResponse::success(message)
+}
+
+/// The DM channels `principal` has with each of their friends, as (dm_channel_id, friend)
+fn dm_channels_of(principal: Principal) -> Vec<(String, Principal)> {
+    storage::FRIENDS.with(|friends| {
+        friends.borrow()
+            .range((principal, Principal::from_slice(&[]))..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|((_, friend), _)| (generate_dm_channel_id(&principal, &friend), friend))
+            .collect()
+    })
+}
+
+/// Replace one of the caller's sent messages with a tombstone. The record stays in the
+/// channel (same id and timestamp) so other devices syncing the history see the deletion
+#[update]
+fn delete_dm(message_id: String) -> ApiResponse<DirectMessage> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let deleted = storage::DM_MESSAGES.with(|dm_messages| {
+        let mut dm_messages = dm_messages.borrow_mut();
+        for (dm_channel_id, _) in dm_channels_of(caller_principal) {
+            let Some(mut channel_messages) = dm_messages.get(&dm_channel_id) else {
+                continue;
+            };
+            let Some(message) = channel_messages.messages.iter_mut().find(|message| message.id == message_id) else {
+                continue;
+            };
+            
+            if message.sender_principal != caller_principal {
+                return Err("dm_delete_not_sender");
+            }
+            if matches!(message.kind, Some(MessageKind::Deleted { .. })) {
+                return Err("dm_already_deleted");
+            }
+            
+            if let Some(MessageKind::Poll { poll_id }) = message.kind {
+                close_poll(poll_id);
+            }
+            message.text = String::new();
+            message.mentions = None;
+            message.attachment_id = None;
+            message.forwarded_from = None;
+            clear_reactions(&message.id);
+            message.kind = Some(MessageKind::Deleted { deleted_at: ic_cdk::api::time() });
+            let tombstone = message.clone();
+            dm_messages.insert(dm_channel_id, channel_messages);
+            return Ok(tombstone);
+        }
+        Err("dm_message_not_found")
+    });
+    
+    match deleted {
+        Ok(tombstone) => {
+            record_audit(caller_principal, AuditKind::MessageDelete, &tombstone.dm_channel_id, Some(tombstone.id.clone()));
+            if let Some(friend) = dm_channel_partner(caller_principal, &tombstone.dm_channel_id) {
+                let key = (friend, tombstone.dm_channel_id.clone());
+                let marker = storage::DM_READ_MARKERS.with(|markers| markers.borrow().get(&key));
+                recount_unread(friend, &key.1, marker.as_ref());
+            }
+            ApiResponse::success(tombstone)
+        }
+        Err(code) => errors::coded(code, &[]),
+    }
+}
+
+/// Copy a message the caller can read into another of their DM channels, attributed to its
+/// original sender. Groups have no message history, so only DM channels can be targets
+#[update]
+fn forward_message(message_id: String, to_channel_id: String) -> ApiResponse<DirectMessage> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    let Some((_, original)) = find_dm_message(caller_principal, &message_id) else {
+        return errors::coded("dm_message_not_found", &[]);
+    };
+    if !matches!(original.kind, None | Some(MessageKind::Text)) {
+        return errors::coded("message_not_forwardable", &[]);
+    }
+    
+    let Some(recipient) = dm_channel_partner(caller_principal, &to_channel_id) else {
+        return errors::coded("forward_target_not_member", &[]);
+    };
+    if is_blocked_either_way(caller_principal, recipient) {
+        return errors::coded("dm_blocked", &[]);
+    }
+    let now = ic_cdk::api::time();
+    if let Some(rejection) = enforce_post_limits(caller_principal, &to_channel_id, now) {
+        return rejection;
+    }
+    
+    let forwarded_from = original.forwarded_from.clone().unwrap_or(ForwardedFrom {
+        original_sender: original.sender_principal,
+        original_message_id: original.id.clone(),
+        original_timestamp: original.timestamp,
+    });
+    // Mentions in the original named people in its own channel, so none carry over
+    let message = DirectMessage {
+        id: format!("{}_{}", now, caller_principal.to_text()),
+        text: original.text,
+        sender_principal: caller_principal,
+        timestamp: now,
+        dm_channel_id: to_channel_id.clone(),
+        kind: Some(MessageKind::Text),
+        thread_parent_id: None,
+        mentions: Some(Vec::new()),
+        delivery: Some(DeliveryState::Sent),
+        attachment_id: original.attachment_id,
+        forwarded_from: Some(forwarded_from),
+        expires_at: message_expiry(&to_channel_id, now),
+    };
+    
+    storage::DM_MESSAGES.with(|dm_messages| {
+        let mut dm_messages = dm_messages.borrow_mut();
+        let mut channel_messages = dm_messages.get(&to_channel_id).unwrap_or_default();
+        channel_messages.messages.push(message.clone());
+        dm_messages.insert(to_channel_id.clone(), channel_messages);
     });
+    index_dm(&message, [caller_principal, recipient]);
+    bump_unread(recipient, &to_channel_id);
+    if let Some(attachment_id) = message.attachment_id {
+        share_attachment(attachment_id, recipient);
+    }
+    raise_watch_term_alerts(&message.text, AlertSource::DirectMessage { dm_channel_id: to_channel_id }, caller_principal, &message.id);
     
     ApiResponse::success(message)
 }
 
+/// Newest messages first. To load older history pass the oldest timestamp received as
+/// `before_timestamp` while `has_more` is true (or use get_dm_messages_page)
 #[query]
 fn get_dm_messages(friend_principal: Principal, limit: Option<u32>, before_timestamp: Option<u64>) -> ApiResponse<DmMessagesResponse> {
     let caller_principal = caller();
     
     // Cannot get DMs with yourself
     if caller_principal == friend_principal {
-        return ApiResponse::error("Invalid friend principal".to_string());
+        return errors::coded("invalid_friend_principal", &[]);
     }
     
     // Validate friendship (must be friends to read DMs)
@@ -799,14 +3039,17 @@ fn get_dm_messages(friend_principal: Principal, limit: Option<u32>, before_times
         friends.borrow().contains_key(&(caller_principal, friend_principal))
     });
     if !are_friends {
-        return ApiResponse::error("Cannot read DMs: not friends".to_string());
+        return errors::coded("dm_read_not_friends", &[]);
+    }
+    if is_blocked_either_way(caller_principal, friend_principal) {
+        return errors::coded("dm_read_blocked", &[]);
     }
     
     // Generate channel ID
     let dm_channel_id = generate_dm_channel_id(&caller_principal, &friend_principal);
     
-    // Get messages with pagination
-    let limit = limit.unwrap_or(50) as usize;
+    // Get messages with pagination; the limit is capped so a page always fits in one response
+    let limit = pagination::page_size(limit);
     
     let result = storage::DM_MESSAGES.with(|dm_messages| {
         let dm_messages = dm_messages.borrow();
@@ -814,6 +3057,9 @@ fn get_dm_messages(friend_principal: Principal, limit: Option<u32>, before_times
             Some(channel_messages) => {
                 let mut messages: Vec<DirectMessage> = channel_messages.messages.clone();
                 
+                // Thread replies are fetched with get_thread
+                messages.retain(|m| m.thread_parent_id.is_none());
+                
                 // Filter by before_timestamp if provided (for pagination)
                 if let Some(before_ts) = before_timestamp {
                     messages.retain(|m| m.timestamp < before_ts);
@@ -836,3 +3082,5843 @@ fn get_dm_messages(friend_principal: Principal, limit: Option<u32>, before_times
     
     ApiResponse::success(result)
 }
+
+// ============ DISAPPEARING MESSAGE METHODS ============
+
+// Lifetimes a channel's messages can be given
+const MIN_MESSAGE_LIFETIME_SECONDS: u64 = 60;
+const MAX_MESSAGE_LIFETIME_SECONDS: u64 = 90 * 24 * 60 * 60;
+
+const DISAPPEARING_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When a message sent to `dm_channel_id` at `now` should disappear, if the channel has
+/// disappearing messages on
+fn message_expiry(dm_channel_id: &str, now: u64) -> Option<u64> {
+    storage::DISAPPEARING_CHANNELS.with(|channels| channels.borrow().get(&dm_channel_id.to_string()))
+        .and_then(|channel| channel.lifetime_seconds)
+        .map(|lifetime| now.saturating_add(lifetime.saturating_mul(1_000_000_000)))
+}
+
+/// Turn disappearing messages on for one of the caller's DM channels, or off with None.
+/// Applies to both participants and only to messages sent from now on; messages already
+/// sent keep the lifetime they were sent with
+#[update]
+fn set_disappearing_messages(dm_channel_id: String, lifetime_seconds: Option<u64>) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    let Some(partner) = dm_channel_partner(caller_principal, &dm_channel_id) else {
+        return errors::coded("conversation_not_found", &[]);
+    };
+    if lifetime_seconds.is_some_and(|lifetime| !(MIN_MESSAGE_LIFETIME_SECONDS..=MAX_MESSAGE_LIFETIME_SECONDS).contains(&lifetime)) {
+        return errors::coded("message_lifetime_out_of_range", &[
+            ("min", MIN_MESSAGE_LIFETIME_SECONDS.to_string()),
+            ("max", MAX_MESSAGE_LIFETIME_SECONDS.to_string()),
+        ]);
+    }
+    
+    storage::DISAPPEARING_CHANNELS.with(|channels| {
+        let mut channels = channels.borrow_mut();
+        if lifetime_seconds.is_none() && !channels.contains_key(&dm_channel_id) {
+            return;
+        }
+        channels.insert(dm_channel_id.clone(), DisappearingChannel {
+            participants: vec![caller_principal, partner],
+            lifetime_seconds,
+        });
+    });
+    
+    ApiResponse::success(())
+}
+
+/// Lifetime in seconds given to new messages in one of the caller's DM channels; None when off
+#[query]
+fn get_disappearing_messages(dm_channel_id: String) -> ApiResponse<Option<u64>> {
+    if dm_channel_partner(caller(), &dm_channel_id).is_none() {
+        return errors::coded("conversation_not_found", &[]);
+    }
+    
+    let lifetime = storage::DISAPPEARING_CHANNELS.with(|channels| channels.borrow().get(&dm_channel_id))
+        .and_then(|channel| channel.lifetime_seconds);
+    ApiResponse::success(lifetime)
+}
+
+/// Remove expired messages from every channel that has had disappearing messages on. Channels
+/// turned off are forgotten once nothing sent while they were on remains
+fn purge_expired_messages() {
+    let now = ic_cdk::api::time();
+    let channels: Vec<(String, DisappearingChannel)> = storage::DISAPPEARING_CHANNELS.with(|channels| {
+        channels.borrow().iter().collect()
+    });
+    
+    for (dm_channel_id, channel) in channels {
+        let Some(mut channel_messages) = storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&dm_channel_id)) else {
+            if channel.lifetime_seconds.is_none() {
+                storage::DISAPPEARING_CHANNELS.with(|channels| channels.borrow_mut().remove(&dm_channel_id));
+            }
+            continue;
+        };
+        
+        let (expired, kept): (Vec<DirectMessage>, Vec<DirectMessage>) = channel_messages.messages
+            .into_iter()
+            .partition(|message| message.expires_at.is_some_and(|expires_at| expires_at <= now));
+        let still_expiring = kept.iter().any(|message| message.expires_at.is_some());
+        channel_messages.messages = kept;
+        
+        if !expired.is_empty() {
+            for message in &expired {
+                if let Some(MessageKind::Poll { poll_id }) = message.kind {
+                    close_poll(poll_id);
+                }
+                clear_reactions(&message.id);
+            }
+            storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow_mut().insert(dm_channel_id.clone(), channel_messages));
+            for &participant in &channel.participants {
+                let key = (participant, dm_channel_id.clone());
+                let marker = storage::DM_READ_MARKERS.with(|markers| markers.borrow().get(&key));
+                recount_unread(participant, &dm_channel_id, marker.as_ref());
+            }
+        }
+        
+        if channel.lifetime_seconds.is_none() && !still_expiring {
+            storage::DISAPPEARING_CHANNELS.with(|channels| channels.borrow_mut().remove(&dm_channel_id));
+        }
+    }
+}
+
+// ============ MESSAGE SEARCH METHODS ============
+
+const DEFAULT_MESSAGE_SEARCH_LIMIT: u32 = 20;
+const MAX_MESSAGE_SEARCH_LIMIT: u32 = 100;
+
+// Users whose messages are indexed per pass when rebuilding the index after an upgrade
+const MESSAGE_INDEX_REBUILD_BATCH: usize = 50;
+
+fn index_dm(message: &DirectMessage, participants: [Principal; 2]) {
+    for participant in participants {
+        let message_ref = MessageRef::Dm {
+            dm_channel_id: message.dm_channel_id.clone(),
+            message_id: message.id.clone(),
+        };
+        message_index::index_message(participant, message_ref, &message.text);
+    }
+}
+
+fn dm_snippet(message: &DirectMessage) -> MessageSnippet {
+    MessageSnippet {
+        id: message.id.clone(),
+        text: message.text.clone(),
+        sender: message.sender_principal.to_text(),
+        timestamp: message.timestamp,
+    }
+}
+
+fn chat_snippet(message: &ChatMessage) -> MessageSnippet {
+    MessageSnippet {
+        id: message.id.clone(),
+        text: message.text.clone(),
+        sender: message.sender.clone(),
+        timestamp: message.timestamp,
+    }
+}
+
+/// Sort key putting DM (nanosecond) and chat (millisecond) timestamps on one scale
+fn hit_time(hit: &MessageSearchHit) -> u64 {
+    match hit.source {
+        MessageSearchSource::DirectMessage { .. } => hit.message.timestamp,
+        MessageSearchSource::Chat { .. } => hit.message.timestamp.saturating_mul(1_000_000),
+    }
+}
+
+/// The caller's DMs (with friends they have not blocked) and synced chat messages matching
+/// `query`, newest first. Every word must appear; the last one may be a prefix
+#[query]
+fn search_my_messages(query: String, limit: Option<u32>) -> ApiResponse<Vec<MessageSearchHit>> {
+    let caller_principal = caller();
+    if query.trim().is_empty() {
+        return errors::coded("search_query_empty", &[]);
+    }
+    let limit = limit.unwrap_or(DEFAULT_MESSAGE_SEARCH_LIMIT).clamp(1, MAX_MESSAGE_SEARCH_LIMIT) as usize;
+    
+    let mut dm_matches: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut chat_matches: HashSet<String> = HashSet::new();
+    for message_ref in message_index::lookup(caller_principal, &query) {
+        match message_ref {
+            MessageRef::Dm { dm_channel_id, message_id } => {
+                dm_matches.entry(dm_channel_id).or_default().insert(message_id);
+            }
+            MessageRef::Chat { message_id } => {
+                chat_matches.insert(message_id);
+            }
+        }
+    }
+    
+    let mut hits: Vec<MessageSearchHit> = Vec::new();
+    
+    // Index entries outlive unfriending and deletion, so resolve them against current state
+    for (dm_channel_id, friend) in dm_channels_of(caller_principal) {
+        let Some(message_ids) = dm_matches.get(&dm_channel_id) else {
+            continue;
+        };
+        if is_blocked_either_way(caller_principal, friend) {
+            continue;
+        }
+        let messages = storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&dm_channel_id))
+            .map(|channel| channel.messages)
+            .unwrap_or_default();
+        
+        for (position, message) in messages.iter().enumerate() {
+            if !message_ids.contains(&message.id) || !message_index::matches(&message.text, &query) {
+                continue;
+            }
+            hits.push(MessageSearchHit {
+                message: dm_snippet(message),
+                source: MessageSearchSource::DirectMessage { dm_channel_id: dm_channel_id.clone(), friend },
+                previous: position.checked_sub(1).and_then(|i| messages.get(i)).map(dm_snippet),
+                next: messages.get(position + 1).map(dm_snippet),
+            });
+        }
+    }
+    
+    if !chat_matches.is_empty() {
+        let messages = storage::USER_DATA_SYNC.with(|sync_data| sync_data.borrow().get(&caller_principal))
+            .map(|data| data.chat_messages)
+            .unwrap_or_default();
+        
+        for (position, message) in messages.iter().enumerate() {
+            if !chat_matches.contains(&message.id) || !message_index::matches(&message.text, &query) {
+                continue;
+            }
+            let same_channel = |other: &&ChatMessage| other.channel == message.channel;
+            hits.push(MessageSearchHit {
+                message: chat_snippet(message),
+                source: MessageSearchSource::Chat { channel: message.channel.clone() },
+                previous: messages[..position].iter().rev().find(same_channel).map(chat_snippet),
+                next: messages[position + 1..].iter().find(same_channel).map(chat_snippet),
+            });
+        }
+    }
+    
+    hits.sort_by_key(|hit| std::cmp::Reverse(hit_time(hit)));
+    hits.truncate(limit);
+    
+    diagnosed("search_my_messages", ApiResponse::success(hits))
+}
+
+/// Re-index every user's DMs and synced chat after an upgrade, a batch of users at a time
+fn rebuild_message_index(after: Option<Principal>) {
+    let lower = match after {
+        Some(principal) => Bound::Excluded(principal),
+        None => Bound::Unbounded,
+    };
+    let users: Vec<Principal> = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow()
+            .range((lower, Bound::Unbounded))
+            .take(MESSAGE_INDEX_REBUILD_BATCH)
+            .map(|(principal, _)| principal)
+            .collect()
+    });
+    
+    for &user in &users {
+        for (dm_channel_id, _) in dm_channels_of(user) {
+            let messages = storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&dm_channel_id))
+                .map(|channel| channel.messages)
+                .unwrap_or_default();
+            for message in &messages {
+                let message_ref = MessageRef::Dm { dm_channel_id: dm_channel_id.clone(), message_id: message.id.clone() };
+                message_index::index_message(user, message_ref, &message.text);
+            }
+        }
+        
+        let chat = storage::USER_DATA_SYNC.with(|sync_data| sync_data.borrow().get(&user))
+            .map(|data| data.chat_messages)
+            .unwrap_or_default();
+        for message in &chat {
+            message_index::index_message(user, MessageRef::Chat { message_id: message.id.clone() }, &message.text);
+        }
+    }
+    
+    if users.len() == MESSAGE_INDEX_REBUILD_BATCH {
+        let last = users.last().copied();
+        ic_cdk_timers::set_timer(Duration::ZERO, move || rebuild_message_index(last));
+    }
+}
+
+// ============ READ RECEIPT METHODS ============
+
+/// Record that the caller has read `channel_id` up to `message_id`. Markers never move
+/// backwards, so marking an older message is a no-op; returns the caller's marker
+#[update]
+fn mark_read(channel_id: String, message_id: String) -> ApiResponse<ReadMarker> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    if dm_channel_partner(caller_principal, &channel_id).is_none() {
+        return errors::coded("dm_read_not_friends", &[]);
+    }
+    
+    let message = storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&channel_id))
+        .and_then(|channel| channel.messages.into_iter().find(|message| message.id == message_id));
+    let Some(message) = message else {
+        return errors::coded("dm_message_not_found", &[]);
+    };
+    
+    let key = (caller_principal, channel_id);
+    storage::DM_READ_MARKERS.with(|markers| {
+        let mut markers = markers.borrow_mut();
+        match markers.get(&key) {
+            Some(marker) if marker.message_timestamp >= message.timestamp => ApiResponse::success(marker),
+            _ => {
+                let marker = ReadMarker {
+                    last_read_message_id: message.id,
+                    message_timestamp: message.timestamp,
+                    read_at: ic_cdk::api::time(),
+                };
+                advance_delivery(&key.1, caller_principal, DeliveryState::Read, |received| {
+                    received.timestamp <= marker.message_timestamp
+                });
+                markers.insert(key.clone(), marker.clone());
+                recount_unread(caller_principal, &key.1, Some(&marker));
+                ApiResponse::success(marker)
+            }
+        }
+    })
+}
+
+/// Both participants' read markers for a DM channel the caller belongs to
+#[query]
+fn get_read_state(channel_id: String) -> ApiResponse<ReadState> {
+    let caller_principal = caller();
+    
+    let Some(friend) = dm_channel_partner(caller_principal, &channel_id) else {
+        return errors::coded("dm_read_not_friends", &[]);
+    };
+    
+    let state = storage::DM_READ_MARKERS.with(|markers| {
+        let markers = markers.borrow();
+        ReadState {
+            mine: markers.get(&(caller_principal, channel_id.clone())),
+            theirs: markers.get(&(friend, channel_id)),
+        }
+    });
+    
+    ApiResponse::success(state)
+}
+
+/// Messages `reader` received in `dm_channel_id` after their read marker. Thread replies
+/// (tracked by get_unread_threads) and deleted messages are not counted
+fn count_unread_in_channel(reader: Principal, dm_channel_id: &str, marker: Option<&ReadMarker>) -> u32 {
+    let read_up_to = marker.map(|marker| marker.message_timestamp).unwrap_or(0);
+    storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&dm_channel_id.to_string()))
+        .map(|channel| {
+            channel.messages.iter()
+                .filter(|message| message.sender_principal != reader && message.timestamp > read_up_to)
+                .filter(|message| message.thread_parent_id.is_none())
+                .filter(|message| !matches!(message.kind, Some(MessageKind::Deleted { .. })))
+                .count() as u32
+        })
+        .unwrap_or(0)
+}
+
+fn recount_unread(reader: Principal, dm_channel_id: &str, marker: Option<&ReadMarker>) {
+    let unread = count_unread_in_channel(reader, dm_channel_id, marker);
+    storage::UNREAD_COUNTS.with(|counts| {
+        counts.borrow_mut().insert((reader, dm_channel_id.to_string()), unread);
+    });
+}
+
+/// Count a new main-history message for its recipient; call after storing it
+fn bump_unread(reader: Principal, dm_channel_id: &str) {
+    let key = (reader, dm_channel_id.to_string());
+    match storage::UNREAD_COUNTS.with(|counts| counts.borrow().get(&key)) {
+        Some(unread) => {
+            storage::UNREAD_COUNTS.with(|counts| counts.borrow_mut().insert(key, unread + 1));
+        }
+        None => {
+            let marker = storage::DM_READ_MARKERS.with(|markers| markers.borrow().get(&key));
+            recount_unread(reader, dm_channel_id, marker.as_ref());
+        }
+    }
+}
+
+/// Unread counts for all of the caller's DM channels that are not archived, for rendering
+/// badges in one call. Groups keep no message history, so they have nothing to count
+#[query]
+fn get_unread_summary() -> ApiResponse<UnreadSummary> {
+    let caller_principal = caller();
+    
+    let conversations: Vec<UnreadCount> = dm_channels_of(caller_principal)
+        .into_iter()
+        .filter(|(dm_channel_id, _)| !is_archived(caller_principal, dm_channel_id))
+        .map(|(dm_channel_id, friend)| {
+            let key = (caller_principal, dm_channel_id);
+            let unread = storage::UNREAD_COUNTS.with(|counts| counts.borrow().get(&key))
+                .unwrap_or_else(|| {
+                    let marker = storage::DM_READ_MARKERS.with(|markers| markers.borrow().get(&key));
+                    count_unread_in_channel(caller_principal, &key.1, marker.as_ref())
+                });
+            let muted = is_muted(caller_principal, &key.1);
+            UnreadCount { dm_channel_id: key.1, friend, unread, muted }
+        })
+        .collect();
+    let total = conversations.iter()
+        .filter(|conversation| !conversation.muted)
+        .map(|conversation| conversation.unread)
+        .sum();
+    
+    diagnosed("get_unread_summary", ApiResponse::success(UnreadSummary { conversations, total }))
+}
+
+/// Move messages `recipient` received in `dm_channel_id` that match `covers` forward to
+/// `state`; returns how many changed
+fn advance_delivery(
+    dm_channel_id: &str,
+    recipient: Principal,
+    state: DeliveryState,
+    covers: impl Fn(&DirectMessage) -> bool,
+) -> u32 {
+    storage::DM_MESSAGES.with(|dm_messages| {
+        let mut dm_messages = dm_messages.borrow_mut();
+        let Some(mut channel) = dm_messages.get(&dm_channel_id.to_string()) else {
+            return 0;
+        };
+        
+        let mut advanced = 0;
+        for message in channel.messages.iter_mut() {
+            let current = message.delivery.unwrap_or(DeliveryState::Sent);
+            if message.sender_principal != recipient && current < state && covers(message) {
+                message.delivery = Some(state);
+                advanced += 1;
+            }
+        }
+        
+        if advanced > 0 {
+            dm_messages.insert(dm_channel_id.to_string(), channel);
+        }
+        advanced
+    })
+}
+
+/// Confirm that one of the caller's devices received these DMs in `dm_channel_id`, so their
+/// senders see them as delivered. Unknown ids and the caller's own messages are ignored;
+/// returns how many messages moved to Delivered
+#[update]
+fn ack_delivered(dm_channel_id: String, message_ids: Vec<String>) -> ApiResponse<u32> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    if dm_channel_partner(caller_principal, &dm_channel_id).is_none() {
+        return errors::coded("conversation_not_found", &[]);
+    }
+    
+    let message_ids: HashSet<String> = message_ids.into_iter().collect();
+    let acknowledged = advance_delivery(&dm_channel_id, caller_principal, DeliveryState::Delivered, |received| {
+        message_ids.contains(&received.id)
+    });
+    
+    ApiResponse::success(acknowledged)
+}
+
+// A typing indicator lapses this long after the last set_typing
+const TYPING_TTL_NS: u64 = 5 * 1_000_000_000;
+
+/// Mark the caller as typing in a DM channel; clients repeat this while the user types
+#[update]
+fn set_typing(channel_id: String) -> ApiResponse<()> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    
+    if dm_channel_partner(caller_principal, &channel_id).is_none() {
+        return errors::coded("dm_send_not_friends", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    let cutoff = now.saturating_sub(TYPING_TTL_NS);
+    storage::TYPING.with(|typing| {
+        let mut typing = typing.borrow_mut();
+        typing.retain(|_, typists| {
+            typists.retain(|_, last_typed| *last_typed >= cutoff);
+            !typists.is_empty()
+        });
+        typing.entry(channel_id).or_default().insert(caller_principal, now);
+    });
+    
+    ApiResponse::success(())
+}
+
+/// Others currently typing in a DM channel the caller belongs to
+#[query]
+fn get_typing(channel_id: String) -> ApiResponse<Vec<Principal>> {
+    let caller_principal = caller();
+    
+    if dm_channel_partner(caller_principal, &channel_id).is_none() {
+        return errors::coded("dm_read_not_friends", &[]);
+    }
+    
+    let cutoff = ic_cdk::api::time().saturating_sub(TYPING_TTL_NS);
+    let typists = storage::TYPING.with(|typing| {
+        typing.borrow()
+            .get(&channel_id)
+            .map(|typists| {
+                typists.iter()
+                    .filter(|(principal, last_typed)| **principal != caller_principal && **last_typed >= cutoff)
+                    .map(|(principal, _)| *principal)
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    
+    ApiResponse::success(typists)
+}
+
+// ============ THREAD METHODS ============
+
+// Threads hang off a top-level DM message. Replies live in the same channel with
+// thread_parent_id set and are left out of get_dm_messages; read markers are per thread.
+
+/// Locate a message in one of `principal`'s DM channels, with the friend on the other side
+fn find_dm_message(principal: Principal, message_id: &str) -> Option<(Principal, DirectMessage)> {
+    dm_channels_of(principal).into_iter().find_map(|(dm_channel_id, friend)| {
+        storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&dm_channel_id))
+            .and_then(|channel| channel.messages.into_iter().find(|message| message.id == message_id))
+            .map(|message| (friend, message))
+    })
+}
+
+fn thread_replies(dm_channel_id: &str, parent_id: &str) -> Vec<DirectMessage> {
+    let mut replies: Vec<DirectMessage> = storage::DM_MESSAGES.with(|dm_messages| {
+        dm_messages.borrow()
+            .get(&dm_channel_id.to_string())
+            .map(|channel| channel.messages)
+            .unwrap_or_default()
+    })
+    .into_iter()
+    .filter(|message| message.thread_parent_id.as_deref() == Some(parent_id))
+    .collect();
+    replies.sort_by_key(|message| message.timestamp);
+    replies
+}
+
+/// Replies from the other participant newer than `reader`'s read marker
+fn count_unread(reader: Principal, parent_id: &str, replies: &[DirectMessage]) -> u32 {
+    let last_read = storage::THREAD_READS.with(|reads| reads.borrow().get(&(reader, parent_id.to_string()))).unwrap_or(0);
+    replies.iter()
+        .filter(|reply| reply.sender_principal != reader && reply.timestamp > last_read)
+        .count() as u32
+}
+
+fn mark_read_up_to(reader: Principal, parent_id: &str, timestamp: u64) {
+    storage::THREAD_READS.with(|reads| {
+        let mut reads = reads.borrow_mut();
+        let key = (reader, parent_id.to_string());
+        if reads.get(&key).is_none_or(|last_read| last_read < timestamp) {
+            reads.insert(key, timestamp);
+        }
+    });
+}
+
+/// Reply in the thread under `parent_message_id`, starting the thread if it has no replies yet
+#[update]
+fn reply_in_thread(parent_message_id: String, text: String) -> ApiResponse<DirectMessage> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    
+    let Some((friend, parent)) = find_dm_message(caller_principal, &parent_message_id) else {
+        return errors::coded("dm_message_not_found", &[]);
+    };
+    if matches!(parent.kind, Some(MessageKind::Deleted { .. })) {
+        return errors::coded("thread_parent_deleted", &[]);
+    }
+    if parent.thread_parent_id.is_some() {
+        return errors::coded("thread_parent_is_reply", &[]);
+    }
+    if text.chars().count() > MAX_MESSAGE_CHARS {
+        return errors::coded("message_too_long", &[("max", MAX_MESSAGE_CHARS.to_string())]);
+    }
+    if is_blocked_either_way(caller_principal, friend) {
+        return errors::coded("dm_blocked", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    if let Some(rejection) = enforce_post_limits(caller_principal, &parent.dm_channel_id, now) {
+        return rejection;
+    }
+    let mentions: Vec<Mention> = resolve_mentions(&text)
+        .into_iter()
+        .filter(|mention| mention.principal == friend)
+        .collect();
+    let reply = DirectMessage {
+        id: format!("{}_{}", now, caller_principal.to_text()),
+        text,
+        sender_principal: caller_principal,
+        timestamp: now,
+        dm_channel_id: parent.dm_channel_id.clone(),
+        kind: Some(MessageKind::Text),
+        thread_parent_id: Some(parent.id.clone()),
+        mentions: Some(mentions),
+        delivery: Some(DeliveryState::Sent),
+        attachment_id: None,
+        forwarded_from: None,
+        expires_at: message_expiry(&parent.dm_channel_id, now),
+    };
+    
+    storage::DM_MESSAGES.with(|dm_messages| {
+        let mut dm_messages = dm_messages.borrow_mut();
+        let mut channel_messages = dm_messages.get(&parent.dm_channel_id).unwrap_or_default();
+        channel_messages.messages.push(reply.clone());
+        dm_messages.insert(parent.dm_channel_id.clone(), channel_messages);
+    });
+    index_dm(&reply, [caller_principal, friend]);
+    mark_read_up_to(caller_principal, &parent.id, now);
+    
+    let source = AlertSource::DirectMessage { dm_channel_id: parent.dm_channel_id };
+    notify_mentions(caller_principal, reply.mentions.as_deref().unwrap_or_default(), &source, &reply.id, &reply.text);
+    raise_watch_term_alerts(&reply.text, source, caller_principal, &reply.id);
+    
+    ApiResponse::success(reply)
+}
+
+/// A thread's parent and replies, with how many replies the caller has not read
+#[query]
+fn get_thread(parent_message_id: String) -> ApiResponse<ThreadView> {
+    let caller_principal = caller();
+    
+    let Some((friend, parent)) = find_dm_message(caller_principal, &parent_message_id) else {
+        return errors::coded("dm_message_not_found", &[]);
+    };
+    if is_blocked_either_way(caller_principal, friend) {
+        return errors::coded("dm_read_blocked", &[]);
+    }
+    
+    let replies = thread_replies(&parent.dm_channel_id, &parent.id);
+    let unread_count = count_unread(caller_principal, &parent.id, &replies);
+    
+    ApiResponse::success(ThreadView { parent, replies, unread_count })
+}
+
+/// Mark every reply currently in the thread as read by the caller
+#[update]
+fn mark_thread_read(parent_message_id: String) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some((_, parent)) = find_dm_message(caller_principal, &parent_message_id) else {
+        return errors::coded("dm_message_not_found", &[]);
+    };
+    
+    let latest = thread_replies(&parent.dm_channel_id, &parent.id)
+        .last()
+        .map(|reply| reply.timestamp)
+        .unwrap_or(parent.timestamp);
+    mark_read_up_to(caller_principal, &parent.id, latest);
+    
+    ApiResponse::success(())
+}
+
+/// Threads across the caller's DM channels with unread replies, most recent activity first
+#[query]
+fn get_unread_threads() -> ApiResponse<Vec<UnreadThread>> {
+    let caller_principal = caller();
+    
+    let mut unread: Vec<UnreadThread> = Vec::new();
+    for (dm_channel_id, friend) in dm_channels_of(caller_principal) {
+        if is_blocked_either_way(caller_principal, friend) {
+            continue;
+        }
+        let messages = storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&dm_channel_id))
+            .map(|channel| channel.messages)
+            .unwrap_or_default();
+        
+        let mut threads: HashMap<String, Vec<DirectMessage>> = HashMap::new();
+        for message in messages {
+            if let Some(parent_id) = message.thread_parent_id.clone() {
+                threads.entry(parent_id).or_default().push(message);
+            }
+        }
+        
+        for (parent_id, replies) in threads {
+            let unread_count = count_unread(caller_principal, &parent_id, &replies);
+            if unread_count > 0 {
+                unread.push(UnreadThread {
+                    dm_channel_id: dm_channel_id.clone(),
+                    last_reply_at: replies.iter().map(|reply| reply.timestamp).max().unwrap_or(0),
+                    parent_id,
+                    unread_count,
+                });
+            }
+        }
+    }
+    unread.sort_by_key(|thread| std::cmp::Reverse(thread.last_reply_at));
+    
+    ApiResponse::success(unread)
+}
+
+// ============ REACTION METHODS ============
+
+const MAX_EMOJI_BYTES: usize = 32;
+const MAX_REACTIONS_PER_USER: usize = 10;
+
+fn message_reactions(message_id: &str, viewer: Principal) -> Vec<ReactionCount> {
+    let mut counts: Vec<ReactionCount> = Vec::new();
+    storage::REACTIONS.with(|reactions| {
+        for ((_, reactor), user_reactions) in reactions.borrow()
+            .range((message_id.to_string(), Principal::from_slice(&[]))..)
+            .take_while(|((id, _), _)| id == message_id)
+        {
+            for emoji in user_reactions.emojis {
+                match counts.iter_mut().find(|count| count.emoji == emoji) {
+                    Some(count) => {
+                        count.count += 1;
+                        count.reacted_by_me |= reactor == viewer;
+                    }
+                    None => counts.push(ReactionCount { emoji, count: 1, reacted_by_me: reactor == viewer }),
+                }
+            }
+        }
+    });
+    counts
+}
+
+fn clear_reactions(message_id: &str) {
+    storage::REACTIONS.with(|reactions| {
+        let mut reactions = reactions.borrow_mut();
+        let keys: Vec<(String, Principal)> = reactions
+            .range((message_id.to_string(), Principal::from_slice(&[]))..)
+            .take_while(|((id, _), _)| id == message_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            reactions.remove(&key);
+        }
+    });
+}
+
+/// React to a message in one of the caller's DM channels; returns the message's updated counts
+#[update]
+fn add_reaction(message_id: String, emoji: String) -> ApiResponse<Vec<ReactionCount>> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    
+    let emoji = emoji.trim().to_string();
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_BYTES || emoji.chars().any(|c| c.is_alphanumeric() || c.is_whitespace()) {
+        return errors::coded("reaction_invalid_emoji", &[("max_bytes", MAX_EMOJI_BYTES.to_string())]);
+    }
+    
+    let Some((friend, message)) = find_dm_message(caller_principal, &message_id) else {
+        return errors::coded("dm_message_not_found", &[]);
+    };
+    if matches!(message.kind, Some(MessageKind::Deleted { .. })) {
+        return errors::coded("dm_already_deleted", &[]);
+    }
+    if is_blocked_either_way(caller_principal, friend) {
+        return errors::coded("dm_blocked", &[]);
+    }
+    
+    let key = (message_id.clone(), caller_principal);
+    let mut user_reactions = storage::REACTIONS.with(|reactions| reactions.borrow().get(&key)).unwrap_or_default();
+    if !user_reactions.emojis.contains(&emoji) {
+        if user_reactions.emojis.len() >= MAX_REACTIONS_PER_USER {
+            return errors::coded("reaction_limit_reached", &[("max", MAX_REACTIONS_PER_USER.to_string())]);
+        }
+        user_reactions.emojis.push(emoji);
+        storage::REACTIONS.with(|reactions| {
+            reactions.borrow_mut().insert(key, user_reactions);
+        });
+    }
+    
+    ApiResponse::success(message_reactions(&message_id, caller_principal))
+}
+
+#[update]
+fn remove_reaction(message_id: String, emoji: String) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    let key = (message_id, caller_principal);
+    let emoji = emoji.trim();
+    
+    storage::REACTIONS.with(|reactions| {
+        let mut reactions = reactions.borrow_mut();
+        let Some(mut user_reactions) = reactions.get(&key).filter(|r| r.emojis.iter().any(|e| e == emoji)) else {
+            return errors::coded("reaction_not_found", &[]);
+        };
+        user_reactions.emojis.retain(|e| e != emoji);
+        if user_reactions.emojis.is_empty() {
+            reactions.remove(&key);
+        } else {
+            reactions.insert(key, user_reactions);
+        }
+        ApiResponse::success(())
+    })
+}
+
+/// Reaction counts for every message with reactions in a DM channel the caller belongs to
+#[query]
+fn get_dm_reactions(dm_channel_id: String) -> ApiResponse<Vec<MessageReactions>> {
+    let caller_principal = caller();
+    
+    let Some(friend) = dm_channel_partner(caller_principal, &dm_channel_id) else {
+        return errors::coded("dm_read_not_friends", &[]);
+    };
+    if is_blocked_either_way(caller_principal, friend) {
+        return errors::coded("dm_read_blocked", &[]);
+    }
+    
+    let messages = storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&dm_channel_id))
+        .map(|channel| channel.messages)
+        .unwrap_or_default();
+    
+    let reactions = messages
+        .into_iter()
+        .map(|message| MessageReactions {
+            reactions: message_reactions(&message.id, caller_principal),
+            message_id: message.id,
+        })
+        .filter(|message| !message.reactions.is_empty())
+        .collect();
+    
+    ApiResponse::success(reactions)
+}
+
+// ============ POLL METHODS ============
+
+// Polls are posted into DM channels as a Poll-kind message; the other channels are each
+// user's private AI chats, where there is nobody to vote.
+
+const MIN_POLL_OPTIONS: usize = 2;
+const MAX_POLL_OPTIONS: usize = 10;
+
+/// The friend `principal` shares the DM channel `channel_id` with, if any
+fn dm_channel_partner(principal: Principal, channel_id: &str) -> Option<Principal> {
+    storage::FRIENDS.with(|friends| {
+        friends.borrow()
+            .range((principal, Principal::from_slice(&[]))..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|((_, friend), _)| friend)
+            .find(|friend| generate_dm_channel_id(&principal, friend) == channel_id)
+    })
+}
+
+fn poll_is_open(poll: &Poll, now: u64) -> bool {
+    !poll.closed && poll.closes_at > now
+}
+
+fn poll_results(mut poll: Poll, viewer: Principal) -> PollResults {
+    poll.closed = !poll_is_open(&poll, ic_cdk::api::time());
+    
+    let mut counts = vec![0u32; poll.options.len()];
+    let mut my_vote = None;
+    storage::POLL_VOTES.with(|votes| {
+        for ((_, voter), option) in votes.borrow()
+            .range((poll.id, Principal::from_slice(&[]))..)
+            .take_while(|((id, _), _)| *id == poll.id)
+        {
+            if let Some(count) = counts.get_mut(option as usize) {
+                *count += 1;
+            }
+            if voter == viewer {
+                my_vote = Some(option);
+            }
+        }
+    });
+    let total_votes = counts.iter().sum();
+    
+    PollResults { poll, counts, total_votes, my_vote }
+}
+
+fn close_poll(poll_id: u64) {
+    storage::POLLS.with(|polls| {
+        let mut polls = polls.borrow_mut();
+        if let Some(mut poll) = polls.get(&poll_id).filter(|poll| !poll.closed) {
+            poll.closed = true;
+            polls.insert(poll_id, poll);
+        }
+    });
+}
+
+fn schedule_poll_close(poll_id: u64, closes_at: u64) {
+    let delay = Duration::from_nanos(closes_at.saturating_sub(ic_cdk::api::time()));
+    ic_cdk_timers::set_timer(delay, move || close_poll(poll_id));
+}
+
+/// Timers do not survive upgrades, so re-arm one for every poll still open
+fn reschedule_poll_closes() {
+    let open: Vec<(u64, u64)> = storage::POLLS.with(|polls| {
+        polls.borrow()
+            .iter()
+            .filter(|(_, poll)| !poll.closed)
+            .map(|(id, poll)| (id, poll.closes_at))
+            .collect()
+    });
+    for (poll_id, closes_at) in open {
+        schedule_poll_close(poll_id, closes_at);
+    }
+}
+
+/// Post a poll into a DM channel the caller belongs to; it closes itself at `closes_at`
+#[update]
+fn create_poll(channel_id: String, question: String, options: Vec<String>, closes_at: u64) -> ApiResponse<Poll> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+    
+    let question = question.trim().to_string();
+    if question.is_empty() {
+        return errors::coded("poll_question_empty", &[]);
+    }
+    
+    let mut unique_options: Vec<String> = Vec::new();
+    for option in options.iter().map(|option| option.trim()).filter(|option| !option.is_empty()) {
+        if !unique_options.iter().any(|existing| existing == option) {
+            unique_options.push(option.to_string());
+        }
+    }
+    if !(MIN_POLL_OPTIONS..=MAX_POLL_OPTIONS).contains(&unique_options.len()) {
+        return errors::coded("poll_option_count", &[
+            ("min", MIN_POLL_OPTIONS.to_string()),
+            ("max", MAX_POLL_OPTIONS.to_string()),
+        ]);
+    }
+    
+    if closes_at <= now {
+        return errors::coded("poll_close_in_past", &[]);
+    }
+    
+    let Some(partner) = dm_channel_partner(caller_principal, &channel_id) else {
+        return errors::coded("poll_channel_not_member", &[]);
+    };
+    if is_blocked_either_way(caller_principal, partner) {
+        return errors::coded("dm_blocked", &[]);
+    }
+    if let Some(rejection) = enforce_post_limits(caller_principal, &channel_id, now) {
+        return rejection;
+    }
+    
+    let poll = storage::POLLS.with(|polls| {
+        let mut polls = polls.borrow_mut();
+        let id = polls.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        let poll = Poll {
+            id,
+            channel_id: channel_id.clone(),
+            creator: caller_principal,
+            question,
+            options: unique_options,
+            created_at: now,
+            closes_at,
+            closed: false,
+        };
+        polls.insert(id, poll.clone());
+        poll
+    });
+    
+    let message = DirectMessage {
+        id: format!("{}_{}", now, caller_principal.to_text()),
+        text: poll.question.clone(),
+        sender_principal: caller_principal,
+        timestamp: now,
+        dm_channel_id: channel_id.clone(),
+        kind: Some(MessageKind::Poll { poll_id: poll.id }),
+        thread_parent_id: None,
+        mentions: None,
+        delivery: Some(DeliveryState::Sent),
+        attachment_id: None,
+        forwarded_from: None,
+        expires_at: message_expiry(&channel_id, now),
+    };
+    storage::DM_MESSAGES.with(|dm_messages| {
+        let mut dm_messages = dm_messages.borrow_mut();
+        let mut channel_messages = dm_messages.get(&channel_id).unwrap_or_default();
+        channel_messages.messages.push(message.clone());
+        dm_messages.insert(channel_id.clone(), channel_messages);
+    });
+    index_dm(&message, [caller_principal, partner]);
+    bump_unread(partner, &channel_id);
+    
+    raise_watch_term_alerts(
+        &format!("{} {}", poll.question, poll.options.join(" ")),
+        AlertSource::DirectMessage { dm_channel_id: channel_id },
+        caller_principal,
+        &message.id,
+    );
+    
+    schedule_poll_close(poll.id, poll.closes_at);
+    
+    ApiResponse::success(poll)
+}
+
+/// Cast the caller's single vote for `option` (index into the poll's options)
+#[update]
+fn vote_poll(poll_id: u64, option: u32) -> ApiResponse<PollResults> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    
+    let Some(poll) = storage::POLLS.with(|polls| polls.borrow().get(&poll_id)) else {
+        return errors::coded("poll_not_found", &[]);
+    };
+    if dm_channel_partner(caller_principal, &poll.channel_id).is_none() {
+        return errors::coded("poll_channel_not_member", &[]);
+    }
+    if !poll_is_open(&poll, ic_cdk::api::time()) {
+        return errors::coded("poll_closed", &[]);
+    }
+    if option as usize >= poll.options.len() {
+        return errors::coded("poll_option_invalid", &[("option", option.to_string())]);
+    }
+    
+    let already_voted = storage::POLL_VOTES.with(|votes| {
+        let mut votes = votes.borrow_mut();
+        if votes.contains_key(&(poll_id, caller_principal)) {
+            return true;
+        }
+        votes.insert((poll_id, caller_principal), option);
+        false
+    });
+    if already_voted {
+        return errors::coded("poll_already_voted", &[]);
+    }
+    
+    ApiResponse::success(poll_results(poll, caller_principal))
+}
+
+/// Tallies for a poll in one of the caller's DM channels, with the caller's own vote
+#[query]
+fn get_poll_results(poll_id: u64) -> ApiResponse<PollResults> {
+    let caller_principal = caller();
+    
+    let Some(poll) = storage::POLLS.with(|polls| polls.borrow().get(&poll_id)) else {
+        return errors::coded("poll_not_found", &[]);
+    };
+    if dm_channel_partner(caller_principal, &poll.channel_id).is_none() {
+        return errors::coded("poll_channel_not_member", &[]);
+    }
+    
+    ApiResponse::success(poll_results(poll, caller_principal))
+}
+
+// ============ EVENT METHODS ============
+
+const MAX_EVENT_INVITEES: usize = 50;
+const EVENT_REMINDER_LEAD: u64 = 60 * 60 * 1_000_000_000; // 1 hour in nanoseconds
+
+fn send_event_reminders(event_id: u64) {
+    let Some(mut event) = storage::EVENTS.with(|events| events.borrow().get(&event_id)) else {
+        return;
+    };
+    if event.reminded {
+        return;
+    }
+    
+    let text = format!("Starting in less than an hour: {}", event.title);
+    let recipients = std::iter::once(event.creator).chain(
+        event.invitees.iter()
+            .filter(|(_, status)| *status != RsvpStatus::Declined)
+            .map(|(invitee, _)| *invitee),
+    );
+    for recipient in recipients {
+        push_notification(recipient, NotificationKind::EventReminder { event_id }, text.clone());
+    }
+    
+    event.reminded = true;
+    storage::EVENTS.with(|events| {
+        events.borrow_mut().insert(event_id, event);
+    });
+}
+
+fn schedule_event_reminder(event_id: u64, starts_at: u64) {
+    let remind_at = starts_at.saturating_sub(EVENT_REMINDER_LEAD);
+    let delay = Duration::from_nanos(remind_at.saturating_sub(ic_cdk::api::time()));
+    ic_cdk_timers::set_timer(delay, move || send_event_reminders(event_id));
+}
+
+/// Timers do not survive upgrades, so re-arm one for every event not yet reminded
+fn reschedule_event_reminders() {
+    let pending: Vec<(u64, u64)> = storage::EVENTS.with(|events| {
+        events.borrow()
+            .iter()
+            .filter(|(_, event)| !event.reminded)
+            .map(|(id, event)| (id, event.starts_at))
+            .collect()
+    });
+    for (event_id, starts_at) in pending {
+        schedule_event_reminder(event_id, starts_at);
+    }
+}
+
+/// Schedule an event with some of the caller's friends; they get an invite in their inbox
+/// and everyone not declining gets a reminder an hour before it starts
+#[update]
+fn create_event(title: String, starts_at: u64, invitees: Vec<Principal>) -> ApiResponse<Event> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+    
+    let title = title.trim().to_string();
+    if title.is_empty() {
+        return errors::coded("event_title_empty", &[]);
+    }
+    if starts_at <= now {
+        return errors::coded("event_start_in_past", &[]);
+    }
+    
+    let mut unique_invitees: Vec<Principal> = Vec::new();
+    for invitee in invitees {
+        if invitee != caller_principal && !unique_invitees.contains(&invitee) {
+            unique_invitees.push(invitee);
+        }
+    }
+    if unique_invitees.is_empty() || unique_invitees.len() > MAX_EVENT_INVITEES {
+        return errors::coded("event_invitee_count", &[("max", MAX_EVENT_INVITEES.to_string())]);
+    }
+    let not_friend = storage::FRIENDS.with(|friends| {
+        let friends = friends.borrow();
+        unique_invitees.iter().find(|invitee| !friends.contains_key(&(caller_principal, **invitee))).copied()
+    });
+    if let Some(invitee) = not_friend {
+        return errors::coded("event_invitee_not_friend", &[("principal", invitee.to_text())]);
+    }
+    
+    let event = storage::EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let id = events.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        let event = Event {
+            id,
+            creator: caller_principal,
+            title,
+            starts_at,
+            invitees: unique_invitees.iter().map(|invitee| (*invitee, RsvpStatus::Pending)).collect(),
+            created_at: now,
+            reminded: false,
+        };
+        events.insert(id, event.clone());
+        event
+    });
+    
+    let creator_name = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal))
+        .map(|profile| profile.display_name)
+        .unwrap_or_else(|| caller_principal.to_text());
+    let invite_text = format!("{} invited you to {}", creator_name, event.title);
+    for invitee in &unique_invitees {
+        push_notification(*invitee, NotificationKind::EventInvite { event_id: event.id }, invite_text.clone());
+    }
+    
+    schedule_event_reminder(event.id, event.starts_at);
+    
+    ApiResponse::success(event)
+}
+
+#[update]
+fn rsvp_event(event_id: u64, status: RsvpStatus) -> ApiResponse<Event> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    storage::EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let Some(mut event) = events.get(&event_id) else {
+            return errors::coded("event_not_found", &[]);
+        };
+        if event.starts_at <= ic_cdk::api::time() {
+            return errors::coded("event_already_started", &[]);
+        }
+        let Some(entry) = event.invitees.iter_mut().find(|(invitee, _)| *invitee == caller_principal) else {
+            return errors::coded("event_not_invited", &[]);
+        };
+        entry.1 = status;
+        events.insert(event_id, event.clone());
+        
+        ApiResponse::success(event)
+    })
+}
+
+/// Events the caller created or was invited to, soonest first; past events only on request
+#[query]
+fn get_my_events(include_past: bool) -> ApiResponse<Vec<Event>> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+    
+    let mut my_events: Vec<Event> = storage::EVENTS.with(|events| {
+        events.borrow()
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| include_past || event.starts_at > now)
+            .filter(|event| {
+                event.creator == caller_principal
+                    || event.invitees.iter().any(|(invitee, _)| *invitee == caller_principal)
+            })
+            .collect()
+    });
+    my_events.sort_by_key(|event| event.starts_at);
+    
+    ApiResponse::success(my_events)
+}
+
+// ============ GROUP METHODS ============
+
+// Groups are joined through invite links rather than the friends graph, so members need
+// not be friends with each other.
+
+const MAX_GROUP_NAME_CHARS: usize = 80;
+
+// Group logs live in CHANNEL_MESSAGES under this prefix and the group id
+const GROUP_CHANNEL_PREFIX: &str = "group:";
+
+// Bytes of randomness in an invite token (hex encoded in the link)
+const INVITE_TOKEN_BYTES: usize = 16;
+
+fn is_group_member(group_id: u64, principal: Principal) -> bool {
+    storage::GROUP_MEMBERS.with(|members| members.borrow().contains_key(&(group_id, principal)))
+}
+
+fn group_members(group_id: u64) -> Vec<Principal> {
+    storage::GROUP_MEMBERS.with(|members| {
+        members.borrow()
+            .range((group_id, Principal::from_slice(&[]))..)
+            .take_while(|((id, _), _)| *id == group_id)
+            .map(|((_, member), _)| member)
+            .collect()
+    })
+}
+
+#[update]
+fn create_group(name: String) -> ApiResponse<Group> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    let name: String = name.trim().chars().take(MAX_GROUP_NAME_CHARS).collect();
+    if name.is_empty() {
+        return errors::coded("group_name_empty", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    let group = storage::GROUPS.with(|groups| {
+        let mut groups = groups.borrow_mut();
+        let id = groups.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        let group = Group { id, name, owner: caller_principal, created_at: now };
+        groups.insert(id, group.clone());
+        group
+    });
+    storage::GROUP_MEMBERS.with(|members| {
+        members.borrow_mut().insert((group.id, caller_principal), now);
+    });
+    
+    ApiResponse::success(group)
+}
+
+#[query]
+fn get_my_groups() -> ApiResponse<Vec<Group>> {
+    let caller_principal = caller();
+    
+    let groups = storage::GROUPS.with(|groups| {
+        groups.borrow()
+            .iter()
+            .filter(|(id, _)| is_group_member(*id, caller_principal))
+            .map(|(_, group)| group)
+            .collect()
+    });
+    
+    ApiResponse::success(groups)
+}
+
+/// Post to a group's log, readable by members through get_channel_messages_page with the
+/// channel `group:<id>`. @mentions of members, by handle or principal, notify them; mentions
+/// of anyone outside the group are dropped
+#[update]
+fn post_group_message(group_id: u64, text: String) -> ApiResponse<ChannelMessage> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !is_group_member(group_id, caller_principal) {
+        return errors::coded("group_not_member", &[]);
+    }
+    if text.trim().is_empty() {
+        return errors::coded("group_message_empty", &[]);
+    }
+    if text.chars().count() > MAX_MESSAGE_CHARS {
+        return errors::coded("message_too_long", &[("max", MAX_MESSAGE_CHARS.to_string())]);
+    }
+    
+    let channel = format!("{}{}", GROUP_CHANNEL_PREFIX, group_id);
+    let now = ic_cdk::api::time();
+    if let Some(rejection) = enforce_post_limits(caller_principal, &channel, now) {
+        return rejection;
+    }
+    
+    let mentions: Vec<Mention> = resolve_mentions(&text)
+        .into_iter()
+        .filter(|mention| is_group_member(group_id, mention.principal))
+        .collect();
+    let message = ChannelMessage {
+        id: format!("{}_{}", now, caller_principal.to_text()),
+        channel: channel.clone(),
+        author: Some(caller_principal),
+        text,
+        timestamp: now,
+        imported_from: None,
+        mentions: Some(mentions),
+    };
+    storage::CHANNEL_MESSAGES.with(|messages| {
+        messages.borrow_mut().insert((channel, channel_message_key(now, &message.id)), message.clone());
+    });
+    
+    let source = AlertSource::GroupPost { group_id };
+    notify_mentions(caller_principal, message.mentions.as_deref().unwrap_or_default(), &source, &message.id, &message.text);
+    raise_watch_term_alerts(&message.text, source, caller_principal, &message.id);
+    
+    ApiResponse::success(message)
+}
+
+#[query]
+fn get_group_members(group_id: u64) -> ApiResponse<Vec<Principal>> {
+    if !is_group_member(group_id, caller()) {
+        return errors::coded("group_not_member", &[]);
+    }
+    
+    ApiResponse::success(group_members(group_id))
+}
+
+#[update]
+fn leave_group(group_id: u64) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some(group) = storage::GROUPS.with(|groups| groups.borrow().get(&group_id)) else {
+        return errors::coded("group_not_found", &[]);
+    };
+    if group.owner == caller_principal {
+        return errors::coded("group_owner_cannot_leave", &[]);
+    }
+    
+    let removed = storage::GROUP_MEMBERS.with(|members| members.borrow_mut().remove(&(group_id, caller_principal)));
+    match removed {
+        Some(_) => ApiResponse::success(()),
+        None => errors::coded("group_not_member", &[]),
+    }
+}
+
+/// Create an invite link for a group the caller belongs to. The invite expires after
+/// `ttl_seconds`, stops working after one join when `single_use` is set, or both
+#[update]
+async fn create_group_invite(group_id: u64, ttl_seconds: Option<u64>, single_use: bool) -> ApiResponse<GroupInvite> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !storage::GROUPS.with(|groups| groups.borrow().contains_key(&group_id)) {
+        return errors::coded("group_not_found", &[]);
+    }
+    if !is_group_member(group_id, caller_principal) {
+        return errors::coded("group_not_member", &[]);
+    }
+    if ttl_seconds.is_none() && !single_use {
+        return errors::coded("group_invite_needs_limit", &[]);
+    }
+    
+    // Tokens are bearer credentials, so they come from the management canister's randomness
+    let random = match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((bytes,)) => bytes,
+        Err((code, msg)) => return errors::coded("random_unavailable", &[("detail", format!("{:?} {}", code, msg))]),
+    };
+    let token: String = random.iter().take(INVITE_TOKEN_BYTES).map(|byte| format!("{:02x}", byte)).collect();
+    
+    let now = ic_cdk::api::time();
+    let invite = GroupInvite {
+        token: token.clone(),
+        group_id,
+        created_by: caller_principal,
+        created_at: now,
+        expires_at: ttl_seconds.map(|ttl| now.saturating_add(ttl.saturating_mul(1_000_000_000))),
+        single_use,
+        uses: 0,
+        revoked: false,
+    };
+    storage::GROUP_INVITES.with(|invites| {
+        invites.borrow_mut().insert(token, invite.clone());
+    });
+    
+    ApiResponse::success(invite)
+}
+
+#[update]
+fn join_group_with_invite(token: String) -> ApiResponse<Group> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    let Some(mut invite) = storage::GROUP_INVITES.with(|invites| invites.borrow().get(&token))
+        .filter(|invite| invite.is_usable(now))
+    else {
+        return errors::coded("group_invite_invalid", &[]);
+    };
+    let Some(group) = storage::GROUPS.with(|groups| groups.borrow().get(&invite.group_id)) else {
+        return errors::coded("group_invite_invalid", &[]);
+    };
+    
+    if is_group_member(group.id, caller_principal) {
+        return errors::coded("group_already_member", &[]);
+    }
+    if is_blocked_either_way(caller_principal, group.owner) || is_blocked_either_way(caller_principal, invite.created_by) {
+        return errors::coded("group_join_blocked", &[]);
+    }
+    
+    invite.uses += 1;
+    storage::GROUP_INVITES.with(|invites| {
+        invites.borrow_mut().insert(token, invite);
+    });
+    storage::GROUP_MEMBERS.with(|members| {
+        members.borrow_mut().insert((group.id, caller_principal), now);
+    });
+    
+    ApiResponse::success(group)
+}
+
+/// Disable an invite; allowed for its creator and the group owner
+#[update]
+fn revoke_group_invite(token: String) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some(mut invite) = storage::GROUP_INVITES.with(|invites| invites.borrow().get(&token)) else {
+        return errors::coded("group_invite_not_found", &[]);
+    };
+    let owner = storage::GROUPS.with(|groups| groups.borrow().get(&invite.group_id)).map(|group| group.owner);
+    if invite.created_by != caller_principal && owner != Some(caller_principal) {
+        return errors::coded("group_invite_not_found", &[]);
+    }
+    
+    invite.revoked = true;
+    storage::GROUP_INVITES.with(|invites| {
+        invites.borrow_mut().insert(token, invite);
+    });
+    
+    ApiResponse::success(())
+}
+
+// ============ ATTACHMENT METHODS ============
+
+// Files are uploaded in chunks small enough for one ingress message, then finalized into an
+// attachment that DMs reference by id. Chunks are content-addressed and reference counted,
+// so a file sent to several friends (or re-uploaded) is stored once.
+
+const MAX_CHUNK_BYTES: usize = 1_800_000;
+const MAX_ATTACHMENT_BYTES: u64 = 50 * 1024 * 1024;
+const MAX_FILE_NAME_CHARS: usize = 255;
+
+// Unfinished uploads older than this are discarded along with their unshared chunks
+const UPLOAD_TTL_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const UPLOAD_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Store `data` (unless an identical chunk exists) and take a reference to it
+fn retain_chunk(data: Vec<u8>) -> [u8; 32] {
+    let hash: [u8; 32] = sha2::Sha256::digest(&data).into();
+    let refs = storage::CHUNK_REFS.with(|refs| refs.borrow().get(&hash)).unwrap_or(0);
+    if refs == 0 {
+        storage::ATTACHMENT_CHUNKS.with(|chunks| {
+            chunks.borrow_mut().insert(hash, data);
+        });
+    }
+    storage::CHUNK_REFS.with(|chunk_refs| {
+        chunk_refs.borrow_mut().insert(hash, refs + 1);
+    });
+    hash
+}
+
+/// Drop a reference taken by retain_chunk, deleting the chunk when it was the last one
+fn release_chunk(hash: &[u8; 32]) {
+    let refs = storage::CHUNK_REFS.with(|refs| refs.borrow().get(hash)).unwrap_or(0);
+    if refs > 1 {
+        storage::CHUNK_REFS.with(|chunk_refs| {
+            chunk_refs.borrow_mut().insert(*hash, refs - 1);
+        });
+        return;
+    }
+    storage::CHUNK_REFS.with(|chunk_refs| {
+        chunk_refs.borrow_mut().remove(hash);
+    });
+    storage::ATTACHMENT_CHUNKS.with(|chunks| {
+        chunks.borrow_mut().remove(hash);
+    });
+}
+
+fn share_attachment(attachment_id: u64, reader: Principal) {
+    storage::ATTACHMENTS.with(|attachments| {
+        let mut attachments = attachments.borrow_mut();
+        if let Some(mut attachment) = attachments.get(&attachment_id) {
+            if !attachment.can_read(&reader) {
+                attachment.readers.push(reader);
+                attachments.insert(attachment_id, attachment);
+            }
+        }
+    });
+}
+
+/// Upload chunk `index` of a file. Pass no upload_id with index 0 to start an upload and use the
+/// returned id for the remaining chunks. Chunks go in order; re-sending an index replaces it
+#[update]
+fn upload_chunk(upload_id: Option<u64>, index: u32, data: Vec<u8>) -> ApiResponse<u64> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    if data.is_empty() {
+        return errors::coded("attachment_chunk_empty", &[]);
+    }
+    if data.len() > MAX_CHUNK_BYTES {
+        return errors::coded("attachment_chunk_too_large", &[("max_bytes", MAX_CHUNK_BYTES.to_string())]);
+    }
+    
+    let now = ic_cdk::api::time();
+    let mut upload = match upload_id {
+        Some(id) => match storage::PENDING_UPLOADS.with(|uploads| uploads.borrow().get(&id)) {
+            Some(upload) if upload.owner == caller_principal => upload,
+            _ => return errors::coded("upload_not_found", &[]),
+        },
+        None => {
+            // Upload ids become attachment ids, so never reuse either
+            let last_upload = storage::PENDING_UPLOADS.with(|uploads| uploads.borrow().last_key_value().map(|(id, _)| id));
+            let last_attachment = storage::ATTACHMENTS.with(|attachments| attachments.borrow().last_key_value().map(|(id, _)| id));
+            let id = last_upload.max(last_attachment).unwrap_or(0) + 1;
+            PendingUpload { id, owner: caller_principal, chunk_hashes: Vec::new(), size: 0, started_at: now }
+        }
+    };
+    
+    let index = index as usize;
+    if index > upload.chunk_hashes.len() {
+        return errors::coded("upload_chunk_out_of_order", &[("expected", upload.chunk_hashes.len().to_string())]);
+    }
+    let replaced_size = match upload.chunk_hashes.get(index) {
+        Some(hash) => storage::ATTACHMENT_CHUNKS.with(|chunks| chunks.borrow().get(hash)).map_or(0, |chunk| chunk.len() as u64),
+        None => 0,
+    };
+    let size = upload.size - replaced_size + data.len() as u64;
+    if size > MAX_ATTACHMENT_BYTES {
+        return errors::coded("attachment_too_large", &[("max_bytes", MAX_ATTACHMENT_BYTES.to_string())]);
+    }
+    
+    let hash = retain_chunk(data);
+    if index == upload.chunk_hashes.len() {
+        upload.chunk_hashes.push(hash);
+    } else {
+        let replaced = std::mem::replace(&mut upload.chunk_hashes[index], hash);
+        release_chunk(&replaced);
+    }
+    upload.size = size;
+    
+    let id = upload.id;
+    storage::PENDING_UPLOADS.with(|uploads| {
+        uploads.borrow_mut().insert(id, upload);
+    });
+    
+    ApiResponse::success(id)
+}
+
+/// Turn a complete upload into an attachment (whose id is the upload id). Repeating the call
+/// for an upload that was already finalized returns the same attachment
+#[update]
+fn finalize_upload(upload_id: u64, file_name: String, mime_type: String) -> ApiResponse<Attachment> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some(upload) = storage::PENDING_UPLOADS.with(|uploads| uploads.borrow().get(&upload_id)) else {
+        return match storage::ATTACHMENTS.with(|attachments| attachments.borrow().get(&upload_id)) {
+            Some(attachment) if attachment.owner == caller_principal => ApiResponse::success(attachment),
+            _ => errors::coded("upload_not_found", &[]),
+        };
+    };
+    if upload.owner != caller_principal {
+        return errors::coded("upload_not_found", &[]);
+    }
+    
+    let file_name: String = file_name.trim().chars().take(MAX_FILE_NAME_CHARS).collect();
+    if file_name.is_empty() {
+        return errors::coded("attachment_name_empty", &[]);
+    }
+    
+    // The upload's chunk references pass to the attachment
+    let attachment = Attachment {
+        id: upload.id,
+        owner: caller_principal,
+        file_name,
+        mime_type: mime_type.trim().to_string(),
+        size: upload.size,
+        chunk_hashes: upload.chunk_hashes,
+        readers: Vec::new(),
+        created_at: ic_cdk::api::time(),
+    };
+    storage::PENDING_UPLOADS.with(|uploads| {
+        uploads.borrow_mut().remove(&upload_id);
+    });
+    storage::ATTACHMENTS.with(|attachments| {
+        attachments.borrow_mut().insert(attachment.id, attachment.clone());
+    });
+    
+    ApiResponse::success(attachment)
+}
+
+/// Attachment metadata; readable by its owner and anyone it was sent to
+#[query]
+fn get_attachment(attachment_id: u64) -> ApiResponse<Attachment> {
+    match storage::ATTACHMENTS.with(|attachments| attachments.borrow().get(&attachment_id)) {
+        Some(attachment) if attachment.can_read(&caller()) => ApiResponse::success(attachment),
+        _ => errors::coded("attachment_not_found", &[]),
+    }
+}
+
+#[query]
+fn get_attachment_chunk(attachment_id: u64, index: u32) -> ApiResponse<Vec<u8>> {
+    let attachment = match storage::ATTACHMENTS.with(|attachments| attachments.borrow().get(&attachment_id)) {
+        Some(attachment) if attachment.can_read(&caller()) => attachment,
+        _ => return errors::coded("attachment_not_found", &[]),
+    };
+    
+    let Some(hash) = attachment.chunk_hashes.get(index as usize) else {
+        return errors::coded("attachment_chunk_out_of_range", &[("chunk_count", attachment.chunk_hashes.len().to_string())]);
+    };
+    match storage::ATTACHMENT_CHUNKS.with(|chunks| chunks.borrow().get(hash)) {
+        Some(chunk) => ApiResponse::success(chunk),
+        None => errors::coded("attachment_chunk_out_of_range", &[("chunk_count", attachment.chunk_hashes.len().to_string())]),
+    }
+}
+
+fn purge_stale_uploads() {
+    let cutoff = ic_cdk::api::time().saturating_sub(UPLOAD_TTL_NS);
+    let stale: Vec<PendingUpload> = storage::PENDING_UPLOADS.with(|uploads| {
+        uploads.borrow()
+            .iter()
+            .filter(|(_, upload)| upload.started_at < cutoff)
+            .map(|(_, upload)| upload)
+            .collect()
+    });
+    
+    for upload in stale {
+        for hash in &upload.chunk_hashes {
+            release_chunk(hash);
+        }
+        storage::PENDING_UPLOADS.with(|uploads| {
+            uploads.borrow_mut().remove(&upload.id);
+        });
+    }
+}
+
+// ============ AVATAR METHODS ============
+
+// Avatars are uploaded in chunks to the next version, then committed, which points the
+// profile at the new version and drops the old one. Profiles (and so friend lists, syncs and
+// search results) carry only the small AvatarRef.
+
+const MAX_AVATAR_CHUNK_BYTES: usize = 1_800_000;
+const MAX_AVATAR_BYTES: u64 = 4 * 1024 * 1024;
+
+// Profiles and friend records converted per pass when moving inline avatars out after an upgrade
+const AVATAR_MIGRATION_BATCH: usize = 50;
+
+fn next_avatar_version(profile: &UserProfile) -> u32 {
+    profile.avatar.as_ref().map_or(0, |avatar| avatar.version) + 1
+}
+
+fn avatar_chunks(principal: Principal, version: u32) -> Vec<Vec<u8>> {
+    storage::AVATAR_CHUNKS.with(|chunks| {
+        chunks.borrow()
+            .range((principal, version, 0)..)
+            .take_while(|((owner, v, _), _)| *owner == principal && *v == version)
+            .map(|(_, chunk)| chunk)
+            .collect()
+    })
+}
+
+fn clear_avatar_chunks(principal: Principal, version: u32) {
+    let keys: Vec<storage::AvatarChunkKey> = storage::AVATAR_CHUNKS.with(|chunks| {
+        chunks.borrow()
+            .range((principal, version, 0)..)
+            .take_while(|((owner, v, _), _)| *owner == principal && *v == version)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    storage::AVATAR_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for key in keys {
+            chunks.remove(&key);
+        }
+    });
+}
+
+/// Point `profile` at the chunks uploaded for its next avatar version and drop the current
+/// one. The caller saves the profile
+fn publish_avatar(profile: &mut UserProfile, mime_type: String) -> Result<AvatarRef, &'static str> {
+    let version = next_avatar_version(profile);
+    let chunks = avatar_chunks(profile.principal, version);
+    if chunks.is_empty() {
+        return Err("avatar_upload_empty");
+    }
+    
+    let mut hasher = sha2::Sha256::new();
+    for chunk in &chunks {
+        hasher.update(chunk);
+    }
+    let avatar = AvatarRef {
+        version,
+        sha256: hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect(),
+        size: chunks.iter().map(|chunk| chunk.len() as u64).sum(),
+        chunk_count: chunks.len() as u32,
+        mime_type,
+        updated_at: ic_cdk::api::time(),
+    };
+    
+    if let Some(previous) = profile.avatar.replace(avatar.clone()) {
+        clear_avatar_chunks(profile.principal, previous.version);
+    }
+    profile.avatar_base64 = None;
+    Ok(avatar)
+}
+
+/// Image type of `bytes` judged by their signature, for the formats accepted as avatars. SVG is
+/// left out because it can carry scripts
+fn sniff_image_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Rejection for an avatar error code; avatar_too_large names the limit
+fn avatar_error<T>(code: &str) -> ApiResponse<T> {
+    match code {
+        "avatar_too_large" => errors::coded(code, &[("max_bytes", MAX_AVATAR_BYTES.to_string())]),
+        _ => errors::coded(code, &[]),
+    }
+}
+
+/// Store an avatar passed inline the old way: a base64 string, optionally as a data URL. The
+/// type is read from the image itself, whatever the data URL claims
+fn set_legacy_avatar(profile: &mut UserProfile, legacy: &str) -> Result<(), &'static str> {
+    let encoded = match legacy.strip_prefix("data:").and_then(|url| url.split_once(";base64,")) {
+        Some((_, encoded)) => encoded,
+        None => legacy,
+    };
+    // Four base64 characters carry three bytes; reject oversized avatars before decoding them
+    if encoded.len() as u64 / 4 * 3 > MAX_AVATAR_BYTES + 3 {
+        return Err("avatar_too_large");
+    }
+    let bytes = base64::decode(encoded).ok_or("avatar_invalid_encoding")?;
+    if bytes.is_empty() {
+        return Err("avatar_upload_empty");
+    }
+    if bytes.len() as u64 > MAX_AVATAR_BYTES {
+        return Err("avatar_too_large");
+    }
+    let mime_type = sniff_image_type(&bytes).ok_or("avatar_invalid_type")?.to_string();
+    
+    let version = next_avatar_version(profile);
+    clear_avatar_chunks(profile.principal, version);
+    storage::AVATAR_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (index, chunk) in bytes.chunks(MAX_AVATAR_CHUNK_BYTES).enumerate() {
+            chunks.insert((profile.principal, version, index as u32), chunk.to_vec());
+        }
+    });
+    publish_avatar(profile, mime_type).map(|_| ())
+}
+
+fn record_avatar_change(principal: Principal, previous: Option<&AvatarRef>) {
+    // Images are too large to keep; only note which version existed
+    let previous = previous.map(|avatar| format!("<image v{} {}>", avatar.version, avatar.sha256));
+    record_audit(principal, AuditKind::ProfileEdit, "avatar", previous);
+}
+
+fn save_profile(profile: UserProfile) {
+    http::certify_profile(&profile);
+    storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow_mut().insert(profile.principal, profile);
+    });
+}
+
+/// Upload chunk `index` of a new avatar. Chunks go in order; sending index 0 again starts over.
+/// Nothing changes on the profile until commit_avatar
+#[update]
+fn upload_avatar_chunk(index: u32, data: Vec<u8>) -> ApiResponse<()> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    let Some(profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) else {
+        return errors::coded("user_not_registered", &[]);
+    };
+    if data.is_empty() {
+        return errors::coded("avatar_chunk_empty", &[]);
+    }
+    if data.len() > MAX_AVATAR_CHUNK_BYTES {
+        return errors::coded("avatar_chunk_too_large", &[("max_bytes", MAX_AVATAR_CHUNK_BYTES.to_string())]);
+    }
+    
+    let version = next_avatar_version(&profile);
+    if index == 0 {
+        clear_avatar_chunks(caller_principal, version);
+    }
+    let uploaded = avatar_chunks(caller_principal, version);
+    if index as usize > uploaded.len() {
+        return errors::coded("avatar_chunk_out_of_order", &[("expected", uploaded.len().to_string())]);
+    }
+    let size: u64 = uploaded.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != index as usize)
+        .map(|(_, chunk)| chunk.len() as u64)
+        .sum::<u64>() + data.len() as u64;
+    if size > MAX_AVATAR_BYTES {
+        return errors::coded("avatar_too_large", &[("max_bytes", MAX_AVATAR_BYTES.to_string())]);
+    }
+    
+    storage::AVATAR_CHUNKS.with(|chunks| {
+        chunks.borrow_mut().insert((caller_principal, version, index), data);
+    });
+    
+    ApiResponse::success(())
+}
+
+/// Make the uploaded chunks the caller's avatar. Accepts PNG, JPEG, GIF and WebP images
+#[update]
+fn commit_avatar(mime_type: String) -> ApiResponse<AvatarRef> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some(mut profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) else {
+        return errors::coded("user_not_registered", &[]);
+    };
+    if !mime_type.trim().to_lowercase().starts_with("image/") {
+        return errors::coded("avatar_invalid_type", &[]);
+    }
+    // Store the type the image really has; a declared type is only a hint
+    let version = next_avatar_version(&profile);
+    let first_chunk = storage::AVATAR_CHUNKS.with(|chunks| chunks.borrow().get(&(caller_principal, version, 0)));
+    let mime_type = match first_chunk.as_deref().map(sniff_image_type) {
+        Some(Some(sniffed)) => sniffed.to_string(),
+        Some(None) => return errors::coded("avatar_invalid_type", &[]),
+        None => return errors::coded("avatar_upload_empty", &[]),
+    };
+    
+    let previous = profile.avatar.clone();
+    match publish_avatar(&mut profile, mime_type) {
+        Ok(avatar) => {
+            record_avatar_change(caller_principal, previous.as_ref());
+            save_profile(profile);
+            record_activity(caller_principal, ActivityKind::ProfileUpdated);
+            ApiResponse::success(avatar)
+        }
+        Err(code) => avatar_error(code),
+    }
+}
+
+#[update]
+fn remove_avatar() -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some(mut profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) else {
+        return errors::coded("user_not_registered", &[]);
+    };
+    let Some(previous) = profile.avatar.take() else {
+        return errors::coded("avatar_not_found", &[]);
+    };
+    
+    clear_avatar_chunks(caller_principal, previous.version);
+    record_avatar_change(caller_principal, Some(&previous));
+    save_profile(profile);
+    record_activity(caller_principal, ActivityKind::ProfileUpdated);
+    
+    ApiResponse::success(())
+}
+
+/// Chunk `index` of a user's current avatar. Older versions are gone once replaced
+#[query]
+fn get_avatar_chunk(principal: Principal, version: u32, index: u32) -> ApiResponse<Vec<u8>> {
+    let current = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal))
+        .and_then(|profile| redact_profile(profile, &caller()).avatar)
+        .is_some_and(|avatar| avatar.version == version);
+    if !current {
+        return errors::coded("avatar_not_found", &[]);
+    }
+    
+    match storage::AVATAR_CHUNKS.with(|chunks| chunks.borrow().get(&(principal, version, index))) {
+        Some(chunk) => ApiResponse::success(chunk),
+        None => errors::coded("avatar_not_found", &[]),
+    }
+}
+
+/// Move avatars stored inline by earlier versions into AVATAR_CHUNKS and strip the copies from
+/// friend records, a batch at a time
+fn migrate_legacy_avatars() {
+    let profiles: Vec<UserProfile> = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow()
+            .iter()
+            .filter(|(_, profile)| profile.avatar_base64.is_some())
+            .take(AVATAR_MIGRATION_BATCH)
+            .map(|(_, profile)| profile)
+            .collect()
+    });
+    for mut profile in profiles.iter().cloned() {
+        let legacy = profile.avatar_base64.take().unwrap_or_default();
+        if let Err(code) = set_legacy_avatar(&mut profile, &legacy) {
+            ic_cdk::println!("dropping unreadable avatar of {}: {}", profile.principal, code);
+        }
+        save_profile(profile);
+    }
+    
+    let friends: Vec<((Principal, Principal), Friend)> = storage::FRIENDS.with(|friends| {
+        friends.borrow()
+            .iter()
+            .filter(|(_, friend)| friend.avatar_base64.is_some())
+            .take(AVATAR_MIGRATION_BATCH)
+            .collect()
+    });
+    storage::FRIENDS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for (key, mut friend) in friends.iter().cloned() {
+            friend.avatar_base64 = None;
+            stored.insert(key, friend);
+        }
+    });
+    
+    if profiles.len() == AVATAR_MIGRATION_BATCH || friends.len() == AVATAR_MIGRATION_BATCH {
+        ic_cdk_timers::set_timer(Duration::ZERO, migrate_legacy_avatars);
+    ic_cdk_timers::set_timer(Duration::ZERO, || rebuild_message_index(None));
+    }
+}
+
+// ============ PROFILE APPEARANCE METHODS ============
+
+// A banner is an image the user uploaded with upload_chunk and finalize_upload, so its bytes
+// live in the shared attachment store. It follows the avatar's privacy setting: whoever may see
+// the avatar can read the banner through get_banner_chunk, whoever the attachment itself was
+// shared with.
+
+const MAX_BANNER_BYTES: u64 = 8 * 1024 * 1024;
+
+/// `color` as "#rrggbb", accepting upper case and the "#rgb" short form
+fn normalize_accent_color(color: &str) -> Option<String> {
+    let hex = color.trim().strip_prefix('#')?.to_lowercase();
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    match hex.len() {
+        6 => Some(format!("#{}", hex)),
+        3 => Some(hex.chars().fold("#".to_string(), |mut color, c| {
+            color.push(c);
+            color.push(c);
+            color
+        })),
+        _ => None,
+    }
+}
+
+/// Use one of the caller's attachments as their profile banner, or remove the banner with None
+#[update]
+fn set_profile_banner(attachment_id: Option<u64>) -> ApiResponse<UserProfile> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    let Some(mut profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) else {
+        return errors::coded("user_not_registered", &[]);
+    };
+    
+    let banner = match attachment_id {
+        Some(id) => {
+            let attachment = match storage::ATTACHMENTS.with(|attachments| attachments.borrow().get(&id)) {
+                Some(attachment) if attachment.owner == caller_principal => attachment,
+                _ => return errors::coded("attachment_not_found", &[]),
+            };
+            if attachment.size > MAX_BANNER_BYTES {
+                return errors::coded("banner_too_large", &[("max_bytes", MAX_BANNER_BYTES.to_string())]);
+            }
+            // The declared type is the uploader's word; read it from the image like avatars
+            let first_chunk = attachment.chunk_hashes.first()
+                .and_then(|hash| storage::ATTACHMENT_CHUNKS.with(|chunks| chunks.borrow().get(hash)));
+            let Some(mime_type) = first_chunk.as_deref().and_then(sniff_image_type) else {
+                return errors::coded("banner_invalid_type", &[]);
+            };
+            Some(BannerRef {
+                attachment_id: id,
+                size: attachment.size,
+                chunk_count: attachment.chunk_hashes.len() as u32,
+                mime_type: mime_type.to_string(),
+            })
+        }
+        None => None,
+    };
+    
+    if profile.banner != banner {
+        let previous = profile.banner.as_ref().map(|banner| format!("<attachment {}>", banner.attachment_id));
+        record_audit(caller_principal, AuditKind::ProfileEdit, "banner", previous);
+        profile.banner = banner;
+        save_profile(profile.clone());
+        record_activity(caller_principal, ActivityKind::ProfileUpdated);
+    }
+    
+    ApiResponse::success(profile)
+}
+
+/// Chunk `index` of a user's current banner, if the caller may see their avatar
+#[query]
+fn get_banner_chunk(principal: Principal, index: u32) -> ApiResponse<Vec<u8>> {
+    let banner = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal))
+        .and_then(|profile| redact_profile(profile, &caller()).banner);
+    let Some(banner) = banner else {
+        return errors::coded("banner_not_found", &[]);
+    };
+    let Some(attachment) = storage::ATTACHMENTS.with(|attachments| attachments.borrow().get(&banner.attachment_id)) else {
+        return errors::coded("banner_not_found", &[]);
+    };
+    
+    match attachment.chunk_hashes.get(index as usize).and_then(|hash| storage::ATTACHMENT_CHUNKS.with(|chunks| chunks.borrow().get(hash))) {
+        Some(chunk) => ApiResponse::success(chunk),
+        None => errors::coded("attachment_chunk_out_of_range", &[("chunk_count", attachment.chunk_hashes.len().to_string())]),
+    }
+}
+
+/// Set the caller's profile theme, or go back to the app default with None
+#[update]
+fn set_profile_theme(theme: Option<ProfileTheme>) -> ApiResponse<UserProfile> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    let Some(mut profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&caller_principal)) else {
+        return errors::coded("user_not_registered", &[]);
+    };
+    
+    let theme = match theme {
+        Some(mut theme) => {
+            if let Some(color) = &theme.accent_color {
+                match normalize_accent_color(color) {
+                    Some(normalized) => theme.accent_color = Some(normalized),
+                    None => return errors::coded("theme_invalid_color", &[("color", color.clone())]),
+                }
+            }
+            Some(theme)
+        }
+        None => None,
+    };
+    
+    if profile.theme != theme {
+        let previous = profile.theme.as_ref().map(|theme| format!("{:?}", theme));
+        record_audit(caller_principal, AuditKind::ProfileEdit, "theme", previous);
+        profile.theme = theme;
+        save_profile(profile.clone());
+    }
+    
+    ApiResponse::success(profile)
+}
+
+// ============ CUSTOM EMOJI METHODS ============
+
+// Every user can keep a pack of custom emoji and stickers, and moderators manage one global
+// pack. Messages refer to them by id (clients write `<:shortcode:id>`), so message text never
+// carries image data. Images are small enough to upload and fetch in a single call.
+
+const MAX_CUSTOM_EMOJI_BYTES: usize = 256 * 1024;
+const MAX_STICKER_BYTES: usize = 1024 * 1024;
+const MAX_EMOJI_PACK_SIZE: usize = 100;
+const MIN_SHORTCODE_LEN: usize = 2;
+const MAX_SHORTCODE_LEN: usize = 32;
+
+/// Emoji in `owner`'s pack, or in the global pack for None
+fn emoji_pack(owner: Option<Principal>) -> Vec<CustomEmoji> {
+    storage::CUSTOM_EMOJI.with(|emoji| {
+        emoji.borrow()
+            .iter()
+            .map(|(_, emoji)| emoji)
+            .filter(|emoji| emoji.owner == owner)
+            .collect()
+    })
+}
+
+fn add_emoji(owner: Option<Principal>, shortcode: String, kind: EmojiKind, mime_type: String, image: Vec<u8>) -> ApiResponse<CustomEmoji> {
+    let shortcode = shortcode.trim().trim_matches(':').to_lowercase();
+    let valid_shortcode = (MIN_SHORTCODE_LEN..=MAX_SHORTCODE_LEN).contains(&shortcode.len())
+        && shortcode.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid_shortcode {
+        return errors::coded("emoji_invalid_shortcode", &[
+            ("min", MIN_SHORTCODE_LEN.to_string()),
+            ("max", MAX_SHORTCODE_LEN.to_string()),
+        ]);
+    }
+    let mime_type = mime_type.trim().to_lowercase();
+    if !mime_type.starts_with("image/") {
+        return errors::coded("emoji_invalid_type", &[]);
+    }
+    if image.is_empty() {
+        return errors::coded("emoji_upload_empty", &[]);
+    }
+    let max_bytes = match kind {
+        EmojiKind::Emoji => MAX_CUSTOM_EMOJI_BYTES,
+        EmojiKind::Sticker => MAX_STICKER_BYTES,
+    };
+    if image.len() > max_bytes {
+        return errors::coded("emoji_too_large", &[("max_bytes", max_bytes.to_string())]);
+    }
+    
+    let pack = emoji_pack(owner);
+    if pack.len() >= MAX_EMOJI_PACK_SIZE {
+        return errors::coded("emoji_pack_full", &[("max", MAX_EMOJI_PACK_SIZE.to_string())]);
+    }
+    if pack.iter().any(|emoji| emoji.shortcode == shortcode) {
+        return errors::coded("emoji_shortcode_taken", &[("shortcode", shortcode)]);
+    }
+    
+    let emoji = storage::CUSTOM_EMOJI.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        let emoji = CustomEmoji {
+            id: stored.last_key_value().map(|(id, _)| id + 1).unwrap_or(1),
+            shortcode,
+            kind,
+            owner,
+            mime_type,
+            size: image.len() as u64,
+            sha256: sha2::Sha256::digest(&image).iter().map(|byte| format!("{:02x}", byte)).collect(),
+            created_at: ic_cdk::api::time(),
+        };
+        stored.insert(emoji.id, emoji.clone());
+        emoji
+    });
+    storage::EMOJI_IMAGES.with(|images| images.borrow_mut().insert(emoji.id, image));
+    
+    ApiResponse::success(emoji)
+}
+
+/// Add an emoji or sticker to the caller's pack. Shortcodes are unique within a pack
+#[update]
+fn upload_emoji(shortcode: String, kind: EmojiKind, mime_type: String, image: Vec<u8>) -> ApiResponse<CustomEmoji> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    add_emoji(Some(caller_principal), shortcode, kind, mime_type, image)
+}
+
+/// Add an emoji or sticker to the global pack everyone can use (moderators only)
+#[update]
+fn upload_global_emoji(shortcode: String, kind: EmojiKind, mime_type: String, image: Vec<u8>) -> ApiResponse<CustomEmoji> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    add_emoji(None, shortcode, kind, mime_type, image)
+}
+
+/// Remove an emoji from the caller's pack, or from the global pack for moderators. Messages
+/// that still refer to it show it as missing
+#[update]
+fn delete_emoji(id: u64) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some(emoji) = storage::CUSTOM_EMOJI.with(|emoji| emoji.borrow().get(&id)) else {
+        return errors::coded("emoji_not_found", &[]);
+    };
+    let allowed = match emoji.owner {
+        Some(owner) => owner == caller_principal,
+        None => is_moderator(&caller_principal),
+    };
+    if !allowed {
+        return errors::coded("emoji_not_found", &[]);
+    }
+    
+    storage::CUSTOM_EMOJI.with(|emoji| emoji.borrow_mut().remove(&id));
+    storage::EMOJI_IMAGES.with(|images| images.borrow_mut().remove(&id));
+    
+    ApiResponse::success(())
+}
+
+/// A user's pack, or the global pack when `owner` is None, oldest first
+#[query]
+fn list_emoji(owner: Option<Principal>) -> ApiResponse<Vec<CustomEmoji>> {
+    ApiResponse::success(emoji_pack(owner))
+}
+
+/// Details of the emoji referenced in a message; ids that no longer exist are left out
+#[query]
+fn get_emoji(ids: Vec<u64>) -> ApiResponse<Vec<CustomEmoji>> {
+    let found = storage::CUSTOM_EMOJI.with(|emoji| {
+        let emoji = emoji.borrow();
+        ids.iter().take(MAX_EMOJI_PACK_SIZE).filter_map(|id| emoji.get(id)).collect()
+    });
+    ApiResponse::success(found)
+}
+
+#[query]
+fn get_emoji_image(id: u64) -> ApiResponse<Vec<u8>> {
+    match storage::EMOJI_IMAGES.with(|images| images.borrow().get(&id)) {
+        Some(image) => ApiResponse::success(image),
+        None => errors::coded("emoji_not_found", &[]),
+    }
+}
+
+// ============ MENTION METHODS ============
+
+// A mention names a user by their @handle. Users can also be mentioned by their display name
+// lowercased with whitespace removed ("Ada Lovelace" -> @adalovelace), which is tried when no
+// one holds the handle, or by their principal (@<principal text>)
+
+const MAX_MENTIONS_PER_MESSAGE: usize = 10;
+const MENTION_SNIPPET_CHARS: usize = 140;
+
+fn mention_handle(display_name: &str) -> String {
+    display_name.chars().filter(|c| !c.is_whitespace()).flat_map(char::to_lowercase).collect()
+}
+
+// Resolves mention tokens to users. Keep one for all messages handled by a call: the name
+// table is read from the display name index once, on the first token that needs it
+#[derive(Default)]
+struct MentionLookup {
+    // mention_handle of each indexed name -> its holder; None where several users share it
+    by_name: Option<HashMap<String, Option<Principal>>>,
+}
+
+impl MentionLookup {
+    fn principal(&mut self, token: &str) -> Option<Principal> {
+        if let Some(claim) = normalize_handle(token).and_then(|handle| storage::HANDLES.with(|handles| handles.borrow().get(&handle))) {
+            return Some(claim.principal);
+        }
+        
+        let by_name = self.by_name.get_or_insert_with(|| {
+            let mut by_name: HashMap<String, Option<Principal>> = HashMap::new();
+            storage::DISPLAY_NAMES.with(|names| {
+                for (name, holders) in names.borrow().iter() {
+                    let holder = match holders.principals[..] {
+                        [holder] => Some(holder),
+                        _ => None,
+                    };
+                    by_name.entry(mention_handle(&name))
+                        .and_modify(|found| if *found != holder { *found = None })
+                        .or_insert(holder);
+                }
+            });
+            by_name
+        });
+        
+        by_name.get(&token.to_lowercase()).copied().flatten().or_else(|| {
+            Principal::from_text(token).ok()
+                .filter(|principal| storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(principal)))
+        })
+    }
+    
+    /// The @handles and @principals in `text` that name registered users, first occurrence of each
+    fn resolve(&mut self, text: &str) -> Vec<Mention> {
+        let handles = text
+            .split('@')
+            .skip(1)
+            .map(|rest| {
+                let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == '-')).unwrap_or(rest.len());
+                rest[..end].trim_end_matches(['.', '-'])
+            })
+            .filter(|handle| !handle.is_empty());
+        
+        let mut mentions: Vec<Mention> = Vec::new();
+        for handle in handles {
+            if let Some(principal) = self.principal(handle) {
+                if !mentions.iter().any(|mention| mention.principal == principal) {
+                    mentions.push(Mention { principal, handle: handle.to_string() });
+                }
+            }
+            if mentions.len() == MAX_MENTIONS_PER_MESSAGE {
+                break;
+            }
+        }
+        mentions
+    }
+}
+
+/// The users mentioned in one message; see MentionLookup::resolve
+fn resolve_mentions(text: &str) -> Vec<Mention> {
+    MentionLookup::default().resolve(text)
+}
+
+/// Tell each mentioned user (other than the author, and not across a block) where they were mentioned
+fn notify_mentions(author: Principal, mentions: &[Mention], source: &AlertSource, message_id: &str, text: &str) {
+    if mentions.is_empty() {
+        return;
+    }
+    
+    let author_name = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&author))
+        .map(|profile| profile.display_name)
+        .unwrap_or_else(|| author.to_text());
+    let snippet: String = text.chars().take(MENTION_SNIPPET_CHARS).collect();
+    
+    for mention in mentions {
+        if mention.principal == author || is_blocked_either_way(author, mention.principal) {
+            continue;
+        }
+        push_notification(
+            mention.principal,
+            NotificationKind::Mention {
+                author,
+                source: source.clone(),
+                message_id: message_id.to_string(),
+            },
+            format!("{} mentioned you: {}", author_name, snippet),
+        );
+    }
+}
+
+// ============ NOTIFICATION METHODS ============
+
+fn notification_preferences(principal: &Principal) -> NotificationPreferences {
+    storage::NOTIFICATION_PREFERENCES.with(|preferences| preferences.borrow().get(principal)).unwrap_or_default()
+}
+
+/// Write to `recipient`'s inbox unless their preferences turn this kind off or the
+/// conversation it comes from is muted
+fn push_notification(recipient: Principal, kind: NotificationKind, text: String) {
+    let preferences = notification_preferences(&recipient);
+    let wanted = match &kind {
+        NotificationKind::EventInvite { .. } | NotificationKind::EventReminder { .. } => preferences.events,
+        NotificationKind::FriendRequestAutoAccepted { .. }
+        | NotificationKind::FriendRequestReceived { .. }
+        | NotificationKind::FriendRequestAccepted { .. }
+        | NotificationKind::NewFollower { .. }
+        | NotificationKind::FollowRequested { .. }
+        | NotificationKind::FollowApproved { .. } => true,
+        NotificationKind::Mention { author, source, .. } => {
+            let conversation = match source {
+                AlertSource::DirectMessage { dm_channel_id } => Some(dm_channel_id.as_str()),
+                AlertSource::ChannelMessage { channel } => channel.as_deref(),
+                AlertSource::GroupPost { .. } | AlertSource::UserReport { .. } => None,
+            };
+            let muted = conversation.is_some_and(|channel_id| is_muted(recipient, channel_id));
+            !muted && match preferences.mentions {
+                MentionSetting::Everyone => true,
+                MentionSetting::FriendsOnly => storage::FRIENDS.with(|friends| friends.borrow().contains_key(&(recipient, *author))),
+                MentionSetting::Off => false,
+            }
+        }
+    };
+    if !wanted {
+        return;
+    }
+    
+    let now = ic_cdk::api::time();
+    storage::USER_NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        // Ids are creation timestamps, bumped on collision, so they sort oldest first
+        let mut id = now;
+        while notifications.contains_key(&(recipient, id)) {
+            id += 1;
+        }
+        notifications.insert((recipient, id), UserNotification {
+            id,
+            kind,
+            text,
+            created_at: now,
+            read: false,
+        });
+    });
+}
+
+/// The mute end of `channel_id` for `principal` if it is muted right now (Some(None) = indefinitely)
+fn active_mute(principal: Principal, channel_id: &str) -> Option<Option<u64>> {
+    let now = ic_cdk::api::time();
+    storage::CONVERSATION_MUTES.with(|mutes| mutes.borrow().get(&(principal, channel_id.to_string())))
+        .filter(|until| until.is_none_or(|until| until > now))
+}
+
+fn is_muted(principal: Principal, channel_id: &str) -> bool {
+    active_mute(principal, channel_id).is_some()
+}
+
+/// Drop `principal`'s mutes that have run out
+fn prune_expired_mutes(principal: Principal) {
+    let now = ic_cdk::api::time();
+    storage::CONVERSATION_MUTES.with(|mutes| {
+        let mut mutes = mutes.borrow_mut();
+        let expired: Vec<(Principal, String)> = mutes
+            .range((principal, String::new())..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .filter(|(_, until)| until.is_some_and(|until| until <= now))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            mutes.remove(&key);
+        }
+    });
+}
+
+/// Silence mention notifications from a DM channel or chat room until `until` (or until
+/// unmuted), and leave its unread count out of the badge total. Muting again replaces the end
+#[update]
+fn mute_conversation(channel_id: String, until: Option<u64>) -> ApiResponse<ConversationMute> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    if until.is_some_and(|until| until <= ic_cdk::api::time()) {
+        return errors::coded("mute_end_in_past", &[]);
+    }
+    let is_room = channel_id.starts_with('#');
+    if !is_room && dm_channel_partner(caller_principal, &channel_id).is_none() {
+        return errors::coded("conversation_not_found", &[]);
+    }
+    
+    prune_expired_mutes(caller_principal);
+    storage::CONVERSATION_MUTES.with(|mutes| {
+        mutes.borrow_mut().insert((caller_principal, channel_id.clone()), until);
+    });
+    
+    ApiResponse::success(ConversationMute { channel_id, until })
+}
+
+#[update]
+fn unmute_conversation(channel_id: String) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let was_muted = is_muted(caller_principal, &channel_id);
+    storage::CONVERSATION_MUTES.with(|mutes| mutes.borrow_mut().remove(&(caller_principal, channel_id)));
+    prune_expired_mutes(caller_principal);
+    
+    if was_muted {
+        ApiResponse::success(())
+    } else {
+        errors::coded("conversation_not_muted", &[])
+    }
+}
+
+/// The caller's conversations that are muted right now, for syncing mute state across devices
+#[query]
+fn get_muted_conversations() -> ApiResponse<Vec<ConversationMute>> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+    
+    let muted = storage::CONVERSATION_MUTES.with(|mutes| {
+        mutes.borrow()
+            .range((caller_principal, String::new())..)
+            .take_while(|((owner, _), _)| *owner == caller_principal)
+            .filter(|(_, until)| until.is_none_or(|until| until > now))
+            .map(|((_, channel_id), until)| ConversationMute { channel_id, until })
+            .collect()
+    });
+    
+    ApiResponse::success(muted)
+}
+
+#[query]
+fn get_notification_preferences() -> ApiResponse<NotificationPreferences> {
+    ApiResponse::success(notification_preferences(&caller()))
+}
+
+#[update]
+fn set_notification_preferences(preferences: NotificationPreferences) -> ApiResponse<NotificationPreferences> {
+    if let Some(rejection) = reject_if_anonymous(&caller()) {
+        return rejection;
+    }
+    storage::NOTIFICATION_PREFERENCES.with(|stored| {
+        stored.borrow_mut().insert(caller(), preferences.clone());
+    });
+    ApiResponse::success(preferences)
+}
+
+/// The caller's inbox, newest first. Pass the last id seen as `before_id` for the next page,
+/// or the newest id seen as `since` to get only what arrived after it
+#[query]
+fn get_notifications(limit: Option<u32>, before_id: Option<u64>, since: Option<u64>) -> ApiResponse<Vec<UserNotification>> {
+    let caller_principal = caller();
+    let limit = limit.unwrap_or(50) as usize;
+    let upper = before_id.unwrap_or(u64::MAX);
+    let lower = since.map_or(0, |since| since.saturating_add(1));
+    
+    let mut notifications: Vec<UserNotification> = storage::USER_NOTIFICATIONS.with(|notifications| {
+        notifications.borrow()
+            .range((caller_principal, lower)..(caller_principal, upper))
+            .map(|(_, notification)| notification)
+            .collect()
+    });
+    notifications.reverse();
+    notifications.truncate(limit);
+    
+    ApiResponse::success(notifications)
+}
+
+/// Inbox entries for messages that mentioned the caller, newest first; pass the last id seen
+/// as `before_id` for the next page
+#[query]
+fn get_mentions(limit: Option<u32>, before_id: Option<u64>) -> ApiResponse<Vec<UserNotification>> {
+    let caller_principal = caller();
+    let limit = limit.unwrap_or(50) as usize;
+    let upper = before_id.unwrap_or(u64::MAX);
+    
+    let mut mentions: Vec<UserNotification> = storage::USER_NOTIFICATIONS.with(|notifications| {
+        notifications.borrow()
+            .range((caller_principal, 0)..(caller_principal, upper))
+            .map(|(_, notification)| notification)
+            .filter(|notification| matches!(notification.kind, NotificationKind::Mention { .. }))
+            .collect()
+    });
+    mentions.reverse();
+    mentions.truncate(limit);
+    
+    ApiResponse::success(mentions)
+}
+
+#[update]
+fn mark_notification_read(id: u64) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    storage::USER_NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        match notifications.get(&(caller_principal, id)) {
+            Some(mut notification) => {
+                notification.read = true;
+                notifications.insert((caller_principal, id), notification);
+                ApiResponse::success(())
+            }
+            None => errors::coded("notification_not_found", &[]),
+        }
+    })
+}
+
+/// Mark every unread notification up to and including `up_to_id` read (all of them when
+/// None). Returns how many changed
+#[update]
+fn mark_notifications_read(up_to_id: Option<u64>) -> ApiResponse<u32> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    let upper = up_to_id.unwrap_or(u64::MAX);
+    
+    storage::USER_NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let unread: Vec<UserNotification> = notifications
+            .range((caller_principal, 0)..=(caller_principal, upper))
+            .map(|(_, notification)| notification)
+            .filter(|notification| !notification.read)
+            .collect();
+        for mut notification in unread.iter().cloned() {
+            notification.read = true;
+            notifications.insert((caller_principal, notification.id), notification);
+        }
+        ApiResponse::success(unread.len() as u32)
+    })
+}
+
+// ============ ARCHIVE METHODS ============
+
+fn is_archived(principal: Principal, channel_id: &str) -> bool {
+    storage::ARCHIVED_CHANNELS.with(|archived| archived.borrow().get(&(principal, channel_id.to_string())).is_some())
+}
+
+/// Move a DM channel or chat room out of the caller's default listings. Only the caller's
+/// view changes: messages are kept and the other side is not told. Archiving again keeps
+/// the original time
+#[update]
+fn archive_channel(channel_id: String) -> ApiResponse<ArchivedChannel> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let is_room = channel_id.starts_with('#');
+    if !is_room && dm_channel_partner(caller_principal, &channel_id).is_none() {
+        return errors::coded("conversation_not_found", &[]);
+    }
+    
+    let key = (caller_principal, channel_id.clone());
+    let archived_at = storage::ARCHIVED_CHANNELS.with(|archived| {
+        let mut archived = archived.borrow_mut();
+        archived.get(&key).unwrap_or_else(|| {
+            let now = ic_cdk::api::time();
+            archived.insert(key, now);
+            now
+        })
+    });
+    
+    ApiResponse::success(ArchivedChannel { channel_id, archived_at })
+}
+
+#[update]
+fn unarchive_channel(channel_id: String) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_anonymous(&caller()) {
+        return rejection;
+    }
+    let removed = storage::ARCHIVED_CHANNELS.with(|archived| archived.borrow_mut().remove(&(caller(), channel_id)));
+    match removed {
+        Some(_) => ApiResponse::success(()),
+        None => errors::coded("conversation_not_archived", &[]),
+    }
+}
+
+/// The caller's archived conversations, most recently archived first
+#[query]
+fn get_archived_channels() -> ApiResponse<Vec<ArchivedChannel>> {
+    let caller_principal = caller();
+    
+    let mut channels: Vec<ArchivedChannel> = storage::ARCHIVED_CHANNELS.with(|archived| {
+        archived.borrow()
+            .range((caller_principal, String::new())..)
+            .take_while(|((owner, _), _)| *owner == caller_principal)
+            .map(|((_, channel_id), archived_at)| ArchivedChannel { channel_id, archived_at })
+            .collect()
+    });
+    channels.sort_by_key(|channel| std::cmp::Reverse(channel.archived_at));
+    
+    ApiResponse::success(channels)
+}
+
+// ============ STARRED MESSAGE METHODS ============
+
+// Stars only point at a message, which is looked up again whenever the list is read: deleted
+// messages and ones in channels the user has since left drop out of the list.
+
+const MAX_STARRED_MESSAGES: usize = 500;
+
+/// A message in one of `principal`'s groups. Ids start with the post time, which is also
+/// its place in the group's log
+fn find_group_message(principal: Principal, message_id: &str) -> Option<ChannelMessage> {
+    let timestamp: u64 = message_id.split('_').next()?.parse().ok()?;
+    let group_ids: Vec<u64> = storage::GROUPS.with(|groups| groups.borrow().iter().map(|(id, _)| id).collect());
+    group_ids.into_iter()
+        .filter(|group_id| is_group_member(*group_id, principal))
+        .find_map(|group_id| {
+            let key = (format!("{}{}", GROUP_CHANNEL_PREFIX, group_id), channel_message_key(timestamp, message_id));
+            storage::CHANNEL_MESSAGES.with(|messages| messages.borrow().get(&key))
+        })
+}
+
+/// `message_id` as `principal` can read it now, or None if it is gone or out of reach
+fn read_starred(principal: Principal, message_id: String, star: MessageStar) -> Option<StarredMessage> {
+    if star.channel_id.starts_with(GROUP_CHANNEL_PREFIX) {
+        let message = find_group_message(principal, &message_id)?;
+        return Some(StarredMessage {
+            message_id,
+            channel_id: star.channel_id,
+            author: message.author,
+            text: message.text,
+            timestamp: message.timestamp,
+            starred_at: star.starred_at,
+        });
+    }
+    
+    dm_channel_partner(principal, &star.channel_id)?;
+    let message = storage::DM_MESSAGES.with(|dm_messages| dm_messages.borrow().get(&star.channel_id))?
+        .messages
+        .into_iter()
+        .find(|message| message.id == message_id && !matches!(message.kind, Some(MessageKind::Deleted { .. })))?;
+    Some(StarredMessage {
+        message_id,
+        channel_id: star.channel_id,
+        author: Some(message.sender_principal),
+        text: message.text,
+        timestamp: message.timestamp,
+        starred_at: star.starred_at,
+    })
+}
+
+/// Save a DM or group message to the caller's starred list. Starring again keeps the
+/// original time
+#[update]
+fn star_message(message_id: String) -> ApiResponse<StarredMessage> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let key = (caller_principal, message_id.clone());
+    let existing = storage::MESSAGE_STARS.with(|stars| stars.borrow().get(&key));
+    let star = match existing {
+        Some(star) => star,
+        None => {
+            let channel_id = match find_dm_message(caller_principal, &message_id) {
+                Some((_, message)) if !matches!(message.kind, Some(MessageKind::Deleted { .. })) => message.dm_channel_id,
+                Some(_) => return errors::coded("dm_message_not_found", &[]),
+                None => match find_group_message(caller_principal, &message_id) {
+                    Some(message) => message.channel,
+                    None => return errors::coded("dm_message_not_found", &[]),
+                },
+            };
+            let starred = storage::MESSAGE_STARS.with(|stars| {
+                let stars = stars.borrow();
+                stars.range((caller_principal, String::new())..)
+                    .take_while(|((owner, _), _)| *owner == caller_principal)
+                    .count()
+            });
+            if starred >= MAX_STARRED_MESSAGES {
+                return errors::coded("too_many_starred_messages", &[("max", MAX_STARRED_MESSAGES.to_string())]);
+            }
+            MessageStar { channel_id, starred_at: ic_cdk::api::time() }
+        }
+    };
+    storage::MESSAGE_STARS.with(|stars| stars.borrow_mut().insert(key, star.clone()));
+    
+    match read_starred(caller_principal, message_id, star) {
+        Some(starred) => ApiResponse::success(starred),
+        None => errors::coded("dm_message_not_found", &[]),
+    }
+}
+
+#[update]
+fn unstar_message(message_id: String) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_anonymous(&caller()) {
+        return rejection;
+    }
+    let removed = storage::MESSAGE_STARS.with(|stars| stars.borrow_mut().remove(&(caller(), message_id)));
+    match removed {
+        Some(_) => ApiResponse::success(()),
+        None => errors::coded("message_not_starred", &[]),
+    }
+}
+
+/// The caller's starred messages that they can still read, most recently starred first
+#[query]
+fn get_starred_messages() -> ApiResponse<Vec<StarredMessage>> {
+    let caller_principal = caller();
+    
+    let stars: Vec<(String, MessageStar)> = storage::MESSAGE_STARS.with(|stars| {
+        stars.borrow()
+            .range((caller_principal, String::new())..)
+            .take_while(|((owner, _), _)| *owner == caller_principal)
+            .map(|((_, message_id), star)| (message_id, star))
+            .collect()
+    });
+    let mut starred: Vec<StarredMessage> = stars.into_iter()
+        .filter_map(|(message_id, star)| read_starred(caller_principal, message_id, star))
+        .collect();
+    starred.sort_by_key(|message| std::cmp::Reverse(message.starred_at));
+    
+    ApiResponse::success(starred)
+}
+
+// ============ DRAFT METHODS ============
+
+const MAX_DRAFT_BYTES: usize = 16 * 1024;
+
+/// Save what the caller has typed but not sent in a DM channel or chat room, so another of
+/// their devices can pick it up. Empty text clears the draft; sending a DM clears it too.
+/// The latest save wins
+#[update]
+fn save_draft(channel_id: String, text: String) -> ApiResponse<Option<Draft>> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    if text.len() > MAX_DRAFT_BYTES {
+        return errors::coded("draft_too_long", &[("max", MAX_DRAFT_BYTES.to_string())]);
+    }
+    let is_room = channel_id.starts_with('#');
+    if !is_room && dm_channel_partner(caller_principal, &channel_id).is_none() {
+        return errors::coded("conversation_not_found", &[]);
+    }
+    
+    let key = (caller_principal, channel_id.clone());
+    if text.trim().is_empty() {
+        storage::DRAFTS.with(|drafts| drafts.borrow_mut().remove(&key));
+        return ApiResponse::success(None);
+    }
+    
+    let draft = Draft { channel_id, text, updated_at: ic_cdk::api::time() };
+    storage::DRAFTS.with(|drafts| drafts.borrow_mut().insert(key, draft.clone()));
+    
+    ApiResponse::success(Some(draft))
+}
+
+/// The caller's drafts, most recently edited first
+#[query]
+fn get_drafts() -> ApiResponse<Vec<Draft>> {
+    let caller_principal = caller();
+    
+    let mut drafts: Vec<Draft> = storage::DRAFTS.with(|drafts| {
+        drafts.borrow()
+            .range((caller_principal, String::new())..)
+            .take_while(|((owner, _), _)| *owner == caller_principal)
+            .map(|(_, draft)| draft)
+            .collect()
+    });
+    drafts.sort_by_key(|draft| std::cmp::Reverse(draft.updated_at));
+    
+    ApiResponse::success(drafts)
+}
+
+// ============ PAGINATED LISTING METHODS ============
+
+// Cursor-paginated versions of the list endpoints. Pages are ordered by a stable key and a
+// cursor resumes strictly after the last key returned, so changes between pages never cause
+// skipped or repeated entries. Items created after the first page was served are left out.
+
+#[query]
+fn get_friends_page(limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<Friend>> {
+    let caller_principal = caller();
+    let scope = "friends";
+    let cursor = match pagination::resume(cursor, scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let lower = match pagination::last_principal(&cursor) {
+        Ok(Some(last)) => Bound::Excluded((caller_principal, last)),
+        Ok(None) => Bound::Included((caller_principal, Principal::from_slice(&[]))),
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let page = storage::FRIENDS.with(|friends| {
+        let friends = friends.borrow();
+        let items = friends
+            .range((lower, Bound::Unbounded))
+            .take_while(|((user_principal, _), _)| *user_principal == caller_principal)
+            .filter(|(_, friend)| friend.added_at <= cursor.snapshot_at)
+            .map(|((_, friend_principal), friend)| (friend_principal.as_slice().to_vec(), with_current_profile(friend)));
+        pagination::collect_page(items, pagination::page_size(limit), scope, cursor.snapshot_at)
+    });
+    
+    diagnosed("get_friends_page", ApiResponse::success(page))
+}
+
+#[query]
+fn get_blocked_users_page(limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<BlockedUser>> {
+    let caller_principal = caller();
+    let scope = "blocked";
+    let cursor = match pagination::resume(cursor, scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let lower = match pagination::last_principal(&cursor) {
+        Ok(Some(last)) => Bound::Excluded((caller_principal, last)),
+        Ok(None) => Bound::Included((caller_principal, Principal::from_slice(&[]))),
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let page = storage::BLOCKED_USERS.with(|blocked| {
+        let blocked = blocked.borrow();
+        let items = blocked
+            .range((lower, Bound::Unbounded))
+            .take_while(|((blocker, _), _)| *blocker == caller_principal)
+            .filter(|(_, user)| user.blocked_at <= cursor.snapshot_at)
+            .map(|((_, blocked_principal), user)| (blocked_principal.as_slice().to_vec(), user));
+        pagination::collect_page(items, pagination::page_size(limit), scope, cursor.snapshot_at)
+    });
+    
+    diagnosed("get_blocked_users_page", ApiResponse::success(page))
+}
+
+/// Page through the caller's pending requests via the per-recipient (or per-sender) index
+fn friend_requests_page(
+    scope: &str,
+    limit: Option<u32>,
+    cursor: Option<String>,
+    received: bool,
+) -> ApiResponse<Page<FriendRequest>> {
+    let cursor = match pagination::resume(cursor, scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let caller_principal = caller();
+    let lower = match &cursor.last_key {
+        Some(key) => Bound::Excluded((caller_principal, String::from_utf8_lossy(key).into_owned())),
+        None => Bound::Included((caller_principal, String::new())),
+    };
+    
+    let index = if received { &storage::PENDING_REQUESTS_TO } else { &storage::PENDING_REQUESTS_FROM };
+    let page = index.with(|index| {
+        storage::FRIEND_REQUESTS.with(|requests| {
+            let index = index.borrow();
+            let requests = requests.borrow();
+            let items = index
+                .range((lower, Bound::Unbounded))
+                .take_while(|((owner, _), _)| *owner == caller_principal)
+                .filter(|(_, created_at)| *created_at <= cursor.snapshot_at)
+                .filter_map(|((_, id), _)| requests.get(&id))
+                .map(|req| (req.id.clone().into_bytes(), req));
+            pagination::collect_page(items, pagination::page_size(limit), scope, cursor.snapshot_at)
+        })
+    });
+    
+    ApiResponse::success(page)
+}
+
+#[query]
+fn get_friend_requests_page(limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<FriendRequest>> {
+    friend_requests_page("friend_requests", limit, cursor, true)
+}
+
+#[query]
+fn get_sent_requests_page(limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<FriendRequest>> {
+    friend_requests_page("sent_requests", limit, cursor, false)
+}
+
+/// Newest first. The sort key is (timestamp, id) so messages sharing a timestamp stay ordered
+#[query]
+fn get_dm_messages_page(friend_principal: Principal, limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<DirectMessage>> {
+    let caller_principal = caller();
+    
+    let are_friends = storage::FRIENDS.with(|friends| {
+        friends.borrow().contains_key(&(caller_principal, friend_principal))
+    });
+    if !are_friends {
+        return errors::coded("dm_read_not_friends", &[]);
+    }
+    if is_blocked_either_way(caller_principal, friend_principal) {
+        return errors::coded("dm_read_blocked", &[]);
+    }
+    
+    let dm_channel_id = generate_dm_channel_id(&caller_principal, &friend_principal);
+    let scope = format!("dm:{}", dm_channel_id);
+    let cursor = match pagination::resume(cursor, &scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let sort_key = |message: &DirectMessage| {
+        let mut key = message.timestamp.to_be_bytes().to_vec();
+        key.extend_from_slice(message.id.as_bytes());
+        key
+    };
+    
+    let mut messages: Vec<(Vec<u8>, DirectMessage)> = storage::DM_MESSAGES.with(|dm_messages| {
+        dm_messages.borrow()
+            .get(&dm_channel_id)
+            .map(|channel| channel.messages)
+            .unwrap_or_default()
+    })
+    .into_iter()
+    .filter(|message| message.thread_parent_id.is_none() && message.timestamp <= cursor.snapshot_at)
+    .map(|message| (sort_key(&message), message))
+    .filter(|(key, _)| cursor.last_key.as_ref().is_none_or(|last| key < last))
+    .collect();
+    messages.sort_by(|a, b| b.0.cmp(&a.0));
+    
+    diagnosed("get_dm_messages_page", ApiResponse::success(pagination::collect_page(
+        messages.into_iter(),
+        pagination::page_size(limit),
+        &scope,
+        cursor.snapshot_at,
+    )))
+}
+    
+/// Full history of a DM channel for archiving, oldest first and including thread replies.
+/// Follow the cursor until it is None; messages sent after the first page are left out, so the
+/// batches add up to one consistent snapshot. Participants can export after blocking the
+/// other side, since the export only holds what they already received
+#[query]
+fn export_dm_history(dm_channel_id: String, limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<DirectMessage>> {
+    if dm_channel_partner(caller(), &dm_channel_id).is_none() {
+        return errors::coded("dm_read_not_friends", &[]);
+    }
+    
+    let scope = format!("dm_export:{}", dm_channel_id);
+    let cursor = match pagination::resume(cursor, &scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let mut messages: Vec<(Vec<u8>, DirectMessage)> = storage::DM_MESSAGES.with(|dm_messages| {
+        dm_messages.borrow()
+            .get(&dm_channel_id)
+            .map(|channel| channel.messages)
+            .unwrap_or_default()
+    })
+    .into_iter()
+    .filter(|message| message.timestamp <= cursor.snapshot_at)
+    .map(|message| {
+        let mut key = message.timestamp.to_be_bytes().to_vec();
+        key.extend_from_slice(message.id.as_bytes());
+        (key, message)
+    })
+    .filter(|(key, _)| cursor.last_key.as_ref().is_none_or(|last| key > last))
+    .collect();
+    messages.sort_by(|a, b| a.0.cmp(&b.0));
+    
+    diagnosed("export_dm_history", ApiResponse::success(pagination::collect_page(
+        messages.into_iter(),
+        pagination::page_size(limit),
+        &scope,
+        cursor.snapshot_at,
+    )))
+}
+
+/// Registered users in principal order, redacted for the caller. Replaces get_all_users; avatars
+/// are references, so fetch images with get_avatar_chunk
+#[query]
+fn list_users(limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<UserProfile>> {
+    let scope = "users";
+    let cursor = match pagination::resume(cursor, scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let lower = match pagination::last_principal(&cursor) {
+        Ok(Some(last)) => Bound::Excluded(last),
+        Ok(None) => Bound::Unbounded,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let viewer = caller();
+    let page = storage::USER_PROFILES.with(|profiles| {
+        let profiles = profiles.borrow();
+        let items = profiles
+            .range((lower, Bound::Unbounded))
+            .filter(|(_, profile)| profile.created_at <= cursor.snapshot_at)
+            .map(|(principal, profile)| (principal.as_slice().to_vec(), redact_profile(profile, &viewer)));
+        pagination::collect_page(items, pagination::page_size(limit), scope, cursor.snapshot_at)
+    });
+    
+    diagnosed("list_users", ApiResponse::success(page))
+}
+
+#[query]
+fn search_users_page(query: String, limit: Option<u32>, cursor: Option<String>) -> ApiResponse<Page<UserSearchResult>> {
+    let query_lower = query.to_lowercase();
+    let scope = format!("search:{}", query_lower);
+    let cursor = match pagination::resume(cursor, &scope, ic_cdk::api::time()) {
+        Ok(cursor) => cursor,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let lower = match pagination::last_principal(&cursor) {
+        Ok(Some(last)) => Bound::Excluded(last),
+        Ok(None) => Bound::Unbounded,
+        Err(code) => return errors::coded(code, &[]),
+    };
+    
+    let viewer = caller();
+    let page = storage::USER_PROFILES.with(|profiles| {
+        let profiles = profiles.borrow();
+        let items = profiles
+            .range((lower, Bound::Unbounded))
+            .filter(|(principal, profile)| {
+                profile.created_at <= cursor.snapshot_at
+                    && profile.display_name.to_lowercase().contains(&query_lower)
+                    && !is_banned(principal)
+            })
+            .map(|(principal, profile)| (principal.as_slice().to_vec(), search_result(SearchMatch {
+                relevance: text_relevance(&query_lower, &profile.display_name),
+                field: SearchField::DisplayName,
+                profile: redact_profile(profile, &viewer),
+            })));
+        pagination::collect_page(items, pagination::page_size(limit), &scope, cursor.snapshot_at)
+    });
+    
+    diagnosed("search_users_page", ApiResponse::success(page))
+}
+
+// ============ AUDIT TRAIL METHODS ============
+
+// Changes to the same field within this window share one audit entry
+const AUDIT_COMPACTION_WINDOW_NS: u64 = 60 * 60 * 1_000_000_000;
+
+// Longest previous value kept in an entry
+const AUDIT_VALUE_MAX_CHARS: usize = 200;
+
+const DEFAULT_AUDIT_RETENTION_DAYS: u64 = 90;
+
+// How often entries past the retention period are purged
+const AUDIT_PURGE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const NS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+fn audit_retention_days() -> u64 {
+    storage::NUMERIC_SETTINGS.with(|settings| settings.borrow().get(&storage::AUDIT_RETENTION_DAYS_SETTING))
+        .unwrap_or(DEFAULT_AUDIT_RETENTION_DAYS)
+}
+
+fn truncate_audit_value(value: String) -> String {
+    if value.chars().count() <= AUDIT_VALUE_MAX_CHARS {
+        return value;
+    }
+    let truncated: String = value.chars().take(AUDIT_VALUE_MAX_CHARS).collect();
+    format!("{}…", truncated)
+}
+
+/// Record a user's correction to their own data, folding it into a recent entry for the same field
+fn record_audit(principal: Principal, kind: AuditKind, field: &str, previous_value: Option<String>) {
+    let now = ic_cdk::api::time();
+    let previous_value = previous_value.map(truncate_audit_value);
+    
+    storage::AUDIT_TRAIL.with(|trail| {
+        let mut trail = trail.borrow_mut();
+        
+        let window_start = now.saturating_sub(AUDIT_COMPACTION_WINDOW_NS);
+        let recent = trail
+            .range((principal, 0)..)
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.kind == kind && entry.field == field && entry.last_at >= window_start)
+            .last();
+        
+        let entry = match recent {
+            Some(mut entry) => {
+                entry.changes += 1;
+                entry.last_at = now;
+                // Deletions accumulate ids; edits keep the value from before the first change
+                if kind == AuditKind::MessageDelete {
+                    entry.previous_value = match (entry.previous_value, previous_value) {
+                        (Some(earlier), Some(more)) => Some(truncate_audit_value(format!("{},{}", earlier, more))),
+                        (earlier, more) => earlier.or(more),
+                    };
+                }
+                entry
+            }
+            None => {
+                let mut id = now;
+                while trail.contains_key(&(principal, id)) {
+                    id += 1;
+                }
+                AuditEntry {
+                    id,
+                    principal,
+                    kind,
+                    field: field.to_string(),
+                    previous_value,
+                    changes: 1,
+                    first_at: now,
+                    last_at: now,
+                }
+            }
+        };
+        
+        trail.insert((principal, entry.id), entry);
+    });
+}
+
+/// Drop audit entries whose last change is older than the retention period
+fn purge_audit_trail() {
+    let cutoff = ic_cdk::api::time().saturating_sub(audit_retention_days().saturating_mul(NS_PER_DAY));
+    
+    storage::AUDIT_TRAIL.with(|trail| {
+        let mut trail = trail.borrow_mut();
+        let expired: Vec<(Principal, u64)> = trail
+            .iter()
+            .filter(|(_, entry)| entry.last_at < cutoff)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            trail.remove(&key);
+        }
+    });
+}
+
+/// Audit entries for moderation disputes, newest first; all users when `principal` is None
+#[query]
+fn get_audit_trail(principal: Option<Principal>, limit: Option<u32>) -> ApiResponse<Vec<AuditEntry>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let limit = limit.unwrap_or(100) as usize;
+    
+    let mut entries: Vec<AuditEntry> = storage::AUDIT_TRAIL.with(|trail| {
+        let trail = trail.borrow();
+        match principal {
+            Some(principal) => trail
+                .range((principal, 0)..)
+                .take_while(|((owner, _), _)| *owner == principal)
+                .map(|(_, entry)| entry)
+                .collect(),
+            None => trail.iter().map(|(_, entry)| entry).collect(),
+        }
+    });
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_at));
+    entries.truncate(limit);
+    
+    ApiResponse::success(entries)
+}
+
+#[update]
+fn set_audit_retention_days(days: u64) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    if days == 0 {
+        return errors::coded("retention_too_short", &[]);
+    }
+    
+    storage::NUMERIC_SETTINGS.with(|settings| {
+        settings.borrow_mut().insert(storage::AUDIT_RETENTION_DAYS_SETTING, days);
+    });
+    
+    ApiResponse::success(())
+}
+
+#[query]
+fn get_audit_retention_days() -> ApiResponse<u64> {
+    ApiResponse::success(audit_retention_days())
+}
+
+// ============ ONBOARDING METHODS ============
+
+fn update_onboarding(principal: Principal, apply: impl FnOnce(&mut OnboardingState)) -> OnboardingState {
+    storage::ONBOARDING.with(|onboarding| {
+        let mut onboarding = onboarding.borrow_mut();
+        let mut state = onboarding.get(&principal).unwrap_or_default();
+        apply(&mut state);
+        state.updated_at = ic_cdk::api::time();
+        onboarding.insert(principal, state.clone());
+        state
+    })
+}
+
+#[query]
+fn get_onboarding_state() -> ApiResponse<OnboardingState> {
+    let state = storage::ONBOARDING.with(|onboarding| {
+        onboarding.borrow().get(&caller()).unwrap_or_default()
+    });
+    
+    ApiResponse::success(state)
+}
+
+/// Merge the client's checklist into the stored one. Steps only move forward, so a
+/// device with stale local progress cannot undo steps completed elsewhere
+#[update]
+fn set_onboarding_state(state: OnboardingState) -> ApiResponse<OnboardingState> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let registered = storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal));
+    if !registered {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    let merged = update_onboarding(caller_principal, |stored| {
+        stored.profile_done |= state.profile_done;
+        stored.first_friend_added |= state.first_friend_added;
+        stored.first_ai_chat |= state.first_ai_chat;
+        stored.dismissed |= state.dismissed;
+    });
+    
+    ApiResponse::success(merged)
+}
+
+// ============ JOURNAL METHODS ============
+
+// Largest accepted ciphertext per journal entry
+const MAX_JOURNAL_ENTRY_BYTES: usize = 64 * 1024;
+
+// Journal entries per page
+const DEFAULT_JOURNAL_PAGE: u32 = 20;
+const MAX_JOURNAL_PAGE: u32 = 100;
+
+// vetKD key used to derive per-user journal keys and per-channel DM keys ("dfx_test_key" on a local replica)
+const VETKD_KEY_NAME: &str = "key_1";
+
+// Domain separator so journal keys can never collide with other vetKD uses of this canister
+const JOURNAL_KEY_CONTEXT: &[u8] = b"lain_journal";
+
+// Cycles attached to vetkd_derive_key for key_1
+const VETKD_DERIVE_KEY_CYCLES: u128 = 26_153_846_153;
+
+#[derive(CandidType, Deserialize)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(CandidType)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdPublicKeyReply {
+    public_key: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdDeriveKeyReply {
+    encrypted_key: Vec<u8>,
+}
+
+fn vetkd_key_id() -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: VETKD_KEY_NAME.to_string(),
+    }
+}
+
+/// Key requests call the management canister at the canister's expense, so only registered,
+/// unsuspended users get them, and only within their key request allowance
+fn reject_key_request<T>(principal: Principal) -> Option<ApiResponse<T>> {
+    if let Some(rejection) = reject_if_suspended(&principal) {
+        return Some(rejection);
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&principal)) {
+        return Some(errors::coded("user_not_registered", &[]));
+    }
+    rate_limit::take_key_request(principal, ic_cdk::api::time()).err().map(|retry_after| {
+        errors::coded("key_rate_limited", &[("retry_after_seconds", retry_after.to_string())])
+    })
+}
+
+/// Create a journal entry, or replace the ciphertext of an existing one when `entry_id` is given
+#[update]
+fn save_journal_entry(ciphertext: Vec<u8>, entry_id: Option<u64>) -> ApiResponse<JournalEntry> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let caller_principal = caller();
+    
+    let caller_exists = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow().contains_key(&caller_principal)
+    });
+    if !caller_exists {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    if ciphertext.is_empty() {
+        return errors::coded("journal_entry_empty", &[]);
+    }
+    if ciphertext.len() > MAX_JOURNAL_ENTRY_BYTES {
+        return errors::coded("journal_entry_too_large", &[("max_bytes", MAX_JOURNAL_ENTRY_BYTES.to_string())]);
+    }
+    
+    let now = ic_cdk::api::time();
+    
+    storage::JOURNAL_ENTRIES.with(|journal| {
+        let mut journal = journal.borrow_mut();
+        
+        let entry = match entry_id {
+            Some(id) => match journal.get(&(caller_principal, id)) {
+                Some(existing) => JournalEntry {
+                    ciphertext,
+                    updated_at: now,
+                    ..existing
+                },
+                None => return errors::coded("journal_entry_not_found", &[]),
+            },
+            None => {
+                // Entry ids are creation timestamps, nudged forward if two saves share one
+                let mut id = now;
+                while journal.contains_key(&(caller_principal, id)) {
+                    id += 1;
+                }
+                JournalEntry {
+                    id,
+                    ciphertext,
+                    created_at: now,
+                    updated_at: now,
+                }
+            }
+        };
+        
+        journal.insert((caller_principal, entry.id), entry.clone());
+        ApiResponse::success(entry)
+    })
+}
+
+/// Caller's journal entries, newest first; pass the last id seen as `before_id` for the next page
+#[query]
+fn get_journal_entries(limit: Option<u32>, before_id: Option<u64>) -> ApiResponse<JournalEntriesResponse> {
+    let caller_principal = caller();
+    let limit = limit.unwrap_or(DEFAULT_JOURNAL_PAGE).clamp(1, MAX_JOURNAL_PAGE) as usize;
+    let upper = before_id.unwrap_or(u64::MAX);
+    
+    let mut entries: Vec<JournalEntry> = storage::JOURNAL_ENTRIES.with(|journal| {
+        journal.borrow()
+            .range((caller_principal, 0)..(caller_principal, upper))
+            .rev()
+            .take(limit + 1)
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    
+    let has_more = entries.len() > limit;
+    entries.truncate(limit);
+    
+    ApiResponse::success(JournalEntriesResponse { entries, has_more })
+}
+
+#[update]
+fn delete_journal_entry(entry_id: u64) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_anonymous(&caller()) {
+        return rejection;
+    }
+    let removed = storage::JOURNAL_ENTRIES.with(|journal| {
+        journal.borrow_mut().remove(&(caller(), entry_id))
+    });
+    
+    match removed {
+        Some(_) => ApiResponse::success(()),
+        None => errors::coded("journal_entry_not_found", &[]),
+    }
+}
+
+/// Public key clients use to verify keys returned by get_journal_encryption_key
+#[update]
+async fn get_journal_verification_key() -> ApiResponse<Vec<u8>> {
+    if let Some(rejection) = reject_key_request(caller()) {
+        return rejection;
+    }
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: JOURNAL_KEY_CONTEXT.to_vec(),
+        key_id: vetkd_key_id(),
+    };
+    
+    let result: Result<(VetKdPublicKeyReply,), _> =
+        ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,)).await;
+    
+    match result {
+        Ok((reply,)) => ApiResponse::success(reply.public_key),
+        Err((code, msg)) => errors::coded("key_service_failed", &[("detail", format!("vetkd_public_key: {:?} {}", code, msg))]),
+    }
+}
+
+/// The caller's journal key, encrypted under `transport_public_key`. Keys are bound to the
+/// caller's principal, so nobody else (including this canister) can decrypt their entries
+#[update]
+async fn get_journal_encryption_key(transport_public_key: Vec<u8>) -> ApiResponse<Vec<u8>> {
+    if let Some(rejection) = reject_key_request(caller()) {
+        return rejection;
+    }
+    let args = VetKdDeriveKeyArgs {
+        input: caller().as_slice().to_vec(),
+        context: JOURNAL_KEY_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: vetkd_key_id(),
+    };
+    
+    let result: Result<(VetKdDeriveKeyReply,), _> = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_CYCLES,
+    ).await;
+    
+    match result {
+        Ok((reply,)) => ApiResponse::success(reply.encrypted_key),
+        Err((code, msg)) => errors::coded("key_service_failed", &[("detail", format!("vetkd_derive_key: {:?} {}", code, msg))]),
+    }
+}
+
+// ============ ENCRYPTED DM METHODS ============
+
+// Friends derive the same vetKD key for their DM channel and exchange messages encrypted with
+// it through send_encrypted_dm; the canister only ever sees ciphertext.
+
+// Domain separator so DM keys can never collide with journal keys
+const DM_KEY_CONTEXT: &[u8] = b"lain_dm";
+
+// Largest accepted ciphertext per encrypted DM
+const MAX_ENCRYPTED_DM_BYTES: usize = 16 * 1024;
+
+/// vetKD input for the channel between `a` and `b`: both full principals, length-prefixed in
+/// sorted order. Channel ids only keep principal prefixes, so they are not used
+fn dm_key_input(a: Principal, b: Principal) -> Vec<u8> {
+    let (first, second) = if a.as_slice() <= b.as_slice() { (a, b) } else { (b, a) };
+    let mut input = Vec::with_capacity(2 + first.as_slice().len() + second.as_slice().len());
+    for principal in [first, second] {
+        input.push(principal.as_slice().len() as u8);
+        input.extend_from_slice(principal.as_slice());
+    }
+    input
+}
+
+/// Public key clients use to verify keys returned by get_encrypted_dm_key
+#[update]
+async fn get_dm_verification_key() -> ApiResponse<Vec<u8>> {
+    if let Some(rejection) = reject_key_request(caller()) {
+        return rejection;
+    }
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: DM_KEY_CONTEXT.to_vec(),
+        key_id: vetkd_key_id(),
+    };
+    
+    let result: Result<(VetKdPublicKeyReply,), _> =
+        ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,)).await;
+    
+    match result {
+        Ok((reply,)) => ApiResponse::success(reply.public_key),
+        Err((code, msg)) => errors::coded("key_service_failed", &[("detail", format!("vetkd_public_key: {:?} {}", code, msg))]),
+    }
+}
+
+/// The key for one of the caller's DM channels, encrypted under `transport_public_key`. Both
+/// friends in the channel derive the same key; nobody else can
+#[update]
+async fn get_encrypted_dm_key(dm_channel_id: String, transport_public_key: Vec<u8>) -> ApiResponse<Vec<u8>> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_key_request(caller_principal) {
+        return rejection;
+    }
+    let Some(partner) = dm_channel_partner(caller_principal, &dm_channel_id) else {
+        return errors::coded("conversation_not_found", &[]);
+    };
+    if is_blocked_either_way(caller_principal, partner) {
+        return errors::coded("dm_blocked", &[]);
+    }
+    
+    let args = VetKdDeriveKeyArgs {
+        input: dm_key_input(caller_principal, partner),
+        context: DM_KEY_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: vetkd_key_id(),
+    };
+    
+    let result: Result<(VetKdDeriveKeyReply,), _> = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_CYCLES,
+    ).await;
+    
+    match result {
+        Ok((reply,)) => ApiResponse::success(reply.encrypted_key),
+        Err((code, msg)) => errors::coded("key_service_failed", &[("detail", format!("vetkd_derive_key: {:?} {}", code, msg))]),
+    }
+}
+
+// ============ MODERATION METHODS ============
+
+// Words of message text kept on each side of a watch term match
+const ALERT_CONTEXT_WORDS: usize = 8;
+
+// Moderator notifications per page
+const DEFAULT_MODERATOR_NOTIFICATION_PAGE: u32 = 50;
+const MAX_MODERATOR_NOTIFICATION_PAGE: u32 = 200;
+
+fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+        || storage::ADMINS.with(|admins| admins.borrow().contains_key(principal))
+}
+
+fn is_moderator(principal: &Principal) -> bool {
+    is_admin(principal)
+        || storage::MODERATORS.with(|mods| mods.borrow().contains_key(principal))
+}
+
+/// Lowercase words with surrounding punctuation stripped; used for both terms and messages
+fn normalize_words(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .collect()
+}
+
+fn with_watch_term_index<R>(f: impl FnOnce(&HashMap<String, Vec<Vec<String>>>) -> R) -> R {
+    storage::WATCH_TERM_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let index = index.get_or_insert_with(|| {
+            let mut built: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+            storage::WATCH_TERMS.with(|terms| {
+                for (key, _) in terms.borrow().iter() {
+                    let words: Vec<String> = key.split(' ').map(str::to_string).collect();
+                    built.entry(words[0].clone()).or_default().push(words);
+                }
+            });
+            built
+        });
+        f(index)
+    })
+}
+
+/// Write a moderator notification for every watch term found in `text`
+fn raise_watch_term_alerts(text: &str, source: AlertSource, author: Principal, message_id: &str) {
+    let original: Vec<&str> = text.split_whitespace().collect();
+    let words = normalize_words(text);
+    
+    // (term, index of first matching word, term length in words)
+    let matches: Vec<(String, usize, usize)> = with_watch_term_index(|index| {
+        let mut found = Vec::new();
+        let mut seen = HashSet::new();
+        for (i, word) in words.iter().enumerate() {
+            for term in index.get(word).into_iter().flatten() {
+                if words[i..].starts_with(term) && seen.insert(term.join(" ")) {
+                    found.push((term.join(" "), i, term.len()));
+                }
+            }
+        }
+        found
+    });
+    if matches.is_empty() {
+        return;
+    }
+    
+    let now = ic_cdk::api::time();
+    storage::MODERATOR_NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        for (term, start, len) in matches {
+            let from = start.saturating_sub(ALERT_CONTEXT_WORDS);
+            let to = (start + len + ALERT_CONTEXT_WORDS).min(original.len());
+            let id = notifications.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+            
+            notifications.insert(id, ModeratorNotification {
+                id,
+                term,
+                source: source.clone(),
+                author,
+                message_id: message_id.to_string(),
+                context: original[from..to].join(" "),
+                created_at: now,
+                acknowledged: false,
+            });
+        }
+    });
+}
+
+#[update]
+fn add_moderator(principal: Principal) -> ApiResponse<()> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
+    
+    storage::MODERATORS.with(|mods| {
+        mods.borrow_mut().insert(principal, ic_cdk::api::time());
+    });
+    
+    ApiResponse::success(())
+}
+
+#[update]
+fn remove_moderator(principal: Principal) -> ApiResponse<()> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
+    
+    storage::MODERATORS.with(|mods| {
+        mods.borrow_mut().remove(&principal);
+    });
+    
+    ApiResponse::success(())
+}
+
+#[query]
+fn get_moderators() -> ApiResponse<Vec<Principal>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let moderators = storage::MODERATORS.with(|mods| {
+        mods.borrow().iter().map(|(principal, _)| principal).collect()
+    });
+    
+    ApiResponse::success(moderators)
+}
+
+#[update]
+fn add_watch_term(term: String) -> ApiResponse<WatchTerm> {
+    let caller_principal = caller();
+    if !is_moderator(&caller_principal) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let key = normalize_words(&term)
+        .into_iter()
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ");
+    if key.is_empty() {
+        return errors::coded("watch_term_empty", &[]);
+    }
+    
+    let watch_term = WatchTerm {
+        term: key.clone(),
+        added_by: caller_principal,
+        created_at: ic_cdk::api::time(),
+    };
+    
+    storage::WATCH_TERMS.with(|terms| {
+        terms.borrow_mut().insert(key, watch_term.clone());
+    });
+    storage::WATCH_TERM_INDEX.with(|index| *index.borrow_mut() = None);
+    
+    ApiResponse::success(watch_term)
+}
+
+#[update]
+fn remove_watch_term(term: String) -> ApiResponse<()> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let key = normalize_words(&term)
+        .into_iter()
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ");
+    
+    let removed = storage::WATCH_TERMS.with(|terms| terms.borrow_mut().remove(&key));
+    if removed.is_none() {
+        return errors::coded("watch_term_not_found", &[]);
+    }
+    storage::WATCH_TERM_INDEX.with(|index| *index.borrow_mut() = None);
+    
+    ApiResponse::success(())
+}
+
+#[query]
+fn get_watch_terms() -> ApiResponse<Vec<WatchTerm>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let terms = storage::WATCH_TERMS.with(|terms| {
+        terms.borrow().iter().map(|(_, term)| term).collect()
+    });
+    
+    ApiResponse::success(terms)
+}
+
+/// Watch term alerts, newest first; pass the last id seen as `before_id` for the next page
+#[query]
+fn get_moderator_notifications(limit: Option<u32>, before_id: Option<u64>, include_acknowledged: bool) -> ApiResponse<Vec<ModeratorNotification>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let limit = limit.unwrap_or(DEFAULT_MODERATOR_NOTIFICATION_PAGE).clamp(1, MAX_MODERATOR_NOTIFICATION_PAGE) as usize;
+    let upper = before_id.unwrap_or(u64::MAX);
+    
+    let notifications: Vec<ModeratorNotification> = storage::MODERATOR_NOTIFICATIONS.with(|notifications| {
+        notifications.borrow()
+            .range(..upper)
+            .rev()
+            .map(|(_, notification)| notification)
+            .filter(|notification| include_acknowledged || !notification.acknowledged)
+            .take(limit)
+            .collect()
+    });
+    
+    ApiResponse::success(notifications)
+}
+
+#[update]
+fn acknowledge_moderator_notification(id: u64) -> ApiResponse<()> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    storage::MODERATOR_NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        match notifications.get(&id) {
+            Some(mut notification) => {
+                notification.acknowledged = true;
+                notifications.insert(id, notification);
+                ApiResponse::success(())
+            }
+            None => errors::coded("notification_not_found", &[]),
+        }
+    })
+}
+
+/// Store a report and raise watch term alerts on its details like on any other user text
+fn file_user_report(reporter: Principal, reported: Principal, reason: BlockReason) {
+    let details: Option<String> = reason.details
+        .map(|details| details.trim().chars().take(MAX_REPORT_DETAILS_CHARS).collect())
+        .filter(|details: &String| !details.is_empty());
+    
+    let id = storage::USER_REPORTS.with(|reports| {
+        let mut reports = reports.borrow_mut();
+        let id = reports.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        reports.insert(id, UserReport {
+            id,
+            reporter,
+            reported,
+            category: reason.category,
+            details: details.clone(),
+            created_at: ic_cdk::api::time(),
+            acknowledged: false,
+        });
+        id
+    });
+    
+    if let Some(details) = details {
+        raise_watch_term_alerts(&details, AlertSource::UserReport { reported }, reporter, &id.to_string());
+    }
+}
+
+/// User reports, newest first; pass the last id seen as `before_id` for the next page
+#[query]
+fn get_user_reports(limit: Option<u32>, before_id: Option<u64>, include_acknowledged: bool) -> ApiResponse<Vec<UserReport>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let limit = limit.unwrap_or(DEFAULT_REPORT_PAGE).clamp(1, MAX_REPORT_PAGE) as usize;
+    let upper = before_id.unwrap_or(u64::MAX);
+    
+    let reports: Vec<UserReport> = storage::USER_REPORTS.with(|reports| {
+        reports.borrow()
+            .range(..upper)
+            .rev()
+            .map(|(_, report)| report)
+            .filter(|report| include_acknowledged || !report.acknowledged)
+            .take(limit)
+            .collect()
+    });
+    
+    ApiResponse::success(reports)
+}
+
+#[update]
+fn acknowledge_user_report(id: u64) -> ApiResponse<()> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    storage::USER_REPORTS.with(|reports| {
+        let mut reports = reports.borrow_mut();
+        match reports.get(&id) {
+            Some(mut report) => {
+                report.acknowledged = true;
+                reports.insert(id, report);
+                ApiResponse::success(())
+            }
+            None => errors::coded("report_not_found", &[]),
+        }
+    })
+}
+
+// ============ SLOW MODE METHODS ============
+
+const MAX_SLOW_MODE_SECONDS: u64 = 6 * 60 * 60;
+
+fn slow_mode_seconds(channel_id: &str) -> Option<u64> {
+    storage::SLOW_MODES.with(|modes| modes.borrow().get(&channel_id.to_string()))
+}
+
+fn slow_mode_rejection<T>(channel_id: &str, seconds_remaining: u64) -> ApiResponse<T> {
+    errors::coded("slow_mode_active", &[
+        ("channel", channel_id.to_string()),
+        ("seconds_remaining", seconds_remaining.to_string()),
+    ])
+}
+
+/// Seconds `principal` must still wait before posting in `channel_id` (moderators never wait)
+fn slow_mode_wait(principal: Principal, channel_id: &str, now: u64) -> Option<u64> {
+    let seconds = slow_mode_seconds(channel_id)?;
+    if is_moderator(&principal) {
+        return None;
+    }
+    
+    let last_post = storage::LAST_POSTS.with(|posts| posts.borrow().get(&(principal, channel_id.to_string())))?;
+    let ready_at = last_post + seconds * 1_000_000_000;
+    (now < ready_at).then(|| (ready_at - now).div_ceil(1_000_000_000))
+}
+
+/// Start `principal`'s cooldown in `channel_id` if it is in slow mode
+fn record_slow_mode_post(principal: Principal, channel_id: &str, now: u64) {
+    if slow_mode_seconds(channel_id).is_some() {
+        storage::LAST_POSTS.with(|posts| {
+            posts.borrow_mut().insert((principal, channel_id.to_string()), now);
+        });
+    }
+}
+
+/// Reject a post made before the author's cooldown ran out or over their send rate, otherwise
+/// count it and start a new cooldown. Slow mode is checked first so a post it refuses does not
+/// use up a send
+fn enforce_post_limits<T>(principal: Principal, channel_id: &str, now: u64) -> Option<ApiResponse<T>> {
+    if let Some(seconds_remaining) = slow_mode_wait(principal, channel_id, now) {
+        return Some(slow_mode_rejection(channel_id, seconds_remaining));
+    }
+    if let Some(rejection) = enforce_send_rate(principal, 1, now) {
+        return Some(rejection);
+    }
+    record_slow_mode_post(principal, channel_id, now);
+    None
+}
+
+/// Make each user wait `seconds` between posts in `channel_id`; 0 turns slow mode off
+#[update]
+fn set_channel_slow_mode(channel_id: String, seconds: u64) -> ApiResponse<()> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    if seconds > MAX_SLOW_MODE_SECONDS {
+        return errors::coded("slow_mode_too_long", &[("max_seconds", MAX_SLOW_MODE_SECONDS.to_string())]);
+    }
+    
+    if seconds == 0 {
+        storage::SLOW_MODES.with(|modes| modes.borrow_mut().remove(&channel_id));
+        storage::LAST_POSTS.with(|posts| {
+            let mut posts = posts.borrow_mut();
+            let keys: Vec<(Principal, String)> = posts.iter()
+                .map(|(key, _)| key)
+                .filter(|(_, channel)| *channel == channel_id)
+                .collect();
+            for key in keys {
+                posts.remove(&key);
+            }
+        });
+    } else {
+        storage::SLOW_MODES.with(|modes| modes.borrow_mut().insert(channel_id, seconds));
+    }
+    
+    ApiResponse::success(())
+}
+
+/// Channels with slow mode on, with their cooldown in seconds
+#[query]
+fn get_slow_modes() -> ApiResponse<Vec<(String, u64)>> {
+    ApiResponse::success(storage::SLOW_MODES.with(|modes| modes.borrow().iter().collect()))
+}
+
+// ============ SEND RATE METHODS ============
+
+/// Reject a send once `principal` has used up their rate limit, otherwise count it
+fn enforce_send_rate<T>(principal: Principal, sends: u32, now: u64) -> Option<ApiResponse<T>> {
+    rate_limit::take(principal, sends, now).err().map(|retry_after| {
+        errors::coded("send_rate_limited", &[("retry_after_seconds", retry_after.to_string())])
+    })
+}
+
+/// How many messages the caller can send right now and when the next one frees up
+#[query]
+fn get_send_quota() -> ApiResponse<rate_limit::SendQuota> {
+    ApiResponse::success(rate_limit::quota(caller(), ic_cdk::api::time()))
+}
+
+// ============ LOCALIZATION METHODS ============
+
+// Longest accepted locale tag (e.g. "zh-hant-tw")
+const MAX_LOCALE_LEN: usize = 16;
+
+fn valid_locale(locale: &str) -> bool {
+    !locale.is_empty()
+        && locale.len() <= MAX_LOCALE_LEN
+        && locale.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Locale for the caller's error messages; None reverts to English
+#[update]
+fn set_preferred_locale(locale: Option<String>) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    match locale.map(|l| errors::normalize_locale(&l)) {
+        Some(locale) => {
+            if !valid_locale(&locale) {
+                return errors::coded("invalid_locale", &[("locale", locale)]);
+            }
+            storage::PREFERRED_LOCALES.with(|locales| {
+                locales.borrow_mut().insert(caller_principal, locale);
+            });
+        }
+        None => {
+            storage::PREFERRED_LOCALES.with(|locales| {
+                locales.borrow_mut().remove(&caller_principal);
+            });
+        }
+    }
+    
+    ApiResponse::success(())
+}
+
+/// Error codes with their English templates, for translators
+#[query]
+fn get_error_codes() -> ApiResponse<Vec<(String, String)>> {
+    let codes = errors::BUILTIN_MESSAGES
+        .iter()
+        .map(|(code, message)| (code.to_string(), message.to_string()))
+        .collect();
+    
+    ApiResponse::success(codes)
+}
+
+/// Add or replace (code, template) entries in a locale's catalog
+#[update]
+fn set_message_catalog(locale: String, entries: Vec<(String, String)>) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    let locale = errors::normalize_locale(&locale);
+    if !valid_locale(&locale) {
+        return errors::coded("invalid_locale", &[("locale", locale)]);
+    }
+    if let Some((code, _)) = entries.iter().find(|(code, _)| !errors::BUILTIN_MESSAGES.iter().any(|(c, _)| c == code)) {
+        return errors::coded("unknown_error_code", &[("code", code.clone())]);
+    }
+    
+    storage::MESSAGE_CATALOGS.with(|catalogs| {
+        let mut catalogs = catalogs.borrow_mut();
+        for (code, template) in entries {
+            catalogs.insert((locale.clone(), code), template);
+        }
+    });
+    
+    ApiResponse::success(())
+}
+
+#[update]
+fn clear_message_catalog(locale: String) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    let locale = errors::normalize_locale(&locale);
+    storage::MESSAGE_CATALOGS.with(|catalogs| {
+        let mut catalogs = catalogs.borrow_mut();
+        let keys: Vec<(String, String)> = catalogs
+            .range((locale.clone(), String::new())..)
+            .take_while(|((entry_locale, _), _)| *entry_locale == locale)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            catalogs.remove(&key);
+        }
+    });
+    
+    ApiResponse::success(())
+}
+
+/// Stored (code, template) entries for a locale; codes without an entry fall back to English
+#[query]
+fn get_message_catalog(locale: String) -> ApiResponse<Vec<(String, String)>> {
+    let locale = errors::normalize_locale(&locale);
+    
+    let entries = storage::MESSAGE_CATALOGS.with(|catalogs| {
+        catalogs.borrow()
+            .range((locale.clone(), String::new())..)
+            .take_while(|((entry_locale, _), _)| *entry_locale == locale)
+            .map(|((_, code), template)| (code, template))
+            .collect()
+    });
+    
+    ApiResponse::success(entries)
+}
+
+// ============ API VERSION METHODS ============
+
+/// Current public API version, bumped whenever a method signature or response shape changes
+const API_VERSION: u32 = 2;
+
+/// Oldest API version whose methods are still served through `_v1` wrappers
+const MIN_SUPPORTED_API_VERSION: u32 = 1;
+
+/// Legacy methods kept for old clients: (method, replacement, sunset timestamp in ns)
+const DEPRECATED_METHODS: &[(&str, &str, u64)] = &[
+    // 2027-04-01T00:00:00Z
+    ("search_users_v1", "search_users", 1_806_537_600_000_000_000),
+    // 2027-10-01T00:00:00Z
+    ("get_all_users", "list_users", 1_822_348_800_000_000_000),
+    ("get_all_users_encoded", "list_users", 1_822_348_800_000_000_000),
+];
+
+/// Tag a legacy method's response with its deprecation notice
+fn deprecated<T>(method: &str, response: ApiResponse<T>) -> ApiResponse<T> {
+    match DEPRECATED_METHODS.iter().find(|(name, _, _)| *name == method) {
+        Some((_, replacement, sunset_at)) => response.with_deprecation(DeprecationNotice {
+            message: format!("'{}' is deprecated, use '{}' instead", method, replacement),
+            replacement: Some(replacement.to_string()),
+            sunset_at: *sunset_at,
+        }),
+        None => response,
+    }
+}
+
+#[query]
+fn api_version() -> ApiResponse<ApiVersionInfo> {
+    let deprecated_methods = DEPRECATED_METHODS
+        .iter()
+        .map(|(method, replacement, sunset_at)| DeprecatedMethod {
+            method: method.to_string(),
+            replacement: Some(replacement.to_string()),
+            sunset_at: *sunset_at,
+        })
+        .collect();
+    
+    ApiResponse::success(ApiVersionInfo {
+        version: API_VERSION,
+        min_supported_version: MIN_SUPPORTED_API_VERSION,
+        deprecated_methods,
+    })
+}
+
+// ============ QUERY DIAGNOSTICS METHODS ============
+
+// While diagnostics are on, heavy reads report what they cost and which path answered them,
+// so the frontend can choose between query flavors (certified or fast) and reads worth
+// turning into composite queries stand out. Off by default; it is meant for development.
+
+fn query_diagnostics_enabled() -> bool {
+    storage::NUMERIC_SETTINGS.with(|settings| settings.borrow().get(&storage::QUERY_DIAGNOSTICS_SETTING)) == Some(1)
+}
+
+/// Tag a heavy read's response with its cost, when diagnostics are on
+fn diagnosed<T>(method: &str, response: ApiResponse<T>) -> ApiResponse<T> {
+    if !query_diagnostics_enabled() {
+        return response;
+    }
+    response.with_diagnostics(QueryDiagnostics {
+        method: method.to_string(),
+        instructions: ic_cdk::api::performance_counter(0),
+        // Only non-replicated queries get a data certificate
+        replicated: ic_cdk::api::data_certificate().is_none(),
+        // None of the reads are composite queries yet
+        composite: false,
+    })
+}
+
+/// HTTP counterpart of `diagnosed`: the cost and path go in X-Query-Instructions and
+/// X-Query-Path (certified, uncertified or replicated) headers
+fn diagnosed_http(mut response: http::HttpResponse) -> http::HttpResponse {
+    if !query_diagnostics_enabled() || response.upgrade == Some(true) {
+        return response;
+    }
+    let path = if response.headers.iter().any(|(name, _)| name == "IC-Certificate") {
+        "certified"
+    } else if ic_cdk::api::data_certificate().is_some() {
+        "uncertified"
+    } else {
+        "replicated"
+    };
+    response.headers.push(("X-Query-Instructions".to_string(), ic_cdk::api::performance_counter(0).to_string()));
+    response.headers.push(("X-Query-Path".to_string(), path.to_string()));
+    response
+}
+
+#[update]
+fn set_query_diagnostics(enabled: bool) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    storage::NUMERIC_SETTINGS.with(|settings| {
+        settings.borrow_mut().insert(storage::QUERY_DIAGNOSTICS_SETTING, enabled as u64);
+    });
+    
+    ApiResponse::success(())
+}
+
+#[query]
+fn get_query_diagnostics() -> ApiResponse<bool> {
+    ApiResponse::success(query_diagnostics_enabled())
+}
+
+// ============ HEALTH METHODS ============
+
+/// Round-trip a value through the scratch region to prove stable memory is writable and readable
+fn stable_self_test(now: u64) -> bool {
+    storage::SCRATCH.with(|scratch| {
+        let mut scratch = scratch.borrow_mut();
+        scratch.insert(0, now);
+        let ok = scratch.get(&0) == Some(now);
+        scratch.remove(&0);
+        ok
+    })
+}
+
+#[query]
+fn health() -> ApiResponse<HealthStatus> {
+    let now = ic_cdk::api::time();
+    
+    let store_counts = vec![
+        ("user_profiles".to_string(), storage::USER_PROFILES.with(|m| m.borrow().len())),
+        ("friends".to_string(), storage::FRIENDS.with(|m| m.borrow().len())),
+        ("friend_requests".to_string(), storage::FRIEND_REQUESTS.with(|m| m.borrow().len())),
+        ("blocked_users".to_string(), storage::BLOCKED_USERS.with(|m| m.borrow().len())),
+        ("user_data_sync".to_string(), storage::USER_DATA_SYNC.with(|m| m.borrow().len())),
+        ("dm_channels".to_string(), storage::DM_MESSAGES.with(|m| m.borrow().len())),
+        ("journal_entries".to_string(), storage::JOURNAL_ENTRIES.with(|m| m.borrow().len())),
+        ("app_grants".to_string(), storage::APP_GRANTS.with(|m| m.borrow().len())),
+        ("polls".to_string(), storage::POLLS.with(|m| m.borrow().len())),
+        ("events".to_string(), storage::EVENTS.with(|m| m.borrow().len())),
+        ("groups".to_string(), storage::GROUPS.with(|m| m.borrow().len())),
+        ("public_profiles".to_string(), storage::PUBLIC_PROFILES.with(|m| m.borrow().len())),
+        ("trust_records".to_string(), storage::TRUST_RECORDS.with(|m| m.borrow().len())),
+        ("unread_counts".to_string(), storage::UNREAD_COUNTS.with(|m| m.borrow().len())),
+        ("conversation_mutes".to_string(), storage::CONVERSATION_MUTES.with(|m| m.borrow().len())),
+        ("disappearing_channels".to_string(), storage::DISAPPEARING_CHANNELS.with(|m| m.borrow().len())),
+        ("auto_accept_policies".to_string(), storage::AUTO_ACCEPT_POLICIES.with(|m| m.borrow().len())),
+        ("channel_messages".to_string(), storage::CHANNEL_MESSAGES.with(|m| m.borrow().len())),
+        ("history_sharing_consent".to_string(), storage::HISTORY_SHARING_CONSENT.with(|m| m.borrow().len())),
+        ("archived_channels".to_string(), storage::ARCHIVED_CHANNELS.with(|m| m.borrow().len())),
+        ("drafts".to_string(), storage::DRAFTS.with(|m| m.borrow().len())),
+        ("message_stars".to_string(), storage::MESSAGE_STARS.with(|m| m.borrow().len())),
+        ("privacy_settings".to_string(), storage::PRIVACY_SETTINGS.with(|m| m.borrow().len())),
+        ("user_reports".to_string(), storage::USER_REPORTS.with(|m| m.borrow().len())),
+        ("admins".to_string(), storage::ADMINS.with(|m| m.borrow().len())),
+        ("bans".to_string(), storage::BANS.with(|m| m.borrow().len())),
+        ("activity_log".to_string(), storage::ACTIVITY_LOG.with(|m| m.borrow().len())),
+        ("following".to_string(), storage::FOLLOWING.with(|m| m.borrow().len())),
+        ("follow_requests".to_string(), storage::FOLLOW_REQUESTS.with(|m| m.borrow().len())),
+        ("handles".to_string(), storage::HANDLES.with(|m| m.borrow().len())),
+        ("display_names".to_string(), storage::DISPLAY_NAMES.with(|m| m.borrow().len())),
+        ("reserved_names".to_string(), storage::RESERVED_NAMES.with(|m| m.borrow().len())),
+        ("devices".to_string(), storage::DEVICES.with(|m| m.borrow().len())),
+        ("invite_codes".to_string(), storage::INVITE_CODES.with(|m| m.borrow().len())),
+        ("custom_emoji".to_string(), storage::CUSTOM_EMOJI.with(|m| m.borrow().len())),
+        ("attachments".to_string(), storage::ATTACHMENTS.with(|m| m.borrow().len())),
+        ("attachment_chunks".to_string(), storage::ATTACHMENT_CHUNKS.with(|m| m.borrow().len())),
+        ("user_notifications".to_string(), storage::USER_NOTIFICATIONS.with(|m| m.borrow().len())),
+        ("outbox".to_string(), storage::OUTBOX.with(|m| m.borrow().len())),
+        ("dead_letters".to_string(), storage::DEAD_LETTERS.with(|m| m.borrow().len())),
+    ];
+    
+    ApiResponse::success(HealthStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("GIT_COMMIT").unwrap_or("unknown").to_string(),
+        schema_version: storage::SCHEMA_VERSION,
+        store_counts,
+        self_test_ok: stable_self_test(now),
+        checked_at: now,
+    })
+}
+
+const PAYLOAD_MEASURE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How close the large reads are to the response size ceiling, from the last hourly measurement
+#[query]
+fn get_payload_metrics() -> ApiResponse<payload_size::PayloadMetrics> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    ApiResponse::success(payload_size::metrics())
+}
+
+// ============ BUILD INFO METHODS ============
+
+// Lets operators check a deployed canister against the audited source: rebuild the tagged
+// commit with the reported toolchain, features and SOURCE_DATE_EPOCH and compare module hashes.
+
+/// Comma-separated list recorded by build.rs, empty entries dropped
+fn build_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').filter(|item| !item.is_empty())
+}
+
+#[query]
+fn build_info() -> ApiResponse<BuildInfo> {
+    ApiResponse::success(BuildInfo {
+        package_version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("GIT_COMMIT").unwrap_or("unknown").to_string(),
+        rustc_version: env!("BUILD_RUSTC_VERSION").to_string(),
+        target: env!("BUILD_TARGET").to_string(),
+        profile: env!("BUILD_PROFILE").to_string(),
+        features: build_list(env!("BUILD_FEATURES")).map(str::to_string).collect(),
+        dependencies: build_list(env!("BUILD_DEPENDENCIES"))
+            .filter_map(|dependency| dependency.split_once('='))
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect(),
+        build_timestamp: option_env!("BUILD_TIMESTAMP").and_then(|epoch| epoch.parse().ok()),
+        wasm_module_hash: storage::MODULE_HASH.with(|hash| hash.borrow().clone()),
+    })
+}
+
+/// Look up the installed module's hash through the management canister and cache it
+async fn fetch_module_hash() {
+    let request = ic_cdk::api::management_canister::main::CanisterInfoRequest {
+        canister_id: ic_cdk::id(),
+        num_requested_changes: None,
+    };
+    match ic_cdk::api::management_canister::main::canister_info(request).await {
+        Ok((info,)) => {
+            let hash = info.module_hash.map(|hash| hash.iter().map(|byte| format!("{:02x}", byte)).collect());
+            storage::MODULE_HASH.with(|cached| *cached.borrow_mut() = hash);
+        }
+        Err((code, message)) => ic_cdk::println!("module hash lookup failed: {:?} {}", code, message),
+    }
+}
+
+fn schedule_module_hash_lookup() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(fetch_module_hash()));
+}
+
+// ============ SUSPENSION METHODS ============
+
+// Suspended accounts are rejected from calls that create content or reach out to others.
+// Calls that only reduce contact (remove_friend, reject_friend_request, block_user, ...) and
+// deleting one's own journal entries stay available.
+
+/// The caller's suspension, if one is in force (expired suspensions are ignored)
+fn active_suspension(principal: &Principal) -> Option<Suspension> {
+    let now = ic_cdk::api::time();
+    storage::SUSPENSIONS.with(|suspensions| suspensions.borrow().get(principal))
+        .filter(|suspension| suspension.until.is_none_or(|until| until > now))
+}
+
+/// Tell the AI canister that `principal` was suspended (or that the suspension ended) so it
+/// can stop serving the account too. Goes through the outbox like friend request outcomes
+fn share_suspension(principal: Principal, suspension: Option<Suspension>) {
+    let Some(ai_canister) = storage::SETTINGS.with(|settings| settings.borrow().get(&storage::AI_CANISTER_SETTING)) else {
+        return;
+    };
+    
+    match candid::encode_args((principal, suspension)) {
+        Ok(args) => queue_notification(ai_canister, "apply_suspension", args),
+        Err(err) => ic_cdk::println!("suspension of {} not encoded: {}", principal, err),
+    }
+}
+
+/// Refuse calls from the anonymous principal, which any unauthenticated agent can use, so no
+/// state ends up owned by it
+fn reject_if_anonymous<T>(principal: &Principal) -> Option<ApiResponse<T>> {
+    (*principal == Principal::anonymous()).then(|| errors::coded("not_authenticated", &[]))
+}
+
+fn reject_if_suspended<T>(principal: &Principal) -> Option<ApiResponse<T>> {
+    if let Some(rejection) = reject_if_anonymous(principal) {
+        return Some(rejection);
+    }
+    if let Some(ban) = active_ban(principal) {
+        return Some(errors::coded("account_banned", &[("reason", ban.reason)]));
+    }
+    active_suspension(principal).map(|suspension| {
+        errors::coded("account_suspended", &[("reason", suspension.reason.clone())]).with_suspension(suspension)
+    })
+}
+
+#[update]
+fn suspend_account(principal: Principal, reason: String, until: Option<u64>) -> ApiResponse<Suspension> {
+    let caller_principal = caller();
+    if !is_moderator(&caller_principal) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    if until.is_some_and(|until| until <= now) {
+        return errors::coded("suspension_end_in_past", &[]);
+    }
+    if reason.trim().is_empty() {
+        return errors::coded("suspension_reason_required", &[]);
+    }
+    
+    let suspension = Suspension {
+        principal,
+        reason: reason.trim().to_string(),
+        suspended_by: caller_principal,
+        suspended_at: now,
+        until,
+    };
+    
+    storage::SUSPENSIONS.with(|suspensions| {
+        suspensions.borrow_mut().insert(principal, suspension.clone());
+    });
+    if is_listed(&principal) {
+        schedule_directory_refresh();
+    }
+    share_suspension(principal, Some(suspension.clone()));
+    
+    ApiResponse::success(suspension)
+}
+
+#[update]
+fn lift_suspension(principal: Principal) -> ApiResponse<()> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    match storage::SUSPENSIONS.with(|suspensions| suspensions.borrow_mut().remove(&principal)) {
+        Some(_) => {
+            if is_listed(&principal) {
+                schedule_directory_refresh();
+            }
+            share_suspension(principal, None);
+            ApiResponse::success(())
+        }
+        None => errors::coded("account_not_suspended", &[]),
+    }
+}
+
+/// The caller's active suspension, or None
+#[query]
+fn get_my_suspension() -> ApiResponse<Option<Suspension>> {
+    ApiResponse::success(active_suspension(&caller()))
+}
+
+// Longest appeal message moderators are asked to read
+const MAX_APPEAL_MESSAGE_CHARS: usize = 2_000;
+
+// Appeals per moderator page
+const DEFAULT_APPEAL_PAGE: u32 = 50;
+const MAX_APPEAL_PAGE: u32 = 200;
+
+/// Fill the latest-appeal index from existing appeals after the upgrade that introduced it
+fn index_latest_appeals() {
+    if !storage::LATEST_APPEALS.with(|latest| latest.borrow().is_empty()) {
+        return;
+    }
+    storage::APPEALS.with(|appeals| {
+        storage::LATEST_APPEALS.with(|latest| {
+            let mut latest = latest.borrow_mut();
+            for (id, appeal) in appeals.borrow().iter() {
+                latest.insert(appeal.principal, id);
+            }
+        });
+    });
+}
+
+/// Ask moderators to review the caller's suspension. One appeal per suspension: a second is
+/// refused while the first is pending and after it has been decided
+#[update]
+fn submit_appeal(message: String) -> ApiResponse<Appeal> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    let Some(suspension) = active_suspension(&caller_principal) else {
+        return errors::coded("account_not_suspended", &[]);
+    };
+    
+    let message = message.trim().to_string();
+    if message.is_empty() {
+        return errors::coded("appeal_message_empty", &[]);
+    }
+    if message.chars().count() > MAX_APPEAL_MESSAGE_CHARS {
+        return errors::coded("appeal_message_too_long", &[("max", MAX_APPEAL_MESSAGE_CHARS.to_string())]);
+    }
+    
+    let latest = storage::LATEST_APPEALS.with(|latest| latest.borrow().get(&caller_principal))
+        .and_then(|id| storage::APPEALS.with(|appeals| appeals.borrow().get(&id)));
+    if let Some(latest) = latest {
+        if latest.status == AppealStatus::Pending {
+            return errors::coded("appeal_already_pending", &[]);
+        }
+        if latest.submitted_at >= suspension.suspended_at {
+            return errors::coded("appeal_already_decided", &[]);
+        }
+    }
+    
+    storage::APPEALS.with(|appeals| {
+        let mut appeals = appeals.borrow_mut();
+        let id = appeals.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        let appeal = Appeal {
+            id,
+            principal: caller_principal,
+            suspension_reason: suspension.reason,
+            message,
+            status: AppealStatus::Pending,
+            submitted_at: ic_cdk::api::time(),
+            reviewed_by: None,
+            reviewed_at: None,
+            review_note: None,
+        };
+        appeals.insert(id, appeal.clone());
+        storage::LATEST_APPEALS.with(|latest| latest.borrow_mut().insert(caller_principal, id));
+        
+        ApiResponse::success(appeal)
+    })
+}
+
+/// Appeals for moderators to review, oldest first; filter by status or get all. Pass the
+/// last id seen as `after_id` for the next page
+#[query]
+fn get_appeals(status: Option<AppealStatus>, limit: Option<u32>, after_id: Option<u64>) -> ApiResponse<Vec<Appeal>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let limit = limit.unwrap_or(DEFAULT_APPEAL_PAGE).clamp(1, MAX_APPEAL_PAGE) as usize;
+    let start = after_id.map_or(Bound::Unbounded, Bound::Excluded);
+    
+    let appeals = storage::APPEALS.with(|appeals| {
+        appeals.borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, appeal)| appeal)
+            .filter(|appeal| status.as_ref().is_none_or(|s| appeal.status == *s))
+            .take(limit)
+            .collect()
+    });
+    
+    ApiResponse::success(appeals)
+}
+
+/// Decide a pending appeal; overturning it lifts the suspension
+#[update]
+fn review_appeal(appeal_id: u64, overturn: bool, note: Option<String>) -> ApiResponse<Appeal> {
+    let reviewer = caller();
+    if !is_moderator(&reviewer) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let Some(mut appeal) = storage::APPEALS.with(|appeals| appeals.borrow().get(&appeal_id)) else {
+        return errors::coded("appeal_not_found", &[]);
+    };
+    if appeal.status != AppealStatus::Pending {
+        return errors::coded("appeal_already_reviewed", &[]);
+    }
+    
+    appeal.status = if overturn { AppealStatus::Overturned } else { AppealStatus::Upheld };
+    appeal.reviewed_by = Some(reviewer);
+    appeal.reviewed_at = Some(ic_cdk::api::time());
+    appeal.review_note = note;
+    
+    if overturn {
+        storage::SUSPENSIONS.with(|suspensions| {
+            suspensions.borrow_mut().remove(&appeal.principal);
+        });
+        share_suspension(appeal.principal, None);
+    }
+    storage::APPEALS.with(|appeals| {
+        appeals.borrow_mut().insert(appeal_id, appeal.clone());
+    });
+    
+    ApiResponse::success(appeal)
+}
+
+// ============ ROLE AND BAN METHODS ============
+
+// Admins manage staff and bans; moderators handle day-to-day moderation (watch terms,
+// reports, suspensions, appeals). Only controllers can make admins.
+//
+// A banned account's update calls are turned away before they run by `inspect_message`.
+// That check runs on a single replica and does not cover calls from other canisters, so
+// reject_if_suspended turns banned accounts away again inside the calls that create content.
+
+fn active_ban(principal: &Principal) -> Option<Ban> {
+    storage::BANS.with(|bans| bans.borrow().get(principal))
+}
+
+fn is_banned(principal: &Principal) -> bool {
+    storage::BANS.with(|bans| bans.borrow().contains_key(principal))
+}
+
+#[inspect_message]
+fn inspect_message() {
+    if !is_banned(&caller()) {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+/// Give `principal` a staff role. Admins are made by controllers, moderators by admins
+#[update]
+fn grant_role(principal: Principal, role: Role) -> ApiResponse<()> {
+    let caller_principal = caller();
+    let now = ic_cdk::api::time();
+    
+    match role {
+        Role::Admin => {
+            if !ic_cdk::api::is_controller(&caller_principal) {
+                return errors::coded("unauthorized_controller", &[]);
+            }
+            storage::ADMINS.with(|admins| admins.borrow_mut().insert(principal, now));
+        }
+        Role::Moderator => {
+            if !is_admin(&caller_principal) {
+                return errors::coded("unauthorized_admin", &[]);
+            }
+            storage::MODERATORS.with(|mods| mods.borrow_mut().insert(principal, now));
+        }
+    }
+    
+    ApiResponse::success(())
+}
+
+#[update]
+fn revoke_role(principal: Principal, role: Role) -> ApiResponse<()> {
+    let caller_principal = caller();
+    
+    let removed = match role {
+        Role::Admin => {
+            if !ic_cdk::api::is_controller(&caller_principal) {
+                return errors::coded("unauthorized_controller", &[]);
+            }
+            storage::ADMINS.with(|admins| admins.borrow_mut().remove(&principal))
+        }
+        Role::Moderator => {
+            if !is_admin(&caller_principal) {
+                return errors::coded("unauthorized_admin", &[]);
+            }
+            storage::MODERATORS.with(|mods| mods.borrow_mut().remove(&principal))
+        }
+    };
+    if removed.is_none() {
+        return errors::coded("role_not_held", &[]);
+    }
+    
+    ApiResponse::success(())
+}
+
+/// Granted roles (controllers are admins without an entry)
+#[query]
+fn get_roles() -> ApiResponse<Vec<RoleAssignment>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let mut roles: Vec<RoleAssignment> = storage::ADMINS.with(|admins| {
+        admins.borrow()
+            .iter()
+            .map(|(principal, granted_at)| RoleAssignment { principal, role: Role::Admin, granted_at })
+            .collect()
+    });
+    storage::MODERATORS.with(|mods| {
+        roles.extend(mods.borrow()
+            .iter()
+            .map(|(principal, granted_at)| RoleAssignment { principal, role: Role::Moderator, granted_at }));
+    });
+    
+    ApiResponse::success(roles)
+}
+
+/// The caller's highest role, or None
+#[query]
+fn get_my_role() -> ApiResponse<Option<Role>> {
+    let caller_principal = caller();
+    let role = if is_admin(&caller_principal) {
+        Some(Role::Admin)
+    } else if is_moderator(&caller_principal) {
+        Some(Role::Moderator)
+    } else {
+        None
+    };
+    
+    ApiResponse::success(role)
+}
+
+/// Ban an account until lifted: its update calls are rejected and its profile leaves search
+/// and the public directory
+#[update]
+fn ban_user(principal: Principal, reason: String) -> ApiResponse<Ban> {
+    let caller_principal = caller();
+    if !is_admin(&caller_principal) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
+    if is_moderator(&principal) {
+        return errors::coded("cannot_ban_staff", &[]);
+    }
+    if reason.trim().is_empty() {
+        return errors::coded("ban_reason_required", &[]);
+    }
+    
+    let ban = Ban {
+        principal,
+        reason: reason.trim().to_string(),
+        banned_by: caller_principal,
+        banned_at: ic_cdk::api::time(),
+    };
+    storage::BANS.with(|bans| bans.borrow_mut().insert(principal, ban.clone()));
+    if is_listed(&principal) {
+        schedule_directory_refresh();
+    }
+    // The AI canister knows only suspensions; a ban is one without an end
+    share_suspension(principal, Some(Suspension {
+        principal,
+        reason: ban.reason.clone(),
+        suspended_by: caller_principal,
+        suspended_at: ban.banned_at,
+        until: None,
+    }));
+    
+    ApiResponse::success(ban)
+}
+
+#[update]
+fn unban_user(principal: Principal) -> ApiResponse<()> {
+    if !is_admin(&caller()) {
+        return errors::coded("unauthorized_admin", &[]);
+    }
+    
+    if storage::BANS.with(|bans| bans.borrow_mut().remove(&principal)).is_none() {
+        return errors::coded("account_not_banned", &[]);
+    }
+    if is_listed(&principal) {
+        schedule_directory_refresh();
+    }
+    share_suspension(principal, active_suspension(&principal));
+    
+    ApiResponse::success(())
+}
+
+#[query]
+fn get_bans() -> ApiResponse<Vec<Ban>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let bans = storage::BANS.with(|bans| bans.borrow().iter().map(|(_, ban)| ban).collect());
+    
+    ApiResponse::success(bans)
+}
+
+// ============ TRUST METHODS ============
+
+// Trust tiers let abuse detected elsewhere (AI moderation flags reported by a trusted
+// canister) or by moderators limit an account short of suspending it. Abuse signals only
+// ever lower trust; raising it back is a moderator decision. Every change keeps its reason.
+
+// Distinct moderators flagging an account within the AI canister's window at which it drops
+// to each tier
+const LIMITED_FLAG_THRESHOLD: u32 = 3;
+const RESTRICTED_FLAG_THRESHOLD: u32 = 6;
+
+// Oldest tier changes are dropped beyond this many per account
+const MAX_TRUST_HISTORY: usize = 50;
+
+fn trust_tier(principal: &Principal) -> TrustTier {
+    storage::TRUST_RECORDS.with(|records| records.borrow().get(principal))
+        .map(|record| record.tier)
+        .unwrap_or(TrustTier::Standard)
+}
+
+fn change_trust_tier(principal: Principal, tier: TrustTier, reason: String, source: TrustSource) -> TrustRecord {
+    storage::TRUST_RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        let mut record = records.get(&principal).unwrap_or(TrustRecord {
+            principal,
+            tier: TrustTier::Standard,
+            history: Vec::new(),
+        });
+        
+        record.tier = tier;
+        record.history.push(TrustChange { tier, reason, source, changed_at: ic_cdk::api::time() });
+        if record.history.len() > MAX_TRUST_HISTORY {
+            let excess = record.history.len() - MAX_TRUST_HISTORY;
+            record.history.drain(..excess);
+        }
+        records.insert(principal, record.clone());
+        record
+    })
+}
+
+/// AI moderation flags for `user` from `flag_count` different moderators, reported by a
+/// trusted canister. Lowers the user's tier once the count crosses a threshold; returns the
+/// tier in force afterwards
+#[update]
+fn report_abuse_signal(user: Principal, flag_count: u32, reasons: Vec<String>) -> ApiResponse<TrustTier> {
+    if let Some(rejection) = reject_untrusted_canister() {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&user)) {
+        return errors::coded("user_not_found", &[]);
+    }
+    
+    let signalled = if flag_count >= RESTRICTED_FLAG_THRESHOLD {
+        TrustTier::Restricted
+    } else if flag_count >= LIMITED_FLAG_THRESHOLD {
+        TrustTier::Limited
+    } else {
+        TrustTier::Standard
+    };
+    let current = trust_tier(&user);
+    if signalled <= current {
+        return ApiResponse::success(current);
+    }
+    
+    let reason = format!("AI moderation flags from {} moderators: {}", flag_count, reasons.join("; "));
+    let record = change_trust_tier(user, signalled, reason, TrustSource::Canister(caller()));
+    ApiResponse::success(record.tier)
+}
+
+/// Set an account's tier by hand, e.g. to restore trust after reviewing AI flags
+#[update]
+fn set_trust_tier(user: Principal, tier: TrustTier, reason: String) -> ApiResponse<TrustRecord> {
+    let moderator = caller();
+    if !is_moderator(&moderator) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return errors::coded("trust_reason_required", &[]);
+    }
+    
+    ApiResponse::success(change_trust_tier(user, tier, reason, TrustSource::Moderator(moderator)))
+}
+
+/// An account's tier and the reasoning behind each change
+#[query]
+fn get_trust_record(user: Principal) -> ApiResponse<TrustRecord> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let record = storage::TRUST_RECORDS.with(|records| records.borrow().get(&user))
+        .unwrap_or(TrustRecord { principal: user, tier: TrustTier::Standard, history: Vec::new() });
+    ApiResponse::success(record)
+}
+
+/// Accounts below Standard trust, optionally only those at `tier`
+#[query]
+fn get_reduced_trust_accounts(tier: Option<TrustTier>) -> ApiResponse<Vec<TrustRecord>> {
+    if !is_moderator(&caller()) {
+        return errors::coded("unauthorized_moderator", &[]);
+    }
+    
+    let records = storage::TRUST_RECORDS.with(|records| {
+        records.borrow()
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| record.tier != TrustTier::Standard)
+            .filter(|record| tier.is_none_or(|tier| record.tier == tier))
+            .collect()
+    });
+    ApiResponse::success(records)
+}
+
+// ============ MINI-APP ACCESS METHODS ============
+
+// Mini-apps (games, polls, ...) call the app_* methods as their own principal and only see
+// what the user named in the call has granted them. Grants are replaced on re-grant and kept
+// with revoked_at set once revoked.
+
+/// Rejection unless `user` has granted the calling app `scope` and the grant is still in force
+fn reject_without_app_scope<T>(user: Principal, scope: AppScope) -> Option<ApiResponse<T>> {
+    let app = caller();
+    let grant = storage::APP_GRANTS.with(|grants| grants.borrow().get(&(user, app)));
+    match grant {
+        Some(grant) if grant.allows(scope, ic_cdk::api::time()) => None,
+        _ => Some(errors::coded("app_scope_not_granted", &[("scope", scope.as_str().to_string())])),
+    }
+}
+
+/// Let `app` read the given scopes of the caller's data until `expires_at` (or until revoked)
+#[update]
+fn grant_app_access(app: Principal, app_name: String, scopes: Vec<AppScope>, expires_at: Option<u64>) -> ApiResponse<AppGrant> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    if app == caller_principal {
+        return errors::coded("app_grant_to_self", &[]);
+    }
+    
+    let app_name = app_name.trim().to_string();
+    if app_name.is_empty() {
+        return errors::coded("app_name_required", &[]);
+    }
+    
+    let mut unique_scopes: Vec<AppScope> = Vec::new();
+    for scope in scopes {
+        if !unique_scopes.contains(&scope) {
+            unique_scopes.push(scope);
+        }
+    }
+    if unique_scopes.is_empty() {
+        return errors::coded("app_scopes_required", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return errors::coded("app_grant_expiry_in_past", &[]);
+    }
+    
+    let grant = AppGrant {
+        user: caller_principal,
+        app,
+        app_name,
+        scopes: unique_scopes,
+        granted_at: now,
+        expires_at,
+        revoked_at: None,
+    };
+    
+    storage::APP_GRANTS.with(|grants| {
+        grants.borrow_mut().insert((caller_principal, app), grant.clone());
+    });
+    
+    ApiResponse::success(grant)
+}
+
+#[update]
+fn revoke_app_access(app: Principal) -> ApiResponse<()> {
+    let caller_principal = caller();
+    if let Some(rejection) = reject_if_anonymous(&caller_principal) {
+        return rejection;
+    }
+    
+    storage::APP_GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        match grants.get(&(caller_principal, app)) {
+            Some(mut grant) if grant.revoked_at.is_none() => {
+                grant.revoked_at = Some(ic_cdk::api::time());
+                grants.insert((caller_principal, app), grant);
+                ApiResponse::success(())
+            }
+            _ => errors::coded("app_grant_not_found", &[]),
+        }
+    })
+}
+
+/// Every grant the caller has made, including expired and revoked ones
+#[query]
+fn get_my_app_grants() -> ApiResponse<Vec<AppGrant>> {
+    let caller_principal = caller();
+    
+    let grants = storage::APP_GRANTS.with(|grants| {
+        grants.borrow()
+            .range((caller_principal, Principal::from_slice(&[]))..)
+            .take_while(|((user, _), _)| *user == caller_principal)
+            .map(|(_, grant)| grant)
+            .collect()
+    });
+    
+    ApiResponse::success(grants)
+}
+
+/// `user`'s friends, for an app holding friends-list-read
+#[query]
+fn app_get_friends(user: Principal) -> ApiResponse<Vec<Friend>> {
+    if let Some(rejection) = reject_without_app_scope(user, AppScope::FriendsListRead) {
+        return rejection;
+    }
+    
+    let friends = storage::FRIENDS.with(|friends| {
+        friends.borrow()
+            .range((user, Principal::from_slice(&[]))..)
+            .take_while(|((owner, _), _)| *owner == user)
+            .map(|(_, friend)| friend)
+            .collect()
+    });
+    
+    ApiResponse::success(friends)
+}
+
+/// Send a friend request from `user` to `to`, for an app holding friend-request-send
+#[update]
+fn app_send_friend_request(user: Principal, to: Principal) -> ApiResponse<FriendRequest> {
+    if let Some(rejection) = reject_untrusted_canister() {
+        return rejection;
+    }
+    if let Some(rejection) = reject_without_app_scope(user, AppScope::FriendRequestSend) {
+        return rejection;
+    }
+    
+    create_friend_request(user, to, None)
+}
+
+/// `user`'s profile, for an app holding profile-read
+#[query]
+fn app_get_profile(user: Principal) -> ApiResponse<UserProfile> {
+    if let Some(rejection) = reject_without_app_scope(user, AppScope::ProfileRead) {
+        return rejection;
+    }
+    
+    match storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&user)) {
+        Some(profile) => ApiResponse::success(profile),
+        None => errors::coded("user_not_found", &[]),
+    }
+}
+
+// ============ TEST FIXTURE METHODS ============
+
+// Upper bounds so a typo cannot exhaust the canister's memory
+#[cfg(feature = "test-fixtures")]
+const MAX_FIXTURE_USERS: u32 = 200_000;
+#[cfg(feature = "test-fixtures")]
+const MAX_FIXTURE_FRIENDS_PER_USER: u32 = 100;
+#[cfg(feature = "test-fixtures")]
+const MAX_FIXTURE_MESSAGES_PER_USER: u32 = 500;
+
+/// Populate synthetic users, friendships and chat history in batches across timer ticks.
+/// Only built with the `test-fixtures` feature, for load-test deployments
+#[cfg(feature = "test-fixtures")]
+#[update]
+fn generate_test_data(users: u32, friends_per_user: u32, messages_per_user: u32) -> ApiResponse<fixtures::FixtureProgress> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    if fixtures::is_running() {
+        return errors::coded("fixture_job_running", &[]);
+    }
+    if users == 0
+        || users > MAX_FIXTURE_USERS
+        || friends_per_user > MAX_FIXTURE_FRIENDS_PER_USER
+        || messages_per_user > MAX_FIXTURE_MESSAGES_PER_USER
+    {
+        return errors::coded("fixture_limits_exceeded", &[
+            ("max_users", MAX_FIXTURE_USERS.to_string()),
+            ("max_friends", MAX_FIXTURE_FRIENDS_PER_USER.to_string()),
+            ("max_messages", MAX_FIXTURE_MESSAGES_PER_USER.to_string()),
+        ]);
+    }
+    
+    ApiResponse::success(fixtures::start(users, friends_per_user, messages_per_user))
+}
+
+#[cfg(feature = "test-fixtures")]
+#[query]
+fn get_test_data_progress() -> ApiResponse<Option<fixtures::FixtureProgress>> {
+    ApiResponse::success(fixtures::get_progress())
+}
+
+// ============ CHAOS METHODS ============
+
+/// Failure-injection hook between the steps of a multi-map write; a no-op unless built with
+/// the `chaos` feature
+#[inline(always)]
+fn chaos_write_point(_point: &str) {
+    #[cfg(feature = "chaos")]
+    chaos::write_point(_point);
+}
+
+/// Trap at the next write point named `point` (any point when None) until reset_chaos.
+/// Only built with the `chaos` feature, for integration-test deployments
+#[cfg(feature = "chaos")]
+#[update]
+fn arm_trap_on_write(point: Option<String>) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    chaos::arm_trap(point);
+    ApiResponse::success(())
+}
+
+/// Fail the next `count` outbox deliveries without calling the target, so they go through
+/// the retry and dead-letter path
+#[cfg(feature = "chaos")]
+#[update]
+fn fail_outbox_calls(count: u32) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    chaos::fail_outbox_calls(count);
+    ApiResponse::success(())
+}
+
+/// Grow stable memory by up to `max_pages` WASM pages, or until the canister hits its
+/// storage quota when None. Returns the pages added; they stay allocated until a reinstall
+#[cfg(feature = "chaos")]
+#[update]
+fn fill_stable_memory(max_pages: Option<u64>) -> ApiResponse<u64> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    ApiResponse::success(chaos::fill_stable_memory(max_pages))
+}
+
+#[cfg(feature = "chaos")]
+#[update]
+fn reset_chaos() -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    chaos::reset();
+    ApiResponse::success(())
+}
+
+#[cfg(feature = "chaos")]
+#[query]
+fn get_chaos_state() -> ApiResponse<chaos::ChaosState> {
+    ApiResponse::success(chaos::get_state())
+}
+
+// ============ TRUSTED CANISTER METHODS ============
+
+// Integration endpoints called by other canisters (rather than by apps acting on a user's
+// grant alone) also require the caller to be in the controller-managed trusted set.
+
+fn reject_untrusted_canister<T>() -> Option<ApiResponse<T>> {
+    let trusted = storage::TRUSTED_CANISTERS.with(|trusted| trusted.borrow().contains_key(&caller()));
+    (!trusted).then(|| errors::coded("untrusted_canister", &[]))
+}
+
+/// Replace the trusted canister set. Canisters kept from the previous set keep their
+/// original trusted-since time
+#[update]
+fn set_trusted_canisters(canisters: Vec<Principal>) -> ApiResponse<Vec<(Principal, u64)>> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    let now = ic_cdk::api::time();
+    let canisters: HashSet<Principal> = canisters.into_iter().collect();
+    let trusted = storage::TRUSTED_CANISTERS.with(|trusted| {
+        let mut trusted = trusted.borrow_mut();
+        let removed: Vec<Principal> = trusted.iter()
+            .map(|(canister, _)| canister)
+            .filter(|canister| !canisters.contains(canister))
+            .collect();
+        for canister in removed {
+            trusted.remove(&canister);
+        }
+        for canister in canisters {
+            if !trusted.contains_key(&canister) {
+                trusted.insert(canister, now);
+            }
+        }
+        trusted.iter().collect()
+    });
+    
+    ApiResponse::success(trusted)
+}
+
+/// The trusted canister set with the time each entry was added
+#[query]
+fn get_trusted_canisters() -> ApiResponse<Vec<(Principal, u64)>> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    ApiResponse::success(storage::TRUSTED_CANISTERS.with(|trusted| trusted.borrow().iter().collect()))
+}
+
+// ============ OUTBOX METHODS ============
+
+// Notifications to other canisters whose loss matters (e.g. friend request outcomes) go
+// through a stable outbox and are retried with exponential backoff until the target
+// accepts them. Presence snapshots are superseded every minute, so they are not queued.
+
+// Failed attempts before an entry moves to the dead-letter store
+const OUTBOX_MAX_ATTEMPTS: u32 = 8;
+
+// Wait after the first failure; doubles with each further failure up to the cap
+const OUTBOX_BASE_BACKOFF_NS: u64 = 30 * 1_000_000_000;
+const OUTBOX_MAX_BACKOFF_NS: u64 = 6 * 60 * 60 * 1_000_000_000;
+
+// How often due entries are picked up, and how many per pass
+const OUTBOX_DRAIN_INTERVAL: Duration = Duration::from_secs(30);
+const OUTBOX_BATCH_SIZE: usize = 20;
+
+// An entry whose call is outstanding is not picked up again for this long
+const OUTBOX_IN_FLIGHT_NS: u64 = 5 * 60 * 1_000_000_000;
+
+/// Queue a call of `method` on `target` with Candid-encoded `args` and try it right away
+fn queue_notification(target: Principal, method: &str, args: Vec<u8>) {
+    let now = ic_cdk::api::time();
+    storage::OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        let id = outbox.last_key_value().map(|(id, _)| id + 1).unwrap_or(1);
+        outbox.insert(id, OutboxEntry {
+            id,
+            target,
+            method: method.to_string(),
+            args,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+        });
+    });
+    
+    ic_cdk_timers::set_timer(Duration::ZERO, drain_outbox);
+}
+
+fn outbox_backoff(attempts: u32) -> u64 {
+    let doublings = attempts.saturating_sub(1).min(32);
+    OUTBOX_BASE_BACKOFF_NS.saturating_mul(1u64 << doublings).min(OUTBOX_MAX_BACKOFF_NS)
+}
+
+/// Start delivery of every entry that is due, oldest first
+fn drain_outbox() {
+    let now = ic_cdk::api::time();
+    let due: Vec<OutboxEntry> = storage::OUTBOX.with(|outbox| {
+        outbox.borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.next_attempt_at <= now)
+            .take(OUTBOX_BATCH_SIZE)
+            .collect()
+    });
+    
+    for mut entry in due {
+        entry.next_attempt_at = now + OUTBOX_IN_FLIGHT_NS;
+        storage::OUTBOX.with(|outbox| outbox.borrow_mut().insert(entry.id, entry.clone()));
+        ic_cdk::spawn(deliver_outbox_entry(entry));
+    }
+}
+
+async fn deliver_outbox_entry(entry: OutboxEntry) {
+    #[cfg(feature = "chaos")]
+    if chaos::take_outbox_failure() {
+        return record_outbox_result(entry, Err((ic_cdk::api::call::RejectionCode::SysTransient, "chaos: simulated failure".to_string())));
+    }
+    let result = ic_cdk::api::call::call_raw(entry.target, &entry.method, entry.args.clone(), 0).await;
+    record_outbox_result(entry, result);
+}
+
+/// Drop a delivered entry, or schedule its retry / dead-letter it after a failed call
+fn record_outbox_result(mut entry: OutboxEntry, result: ic_cdk::api::call::CallResult<Vec<u8>>) {
+    chaos_write_point("outbox_delivery");
+    
+    storage::OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        // Dropped by an admin while the call was outstanding
+        if !outbox.contains_key(&entry.id) {
+            return;
+        }
+        
+        let Err((code, message)) = result else {
+            outbox.remove(&entry.id);
+            return;
+        };
+        
+        entry.attempts += 1;
+        entry.last_error = Some(format!("{:?} {}", code, message));
+        if entry.attempts >= OUTBOX_MAX_ATTEMPTS {
+            outbox.remove(&entry.id);
+            ic_cdk::println!("outbox entry {} ({} on {}) dead-lettered: {:?} {}", entry.id, entry.method, entry.target, code, message);
+            storage::DEAD_LETTERS.with(|dead| dead.borrow_mut().insert(entry.id, entry));
+        } else {
+            entry.next_attempt_at = ic_cdk::api::time() + outbox_backoff(entry.attempts);
+            outbox.insert(entry.id, entry);
+        }
+    });
+}
+
+/// Pending and dead-lettered inter-canister notifications
+#[query]
+fn get_outbox() -> ApiResponse<OutboxStatus> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    ApiResponse::success(OutboxStatus {
+        pending: storage::OUTBOX.with(|outbox| outbox.borrow().iter().map(|(_, entry)| entry).collect()),
+        dead_letters: storage::DEAD_LETTERS.with(|dead| dead.borrow().iter().map(|(_, entry)| entry).collect()),
+    })
+}
+
+/// Move a dead-lettered notification back into the outbox with a fresh attempt budget
+#[update]
+fn requeue_dead_letter(id: u64) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    let Some(entry) = storage::DEAD_LETTERS.with(|dead| dead.borrow_mut().remove(&id)) else {
+        return errors::coded("outbox_entry_not_found", &[]);
+    };
+    
+    queue_notification(entry.target, &entry.method, entry.args);
+    ApiResponse::success(())
+}
+
+// ============ ACTIVITY FEED METHODS ============
+
+// Each user's recent activity is kept under their own principal, so a feed is one range
+// read per friend. Old entries are dropped as new ones are written.
+
+const ACTIVITY_RETENTION_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_ACTIVITY_PER_USER: usize = 50;
+const MAX_ACTIVITY_FEED_EVENTS: usize = 100;
+
+// A repeat of the actor's latest event within this window replaces it instead of piling up
+const ACTIVITY_COALESCE_NS: u64 = 10 * 60 * 1_000_000_000;
+
+fn record_activity(actor: Principal, kind: ActivityKind) {
+    let now = ic_cdk::api::time();
+    let lower = (actor, 0);
+    let upper = (actor, u64::MAX);
+    
+    storage::ACTIVITY_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let entries: Vec<((Principal, u64), ActivityKind)> = log.range(lower..=upper).collect();
+        
+        let mut at = now;
+        if let Some(((_, last_at), last_kind)) = entries.last() {
+            if *last_kind == kind && last_at + ACTIVITY_COALESCE_NS > now {
+                log.remove(&(actor, *last_at));
+            }
+            at = now.max(last_at + 1);
+        }
+        
+        let cutoff = now.saturating_sub(ACTIVITY_RETENTION_NS);
+        let excess = (entries.len() + 1).saturating_sub(MAX_ACTIVITY_PER_USER);
+        for (index, (key, _)) in entries.iter().enumerate() {
+            if index < excess || key.1 < cutoff {
+                log.remove(key);
+            }
+        }
+        log.insert((actor, at), kind);
+    });
+}
+
+/// What the caller's friends did after `since` (the whole retained week when None), newest
+/// first. Pass the newest `at` seen as `since` to poll for more
+#[query]
+fn get_activity_feed(since: Option<u64>) -> ApiResponse<Vec<ActivityEvent>> {
+    let lower = since.map_or(0, |since| since.saturating_add(1));
+    let cutoff = ic_cdk::api::time().saturating_sub(ACTIVITY_RETENTION_NS);
+    
+    let mut events: Vec<ActivityEvent> = storage::ACTIVITY_LOG.with(|log| {
+        let log = log.borrow();
+        friend_set(caller())
+            .into_iter()
+            .flat_map(|friend| {
+                log.range((friend, lower.max(cutoff))..=(friend, u64::MAX))
+                    .map(|((actor, at), kind)| ActivityEvent { actor, kind, at })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
+    events.sort_by_key(|event| std::cmp::Reverse(event.at));
+    events.truncate(MAX_ACTIVITY_FEED_EVENTS);
+    
+    diagnosed("get_activity_feed", ApiResponse::success(events))
+}
+
+// ============ PRESENCE METHODS ============
+
+// A member counts as active in a room this long after their last heartbeat
+const PRESENCE_TTL_NS: u64 = 2 * 60 * 1_000_000_000;
+
+// How often active members per room are pushed to the AI canister
+const PRESENCE_PUSH_INTERVAL: Duration = Duration::from_secs(60);
+// Members sent per presence push call; larger snapshots go out in several calls
+const PRESENCE_PUSH_CHUNK_MEMBERS: usize = 10_000;
+
+const MAX_ROOM_ID_CHARS: usize = 64;
+// Rooms one user counts as present in at once; a heartbeat in another drops the stalest
+const MAX_PRESENCE_ROOMS_PER_USER: usize = 5;
+
+// How long a user's last_seen is remembered once they go offline, and how often that is enforced
+const LAST_SEEN_RETENTION_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PRESENCE_EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[update]
+fn heartbeat(room_id: String) -> ApiResponse<()> {
+    if let Some(rejection) = reject_if_suspended(&caller()) {
+        return rejection;
+    }
+    
+    let principal = caller();
+    
+    let registered = storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&principal));
+    if !registered {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    let room_id = room_id.trim().to_string();
+    if room_id.is_empty() {
+        return errors::coded("room_id_empty", &[]);
+    }
+    if room_id.chars().count() > MAX_ROOM_ID_CHARS || room_id.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return errors::coded("room_id_invalid", &[("max", MAX_ROOM_ID_CHARS.to_string())]);
+    }
+    
+    let now = ic_cdk::api::time();
+    storage::PRESENCE.with(|presence| {
+        let mut presence = presence.borrow_mut();
+        storage::PRESENCE_ROOMS.with(|rooms| {
+            let mut rooms = rooms.borrow_mut();
+            let member_rooms = rooms.entry(principal).or_default();
+            if !member_rooms.contains(&room_id) {
+                if member_rooms.len() >= MAX_PRESENCE_ROOMS_PER_USER {
+                    let last_seen = |room: &String| presence.get(room).and_then(|members| members.get(&principal)).copied();
+                    if let Some(stalest) = member_rooms.iter().min_by_key(|room| last_seen(room)).cloned() {
+                        member_rooms.retain(|room| *room != stalest);
+                        if let Some(members) = presence.get_mut(&stalest) {
+                            members.remove(&principal);
+                            if members.is_empty() {
+                                presence.remove(&stalest);
+                            }
+                        }
+                    }
+                }
+                member_rooms.push(room_id.clone());
+            }
+        });
+        presence.entry(room_id).or_default().insert(principal, now);
+    });
+    mark_online(principal, now);
+    
+    ApiResponse::success(())
+}
+
+/// Keep the caller shown as online to their friends; clients call this about once a minute
+/// while open, whether or not a room is
+#[update]
+fn touch_presence() -> ApiResponse<()> {
+    let principal = caller();
+    if let Some(rejection) = reject_if_suspended(&principal) {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    mark_online(principal, ic_cdk::api::time());
+    
+    ApiResponse::success(())
+}
+
+/// Note that `principal` was just seen, telling their friends if they had been offline
+fn mark_online(principal: Principal, now: u64) {
+    let previous = storage::USER_PRESENCE.with(|presence| presence.borrow_mut().insert(principal, now));
+    if previous.is_none_or(|last_seen| !is_recent_presence(last_seen, now)) {
+        record_activity(principal, ActivityKind::CameOnline);
+    }
+}
+
+fn is_recent_presence(last_seen: u64, now: u64) -> bool {
+    last_seen >= now.saturating_sub(PRESENCE_TTL_NS)
+}
+
+/// Online status of each of the caller's friends
+#[query]
+fn get_friends_presence() -> ApiResponse<Vec<FriendPresence>> {
+    let now = ic_cdk::api::time();
+    
+    let statuses = storage::USER_PRESENCE.with(|presence| {
+        let presence = presence.borrow();
+        friends_of(caller())
+            .into_iter()
+            .map(|friend| {
+                let last_seen = presence.get(&friend.principal).copied();
+                let online = last_seen.is_some_and(|last_seen| is_recent_presence(last_seen, now));
+                FriendPresence {
+                    principal: friend.principal,
+                    status: if online { PresenceStatus::Online } else { PresenceStatus::Offline },
+                    last_seen,
+                }
+            })
+            .collect()
+    });
+    
+    ApiResponse::success(statuses)
+}
+
+/// Forget users not seen for a day. Status is worked out from last_seen when read, so going
+/// offline needs no write
+fn expire_user_presence() {
+    let cutoff = ic_cdk::api::time().saturating_sub(LAST_SEEN_RETENTION_NS);
+    storage::USER_PRESENCE.with(|presence| {
+        presence.borrow_mut().retain(|_, last_seen| *last_seen >= cutoff);
+    });
+}
+
+#[query]
+fn get_room_presence(room_id: String) -> ApiResponse<Vec<Principal>> {
+    let cutoff = ic_cdk::api::time().saturating_sub(PRESENCE_TTL_NS);
+    
+    let active = storage::PRESENCE.with(|presence| {
+        presence.borrow()
+            .get(&room_id)
+            .map(|members| {
+                members.iter()
+                    .filter(|(_, last_seen)| **last_seen >= cutoff)
+                    .map(|(principal, _)| *principal)
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    
+    ApiResponse::success(active)
+}
+
+#[update]
+fn set_ai_canister(ai_canister: Principal) -> ApiResponse<()> {
+    if !ic_cdk::api::is_controller(&caller()) {
+        return errors::coded("unauthorized_controller", &[]);
+    }
+    
+    storage::SETTINGS.with(|settings| {
+        settings.borrow_mut().insert(storage::AI_CANISTER_SETTING, ai_canister);
+    });
+    
+    ApiResponse::success(())
+}
+
+/// Drop expired heartbeats and return the active members of every room
+fn collect_active_presence(now: u64) -> Vec<(String, Vec<Principal>)> {
+    let cutoff = now.saturating_sub(PRESENCE_TTL_NS);
+    
+    storage::PRESENCE.with(|presence| {
+        let mut presence = presence.borrow_mut();
+        presence.retain(|_, members| {
+            members.retain(|_, last_seen| *last_seen >= cutoff);
+            !members.is_empty()
+        });
+        
+        storage::PRESENCE_ROOMS.with(|rooms| {
+            let mut rooms = rooms.borrow_mut();
+            rooms.clear();
+            for (room_id, members) in presence.iter() {
+                for principal in members.keys() {
+                    rooms.entry(*principal).or_default().push(room_id.clone());
+                }
+            }
+        });
+        
+        presence.iter()
+            .map(|(room_id, members)| (room_id.clone(), members.keys().copied().collect()))
+            .collect()
+    })
+}
+
+/// Split a presence snapshot into calls of at most PRESENCE_PUSH_CHUNK_MEMBERS members. A
+/// room too large for one call is split across several
+fn presence_chunks(rooms: Vec<(String, Vec<Principal>)>) -> Vec<Vec<(String, Vec<Principal>)>> {
+    let mut chunks = vec![Vec::new()];
+    let mut chunk_members = 0;
+    for (room_id, members) in rooms {
+        for part in members.chunks(PRESENCE_PUSH_CHUNK_MEMBERS) {
+            if chunk_members + part.len() > PRESENCE_PUSH_CHUNK_MEMBERS {
+                chunks.push(Vec::new());
+                chunk_members = 0;
+            }
+            chunk_members += part.len();
+            if let Some(chunk) = chunks.last_mut() {
+                chunk.push((room_id.clone(), part.to_vec()));
+            }
+        }
+    }
+    chunks
+}
+
+/// One-way push of the current presence snapshot; an empty snapshot clears the AI's view.
+/// The first call replaces the AI's snapshot and later ones extend it; calls between two
+/// canisters arrive in the order they were sent
+fn push_presence() {
+    let rooms = collect_active_presence(ic_cdk::api::time());
+    
+    let Some(ai_canister) = storage::SETTINGS.with(|settings| settings.borrow().get(&storage::AI_CANISTER_SETTING)) else {
+        return;
+    };
+    
+    for (index, chunk) in presence_chunks(rooms).into_iter().enumerate() {
+        let continuation = index > 0;
+        if let Err(code) = ic_cdk::notify(ai_canister, "update_room_presence", (chunk, Some(continuation))) {
+            ic_cdk::println!("presence push to {} failed: {:?}", ai_canister, code);
+            return;
+        }
+    }
+}
+
+// ============ PUBLIC DIRECTORY METHODS ============
+
+// Users are only listed in the web directory (/profiles/index) after opting in. Pages are
+// rendered once per change and cached; a change drops the cache, and the pages are rendered
+// again and re-certified a short while later. Until then they are served through update calls.
+
+const DIRECTORY_PAGE_SIZE: usize = 100;
+const DIRECTORY_REFRESH_DELAY: Duration = Duration::from_secs(10);
+
+// Also refresh on a schedule so suspensions that run out are picked up
+const DIRECTORY_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+thread_local! {
+    static DIRECTORY_REFRESH_PENDING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    // Rendered directory pages; None after a change until they are rendered again
+    static DIRECTORY_PAGES: std::cell::RefCell<Option<Vec<Vec<u8>>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Opt in to (or out of) the public profile directory
+#[update]
+fn set_public_profile(enabled: bool) -> ApiResponse<bool> {
+    let caller_principal = caller();
+    
+    if let Some(rejection) = reject_if_suspended(&caller_principal) {
+        return rejection;
+    }
+    if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&caller_principal)) {
+        return errors::coded("user_not_registered", &[]);
+    }
+    
+    storage::PUBLIC_PROFILES.with(|public| {
+        let mut public = public.borrow_mut();
+        if enabled {
+            if !public.contains_key(&caller_principal) {
+                public.insert(caller_principal, ic_cdk::api::time());
+            }
+        } else {
+            public.remove(&caller_principal);
+        }
+    });
+    schedule_directory_refresh();
+    
+    ApiResponse::success(enabled)
+}
+
+#[query]
+fn is_public_profile() -> ApiResponse<bool> {
+    ApiResponse::success(storage::PUBLIC_PROFILES.with(|public| public.borrow().contains_key(&caller())))
+}
+
+fn is_listed(principal: &Principal) -> bool {
+    storage::PUBLIC_PROFILES.with(|public| public.borrow().contains_key(principal))
+}
+
+/// Opted-in, unsuspended users in principal order
+fn directory_entries() -> Vec<DirectoryEntry> {
+    let listed: Vec<Principal> = storage::PUBLIC_PROFILES.with(|public| {
+        public.borrow().iter().map(|(principal, _)| principal).collect()
+    });
+    
+    listed.into_iter()
+        .filter(|principal| active_suspension(principal).is_none() && !is_banned(principal))
+        .filter_map(|principal| storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal)))
+        .map(|profile| DirectoryEntry {
+            profile_url: http::profile_path(&profile.principal),
+            principal: profile.principal,
+            display_name: profile.display_name,
+        })
+        .collect()
+}
+
+/// Response body of each directory page; an empty directory still has page 1
+fn directory_page_bodies(entries: Vec<DirectoryEntry>) -> Vec<Vec<u8>> {
+    let total = entries.len() as u64;
+    let page_count = entries.len().div_ceil(DIRECTORY_PAGE_SIZE).max(1) as u32;
+    let mut chunks: Vec<Vec<DirectoryEntry>> = entries.chunks(DIRECTORY_PAGE_SIZE).map(<[_]>::to_vec).collect();
+    if chunks.is_empty() {
+        chunks.push(Vec::new());
+    }
+    
+    chunks.into_iter()
+        .enumerate()
+        .map(|(index, profiles)| {
+            let page = index as u32 + 1;
+            let page = DirectoryPage {
+                page,
+                page_count,
+                total,
+                profiles,
+                next: (page < page_count).then(|| http::directory_path(page + 1)),
+            };
+            serde_json::to_vec(&page).unwrap_or_default()
+        })
+        .collect()
+}
+
+/// One directory page, rendering (and caching) the directory first if it changed since
+fn directory_page_body(page: u32) -> Option<Vec<u8>> {
+    let cached = DIRECTORY_PAGES.with(|pages| {
+        pages.borrow().as_ref().map(|pages| pages.get(page as usize - 1).cloned())
+    });
+    if let Some(body) = cached {
+        return body;
+    }
+    
+    let pages = directory_page_bodies(directory_entries());
+    let body = pages.get(page as usize - 1).cloned();
+    DIRECTORY_PAGES.with(|cached| *cached.borrow_mut() = Some(pages));
+    body
+}
+
+fn refresh_directory() {
+    DIRECTORY_REFRESH_PENDING.with(|pending| pending.set(false));
+    let pages = directory_page_bodies(directory_entries());
+    http::certify_directory(&pages);
+    DIRECTORY_PAGES.with(|cached| *cached.borrow_mut() = Some(pages));
+}
+
+/// Drop the cached directory and re-certify it shortly, coalescing bursts of changes into one
+/// rebuild
+fn schedule_directory_refresh() {
+    DIRECTORY_PAGES.with(|cached| *cached.borrow_mut() = None);
+    if DIRECTORY_REFRESH_PENDING.with(|pending| pending.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(DIRECTORY_REFRESH_DELAY, refresh_directory);
+}
+
+// ============ HTTP METHODS ============
+
+#[derive(Deserialize)]
+struct HttpSearchBody {
+    query: String,
+}
+
+fn http_search(query: &str) -> http::HttpResponse {
+    let results: Vec<UserSearchResult> = ranked_user_search(query, SearchScope::Substring, caller(), 0, SEARCH_RESULT_LIMIT)
+        .into_iter()
+        .map(search_result)
+        .collect();
+    
+    http::json_response(200, &results)
+}
+
+/// Read-only JSON API, described by the OpenAPI document at /api/schema. Profile pages, directory
+/// pages and the schema are answered here with a certificate; friends, search and every POST
+/// are upgraded to `http_request_update`
+#[query]
+fn http_request(request: http::HttpRequest) -> http::HttpResponse {
+    let (path, _) = http::split_url(&request.url);
+    
+    let response = match (request.method.as_str(), http::route(path)) {
+        ("GET", http::Route::Profile(principal)) => {
+            let Some(profile) = storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal)) else {
+                return http::error_response(404, "User not found");
+            };
+            let body = http::profile_body(&profile);
+            let Some(certificate) = http::certificate_header(path, &body) else {
+                // Not certified yet (backfill still running); answer through consensus instead
+                return http::upgrade_response();
+            };
+            
+            let mut response = http::json_body_response(200, body);
+            response.headers.push(certificate);
+            response
+        }
+        ("GET", http::Route::Schema) => http::schema_response(),
+        ("GET", http::Route::Directory(page)) => {
+            let Some(body) = directory_page_body(page) else {
+                return http::error_response(404, "Not found");
+            };
+            let Some(certificate) = http::certificate_header(path, &body) else {
+                // Listing changed since the last certification; answer through consensus instead
+                return http::upgrade_response();
+            };
+            
+            let mut response = http::directory_response(body);
+            response.headers.push(certificate);
+            response
+        }
+        ("GET", http::Route::NotFound) => http::error_response(404, "Not found"),
+        ("GET", _) | ("POST", _) => http::upgrade_response(),
+        _ => http::error_response(405, "Method not allowed"),
+    };
+    
+    diagnosed_http(http::compress_response(&request, response))
+}
+
+#[update]
+fn http_request_update(request: http::HttpRequest) -> http::HttpResponse {
+    let (path, query) = http::split_url(&request.url);
+    
+    let response = match (request.method.as_str(), http::route(path)) {
+        ("GET", http::Route::Profile(principal)) => {
+            match storage::USER_PROFILES.with(|profiles| profiles.borrow().get(&principal)) {
+                Some(profile) => http::json_response(200, &redact_profile(profile, &caller())),
+                None => http::error_response(404, "User not found"),
+            }
+        }
+        ("GET", http::Route::ProfileFriends(principal)) => {
+            // Friend lists are private, as with get_friends
+            if caller() != principal {
+                return http::error_response(403, "Friends are only listed to their owner");
+            }
+            if !storage::USER_PROFILES.with(|profiles| profiles.borrow().contains_key(&principal)) {
+                return http::error_response(404, "User not found");
+            }
+            http::json_response(200, &friends_of(principal))
+        }
+        ("GET", http::Route::Search) => match http::query_param(query, "q") {
+            Some(query) => http_search(&query),
+            None => http::error_response(400, "Missing query parameter 'q'"),
+        },
+        ("GET", http::Route::Schema) => http::schema_response(),
+        ("GET", http::Route::Directory(page)) => match directory_page_body(page) {
+            Some(body) => http::directory_response(body),
+            None => http::error_response(404, "Not found"),
+        },
+        ("POST", http::Route::Search) => match serde_json::from_slice::<HttpSearchBody>(&request.body) {
+            Ok(body) => http_search(&body.query),
+            Err(_) => http::error_response(400, "Body must be JSON of the form {\"query\": \"...\"}"),
+        },
+        (_, http::Route::NotFound) => http::error_response(404, "Not found"),
+        _ => http::error_response(405, "Method not allowed"),
+    };
+    
+    diagnosed_http(http::compress_response(&request, response))
+}
+
+// ============ LIFECYCLE ============
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(PRESENCE_PUSH_INTERVAL, push_presence);
+    ic_cdk_timers::set_timer_interval(PRESENCE_EXPIRY_INTERVAL, expire_user_presence);
+    ic_cdk_timers::set_timer_interval(AUDIT_PURGE_INTERVAL, purge_audit_trail);
+    ic_cdk_timers::set_timer_interval(OUTBOX_DRAIN_INTERVAL, drain_outbox);
+    ic_cdk_timers::set_timer_interval(UPLOAD_PURGE_INTERVAL, purge_stale_uploads);
+    ic_cdk_timers::set_timer_interval(DISAPPEARING_PURGE_INTERVAL, purge_expired_messages);
+    ic_cdk_timers::set_timer_interval(FRIEND_REQUEST_SWEEP_INTERVAL, sweep_friend_requests);
+    ic_cdk_timers::set_timer_interval(PAYLOAD_MEASURE_INTERVAL, payload_size::start_measurement);
+    payload_size::start_measurement();
+    ic_cdk_timers::set_timer_interval(DIRECTORY_REFRESH_INTERVAL, refresh_directory);
+}
+
+#[init]
+fn init() {
+    start_timers();
+    http::init_certification();
+    refresh_directory();
+    schedule_module_hash_lookup();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    index_display_names();
+    index_latest_appeals();
+    index_pending_friend_requests();
+    start_timers();
+    http::restore_certification();
+    refresh_directory();
+    ic_cdk_timers::set_timer(Duration::ZERO, migrate_legacy_avatars);
+    reschedule_poll_closes();
+    reschedule_event_reminders();
+    schedule_module_hash_lookup();
+}
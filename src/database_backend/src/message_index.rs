@@ -0,0 +1,90 @@
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Inverted index over each user's DMs and synced chat messages, for search_my_messages.
+// Heap only: filled as messages are written and rebuilt from storage after an upgrade. Entries
+// are never removed; deleted or replaced messages are dropped when a lookup is resolved.
+
+// Shorter words are too common to be worth indexing
+const MIN_TERM_CHARS: usize = 2;
+const MAX_TERM_CHARS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageRef {
+    Dm { dm_channel_id: String, message_id: String },
+    Chat { message_id: String },
+}
+
+thread_local! {
+    // user -> term -> messages containing it
+    static INDEX: RefCell<HashMap<Principal, BTreeMap<String, HashSet<MessageRef>>>> = RefCell::new(HashMap::new());
+}
+
+/// Lowercased words of `text`, deduplicated
+fn terms(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| (MIN_TERM_CHARS..=MAX_TERM_CHARS).contains(&word.chars().count()))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Whole words of a query and the trailing word, which is matched as a prefix
+fn query_words(query: &str) -> Option<(Vec<String>, String)> {
+    let mut words: Vec<String> = query.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let last = words.pop()?;
+    // Words too short to be indexed cannot narrow the results
+    words.retain(|word| word.chars().count() >= MIN_TERM_CHARS);
+    Some((words, last))
+}
+
+pub fn index_message(owner: Principal, message: MessageRef, text: &str) {
+    INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let user_index = index.entry(owner).or_default();
+        for term in terms(text) {
+            user_index.entry(term).or_default().insert(message.clone());
+        }
+    });
+}
+
+/// Messages of `owner` containing every term of `query`. The last term also matches as a
+/// prefix, so results can update while the user is still typing
+pub fn lookup(owner: Principal, query: &str) -> Vec<MessageRef> {
+    let Some((words, last)) = query_words(query) else {
+        return Vec::new();
+    };
+
+    INDEX.with(|index| {
+        let index = index.borrow();
+        let Some(user_index) = index.get(&owner) else {
+            return Vec::new();
+        };
+
+        let mut matches: HashSet<MessageRef> = user_index.range(last.clone()..)
+            .take_while(|(term, _)| term.starts_with(&last))
+            .flat_map(|(_, messages)| messages.iter().cloned())
+            .collect();
+        for word in &words {
+            let containing = user_index.get(word);
+            matches.retain(|message| containing.is_some_and(|containing| containing.contains(message)));
+        }
+        matches.into_iter().collect()
+    })
+}
+
+/// Whether `text` still matches `query` the way lookup does; screens out stale index entries
+pub fn matches(text: &str, query: &str) -> bool {
+    let Some((words, last)) = query_words(query) else {
+        return false;
+    };
+    let text_terms = terms(text);
+    words.iter().all(|word| text_terms.contains(word)) && text_terms.iter().any(|term| term.starts_with(&last))
+}
+
+pub fn clear() {
+    INDEX.with(|index| index.borrow_mut().clear());
+}
@@ -0,0 +1,88 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+
+use crate::types::Page;
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+// Contents of a cursor token. Clients must treat the encoded token as opaque
+#[derive(CandidType, Deserialize)]
+struct CursorToken {
+    scope: String,       // Which list (and which query) the token belongs to
+    last_key: Vec<u8>,   // Sort key of the last item already returned
+    snapshot_at: u64,    // Items created after the first page are left out of later pages
+}
+
+/// Decoded position within a paginated listing
+pub struct Cursor {
+    pub last_key: Option<Vec<u8>>,
+    pub snapshot_at: u64,
+}
+
+/// Start a new listing (no token) or resume one; tokens from a different listing are rejected
+/// Errors are message catalog codes
+pub fn resume(token: Option<String>, scope: &str, now: u64) -> Result<Cursor, &'static str> {
+    let Some(token) = token else {
+        return Ok(Cursor { last_key: None, snapshot_at: now });
+    };
+    
+    let bytes = decode_hex(&token).ok_or("invalid_cursor")?;
+    let decoded = Decode!(&bytes, CursorToken).map_err(|_| "invalid_cursor")?;
+    if decoded.scope != scope {
+        return Err("cursor_scope_mismatch");
+    }
+    
+    Ok(Cursor { last_key: Some(decoded.last_key), snapshot_at: decoded.snapshot_at })
+}
+
+/// The last key of a listing sorted by principal. Tokens are client input, so a key that is not
+/// a principal is rejected rather than trapping
+pub fn last_principal(cursor: &Cursor) -> Result<Option<Principal>, &'static str> {
+    cursor.last_key.as_deref()
+        .map(|key| Principal::try_from_slice(key).map_err(|_| "invalid_cursor"))
+        .transpose()
+}
+
+pub fn page_size(limit: Option<u32>) -> usize {
+    limit.map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+/// Take one page from `items` (already ordered, filtered and positioned after the cursor),
+/// issuing a next cursor only when more items remain
+pub fn collect_page<T>(items: impl Iterator<Item = (Vec<u8>, T)>, limit: usize, scope: &str, snapshot_at: u64) -> Page<T> {
+    let mut items: Vec<(Vec<u8>, T)> = items.take(limit + 1).collect();
+    let has_more = items.len() > limit;
+    items.truncate(limit);
+    
+    let next_cursor = if has_more {
+        items.last().map(|(key, _)| {
+            let token = CursorToken {
+                scope: scope.to_string(),
+                last_key: key.clone(),
+                snapshot_at,
+            };
+            encode_hex(&Encode!(&token).unwrap())
+        })
+    } else {
+        None
+    };
+    
+    Page {
+        items: items.into_iter().map(|(_, item)| item).collect(),
+        next_cursor,
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,94 @@
+use ic_stable_structures::storable::Bound as StorableBound;
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+// Stable map keyed by (A, B) pairs where either part may be unbounded, such as a String.
+// ic-stable-structures only stores tuple keys whose parts are all bounded (it traps on the
+// first insert otherwise), so pairs are stored as one encoded key: A's length, A, then B.
+// Entries sharing an A are adjacent and ordered by B's bytes, so a range starting at
+// (a, smallest b) walks all of a's entries.
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PairKey(Vec<u8>);
+
+impl Storable for PairKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PairKey(bytes.into_owned())
+    }
+
+    const BOUND: StorableBound = StorableBound::Unbounded;
+}
+
+fn encode<A: Storable, B: Storable>((a, b): &(A, B)) -> PairKey {
+    let a_bytes = a.to_bytes();
+    let b_bytes = b.to_bytes();
+    let mut bytes = Vec::with_capacity(4 + a_bytes.len() + b_bytes.len());
+    bytes.extend_from_slice(&(a_bytes.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&a_bytes);
+    bytes.extend_from_slice(&b_bytes);
+    PairKey(bytes)
+}
+
+fn decode<A: Storable, B: Storable>(key: &PairKey) -> (A, B) {
+    let (len, rest) = key.0.split_at(4);
+    let (a_bytes, b_bytes) = rest.split_at(u32::from_be_bytes(len.try_into().unwrap()) as usize);
+    (A::from_bytes(Cow::Borrowed(a_bytes)), B::from_bytes(Cow::Borrowed(b_bytes)))
+}
+
+fn encode_bound<A: Storable, B: Storable>(bound: Bound<&(A, B)>) -> Bound<PairKey> {
+    match bound {
+        Bound::Included(pair) => Bound::Included(encode(pair)),
+        Bound::Excluded(pair) => Bound::Excluded(encode(pair)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+pub struct PairMap<A, B, V: Storable, M: Memory> {
+    map: StableBTreeMap<PairKey, V, M>,
+    _key: PhantomData<(A, B)>,
+}
+
+impl<A: Storable, B: Storable, V: Storable, M: Memory> PairMap<A, B, V, M> {
+    pub fn init(memory: M) -> Self {
+        PairMap { map: StableBTreeMap::init(memory), _key: PhantomData }
+    }
+
+    pub fn get(&self, key: &(A, B)) -> Option<V> {
+        self.map.get(&encode(key))
+    }
+
+    pub fn insert(&mut self, key: (A, B), value: V) -> Option<V> {
+        self.map.insert(encode(&key), value)
+    }
+
+    pub fn remove(&mut self, key: &(A, B)) -> Option<V> {
+        self.map.remove(&encode(key))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear_new(&mut self) {
+        self.map.clear_new();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((A, B), V)> + '_ {
+        self.map.iter().map(|(key, value)| (decode(&key), value))
+    }
+
+    pub fn range(&self, range: impl RangeBounds<(A, B)>) -> impl DoubleEndedIterator<Item = ((A, B), V)> + '_ {
+        let bounds = (encode_bound(range.start_bound()), encode_bound(range.end_bound()));
+        self.map.range(bounds).map(|(key, value)| (decode(&key), value))
+    }
+}
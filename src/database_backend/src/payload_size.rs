@@ -0,0 +1,141 @@
+//! Response size checks for large reads. Replies over the ICP response ceiling trap, so plain
+//! reads are measured before they are returned, and a timer job periodically measures what
+//! the large reads would return so payloads growing towards the ceiling show up in metrics
+//! before anyone hits it. Query calls cannot keep state, which is why the counters come from
+//! that job rather than from the calls themselves.
+
+use candid::{CandidType, Deserialize, Encode, Principal};
+use std::cell::RefCell;
+use std::ops::Bound;
+use std::time::Duration;
+
+use crate::compression::MAX_PAYLOAD_BYTES;
+use crate::storage;
+
+// Payloads at least this large count as near the limit
+pub const NEAR_LIMIT_BYTES: usize = MAX_PAYLOAD_BYTES / 5 * 4;
+
+// Users measured per timer tick
+const MEASURE_BATCH: usize = 500;
+
+/// What one large read would return, as of the last measurement
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct PayloadReadStats {
+    pub read: String,      // Endpoint measured
+    pub measured: u64,     // Responses measured (one per user for per-user reads)
+    pub near_limit: u64,   // Of those, how many are at least near_limit_bytes
+    pub over_limit: u64,   // and how many would not fit in one response
+    pub largest_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PayloadMetrics {
+    pub limit_bytes: u64,
+    pub near_limit_bytes: u64,
+    pub reads: Vec<PayloadReadStats>,
+    pub measured_at: Option<u64>, // None until the first measurement finishes
+}
+
+#[derive(Default)]
+struct Measurement {
+    all_users_bytes: u64,
+    all_sync_bytes: u64,
+    sync_reads: PayloadReadStats,
+}
+
+thread_local! {
+    // Last finished measurement; heap only, measured again after an upgrade
+    static LATEST: RefCell<Option<(u64, Vec<PayloadReadStats>)>> = const { RefCell::new(None) };
+    // Measurement in progress, if any
+    static IN_PROGRESS: RefCell<Option<Measurement>> = const { RefCell::new(None) };
+}
+
+/// Size in bytes `value` takes as a Candid reply
+pub fn estimate<T: CandidType>(value: &T) -> usize {
+    Encode!(value).map(|bytes| bytes.len()).unwrap_or(usize::MAX)
+}
+
+fn record(stats: &mut PayloadReadStats, size: usize) {
+    stats.measured += 1;
+    if size >= NEAR_LIMIT_BYTES {
+        stats.near_limit += 1;
+    }
+    if size > MAX_PAYLOAD_BYTES {
+        stats.over_limit += 1;
+    }
+    stats.largest_bytes = stats.largest_bytes.max(size as u64);
+}
+
+/// Start measuring unless a measurement is already running
+pub fn start_measurement() {
+    let started = IN_PROGRESS.with(|in_progress| {
+        let mut in_progress = in_progress.borrow_mut();
+        if in_progress.is_some() {
+            return false;
+        }
+        *in_progress = Some(Measurement::default());
+        true
+    });
+    if started {
+        ic_cdk_timers::set_timer(Duration::ZERO, || measure_batch(None));
+    }
+}
+
+/// Measure the users after `after`, continuing on another tick until all are done
+fn measure_batch(after: Option<Principal>) {
+    let lower = match after {
+        Some(principal) => Bound::Excluded(principal),
+        None => Bound::Unbounded,
+    };
+    let profiles: Vec<(Principal, usize)> = storage::USER_PROFILES.with(|profiles| {
+        profiles.borrow()
+            .range((lower, Bound::Unbounded))
+            .take(MEASURE_BATCH)
+            .map(|(principal, profile)| (principal, estimate(&profile)))
+            .collect()
+    });
+
+    IN_PROGRESS.with(|in_progress| {
+        let mut in_progress = in_progress.borrow_mut();
+        let Some(measurement) = in_progress.as_mut() else {
+            return;
+        };
+        for (principal, profile_bytes) in &profiles {
+            measurement.all_users_bytes += *profile_bytes as u64;
+            if let Some(sync) = storage::USER_DATA_SYNC.with(|sync_data| sync_data.borrow().get(principal)) {
+                let sync_bytes = estimate(&sync);
+                measurement.all_sync_bytes += sync_bytes as u64;
+                record(&mut measurement.sync_reads, sync_bytes);
+            }
+        }
+    });
+
+    if profiles.len() == MEASURE_BATCH {
+        let last = profiles.last().map(|(principal, _)| *principal);
+        ic_cdk_timers::set_timer(Duration::ZERO, move || measure_batch(last));
+        return;
+    }
+
+    let Some(measurement) = IN_PROGRESS.with(|in_progress| in_progress.borrow_mut().take()) else {
+        return;
+    };
+    let mut all_users = PayloadReadStats { read: "get_all_users".to_string(), ..Default::default() };
+    record(&mut all_users, measurement.all_users_bytes as usize);
+    let mut sync_reads = measurement.sync_reads;
+    sync_reads.read = "get_user_data_sync".to_string();
+    // The dump returns every sync blob at once
+    let mut sync_dump = PayloadReadStats { read: "debug_get_all_sync_data".to_string(), ..Default::default() };
+    record(&mut sync_dump, measurement.all_sync_bytes as usize);
+
+    LATEST.with(|latest| *latest.borrow_mut() = Some((ic_cdk::api::time(), vec![all_users, sync_reads, sync_dump])));
+}
+
+pub fn metrics() -> PayloadMetrics {
+    let latest = LATEST.with(|latest| latest.borrow().clone());
+    PayloadMetrics {
+        limit_bytes: MAX_PAYLOAD_BYTES as u64,
+        near_limit_bytes: NEAR_LIMIT_BYTES as u64,
+        measured_at: latest.as_ref().map(|(at, _)| *at),
+        reads: latest.map(|(_, reads)| reads).unwrap_or_default(),
+    }
+}
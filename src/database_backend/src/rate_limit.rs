@@ -0,0 +1,115 @@
+//! Per-principal token buckets, so no single user can flood stable memory or spend the
+//! canister's cycles. Every DM, thread reply, forward, poll and room message takes a send
+//! token; every vetKD key request takes a key token. Tokens come back at a steady rate up to a
+//! fixed burst.
+//!
+//! Buckets live on the heap only. An upgrade refills everyone's bucket, which at worst allows
+//! one extra burst.
+
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Sends allowed back to back
+pub const BURST: u32 = 30;
+// Sends allowed per minute once the burst is used up
+pub const REFILL_PER_MINUTE: u32 = 20;
+
+// Key requests allowed back to back, and per minute after that. Each derivation is paid for
+// in cycles, so clients are expected to cache their keys
+const KEY_BURST: u32 = 5;
+const KEY_REFILL_PER_MINUTE: u32 = 2;
+
+// Tokens are tracked in thousandths so slow refills are not lost to rounding
+const MILLI: u64 = 1_000;
+const MINUTE_NANOS: u64 = 60 * 1_000_000_000;
+
+/// A principal's sending allowance right now
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SendQuota {
+    pub available: u32,
+    pub burst: u32,
+    pub refill_per_minute: u32,
+    pub next_token_in_seconds: Option<u64>, // None while the bucket is full
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    millitokens: u64,
+    updated_at: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Limit {
+    burst: u32,
+    refill_per_minute: u32,
+}
+
+const SENDS: Limit = Limit { burst: BURST, refill_per_minute: REFILL_PER_MINUTE };
+const KEY_REQUESTS: Limit = Limit { burst: KEY_BURST, refill_per_minute: KEY_REFILL_PER_MINUTE };
+
+thread_local! {
+    static BUCKETS: RefCell<HashMap<Principal, Bucket>> = RefCell::new(HashMap::new());
+    static KEY_BUCKETS: RefCell<HashMap<Principal, Bucket>> = RefCell::new(HashMap::new());
+}
+
+fn refilled(limit: Limit, bucket: Option<Bucket>, now: u64) -> Bucket {
+    let capacity = limit.burst as u64 * MILLI;
+    let Some(bucket) = bucket else {
+        return Bucket { millitokens: capacity, updated_at: now };
+    };
+    let elapsed = now.saturating_sub(bucket.updated_at) as u128;
+    let gained = elapsed * (limit.refill_per_minute as u64 * MILLI) as u128 / MINUTE_NANOS as u128;
+    Bucket {
+        millitokens: (bucket.millitokens as u128 + gained).min(capacity as u128) as u64,
+        updated_at: now,
+    }
+}
+
+/// Nanoseconds until `bucket` holds `millitokens`
+fn wait_for(limit: Limit, bucket: &Bucket, millitokens: u64) -> u64 {
+    let missing = millitokens.saturating_sub(bucket.millitokens) as u128;
+    (missing * MINUTE_NANOS as u128).div_ceil((limit.refill_per_minute as u64 * MILLI) as u128) as u64
+}
+
+fn take_from(
+    buckets: &RefCell<HashMap<Principal, Bucket>>,
+    limit: Limit,
+    principal: Principal,
+    tokens: u32,
+    now: u64,
+) -> Result<(), u64> {
+    let cost = tokens.min(limit.burst) as u64 * MILLI;
+    let mut buckets = buckets.borrow_mut();
+    let mut bucket = refilled(limit, buckets.get(&principal).copied(), now);
+    if bucket.millitokens < cost {
+        return Err(wait_for(limit, &bucket, cost).div_ceil(1_000_000_000));
+    }
+    bucket.millitokens -= cost;
+    buckets.insert(principal, bucket);
+    Ok(())
+}
+
+/// Take `sends` tokens from `principal`'s bucket, or leave it untouched and return the
+/// seconds until there are enough. More than a full burst costs a full burst, so a client
+/// catching up after being offline is slowed down rather than locked out
+pub fn take(principal: Principal, sends: u32, now: u64) -> Result<(), u64> {
+    BUCKETS.with(|buckets| take_from(buckets, SENDS, principal, sends, now))
+}
+
+/// Take one key request token from `principal`, or return the seconds until there is one
+pub fn take_key_request(principal: Principal, now: u64) -> Result<(), u64> {
+    KEY_BUCKETS.with(|buckets| take_from(buckets, KEY_REQUESTS, principal, 1, now))
+}
+
+pub fn quota(principal: Principal, now: u64) -> SendQuota {
+    let bucket = refilled(SENDS, BUCKETS.with(|buckets| buckets.borrow().get(&principal).copied()), now);
+    let full = bucket.millitokens >= BURST as u64 * MILLI;
+    let next_token = (bucket.millitokens / MILLI + 1) * MILLI;
+    SendQuota {
+        available: (bucket.millitokens / MILLI) as u32,
+        burst: BURST,
+        refill_per_minute: REFILL_PER_MINUTE,
+        next_token_in_seconds: (!full).then(|| wait_for(SENDS, &bucket, next_token).div_ceil(1_000_000_000)),
+    }
+}
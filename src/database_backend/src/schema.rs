@@ -0,0 +1,202 @@
+use candid::types::{Label, TypeInner};
+use candid::CandidType;
+use serde_json::{json, Map, Value};
+
+use crate::types::{DirectoryPage, Friend, UserProfile, UserSearchResult};
+
+// OpenAPI description of the HTTP facade. Component schemas are derived from the same Candid
+// types the endpoints serialize, so the document cannot drift from the responses.
+
+/// Version of the schema document. Bump the major version when a path or field is removed or
+/// changes type, the minor version when one is added
+pub const SCHEMA_VERSION: &str = "1.6.0";
+
+/// JSON schema of a Candid type, matching how serde_json renders the Rust value
+fn json_schema(ty: &TypeInner) -> Value {
+    match ty {
+        TypeInner::Bool => json!({ "type": "boolean" }),
+        TypeInner::Nat | TypeInner::Nat8 | TypeInner::Nat16 | TypeInner::Nat32 => json!({ "type": "integer", "minimum": 0 }),
+        TypeInner::Nat64 => json!({ "type": "integer", "format": "int64", "minimum": 0 }),
+        TypeInner::Int | TypeInner::Int8 | TypeInner::Int16 | TypeInner::Int32 => json!({ "type": "integer" }),
+        TypeInner::Int64 => json!({ "type": "integer", "format": "int64" }),
+        TypeInner::Float32 => json!({ "type": "number", "format": "float" }),
+        TypeInner::Float64 => json!({ "type": "number", "format": "double" }),
+        TypeInner::Text => json!({ "type": "string" }),
+        TypeInner::Principal => json!({ "type": "string", "format": "principal" }),
+        TypeInner::Opt(inner) => {
+            let mut schema = json_schema(inner);
+            if let Value::Object(fields) = &mut schema {
+                fields.insert("nullable".to_string(), Value::Bool(true));
+            }
+            schema
+        }
+        TypeInner::Vec(inner) => json!({ "type": "array", "items": json_schema(inner) }),
+        TypeInner::Record(fields) if fields.iter().all(|field| matches!(*field.id, Label::Unnamed(_))) => {
+            // Tuples serialize as fixed-length arrays
+            let items: Vec<Value> = fields.iter().map(|field| json_schema(&field.ty)).collect();
+            json!({ "type": "array", "items": { "oneOf": items }, "minItems": items.len(), "maxItems": items.len() })
+        }
+        TypeInner::Record(fields) => {
+            let mut properties = Map::new();
+            let mut required = Vec::new();
+            for field in fields {
+                let name = field_name(&field.id);
+                if !matches!(*field.ty, TypeInner::Opt(_)) {
+                    required.push(Value::String(name.clone()));
+                }
+                properties.insert(name, json_schema(&field.ty));
+            }
+            json!({ "type": "object", "properties": properties, "required": required })
+        }
+        TypeInner::Variant(cases) => {
+            // serde's external tagging: unit cases are plain strings, others {"Case": value}
+            let one_of: Vec<Value> = cases.iter()
+                .map(|case| {
+                    let name = field_name(&case.id);
+                    match *case.ty {
+                        TypeInner::Null => json!({ "type": "string", "enum": [name] }),
+                        _ => json!({
+                            "type": "object",
+                            "properties": { name.clone(): json_schema(&case.ty) },
+                            "required": [name],
+                        }),
+                    }
+                })
+                .collect();
+            json!({ "oneOf": one_of })
+        }
+        // Recursive and non-data types have no useful JSON shape
+        _ => json!({}),
+    }
+}
+
+fn field_name(label: &Label) -> String {
+    match label {
+        Label::Named(name) => name.clone(),
+        Label::Id(id) | Label::Unnamed(id) => id.to_string(),
+    }
+}
+
+fn component<T: CandidType>() -> Value {
+    json_schema(&T::ty())
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn not_found() -> Value {
+    json!({ "description": "No such user", "content": json_content(schema_ref("Error")) })
+}
+
+fn principal_parameter() -> Value {
+    json!({
+        "name": "principal",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string", "format": "principal" },
+    })
+}
+
+/// The OpenAPI 3.0 document served at /api/schema
+pub fn document() -> Value {
+    let search_results = json!({
+        "description": "Matching users, best first",
+        "content": json_content(json!({ "type": "array", "items": schema_ref("UserSearchResult") })),
+    });
+
+    let directory_page = json!({
+        "description": "Listed profiles; follow `next` for the following page",
+        "content": json_content(schema_ref("DirectoryPage")),
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "lain.io social API",
+            "version": SCHEMA_VERSION,
+        },
+        "paths": {
+            "/api/profiles/{principal}": {
+                "get": {
+                    "summary": "User profile (certified)",
+                    "parameters": [principal_parameter()],
+                    "responses": {
+                        "200": { "description": "The profile", "content": json_content(schema_ref("UserProfile")) },
+                        "404": not_found(),
+                    },
+                },
+            },
+            "/api/profiles/{principal}/friends": {
+                "get": {
+                    "summary": "The caller's own friends",
+                    "parameters": [principal_parameter()],
+                    "responses": {
+                        "200": {
+                            "description": "The friends list",
+                            "content": json_content(json!({ "type": "array", "items": schema_ref("Friend") })),
+                        },
+                        "403": { "description": "Not the caller's own list", "content": json_content(schema_ref("Error")) },
+                        "404": not_found(),
+                    },
+                },
+            },
+            "/api/search": {
+                "get": {
+                    "summary": "Search users by display name or handle",
+                    "parameters": [{ "name": "q", "in": "query", "required": true, "schema": { "type": "string" } }],
+                    "responses": { "200": search_results.clone() },
+                },
+                "post": {
+                    "summary": "Search users by display name or handle",
+                    "requestBody": { "required": true, "content": json_content(schema_ref("SearchRequest")) },
+                    "responses": { "200": search_results },
+                },
+            },
+            "/profiles/index": {
+                "get": {
+                    "summary": "First page of the directory of users who opted in to public profiles (certified)",
+                    "responses": { "200": directory_page.clone() },
+                },
+            },
+            "/profiles/index/{page}": {
+                "get": {
+                    "summary": "A page of the public profile directory (certified)",
+                    "parameters": [{ "name": "page", "in": "path", "required": true, "schema": { "type": "integer", "minimum": 1 } }],
+                    "responses": {
+                        "200": directory_page,
+                        "404": { "description": "No such page", "content": json_content(schema_ref("Error")) },
+                    },
+                },
+            },
+            "/api/schema": {
+                "get": {
+                    "summary": "This document (certified)",
+                    "responses": { "200": { "description": "OpenAPI document", "content": json_content(json!({ "type": "object" })) } },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "UserProfile": component::<UserProfile>(),
+                "Friend": component::<Friend>(),
+                "UserSearchResult": component::<UserSearchResult>(),
+                "DirectoryPage": component::<DirectoryPage>(),
+                "SearchRequest": {
+                    "type": "object",
+                    "properties": { "query": { "type": "string" } },
+                    "required": ["query"],
+                },
+                "Error": {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } },
+                    "required": ["error"],
+                },
+            },
+        },
+    })
+}
@@ -2,11 +2,16 @@ use candid::Principal;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
 use std::cell::RefCell;
+use std::collections::HashMap;
 
-use crate::types::{BlockedUser, Friend, FriendRequest, UserProfile, UserDataSync, DmMessages};
+use crate::pair_map::PairMap;
+use crate::types::{ChannelMessage, ChannelImportProgress, BlockedUser, Friend, FriendRequest, UserProfile, HandleClaim, NameHolders, ReservedName, Device, InviteCode, UserDataSync, DmMessages, JournalEntry, ActivityKind, WatchTerm, ModeratorNotification, UserReport, Suspension, Appeal, Ban, TrustRecord, DisappearingChannel, Draft, MessageStar, CustomEmoji, AutoAcceptPolicy, OnboardingState, AuditEntry, AppGrant, Poll, Event, UserNotification, NotificationPreferences, PrivacySettings, UserReactions, ReadMarker, OutboxEntry, Group, GroupInvite, PendingUpload, Attachment};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// (owner, avatar version, chunk index)
+pub type AvatarChunkKey = (Principal, u32, u32);
+
 // Memory IDs for different storage structures
 const USER_PROFILES_MEM_ID: MemoryId = MemoryId::new(0);
 const FRIENDS_MEM_ID: MemoryId = MemoryId::new(1);
@@ -14,6 +19,88 @@ const FRIEND_REQUESTS_MEM_ID: MemoryId = MemoryId::new(2);
 const BLOCKED_USERS_MEM_ID: MemoryId = MemoryId::new(3);
 const USER_DATA_SYNC_MEM_ID: MemoryId = MemoryId::new(4);
 const DM_MESSAGES_MEM_ID: MemoryId = MemoryId::new(5);
+const SCRATCH_MEM_ID: MemoryId = MemoryId::new(6);
+const SETTINGS_MEM_ID: MemoryId = MemoryId::new(7);
+const JOURNAL_ENTRIES_MEM_ID: MemoryId = MemoryId::new(8);
+const MODERATORS_MEM_ID: MemoryId = MemoryId::new(9);
+const WATCH_TERMS_MEM_ID: MemoryId = MemoryId::new(10);
+const MODERATOR_NOTIFICATIONS_MEM_ID: MemoryId = MemoryId::new(11);
+const SUSPENSIONS_MEM_ID: MemoryId = MemoryId::new(12);
+const APPEALS_MEM_ID: MemoryId = MemoryId::new(13);
+const ONBOARDING_MEM_ID: MemoryId = MemoryId::new(14);
+const AUDIT_TRAIL_MEM_ID: MemoryId = MemoryId::new(15);
+const NUMERIC_SETTINGS_MEM_ID: MemoryId = MemoryId::new(16);
+const MESSAGE_CATALOGS_MEM_ID: MemoryId = MemoryId::new(17);
+const PREFERRED_LOCALES_MEM_ID: MemoryId = MemoryId::new(18);
+const APP_GRANTS_MEM_ID: MemoryId = MemoryId::new(19);
+const POLLS_MEM_ID: MemoryId = MemoryId::new(20);
+const POLL_VOTES_MEM_ID: MemoryId = MemoryId::new(21);
+const EVENTS_MEM_ID: MemoryId = MemoryId::new(22);
+const USER_NOTIFICATIONS_MEM_ID: MemoryId = MemoryId::new(23);
+const THREAD_READS_MEM_ID: MemoryId = MemoryId::new(24);
+const NOTIFICATION_PREFERENCES_MEM_ID: MemoryId = MemoryId::new(25);
+const REACTIONS_MEM_ID: MemoryId = MemoryId::new(26);
+const SLOW_MODES_MEM_ID: MemoryId = MemoryId::new(27);
+const LAST_POSTS_MEM_ID: MemoryId = MemoryId::new(28);
+const DM_READ_MARKERS_MEM_ID: MemoryId = MemoryId::new(29);
+const TRUSTED_CANISTERS_MEM_ID: MemoryId = MemoryId::new(30);
+const OUTBOX_MEM_ID: MemoryId = MemoryId::new(31);
+const DEAD_LETTERS_MEM_ID: MemoryId = MemoryId::new(32);
+const GROUPS_MEM_ID: MemoryId = MemoryId::new(33);
+const GROUP_MEMBERS_MEM_ID: MemoryId = MemoryId::new(34);
+const GROUP_INVITES_MEM_ID: MemoryId = MemoryId::new(35);
+const PROFILE_BODY_HASHES_MEM_ID: MemoryId = MemoryId::new(36);
+const ATTACHMENT_CHUNKS_MEM_ID: MemoryId = MemoryId::new(37);
+const CHUNK_REFS_MEM_ID: MemoryId = MemoryId::new(38);
+const PENDING_UPLOADS_MEM_ID: MemoryId = MemoryId::new(39);
+const ATTACHMENTS_MEM_ID: MemoryId = MemoryId::new(40);
+const AVATAR_CHUNKS_MEM_ID: MemoryId = MemoryId::new(41);
+const PUBLIC_PROFILES_MEM_ID: MemoryId = MemoryId::new(42);
+const TRUST_RECORDS_MEM_ID: MemoryId = MemoryId::new(43);
+const UNREAD_COUNTS_MEM_ID: MemoryId = MemoryId::new(44);
+const CONVERSATION_MUTES_MEM_ID: MemoryId = MemoryId::new(45);
+const DISAPPEARING_CHANNELS_MEM_ID: MemoryId = MemoryId::new(46);
+const AUTO_ACCEPT_POLICIES_MEM_ID: MemoryId = MemoryId::new(47);
+const CHANNEL_MESSAGES_MEM_ID: MemoryId = MemoryId::new(48);
+const HISTORY_SHARING_CONSENT_MEM_ID: MemoryId = MemoryId::new(49);
+const ARCHIVED_CHANNELS_MEM_ID: MemoryId = MemoryId::new(50);
+const DRAFTS_MEM_ID: MemoryId = MemoryId::new(51);
+const CUSTOM_EMOJI_MEM_ID: MemoryId = MemoryId::new(52);
+const EMOJI_IMAGES_MEM_ID: MemoryId = MemoryId::new(53);
+const MESSAGE_STARS_MEM_ID: MemoryId = MemoryId::new(54);
+const PRIVACY_SETTINGS_MEM_ID: MemoryId = MemoryId::new(55);
+const USER_REPORTS_MEM_ID: MemoryId = MemoryId::new(56);
+const ADMINS_MEM_ID: MemoryId = MemoryId::new(57);
+const BANS_MEM_ID: MemoryId = MemoryId::new(58);
+const ACTIVITY_LOG_MEM_ID: MemoryId = MemoryId::new(59);
+const FOLLOWING_MEM_ID: MemoryId = MemoryId::new(60);
+const FOLLOWERS_MEM_ID: MemoryId = MemoryId::new(61);
+const FOLLOW_REQUESTS_MEM_ID: MemoryId = MemoryId::new(62);
+const HANDLES_MEM_ID: MemoryId = MemoryId::new(63);
+const DISPLAY_NAMES_MEM_ID: MemoryId = MemoryId::new(64);
+const RESERVED_NAMES_MEM_ID: MemoryId = MemoryId::new(65);
+const DEVICES_MEM_ID: MemoryId = MemoryId::new(66);
+const INVITE_CODES_MEM_ID: MemoryId = MemoryId::new(67);
+const LATEST_APPEALS_MEM_ID: MemoryId = MemoryId::new(68);
+const PENDING_REQUESTS_TO_MEM_ID: MemoryId = MemoryId::new(69);
+const PENDING_REQUESTS_FROM_MEM_ID: MemoryId = MemoryId::new(70);
+const DEVICE_KEYS_MEM_ID: MemoryId = MemoryId::new(71);
+// Last id the memory manager hands out, so real maps never land on it
+#[cfg(feature = "chaos")]
+const CHAOS_BALLAST_MEM_ID: MemoryId = MemoryId::new(254);
+
+// Keys into SETTINGS
+pub const AI_CANISTER_SETTING: u8 = 0;
+
+// Keys into NUMERIC_SETTINGS
+pub const AUDIT_RETENTION_DAYS_SETTING: u8 = 0;
+pub const QUERY_DIAGNOSTICS_SETTING: u8 = 1; // 1 = on
+pub const MAX_FRIENDS_SETTING: u8 = 2;
+pub const MAX_OUTSTANDING_REQUESTS_SETTING: u8 = 3;
+pub const INVITE_ONLY_SETTING: u8 = 4; // 1 = registration needs an invite code
+
+// Bump whenever a stored type or map layout changes incompatibly
+pub const SCHEMA_VERSION: u32 = 1;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
@@ -40,6 +127,20 @@ thread_local! {
         )
     );
 
+    // Pending friend requests by recipient: (to_principal, request_id) -> created_at
+    pub static PENDING_REQUESTS_TO: RefCell<PairMap<Principal, String, u64, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_REQUESTS_TO_MEM_ID)),
+        )
+    );
+
+    // Pending friend requests by sender: (from_principal, request_id) -> created_at
+    pub static PENDING_REQUESTS_FROM: RefCell<PairMap<Principal, String, u64, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_REQUESTS_FROM_MEM_ID)),
+        )
+    );
+
     // Blocked users: (blocker_principal, blocked_principal) -> BlockedUser
     pub static BLOCKED_USERS: RefCell<StableBTreeMap<(Principal, Principal), BlockedUser, Memory>> = RefCell::new(
         StableBTreeMap::init(
@@ -60,4 +161,485 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(DM_MESSAGES_MEM_ID)),
         )
     );
+
+    // Scratch region used by the health self-test (writes from queries are discarded)
+    pub static SCRATCH: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SCRATCH_MEM_ID)),
+        )
+    );
+
+    // Canister-wide settings: setting key -> principal (e.g. the AI canister presence is pushed to)
+    pub static SETTINGS: RefCell<StableBTreeMap<u8, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SETTINGS_MEM_ID)),
+        )
+    );
+
+    // Friend activity: (actor, time) -> what they did. Friends read each other's ranges
+    pub static ACTIVITY_LOG: RefCell<StableBTreeMap<(Principal, u64), ActivityKind, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ACTIVITY_LOG_MEM_ID)),
+        )
+    );
+
+    // Private journals: (owner, entry id) -> JournalEntry
+    pub static JOURNAL_ENTRIES: RefCell<StableBTreeMap<(Principal, u64), JournalEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(JOURNAL_ENTRIES_MEM_ID)),
+        )
+    );
+
+    // Moderators: principal -> granted_at (controllers are always moderators)
+    pub static MODERATORS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MODERATORS_MEM_ID)),
+        )
+    );
+
+    // Admins: principal -> granted_at (controllers are always admins)
+    pub static ADMINS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ADMINS_MEM_ID)),
+        )
+    );
+
+    // Watch terms: normalized term -> WatchTerm
+    pub static WATCH_TERMS: RefCell<StableBTreeMap<String, WatchTerm, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(WATCH_TERMS_MEM_ID)),
+        )
+    );
+
+    // Moderator notifications: id (creation order) -> ModeratorNotification
+    pub static MODERATOR_NOTIFICATIONS: RefCell<StableBTreeMap<u64, ModeratorNotification, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MODERATOR_NOTIFICATIONS_MEM_ID)),
+        )
+    );
+
+    // User reports: id (creation order) -> UserReport
+    pub static USER_REPORTS: RefCell<StableBTreeMap<u64, UserReport, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(USER_REPORTS_MEM_ID)),
+        )
+    );
+
+    // Account suspensions: principal -> Suspension
+    pub static SUSPENSIONS: RefCell<StableBTreeMap<Principal, Suspension, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SUSPENSIONS_MEM_ID)),
+        )
+    );
+
+    // Suspension appeals: id (submission order) -> Appeal
+    pub static APPEALS: RefCell<StableBTreeMap<u64, Appeal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(APPEALS_MEM_ID)),
+        )
+    );
+
+    // Each principal's most recent appeal: principal -> appeal id
+    pub static LATEST_APPEALS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(LATEST_APPEALS_MEM_ID)),
+        )
+    );
+
+    // Account bans: principal -> Ban
+    pub static BANS: RefCell<StableBTreeMap<Principal, Ban, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BANS_MEM_ID)),
+        )
+    );
+
+    // Onboarding checklist: principal -> OnboardingState
+    pub static ONBOARDING: RefCell<StableBTreeMap<Principal, OnboardingState, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ONBOARDING_MEM_ID)),
+        )
+    );
+
+    // Data correction audit trail: (principal, entry id) -> AuditEntry
+    pub static AUDIT_TRAIL: RefCell<StableBTreeMap<(Principal, u64), AuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(AUDIT_TRAIL_MEM_ID)),
+        )
+    );
+
+    // Canister-wide numeric settings: setting key -> value
+    pub static NUMERIC_SETTINGS: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(NUMERIC_SETTINGS_MEM_ID)),
+        )
+    );
+
+    // Localized error messages: (locale, error code) -> message template
+    pub static MESSAGE_CATALOGS: RefCell<PairMap<String, String, String, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MESSAGE_CATALOGS_MEM_ID)),
+        )
+    );
+
+    // Locale each user wants error messages in: principal -> locale tag
+    pub static PREFERRED_LOCALES: RefCell<StableBTreeMap<Principal, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PREFERRED_LOCALES_MEM_ID)),
+        )
+    );
+
+    // Mini-app access grants: (user, app principal) -> AppGrant (revoked grants are kept for the record)
+    pub static APP_GRANTS: RefCell<StableBTreeMap<(Principal, Principal), AppGrant, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(APP_GRANTS_MEM_ID)),
+        )
+    );
+
+    // Polls: id (creation order) -> Poll
+    pub static POLLS: RefCell<StableBTreeMap<u64, Poll, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(POLLS_MEM_ID)),
+        )
+    );
+
+    // Poll votes: (poll id, voter) -> option index
+    pub static POLL_VOTES: RefCell<StableBTreeMap<(u64, Principal), u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(POLL_VOTES_MEM_ID)),
+        )
+    );
+
+    // Events: id (creation order) -> Event
+    pub static EVENTS: RefCell<StableBTreeMap<u64, Event, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(EVENTS_MEM_ID)),
+        )
+    );
+
+    // Notification inbox: (recipient, notification id) -> UserNotification
+    pub static USER_NOTIFICATIONS: RefCell<StableBTreeMap<(Principal, u64), UserNotification, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(USER_NOTIFICATIONS_MEM_ID)),
+        )
+    );
+
+    // Thread read markers: (reader, thread parent message id) -> timestamp of the last reply read
+    pub static THREAD_READS: RefCell<PairMap<Principal, String, u64, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(THREAD_READS_MEM_ID)),
+        )
+    );
+
+    // Notification preferences: principal -> NotificationPreferences (absent = defaults)
+    pub static NOTIFICATION_PREFERENCES: RefCell<StableBTreeMap<Principal, NotificationPreferences, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(NOTIFICATION_PREFERENCES_MEM_ID)),
+        )
+    );
+
+    // Follows: (follower, followed) -> since, and the same pairs the other way round so both
+    // sides list with a range read
+    pub static FOLLOWING: RefCell<StableBTreeMap<(Principal, Principal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FOLLOWING_MEM_ID)),
+        )
+    );
+    pub static FOLLOWERS: RefCell<StableBTreeMap<(Principal, Principal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FOLLOWERS_MEM_ID)),
+        )
+    );
+
+    // Follows waiting for approval: (followed, follower) -> requested_at
+    pub static FOLLOW_REQUESTS: RefCell<StableBTreeMap<(Principal, Principal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FOLLOW_REQUESTS_MEM_ID)),
+        )
+    );
+
+    // Handle index: lowercase handle -> owner. Always matches UserProfile.handle
+    pub static HANDLES: RefCell<StableBTreeMap<String, HandleClaim, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(HANDLES_MEM_ID)),
+        )
+    );
+
+    // Display name index: lowercase display name -> holders, for uniqueness and prefix search.
+    // Names shared by several accounts before the index existed list all of them
+    pub static DISPLAY_NAMES: RefCell<StableBTreeMap<String, NameHolders, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DISPLAY_NAMES_MEM_ID)),
+        )
+    );
+
+    // Display names and handles no one may take: normalized name -> entry
+    pub static RESERVED_NAMES: RefCell<StableBTreeMap<String, ReservedName, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RESERVED_NAMES_MEM_ID)),
+        )
+    );
+
+    // Linked devices: (owner, device id) -> Device, revoked ones included
+    pub static DEVICES: RefCell<StableBTreeMap<(Principal, u64), Device, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DEVICES_MEM_ID)),
+        )
+    );
+
+    // Confirmed device keys: key -> (owner, device id). Kept after revocation so a key is never linked twice
+    pub static DEVICE_KEYS: RefCell<StableBTreeMap<Principal, (Principal, u64), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DEVICE_KEYS_MEM_ID)),
+        )
+    );
+
+    // Invite codes: code -> InviteCode. Used codes stay as the record of who invited whom
+    pub static INVITE_CODES: RefCell<StableBTreeMap<String, InviteCode, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(INVITE_CODES_MEM_ID)),
+        )
+    );
+
+    // Privacy settings: principal -> PrivacySettings (absent = defaults)
+    pub static PRIVACY_SETTINGS: RefCell<StableBTreeMap<Principal, PrivacySettings, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PRIVACY_SETTINGS_MEM_ID)),
+        )
+    );
+
+    // DM reactions: (message id, reactor) -> emojis that user put on the message
+    pub static REACTIONS: RefCell<PairMap<String, Principal, UserReactions, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(REACTIONS_MEM_ID)),
+        )
+    );
+
+    // Slow mode: channel id -> seconds each user must wait between posts
+    pub static SLOW_MODES: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SLOW_MODES_MEM_ID)),
+        )
+    );
+
+    // Last post per user in slow-mode channels: (principal, channel id) -> time
+    pub static LAST_POSTS: RefCell<PairMap<Principal, String, u64, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(LAST_POSTS_MEM_ID)),
+        )
+    );
+
+    // DM read receipts: (reader, dm_channel_id) -> ReadMarker
+    pub static DM_READ_MARKERS: RefCell<PairMap<Principal, String, ReadMarker, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DM_READ_MARKERS_MEM_ID)),
+        )
+    );
+
+    // (reader, dm_channel_id) -> messages from the other participant after the reader's read
+    // marker. Absent = not counted yet (channels from before counting); computed on demand
+    pub static UNREAD_COUNTS: RefCell<PairMap<Principal, String, u32, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(UNREAD_COUNTS_MEM_ID)),
+        )
+    );
+
+    // Muted conversations: (user, channel_id) -> muted until (None = until unmuted).
+    // Expired entries are ignored and dropped on the user's next mute change
+    pub static CONVERSATION_MUTES: RefCell<PairMap<Principal, String, Option<u64>, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CONVERSATION_MUTES_MEM_ID)),
+        )
+    );
+
+    // DM channels with disappearing messages: dm_channel_id -> state. Absent = never turned on
+    pub static DISAPPEARING_CHANNELS: RefCell<StableBTreeMap<String, DisappearingChannel, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DISAPPEARING_CHANNELS_MEM_ID)),
+        )
+    );
+
+    // Friend request auto-accept rules: principal -> policy. Absent = every request waits for an answer
+    pub static AUTO_ACCEPT_POLICIES: RefCell<StableBTreeMap<Principal, AutoAcceptPolicy, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(AUTO_ACCEPT_POLICIES_MEM_ID)),
+        )
+    );
+
+    // Shared chat room log: (channel, zero-padded timestamp + "_" + message id) -> message,
+    // so each room's messages are stored oldest first
+    pub static CHANNEL_MESSAGES: RefCell<PairMap<String, String, ChannelMessage, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CHANNEL_MESSAGES_MEM_ID)),
+        )
+    );
+
+    // Users who agreed to their synced room messages being imported into the shared log:
+    // principal -> agreed at. Absent = not agreed
+    pub static HISTORY_SHARING_CONSENT: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(HISTORY_SHARING_CONSENT_MEM_ID)),
+        )
+    );
+
+    // Conversations a user archived: (user, channel_id) -> archived at. Messages are untouched
+    pub static ARCHIVED_CHANNELS: RefCell<PairMap<Principal, String, u64, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ARCHIVED_CHANNELS_MEM_ID)),
+        )
+    );
+
+    // Starred messages: (user, message_id) -> where the message lives
+    pub static MESSAGE_STARS: RefCell<PairMap<Principal, String, MessageStar, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MESSAGE_STARS_MEM_ID)),
+        )
+    );
+
+    // Unsent message drafts: (user, channel_id) -> draft, shared by all of the user's devices
+    pub static DRAFTS: RefCell<PairMap<Principal, String, Draft, Memory>> = RefCell::new(
+        PairMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DRAFTS_MEM_ID)),
+        )
+    );
+
+    // Canisters allowed to call integration endpoints: canister -> trusted since
+    pub static TRUSTED_CANISTERS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRUSTED_CANISTERS_MEM_ID)),
+        )
+    );
+
+    // Inter-canister notifications awaiting delivery: id -> entry
+    pub static OUTBOX: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(OUTBOX_MEM_ID)),
+        )
+    );
+
+    // Notifications that exhausted their attempts, kept for inspection: id -> entry
+    pub static DEAD_LETTERS: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DEAD_LETTERS_MEM_ID)),
+        )
+    );
+
+    // Groups by id
+    pub static GROUPS: RefCell<StableBTreeMap<u64, Group, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(GROUPS_MEM_ID)),
+        )
+    );
+
+    // Group membership: (group_id, member) -> joined_at
+    pub static GROUP_MEMBERS: RefCell<StableBTreeMap<(u64, Principal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(GROUP_MEMBERS_MEM_ID)),
+        )
+    );
+
+    // Group invite links: token -> invite
+    pub static GROUP_INVITES: RefCell<StableBTreeMap<String, GroupInvite, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(GROUP_INVITES_MEM_ID)),
+        )
+    );
+
+    // sha256 of each user's certified HTTP profile body: principal -> hash
+    pub static PROFILE_BODY_HASHES: RefCell<StableBTreeMap<Principal, [u8; 32], Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PROFILE_BODY_HASHES_MEM_ID)),
+        )
+    );
+
+    // Attachment content: sha256 -> chunk bytes. Identical chunks are stored once
+    pub static ATTACHMENT_CHUNKS: RefCell<StableBTreeMap<[u8; 32], Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ATTACHMENT_CHUNKS_MEM_ID)),
+        )
+    );
+
+    // How many upload/attachment chunk slots point at each stored chunk: sha256 -> count
+    pub static CHUNK_REFS: RefCell<StableBTreeMap<[u8; 32], u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CHUNK_REFS_MEM_ID)),
+        )
+    );
+
+    // Uploads not yet finalized: upload id -> upload
+    pub static PENDING_UPLOADS: RefCell<StableBTreeMap<u64, PendingUpload, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_UPLOADS_MEM_ID)),
+        )
+    );
+
+    // Finalized attachments: id -> attachment
+    pub static ATTACHMENTS: RefCell<StableBTreeMap<u64, Attachment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ATTACHMENTS_MEM_ID)),
+        )
+    );
+
+    // Avatar images: chunk key -> bytes. Holds the live version and
+    // at most one version being uploaded
+    pub static AVATAR_CHUNKS: RefCell<StableBTreeMap<AvatarChunkKey, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(AVATAR_CHUNKS_MEM_ID)),
+        )
+    );
+
+    // Custom emoji and stickers: id -> details. Ids are never reused
+    pub static CUSTOM_EMOJI: RefCell<StableBTreeMap<u64, CustomEmoji, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CUSTOM_EMOJI_MEM_ID)),
+        )
+    );
+
+    // Custom emoji images: id -> bytes
+    pub static EMOJI_IMAGES: RefCell<StableBTreeMap<u64, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(EMOJI_IMAGES_MEM_ID)),
+        )
+    );
+
+    // Users listed in the public web directory: principal -> opted in at. Absent = not listed
+    pub static PUBLIC_PROFILES: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PUBLIC_PROFILES_MEM_ID)),
+        )
+    );
+
+    // Trust tier and its change history per account; absent = Standard with no history
+    pub static TRUST_RECORDS: RefCell<StableBTreeMap<Principal, TrustRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TRUST_RECORDS_MEM_ID)),
+        )
+    );
+
+    // Keyword index over WATCH_TERMS: first word -> terms (as word lists) starting with it.
+    // Heap only, rebuilt lazily after the terms change or the canister upgrades
+    pub static WATCH_TERM_INDEX: RefCell<Option<HashMap<String, Vec<Vec<String>>>>> = const { RefCell::new(None) };
+
+    // Hex sha256 of the installed wasm module, looked up after each install or upgrade.
+    // Heap only: a module cannot embed its own hash at build time
+    pub static MODULE_HASH: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // Channel history import job, if one has been started since the last upgrade
+    pub static CHANNEL_IMPORT: RefCell<Option<ChannelImportProgress>> = const { RefCell::new(None) };
+
+    // Room presence: room_id -> (principal -> last heartbeat). Heap only, rebuilt from heartbeats
+    pub static PRESENCE: RefCell<HashMap<String, HashMap<Principal, u64>>> = RefCell::new(HashMap::new());
+    // The same heartbeats by member: principal -> rooms they are present in
+    pub static PRESENCE_ROOMS: RefCell<HashMap<Principal, Vec<String>>> = RefCell::new(HashMap::new());
+
+    // Online status: principal -> last touch_presence or heartbeat. Heap only, like room presence
+    pub static USER_PRESENCE: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+
+    // DM typing indicators: channel_id -> (principal -> last set_typing). Heap only, short-lived by design
+    pub static TYPING: RefCell<HashMap<String, HashMap<Principal, u64>>> = RefCell::new(HashMap::new());
+}
+
+/// Scratch region the `chaos` feature grows to push the canister up to its storage quota
+#[cfg(feature = "chaos")]
+pub fn chaos_ballast_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CHAOS_BALLAST_MEM_ID))
 }
@@ -9,6 +9,45 @@ pub struct UserSearchResult {
     pub principal: Principal,
     pub display_name: String,
     pub created_at: u64,
+    pub handle: Option<String>,
+    pub matched_field: SearchField,
+    // Text relevance before social ranking: 300 exact, 200 prefix, 100 substring, below 100 a
+    // fuzzy match. Bio matches count half
+    pub score: u32,
+}
+
+// Profile field a search result matched on
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SearchField {
+    DisplayName,
+    Handle,
+    Bio,
+}
+
+// How the caller relates to another user
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Relationship {
+    None,
+    PendingOutgoing,
+    PendingIncoming,
+    Friends,
+    BlockedByMe,
+    BlockedMe,
+}
+
+// "People you may know" entry
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FriendSuggestion {
+    pub principal: Principal,
+    pub display_name: String,
+    pub mutual_friends: u32,
+}
+
+// Per-user caps on friendships and unanswered sent requests
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FriendQuotas {
+    pub max_friends: u64,
+    pub max_outstanding_requests: u64,
 }
 
 // UserProfile matches TypeScript interface
@@ -16,11 +55,197 @@ pub struct UserSearchResult {
 pub struct UserProfile {
     pub principal: Principal,
     pub display_name: String,
-    pub avatar_base64: Option<String>,
+    pub avatar_base64: Option<String>, // Legacy inline avatar, now always None; see `avatar`
     pub bio: Option<String>,
+    pub created_at: u64, // 0 when hidden by the owner's privacy settings
+    pub avatar: Option<AvatarRef>, // Fetch the image with get_avatar_chunk
+    // Unique @handle, stored lowercase without the '@'; None until one is claimed. Left out of
+    // profile pages while unset so pages certified before handles existed stay valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handle: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner: Option<BannerRef>, // Fetch the image with get_banner_chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theme: Option<ProfileTheme>,
+}
+
+// A display name or handle users may not take, set by an admin
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReservedName {
+    pub name: String, // Lowercase letters and digits only; names are compared the same way
+    pub matching: NameMatch,
+    pub added_by: Principal,
+    pub created_at: u64,
+}
+
+impl Storable for ReservedName {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum NameMatch {
+    Exact,    // e.g. "admin": blocks "Admin" and "ad_min" but not "badminton"
+    Contains, // For slurs: blocks any name containing it
+}
+
+// A device or session a user has linked; the sync data it sends is stamped with its id
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Device {
+    pub id: u64, // Per user, never reused
+    pub name: String,
+    pub registered_at: u64,
+    pub last_seen: Option<u64>,  // Last sync from the device
+    pub revoked_at: Option<u64>, // Syncs from or naming a revoked device are refused
+    pub key: Option<Principal>,  // Principal the device signs its syncs with; None for devices linked before keys
+    pub confirmed_at: Option<u64>, // When the device proved it holds `key` with confirm_device
+}
+
+impl Storable for Device {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A single-use registration code, required to register while invite mode is on
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct InviteCode {
+    pub code: String,
+    pub inviter: Principal, // The member it was minted for, or the admin who minted it
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub used_by: Option<Principal>, // The account registered with it
+    pub used_at: Option<u64>,
+}
+
+impl Storable for InviteCode {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Accounts holding one display name in DISPLAY_NAMES. Only names several accounts shared
+// before the index existed have more than one holder
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NameHolders {
+    pub principals: Vec<Principal>,
+}
+
+impl Storable for NameHolders {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Owner of a handle in HANDLES
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HandleClaim {
+    pub principal: Principal,
+    pub claimed_at: u64,
+}
+
+impl Storable for HandleClaim {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Current avatar of a profile; the image lives in AVATAR_CHUNKS. Clients can cache by sha256
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AvatarRef {
+    pub version: u32, // Bumped on every change
+    pub sha256: String, // Hex digest of the whole image
+    pub size: u64,
+    pub chunk_count: u32,
+    pub mime_type: String,
+    pub updated_at: u64,
+}
+
+// Profile banner: an image the owner uploaded as an attachment
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BannerRef {
+    pub attachment_id: u64,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub mime_type: String,
+}
+
+// How a profile page is styled
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProfileTheme {
+    pub accent_color: Option<String>, // "#rrggbb", lowercase
+    pub mode: ThemeMode,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ThemeMode {
+    System,
+    Light,
+    Dark,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum EmojiKind {
+    Emoji,
+    Sticker,
+}
+
+// A custom emoji or sticker. Messages refer to it by id; the image lives in EMOJI_IMAGES
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CustomEmoji {
+    pub id: u64,
+    pub shortcode: String,
+    pub kind: EmojiKind,
+    pub owner: Option<Principal>, // None = global pack, managed by moderators
+    pub mime_type: String,
+    pub size: u64,
+    pub sha256: String, // Hex digest of the image, for client caches
     pub created_at: u64,
 }
 
+impl Storable for CustomEmoji {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 // Chat message for sync
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct ChatMessage {
@@ -29,6 +254,16 @@ pub struct ChatMessage {
     pub sender: String, // 'me' or 'bot'
     pub timestamp: u64,
     pub channel: Option<String>,
+    pub thread_parent_id: Option<String>, // Id of the message this replies to in a thread
+    pub mentions: Option<Vec<Mention>>,   // Resolved by the canister when first synced; client values are ignored
+    pub device_id: Option<u64>,           // Device that first synced the message; set by the canister
+}
+
+// An @handle in a message, resolved to the user it names
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Mention {
+    pub principal: Principal,
+    pub handle: String, // As written, without the '@'
 }
 
 // User data sync payload
@@ -39,6 +274,59 @@ pub struct UserDataSync {
     pub last_sync: u64,
 }
 
+// Everything stored for one user, returned by export_my_data
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DataExport {
+    pub profile: Option<UserProfile>,
+    pub privacy_settings: PrivacySettings,
+    pub friends: Vec<Friend>,
+    pub friend_requests: Vec<FriendRequest>, // Sent and received, whatever their status
+    pub blocked_users: Vec<BlockedUser>,
+    pub following: Vec<FollowEntry>,
+    pub followers: Vec<FollowEntry>,
+    pub direct_messages: Vec<DirectMessage>, // Both sides of every conversation, oldest first per channel
+    pub sync_data: Option<UserDataSync>,
+    pub journal_entries: Vec<JournalEntry>, // Still encrypted with the user's key
+    pub devices: Vec<Device>,
+}
+
+// A message in a shared chat room's or group's log
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ChannelMessage {
+    pub id: String,
+    pub channel: String,
+    pub author: Option<Principal>, // None = Lain
+    pub text: String,
+    pub timestamp: u64,
+    pub imported_from: Option<Principal>, // Set on history imported from this user's synced messages
+    pub mentions: Option<Vec<Mention>>,   // Group members named in a group post; None elsewhere
+}
+
+impl Storable for ChannelMessage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Progress of the job merging consenting users' synced room history into the channel log
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ChannelImportProgress {
+    pub channels: Option<Vec<String>>, // Rooms being imported; None = every room
+    pub next_user: Option<Principal>,  // Users up to and including this one have been scanned
+    pub users_scanned: u64,
+    pub users_consenting: u64,
+    pub messages_imported: u64,
+    pub duplicates_skipped: u64,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+}
+
 // Sync response
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct SyncResponse {
@@ -75,9 +363,11 @@ impl Storable for UserDataSync {
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Friend {
     pub principal: Principal,
-    pub display_name: String,
-    pub avatar_base64: Option<String>,
+    pub display_name: String, // Copied when stored; listings replace it with the current name
+    pub avatar_base64: Option<String>, // Legacy, now always None; fetch the friend's profile avatar
     pub added_at: u64,
+    #[serde(skip_serializing)] // Pins are private; never part of JSON responses
+    pub favorited_at: Option<u64>, // Set while the owner of this edge has the friend pinned
 }
 
 impl Storable for Friend {
@@ -102,6 +392,7 @@ pub struct FriendRequest {
     pub to_display_name: String,
     pub status: FriendRequestStatus,
     pub created_at: u64,
+    pub note: Option<String>, // Short message from the sender
 }
 
 impl Storable for FriendRequest {
@@ -122,6 +413,32 @@ pub enum FriendRequestStatus {
     Pending,
     Accepted,
     Rejected,
+    Expired, // Left unanswered for too long
+}
+
+// A condition under which incoming friend requests are accepted without asking
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AutoAcceptRule {
+    MutualFriends { min: u32 }, // Sender shares at least `min` friends with the user
+    SharedGroup,                // Sender is in one of the user's groups
+}
+
+// Requests matching any rule are accepted as soon as they are sent
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AutoAcceptPolicy {
+    pub rules: Vec<AutoAcceptRule>,
+}
+
+impl Storable for AutoAcceptPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 // BlockedUser matches TypeScript interface
@@ -152,6 +469,38 @@ pub struct DirectMessage {
     pub sender_principal: Principal,
     pub timestamp: u64,
     pub dm_channel_id: String,
+    pub kind: Option<MessageKind>, // None on messages stored before kinds existed (plain text)
+    pub thread_parent_id: Option<String>, // Set on thread replies, which are left out of the main history
+    pub mentions: Option<Vec<Mention>>,
+    pub delivery: Option<DeliveryState>, // None on messages stored before delivery tracking
+    pub attachment_id: Option<u64>, // File sent with the message; fetch it with get_attachment
+    pub forwarded_from: Option<ForwardedFrom>, // Set on copies made by forward_message
+    pub expires_at: Option<u64>, // Sent while the channel had disappearing messages on; purged after this
+}
+
+// Where a forwarded message first came from. Forwarding a forward keeps the first origin
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ForwardedFrom {
+    pub original_sender: Principal,
+    pub original_message_id: String,
+    pub original_timestamp: u64,
+}
+
+// Recipient-side progress of a DM, shown to the sender as ticks. Only ever moves forward
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum DeliveryState {
+    Sent,
+    Delivered, // A recipient device fetched it and called ack_delivered
+    Read, // Covered by the recipient's read marker
+}
+
+// How a client should render a message
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum MessageKind {
+    Text,
+    Poll { poll_id: u64 }, // `text` holds the question; fetch options and tallies with get_poll_results
+    Deleted { deleted_at: u64 }, // Tombstone left by delete_dm; `text` is empty
+    Encrypted { ciphertext: Vec<u8> }, // Sent with send_encrypted_dm; `text` is empty
 }
 
 // Wrapper for storing DM messages in stable storage
@@ -179,28 +528,1082 @@ pub struct DmMessagesResponse {
     pub has_more: bool,
 }
 
-// Response types for API
+// Something a user did that their friends see in the activity feed
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ActivityKind {
+    BecameFriends { friend: Principal },
+    ProfileUpdated,
+    CameOnline,
+}
+
+impl Storable for ActivityKind {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct ApiResponse<T> {
-    pub success: bool,
-    pub data: Option<T>,
-    pub error: Option<String>,
+pub struct ActivityEvent {
+    pub actor: Principal,
+    pub kind: ActivityKind,
+    pub at: u64,
 }
 
-impl<T> ApiResponse<T> {
-    pub fn success(data: T) -> Self {
-        Self {
-            success: true,
-            data: Some(data),
-            error: None,
-        }
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PresenceStatus {
+    Online,
+    Offline,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FriendPresence {
+    pub principal: Principal,
+    pub status: PresenceStatus,
+    pub last_seen: Option<u64>, // None when not seen within the last day
+}
+
+// Private journal entry. The body is encrypted on the client with a vetKD-derived key,
+// so the canister only ever sees ciphertext; journals are never shared with the AI canister
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub ciphertext: Vec<u8>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
     }
 
-    pub fn error(msg: String) -> Self {
-        Self {
-            success: false,
-            data: None,
-            error: Some(msg),
-        }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Response for get_journal_entries with pagination info
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntriesResponse {
+    pub entries: Vec<JournalEntry>,
+    pub has_more: bool,
+}
+
+// Term moderators are alerted about when it appears in channel messages or DMs
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WatchTerm {
+    pub term: String,
+    pub added_by: Principal,
+    pub created_at: u64,
+}
+
+impl Storable for WatchTerm {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Where a watch term was spotted
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AlertSource {
+    ChannelMessage { channel: Option<String> },
+    DirectMessage { dm_channel_id: String },
+    GroupPost { group_id: u64 },
+    UserReport { reported: Principal }, // message_id holds the report id
+}
+
+// Moderator notification raised by a watch term match
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ModeratorNotification {
+    pub id: u64,
+    pub term: String,
+    pub source: AlertSource,
+    pub author: Principal,
+    pub message_id: String,
+    pub context: String, // Words surrounding the match, not the whole message
+    pub created_at: u64,
+    pub acknowledged: bool,
+}
+
+impl Storable for ModeratorNotification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ReportCategory {
+    Spam,
+    Harassment,
+    Impersonation,
+    InappropriateContent,
+    Other,
+}
+
+// Why a user was blocked; passing one to block_user files a report
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BlockReason {
+    pub category: ReportCategory,
+    pub details: Option<String>,
+}
+
+// Report of a user for moderators to review
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserReport {
+    pub id: u64,
+    pub reporter: Principal,
+    pub reported: Principal,
+    pub category: ReportCategory,
+    pub details: Option<String>,
+    pub created_at: u64,
+    pub acknowledged: bool,
+}
+
+impl Storable for UserReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// One page of a cursor-paginated listing. `next_cursor` is an opaque token; pass it back
+// unchanged to get the next page. None means the listing is complete
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+// Account suspension set by an admin
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Suspension {
+    pub principal: Principal,
+    pub reason: String,
+    pub suspended_by: Principal,
+    pub suspended_at: u64,
+    pub until: Option<u64>, // None = until lifted by an admin
+}
+
+impl Storable for Suspension {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AppealStatus {
+    Pending,
+    Upheld,     // Suspension stays in place
+    Overturned, // Suspension lifted
+}
+
+// Suspended user's request to have the suspension reviewed
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Appeal {
+    pub id: u64,
+    pub principal: Principal,
+    pub suspension_reason: String,
+    pub message: String,
+    pub status: AppealStatus,
+    pub submitted_at: u64,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub review_note: Option<String>,
+}
+
+impl Storable for Appeal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Staff roles. Controllers are always admins; admins can do everything moderators can
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Admin,
+    Moderator,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoleAssignment {
+    pub principal: Principal,
+    pub role: Role,
+    pub granted_at: u64,
+}
+
+// Permanent account ban set by an admin; lasts until lifted
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Ban {
+    pub principal: Principal,
+    pub reason: String,
+    pub banned_by: Principal,
+    pub banned_at: u64,
+}
+
+impl Storable for Ban {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// How much an account may reach out to others. Ordered from most to least trusted
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrustTier {
+    Standard,
+    Limited,    // Ranked lower in user search
+    Restricted, // Also cannot send friend requests
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TrustSource {
+    Canister(Principal),  // Abuse signal from a trusted canister (AI moderation)
+    Moderator(Principal),
+}
+
+// One tier change with the reasoning shown to admins
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TrustChange {
+    pub tier: TrustTier,
+    pub reason: String,
+    pub source: TrustSource,
+    pub changed_at: u64,
+}
+
+// Accounts without a record are Standard
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TrustRecord {
+    pub principal: Principal,
+    pub tier: TrustTier,
+    pub history: Vec<TrustChange>, // Oldest first
+}
+
+impl Storable for TrustRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Per-user onboarding checklist so the UI can resume onboarding on any device
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OnboardingState {
+    pub profile_done: bool,
+    pub first_friend_added: bool,
+    pub first_ai_chat: bool,
+    pub dismissed: bool,           // User skipped the rest of the checklist
+    pub updated_at: u64,
+}
+
+impl Storable for OnboardingState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AuditKind {
+    ProfileEdit,   // `field` is the profile field
+    MessageDelete, // `field` is the channel the messages were removed from
+}
+
+// Audit trail entry for a user's own data corrections. Repeated changes to the same field
+// within a short window are compacted into one entry
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub principal: Principal,
+    pub kind: AuditKind,
+    pub field: String,
+    pub previous_value: Option<String>, // Value before the first change (truncated); deleted message ids for deletions
+    pub changes: u32,
+    pub first_at: u64,
+    pub last_at: u64,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Data a third-party mini-app may read on a user's behalf
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AppScope {
+    FriendsListRead,
+    ProfileRead,
+    FriendRequestSend, // Lets the AI canister turn a recommendation into a friend request
+}
+
+impl AppScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppScope::FriendsListRead => "friends-list-read",
+            AppScope::ProfileRead => "profile-read",
+            AppScope::FriendRequestSend => "friend-request-send",
+        }
+    }
+}
+
+// A user's approval for a mini-app principal to read the listed scopes
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AppGrant {
+    pub user: Principal,
+    pub app: Principal,
+    pub app_name: String,
+    pub scopes: Vec<AppScope>,
+    pub granted_at: u64,
+    pub expires_at: Option<u64>, // None = until revoked
+    pub revoked_at: Option<u64>,
+}
+
+impl AppGrant {
+    pub fn allows(&self, scope: AppScope, now: u64) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at.is_none_or(|expires_at| expires_at > now)
+            && self.scopes.contains(&scope)
+    }
+}
+
+impl Storable for AppGrant {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Poll posted into a DM channel. Either participant may vote once until it closes
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Poll {
+    pub id: u64,
+    pub channel_id: String,
+    pub creator: Principal,
+    pub question: String,
+    pub options: Vec<String>,
+    pub created_at: u64,
+    pub closes_at: u64,
+    pub closed: bool,
+}
+
+impl Storable for Poll {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PollResults {
+    pub poll: Poll,
+    pub counts: Vec<u32>, // Votes per option, in option order
+    pub total_votes: u32,
+    pub my_vote: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum RsvpStatus {
+    Pending, // Invited, no answer yet
+    Going,
+    Maybe,
+    Declined,
+}
+
+// Event a user schedules with some of their friends
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub id: u64,
+    pub creator: Principal,
+    pub title: String,
+    pub starts_at: u64,
+    pub invitees: Vec<(Principal, RsvpStatus)>,
+    pub created_at: u64,
+    pub reminded: bool,
+}
+
+impl Storable for Event {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum NotificationKind {
+    EventInvite { event_id: u64 },
+    EventReminder { event_id: u64 },
+    Mention { author: Principal, source: AlertSource, message_id: String },
+    FriendRequestAutoAccepted { request_id: String, friend: Principal }, // Sent to both new friends
+    FriendRequestReceived { request_id: String, from: Principal },
+    FriendRequestAccepted { request_id: String, by: Principal },
+    NewFollower { follower: Principal },
+    FollowRequested { follower: Principal },
+    FollowApproved { by: Principal },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MentionSetting {
+    Everyone,
+    FriendsOnly,
+    Off,
+}
+
+// Which notifications a user wants written to their inbox
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationPreferences {
+    pub mentions: MentionSetting,
+    pub events: bool, // Invites and reminders
+}
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        Self {
+            mentions: MentionSetting::Everyone,
+            events: true,
+        }
+    }
+}
+
+impl Storable for NotificationPreferences {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Who may send a user friend requests
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FriendRequestSetting {
+    Everyone,
+    FriendsOfFriends, // Senders sharing at least one friend
+    Nobody,
+}
+
+// Who may see a profile field
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ProfileVisibility {
+    Public,
+    FriendsOnly,
+    Private,
+}
+
+// Profile field visibility is optional so settings saved before it existed still decode;
+// None means Public
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PrivacySettings {
+    pub friend_requests: FriendRequestSetting,
+    pub bio: Option<ProfileVisibility>,
+    pub avatar: Option<ProfileVisibility>, // Covers the profile banner too
+    pub created_at: Option<ProfileVisibility>,
+    pub approve_followers: Option<bool>, // None means follows take effect right away
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            friend_requests: FriendRequestSetting::Everyone,
+            bio: None,
+            avatar: None,
+            created_at: None,
+            approve_followers: None,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FollowStatus {
+    Following,
+    Requested, // Waiting for the followed user to approve
+}
+
+// One side of a follow, as listed by get_followers, get_following and get_follow_requests
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FollowEntry {
+    pub principal: Principal,
+    pub display_name: String,
+    pub since: u64,
+}
+
+impl Storable for PrivacySettings {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Entry in a user's notification inbox
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserNotification {
+    pub id: u64,
+    pub kind: NotificationKind,
+    pub text: String,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+impl Storable for UserNotification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A thread in a DM channel: its parent message and the replies, oldest first
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ThreadView {
+    pub parent: DirectMessage,
+    pub replies: Vec<DirectMessage>,
+    pub unread_count: u32,
+}
+
+// Thread with replies the caller has not read yet
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UnreadThread {
+    pub dm_channel_id: String,
+    pub parent_id: String,
+    pub unread_count: u32,
+    pub last_reply_at: u64,
+}
+
+// Emojis one user has put on one message
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UserReactions {
+    pub emojis: Vec<String>,
+}
+
+impl Storable for UserReactions {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: u32,
+    pub reacted_by_me: bool,
+}
+
+// Aggregated reactions on one message, emojis in order of first use
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MessageReactions {
+    pub message_id: String,
+    pub reactions: Vec<ReactionCount>,
+}
+
+// How far a participant has read in a DM channel
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReadMarker {
+    pub last_read_message_id: String,
+    pub message_timestamp: u64, // Timestamp of that message, so markers only move forward
+    pub read_at: u64,
+}
+
+impl Storable for ReadMarker {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Read markers of both participants in a DM channel
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReadState {
+    pub mine: Option<ReadMarker>,
+    pub theirs: Option<ReadMarker>,
+}
+
+// Unread messages in one of the caller's DM channels
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UnreadCount {
+    pub dm_channel_id: String,
+    pub friend: Principal,
+    pub unread: u32,
+    pub muted: bool, // Muted conversations are left out of the total
+}
+
+// Disappearing-message state of a DM channel
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DisappearingChannel {
+    pub participants: Vec<Principal>,
+    // None = turned off; kept until the last message sent while it was on has been purged
+    pub lifetime_seconds: Option<u64>,
+}
+
+impl Storable for DisappearingChannel {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A conversation (DM channel or chat room) the user silenced
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConversationMute {
+    pub channel_id: String,
+    pub until: Option<u64>, // None = until unmuted
+}
+
+// A conversation (DM channel or chat room) the user moved out of their default listings
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ArchivedChannel {
+    pub channel_id: String,
+    pub archived_at: u64,
+}
+
+// A message the user starred: (user, message_id) -> where it lives. The message itself is
+// looked up when listed
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MessageStar {
+    pub channel_id: String, // DM channel id or group channel (group:<id>)
+    pub starred_at: u64,
+}
+
+impl Storable for MessageStar {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A starred message as it reads now
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StarredMessage {
+    pub message_id: String,
+    pub channel_id: String,
+    pub author: Option<Principal>, // None = Lain
+    pub text: String,
+    pub timestamp: u64,
+    pub starred_at: u64,
+}
+
+// Unsent text in a conversation (DM channel or chat room), synced across the user's devices
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Draft {
+    pub channel_id: String,
+    pub text: String,
+    pub updated_at: u64,
+}
+
+impl Storable for Draft {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Everything the client needs to render unread badges
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UnreadSummary {
+    pub conversations: Vec<UnreadCount>,
+    pub total: u32, // Unread messages in conversations that are not muted
+}
+
+// Multi-member group; membership lives in GROUP_MEMBERS
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Group {
+    pub id: u64,
+    pub name: String,
+    pub owner: Principal,
+    pub created_at: u64,
+}
+
+impl Storable for Group {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Link token that lets anyone holding it join a group, friend or not
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GroupInvite {
+    pub token: String,
+    pub group_id: u64,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub expires_at: Option<u64>, // None = no expiry (then it must be single-use)
+    pub single_use: bool,
+    pub uses: u32,
+    pub revoked: bool,
+}
+
+impl GroupInvite {
+    pub fn is_usable(&self, now: u64) -> bool {
+        !self.revoked
+            && self.expires_at.is_none_or(|expires_at| expires_at > now)
+            && !(self.single_use && self.uses > 0)
+    }
+}
+
+impl Storable for GroupInvite {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Where a search hit was found
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum MessageSearchSource {
+    DirectMessage { dm_channel_id: String, friend: Principal },
+    Chat { channel: Option<String> },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MessageSnippet {
+    pub id: String,
+    pub text: String,
+    pub sender: String, // Principal text for DMs; 'me' or 'bot' for chat
+    pub timestamp: u64, // As stored: nanoseconds for DMs, client milliseconds for chat
+}
+
+// A message matching search_my_messages, with its neighbours in the same channel
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MessageSearchHit {
+    pub message: MessageSnippet,
+    pub source: MessageSearchSource,
+    pub previous: Option<MessageSnippet>,
+    pub next: Option<MessageSnippet>,
+}
+
+// One listed profile on a public directory page
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DirectoryEntry {
+    pub principal: Principal,
+    pub display_name: String,
+    pub profile_url: String,
+}
+
+// Page of the public profile directory served at /profiles/index
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DirectoryPage {
+    pub page: u32, // 1-based
+    pub page_count: u32,
+    pub total: u64,
+    pub profiles: Vec<DirectoryEntry>,
+    pub next: Option<String>, // Path of the following page
+}
+
+// File upload in progress. Chunks are stored content-addressed in ATTACHMENT_CHUNKS;
+// finalize_upload turns the upload into an Attachment with the same id
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PendingUpload {
+    pub id: u64,
+    pub owner: Principal,
+    pub chunk_hashes: Vec<[u8; 32]>, // sha256 of each chunk, in order
+    pub size: u64,
+    pub started_at: u64,
+}
+
+impl Storable for PendingUpload {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Uploaded file, downloaded chunk by chunk with get_attachment_chunk
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub id: u64,
+    pub owner: Principal,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub chunk_hashes: Vec<[u8; 32]>, // Lets clients verify each downloaded chunk
+    pub readers: Vec<Principal>, // Besides the owner: recipients of DMs it was attached to
+    pub created_at: u64,
+}
+
+impl Attachment {
+    pub fn can_read(&self, principal: &Principal) -> bool {
+        self.owner == *principal || self.readers.contains(principal)
+    }
+}
+
+impl Storable for Attachment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Inter-canister notification waiting for delivery, or dead-lettered after too many failures
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub target: Principal,
+    pub method: String,
+    pub args: Vec<u8>, // Candid-encoded argument tuple
+    pub attempts: u32, // Failed attempts so far
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+}
+
+impl Storable for OutboxEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OutboxStatus {
+    pub pending: Vec<OutboxEntry>,
+    pub dead_letters: Vec<OutboxEntry>,
+}
+
+// Health check report for deployment scripts
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HealthStatus {
+    pub version: String,
+    pub git_commit: String,
+    pub schema_version: u32,
+    pub store_counts: Vec<(String, u64)>,
+    pub self_test_ok: bool,
+    pub checked_at: u64,
+}
+
+// What was compiled into the running module, recorded by build.rs
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BuildInfo {
+    pub package_version: String,
+    pub git_commit: String,
+    pub rustc_version: String,
+    pub target: String,
+    pub profile: String,
+    pub features: Vec<String>,
+    pub dependencies: Vec<(String, String)>, // (crate, version) as locked in Cargo.lock
+    pub build_timestamp: Option<u64>,        // SOURCE_DATE_EPOCH seconds; None if not set
+    pub wasm_module_hash: Option<String>,    // Hex sha256 of the installed module; None until looked up
+}
+
+// Attached to responses from legacy methods that are scheduled for removal
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DeprecationNotice {
+    pub message: String,
+    pub replacement: Option<String>,
+    pub sunset_at: u64,
+}
+
+// Entry in the api_version() deprecation table
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DeprecatedMethod {
+    pub method: String,
+    pub replacement: Option<String>,
+    pub sunset_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApiVersionInfo {
+    pub version: u32,
+    pub min_supported_version: u32,
+    pub deprecated_methods: Vec<DeprecatedMethod>,
+}
+
+// Cost and path of a heavy query, for choosing between query flavors
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct QueryDiagnostics {
+    pub method: String,
+    pub instructions: u64,  // Consumed by the call up to building the reply
+    pub replicated: bool,   // Answered through consensus (certified) rather than by one replica (fast)
+    pub composite: bool,    // Answered by a composite query
+}
+
+// Response types for API
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApiResponse<T> {
+    pub success: bool,
+    pub data: Option<T>,
+    pub error: Option<String>,
+    pub error_code: Option<String>,           // Stable code clients match on; `error` is display text
+    pub error_params: Vec<(String, String)>,  // Values substituted into the message template
+    pub deprecation: Option<DeprecationNotice>,
+    pub suspension: Option<Suspension>,
+    pub diagnostics: Option<QueryDiagnostics>, // Only on heavy queries while diagnostics are on
+}
+
+impl<T> ApiResponse<T> {
+    pub fn success(data: T) -> Self {
+        Self {
+            success: true,
+            data: Some(data),
+            error: None,
+            error_code: None,
+            error_params: Vec::new(),
+            deprecation: None,
+            suspension: None,
+            diagnostics: None,
+        }
+    }
+
+    pub fn coded_error(code: String, params: Vec<(String, String)>, message: String) -> Self {
+        Self {
+            success: false,
+            data: None,
+            error: Some(message),
+            error_code: Some(code),
+            error_params: params,
+            deprecation: None,
+            suspension: None,
+            diagnostics: None,
+        }
+    }
+
+    /// Attach the caller's suspension (reason and expiry) to a rejection
+    pub fn with_suspension(mut self, suspension: Suspension) -> Self {
+        self.suspension = Some(suspension);
+        self
+    }
+
+    pub fn with_deprecation(mut self, notice: DeprecationNotice) -> Self {
+        self.deprecation = Some(notice);
+        self
+    }
+
+    pub fn with_diagnostics(mut self, diagnostics: QueryDiagnostics) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
     }
 }